@@ -0,0 +1,67 @@
+#ifndef DCC_STREAM_H
+#define DCC_STREAM_H
+
+/* Generated by cbindgen from capi/src/lib.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A capture session.  Opaque to C.
+typedef struct DccCapture DccCapture;
+
+// Called from the capture thread for every word, with microseconds since the start
+typedef void (*DccRecordCallback)(void *user, uint64_t timestamp_us, uint32_t value);
+
+// Configure a capture.  Nothing is opened until `dcc_start`.  Returns NULL if `cable` is NULL
+// or not UTF-8, or the configuration is invalid.
+//
+// # Safety
+//
+// `cable` must be NULL or a NUL terminated string.
+struct DccCapture *dcc_open(const char *cable,
+                            uint32_t baud,
+                            uint32_t tap_index,
+                            uint32_t ap_num,
+                            uint32_t debug_base);
+
+// Set the function called for every word.  Must be called before `dcc_start`.
+//
+// # Safety
+//
+// `dcc` must come from `dcc_open`, and `user` must be usable from another thread.
+int32_t dcc_set_callback(struct DccCapture *dcc, DccRecordCallback callback, void *user);
+
+// Open the cable, attach to the core and start calling the callback from a new thread
+//
+// # Safety
+//
+// `dcc` must come from `dcc_open`.
+int32_t dcc_start(struct DccCapture *dcc);
+
+// Whether the capture thread is still running: 1 if it is, 0 once it has ended because of an
+// error that `dcc_stop` returns, or if it wasn't started
+//
+// # Safety
+//
+// `dcc` must come from `dcc_open`.
+int32_t dcc_running(const struct DccCapture *dcc);
+
+// Stop the capture thread and wait for it, returning how the capture ended.  The batch being
+// read is finished and passed to the callback before this returns, and the core is restored
+// to how it was found.
+//
+// # Safety
+//
+// `dcc` must come from `dcc_open`.
+int32_t dcc_stop(struct DccCapture *dcc);
+
+// Stop the capture if it is running and free `dcc`
+//
+// # Safety
+//
+// `dcc` must be NULL or come from `dcc_open`, and must not be used afterwards.
+void dcc_close(struct DccCapture *dcc);
+
+#endif  /* DCC_STREAM_H */
@@ -0,0 +1,208 @@
+//! C interface to dcc-stream, for capturing the DCC in-process from C or C++ test code.  The
+//! header is generated into `include/dcc_stream.h` by the build.
+//!
+//! ```c
+//! static void on_word(void *user, uint64_t timestamp_us, uint32_t value) {
+//!     printf("%llu: %x\n", (unsigned long long)timestamp_us, value);
+//! }
+//!
+//! DccCapture *dcc = dcc_open("jlink", 1000000, 0, 1, 0x80010000);
+//! dcc_set_callback(dcc, on_word, NULL);
+//! dcc_start(dcc);
+//! ...
+//! while (dcc_running(dcc)) {
+//!     ...
+//! }
+//! int status = dcc_stop(dcc);
+//! dcc_close(dcc);
+//! ```
+//!
+//! Functions returning `int32_t` return 0 on success or one of the dcc-stream exit codes.
+use std::ffi::{c_char, c_void, CStr};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use dcc_stream::{CancelToken, DccError, DccStreamBuilder};
+
+/// Called from the capture thread for every word, with microseconds since the start
+pub type DccRecordCallback = Option<extern "C" fn(user: *mut c_void, timestamp_us: u64, value: u32)>;
+
+// Consecutive failed reads before the capture gives up, and the sleep after the first of
+// them, doubled for each one after it
+const MAX_FAILURES: u32 = 8;
+const FAILURE_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Returned when an argument is NULL or the call doesn't make sense in the current state, as
+/// for a command line usage error
+const DCC_EINVAL: i32 = 2;
+
+struct Callback {
+    func: extern "C" fn(*mut c_void, u64, u32),
+    user: *mut c_void,
+}
+
+// The C caller promises that `user` may be used from the capture thread
+unsafe impl Send for Callback {}
+
+/// A capture session.  Opaque to C.
+pub struct DccCapture {
+    builder: DccStreamBuilder,
+    callback: DccRecordCallback,
+    user: *mut c_void,
+    stop: Option<CancelToken>,
+    thread: Option<JoinHandle<Result<(), DccError>>>,
+}
+
+/// Configure a capture.  Nothing is opened until `dcc_start`.  Returns NULL if `cable` is NULL
+/// or not UTF-8, or the configuration is invalid.
+///
+/// # Safety
+///
+/// `cable` must be NULL or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn dcc_open(cable: *const c_char, baud: u32, tap_index: u32, ap_num: u32, debug_base: u32) -> *mut DccCapture {
+    if cable.is_null() {
+        return std::ptr::null_mut();
+    }
+    let Ok(cable) = CStr::from_ptr(cable).to_str() else {
+        return std::ptr::null_mut();
+    };
+    let builder = DccStreamBuilder::new(cable, debug_base)
+        .baud(baud)
+        .tap_index(tap_index as usize)
+        .ap_num(ap_num);
+    if builder.validate().is_err() {
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(DccCapture {
+        builder,
+        callback: None,
+        user: std::ptr::null_mut(),
+        stop: None,
+        thread: None,
+    }))
+}
+
+/// Set the function called for every word.  Must be called before `dcc_start`.
+///
+/// # Safety
+///
+/// `dcc` must come from `dcc_open`, and `user` must be usable from another thread.
+#[no_mangle]
+pub unsafe extern "C" fn dcc_set_callback(dcc: *mut DccCapture, callback: DccRecordCallback, user: *mut c_void) -> i32 {
+    let Some(dcc) = dcc.as_mut() else {
+        return DCC_EINVAL;
+    };
+    if dcc.thread.is_some() {
+        return DCC_EINVAL;
+    }
+    dcc.callback = callback;
+    dcc.user = user;
+    0
+}
+
+/// Open the cable, attach to the core and start calling the callback from a new thread
+///
+/// # Safety
+///
+/// `dcc` must come from `dcc_open`.
+#[no_mangle]
+pub unsafe extern "C" fn dcc_start(dcc: *mut DccCapture) -> i32 {
+    let Some(dcc) = dcc.as_mut() else {
+        return DCC_EINVAL;
+    };
+    let Some(func) = dcc.callback else {
+        return DCC_EINVAL;
+    };
+    if dcc.thread.is_some() {
+        return DCC_EINVAL;
+    }
+    let callback = Callback { func, user: dcc.user };
+    let builder = dcc.builder.clone();
+    let stop = CancelToken::new();
+    dcc.stop = Some(stop.clone());
+    dcc.thread = Some(thread::spawn(move || capture(builder, callback, &stop)));
+    0
+}
+
+fn capture(builder: DccStreamBuilder, callback: Callback, stop: &CancelToken) -> Result<(), DccError> {
+    let mut stream = builder.build()?;
+    stream.attach(stop)?;
+    let mut failures = 0;
+    while !stop.is_cancelled() {
+        // Read errors are retried, until they keep coming
+        match stream.next_record() {
+            Ok(record) => {
+                failures = 0;
+                (callback.func)(callback.user, record.timestamp as u64, record.value);
+            }
+            Err(e) => {
+                failures += 1;
+                if failures == MAX_FAILURES {
+                    let _ = stream.restore(false);
+                    return Err(e);
+                }
+                let _ = stream.clear_sticky();
+                thread::sleep(FAILURE_BACKOFF * (1 << (failures - 1)));
+            }
+        }
+    }
+    for record in stream.drain() {
+        (callback.func)(callback.user, record.timestamp as u64, record.value);
+    }
+    stream.restore(false)
+}
+
+/// Whether the capture thread is still running: 1 if it is, 0 once it has ended because of an
+/// error that `dcc_stop` returns, or if it wasn't started
+///
+/// # Safety
+///
+/// `dcc` must come from `dcc_open`.
+#[no_mangle]
+pub unsafe extern "C" fn dcc_running(dcc: *const DccCapture) -> i32 {
+    let Some(dcc) = dcc.as_ref() else {
+        return 0;
+    };
+    dcc.thread.as_ref().is_some_and(|thread| !thread.is_finished()) as i32
+}
+
+/// Stop the capture thread and wait for it, returning how the capture ended.  The batch being
+/// read is finished and passed to the callback before this returns, and the core is restored
+/// to how it was found.
+///
+/// # Safety
+///
+/// `dcc` must come from `dcc_open`.
+#[no_mangle]
+pub unsafe extern "C" fn dcc_stop(dcc: *mut DccCapture) -> i32 {
+    let Some(dcc) = dcc.as_mut() else {
+        return DCC_EINVAL;
+    };
+    let Some(thread) = dcc.thread.take() else {
+        return DCC_EINVAL;
+    };
+    if let Some(stop) = dcc.stop.take() {
+        stop.cancel();
+    }
+    match thread.join() {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => e.exit_code(),
+        // jtag_adi panics on faults it can't handle itself
+        Err(_) => DccError::AccessFault(String::new()).exit_code(),
+    }
+}
+
+/// Stop the capture if it is running and free `dcc`
+///
+/// # Safety
+///
+/// `dcc` must be NULL or come from `dcc_open`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn dcc_close(dcc: *mut DccCapture) {
+    if dcc.is_null() {
+        return;
+    }
+    dcc_stop(dcc);
+    drop(Box::from_raw(dcc));
+}
@@ -0,0 +1,30 @@
+//! Feed arbitrary target output to the text decoder: `cargo +nightly fuzz run decode_text`.  The
+//! seeds in corpus/decode_text are raw captures as written by `--format raw`.
+#![no_main]
+
+use dcc_stream::decode::{DecoderKind, FrameData, MAX_FRAME};
+use dcc_stream::Record;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = DecoderKind::Text.build();
+    let mut frames = vec![];
+    for (i, word) in data.chunks(4).enumerate() {
+        let mut bytes = [0; 4];
+        bytes[..word.len()].copy_from_slice(word);
+        let record = Record {
+            timestamp: i as u128,
+            value: u32::from_le_bytes(bytes),
+            interpolated: false,
+        };
+        decoder.push(&record, &mut frames);
+    }
+    decoder.finish(&mut frames);
+    for frame in frames {
+        match frame.data {
+            FrameData::Text(text) => assert!(text.len() <= MAX_FRAME * 3),
+            FrameData::Bytes(bytes) => assert!(bytes.len() <= MAX_FRAME),
+            _ => {}
+        }
+    }
+});
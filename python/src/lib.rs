@@ -0,0 +1,144 @@
+//! Python bindings, built with maturin as the `dccstream` module:
+//!
+//! ```python
+//! import dccstream
+//!
+//! dcc = dccstream.connect("jlink", 0x80010000, baud=4000000)
+//! dcc.write_mem(0x40001000, 1)
+//! for timestamp, value in dcc:
+//!     if value == 0xdeadbeef:
+//!         break
+//! dcc.close()
+//! ```
+//!
+//! The capture holds the GIL while it waits on the cable, so use it from one thread.
+use clap::ValueEnum;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+use dcc_stream::{Arch, CancelToken, DccStream, DccStreamBuilder};
+
+create_exception!(dccstream, DccError, PyException, "A dcc-stream operation failed");
+
+fn to_py(e: dcc_stream::DccError) -> PyErr {
+    DccError::new_err(e.to_string())
+}
+
+/// An attached DCC capture.  Iterating yields `(timestamp_us, value)` tuples forever.
+#[pyclass(name = "DccStream", unsendable)]
+struct PyDccStream {
+    dcc: Option<DccStream>,
+}
+
+impl PyDccStream {
+    fn dcc(&mut self) -> PyResult<&mut DccStream> {
+        self.dcc.as_mut().ok_or_else(|| DccError::new_err("stream is closed"))
+    }
+}
+
+#[pymethods]
+impl PyDccStream {
+    /// Read `count` records, returning a list of `(timestamp_us, value)` tuples
+    fn read(&mut self, py: Python<'_>, count: usize) -> PyResult<Vec<(u128, u32)>> {
+        let mut records = Vec::with_capacity(count);
+        for _ in 0..count {
+            records.push(self.__next__(py)?);
+        }
+        Ok(records)
+    }
+
+    /// Return the records already read from the target but not yet returned, e.g. before
+    /// closing
+    fn drain(&mut self) -> PyResult<Vec<(u128, u32)>> {
+        let records = self.dcc()?.drain();
+        Ok(records.into_iter().map(|r| (r.timestamp, r.value)).collect())
+    }
+
+    /// Read a word from the debug AP's address space
+    fn read_mem(&mut self, addr: u32) -> PyResult<u32> {
+        self.dcc()?.read_mem(addr).map_err(to_py)
+    }
+
+    /// Write a word to the debug AP's address space
+    fn write_mem(&mut self, addr: u32, value: u32) -> PyResult<()> {
+        self.dcc()?.write_mem(addr, value).map_err(to_py)
+    }
+
+    /// Restore the core to how it was found and release the cable
+    #[pyo3(signature = (relock=false))]
+    fn close(&mut self, relock: bool) -> PyResult<()> {
+        match self.dcc.take() {
+            Some(mut dcc) => dcc.restore(relock).map_err(to_py),
+            None => Ok(()),
+        }
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<(u128, u32)> {
+        // Let Ctrl-C through between records
+        py.check_signals()?;
+        let record = self.dcc()?.next_record().map_err(to_py)?;
+        Ok((record.timestamp, record.value))
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _ty: Option<Bound<'_, PyAny>>,
+        _value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        self.close(false)
+    }
+}
+
+/// Open the cable and attach to the core whose debug registers are at `debug_base`.  `arch` is
+/// named as for the CLI's --arch, `armv7` or `armv8`.
+#[pyfunction]
+#[pyo3(signature = (cable, debug_base, baud=1_000_000, tap_index=0, ap_num=1, arch="armv7", queue_size=16, nodups=false))]
+#[allow(clippy::too_many_arguments)]
+fn connect(
+    cable: &str,
+    debug_base: u32,
+    baud: u32,
+    tap_index: usize,
+    ap_num: u32,
+    arch: &str,
+    queue_size: usize,
+    nodups: bool,
+) -> PyResult<PyDccStream> {
+    let arch = Arch::from_str(arch, true).map_err(|_| {
+        let names: Vec<_> = Arch::value_variants()
+            .iter()
+            .filter_map(|a| a.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        PyValueError::new_err(format!("arch {} isn't one of {}", arch, names.join(", ")))
+    })?;
+    let mut dcc = DccStreamBuilder::new(cable, debug_base)
+        .baud(baud)
+        .tap_index(tap_index)
+        .ap_num(ap_num)
+        .arch(arch)
+        .queue_size(queue_size)
+        .nodups(nodups)
+        .build()
+        .map_err(to_py)?;
+    dcc.attach(&CancelToken::new()).map_err(to_py)?;
+    Ok(PyDccStream { dcc: Some(dcc) })
+}
+
+#[pymodule]
+fn dccstream(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_class::<PyDccStream>()?;
+    m.add("DccError", m.py().get_type::<DccError>())?;
+    Ok(())
+}
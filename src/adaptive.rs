@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+// Batches slower than this hold up the output, so the queue shrinks even if data is plentiful
+const MAX_BATCH_LATENCY: Duration = Duration::from_millis(50);
+// Grow when fewer than this fraction of a batch are stale, shrink when more than STALE_SHRINK
+const STALE_GROW: f64 = 0.1;
+const STALE_SHRINK: f64 = 0.5;
+// With rate tuning, batches are sized and spaced to hold this much of the target's output
+const BATCH_PERIOD: Duration = Duration::from_millis(10);
+// Weight of the newest batch in the rate estimate
+const RATE_ALPHA: f64 = 0.2;
+
+/// What `AdaptiveQueue` sizes batches by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Tuning {
+    /// Double or halve by the fraction of the last batch that was stale
+    Stale,
+    /// Fit the estimated production rate of the target, and wait between batches when it
+    /// can't fill the smallest one
+    Rate,
+}
+
+/// Number of words that aren't repeats of the one before
+fn fresh(last: &mut Option<u32>, words: &[u32], txfull: bool) -> usize {
+    if txfull {
+        return words.len();
+    }
+    let mut fresh = 0;
+    for &word in words {
+        if *last != Some(word) {
+            fresh += 1;
+        }
+        *last = Some(word);
+    }
+    fresh
+}
+
+/// Exponentially weighted estimate of how many words per second the target is sending
+pub struct RateEstimate {
+    rate: f64,
+    txfull: bool,
+    last: Option<u32>,
+    last_start: Option<u128>,
+}
+
+impl RateEstimate {
+    pub fn new(txfull: bool) -> Self {
+        Self {
+            rate: 0.0,
+            txfull,
+            last: None,
+            last_start: None,
+        }
+    }
+
+    /// Account for `words` from a batch started at `start` microseconds, returning the new
+    /// estimate
+    pub fn update(&mut self, start: u128, words: &[u32]) -> f64 {
+        let fresh = fresh(&mut self.last, words, self.txfull) as f64;
+        if let Some(prev) = self.last_start.filter(|prev| *prev < start) {
+            let rate = fresh * 1e6 / (start - prev) as f64;
+            self.rate = RATE_ALPHA * rate + (1.0 - RATE_ALPHA) * self.rate;
+        }
+        self.last_start = Some(start);
+        self.rate
+    }
+}
+
+/// Picks the next batch size from how the last batch went, see `Tuning`
+#[derive(Clone)]
+pub struct AdaptiveQueue {
+    tuning: Tuning,
+    min: usize,
+    max: usize,
+    /// Batches only hold the words DSCR.TXfull showed, so stale reads are the missing ones
+    txfull: bool,
+    last: Option<u32>,
+}
+
+impl AdaptiveQueue {
+    pub fn new(tuning: Tuning, min: usize, max: usize, txfull: bool) -> Self {
+        Self {
+            tuning,
+            min,
+            max,
+            txfull,
+            last: None,
+        }
+    }
+
+    /// The size to use after reading `words` with a batch of `size`, which took `elapsed`
+    /// while the target was sending `rate` words per second
+    pub fn update(&mut self, size: usize, words: &[u32], elapsed: Duration, rate: f64) -> usize {
+        if self.tuning == Tuning::Rate {
+            let next = if elapsed > MAX_BATCH_LATENCY {
+                size / 2
+            } else {
+                (rate * BATCH_PERIOD.as_secs_f64()).ceil() as usize
+            };
+            return next.clamp(self.min, self.max);
+        }
+        let ratio = if self.txfull {
+            (size - words.len().min(size)) as f64 / size as f64
+        } else if words.is_empty() {
+            return size;
+        } else {
+            let stale = words.len() - fresh(&mut self.last, words, false);
+            stale as f64 / words.len() as f64
+        };
+        let next = if ratio > STALE_SHRINK || elapsed > MAX_BATCH_LATENCY {
+            size / 2
+        } else if ratio < STALE_GROW {
+            size * 2
+        } else {
+            size
+        };
+        next.clamp(self.min, self.max)
+    }
+
+    /// How long to wait before reading the next batch of `size`, which took `elapsed`, so it
+    /// isn't read before the target has had time to fill it
+    pub fn poll_sleep(&self, size: usize, elapsed: Duration, rate: f64) -> Duration {
+        if self.tuning != Tuning::Rate {
+            return Duration::ZERO;
+        }
+        let fill = if rate > 0.0 {
+            Duration::from_secs_f64((size as f64 / rate).min(BATCH_PERIOD.as_secs_f64()))
+        } else {
+            BATCH_PERIOD
+        };
+        fill.saturating_sub(elapsed)
+    }
+}
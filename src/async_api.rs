@@ -0,0 +1,94 @@
+//! Async capture for tokio users, behind the `async` feature.
+//!
+//! JTAG transfers block, so the capture itself runs on tokio's blocking thread pool and hands
+//! words over a channel.  Cancelling the token ends the stream once the records already read
+//! have been passed on; dropping the stream ends the capture straight away.  Either way the
+//! core is restored.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), dcc_stream::DccError> {
+//! use dcc_stream::{async_api, CancelToken, DccStreamBuilder};
+//!
+//! let stop = CancelToken::new();
+//! let records = async_api::capture(DccStreamBuilder::new("jlink", 0x80010000), stop.clone());
+//! let mut log = Vec::new();
+//! async_api::write_text(records, &mut log).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::panic::{self, AssertUnwindSafe};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::builder::DccStreamBuilder;
+use crate::cancel::CancelToken;
+use crate::error::DccError;
+use crate::stream::Record;
+
+// Records buffered between the capture thread and the consumer
+const CHANNEL_DEPTH: usize = 1024;
+
+/// Build the stream, attach and pass on its records.  Failed reads are passed on as errors and
+/// the capture carries on; failing to open or attach ends the stream.
+///
+/// Must be called from within a tokio runtime.
+pub fn capture(builder: DccStreamBuilder, stop: CancelToken) -> impl Stream<Item = Result<Record, DccError>> {
+    let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+    tokio::task::spawn_blocking(move || {
+        // jtag_adi panics on faults it can't handle itself
+        if panic::catch_unwind(AssertUnwindSafe(|| run_capture(&tx, builder, &stop))).is_err() {
+            let _ = tx.blocking_send(Err(DccError::AccessFault("panic in debug transport".to_string())));
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+fn run_capture(tx: &Sender<Result<Record, DccError>>, builder: DccStreamBuilder, stop: &CancelToken) {
+    let mut dcc = match builder.build() {
+        Ok(dcc) => dcc,
+        Err(e) => {
+            let _ = tx.blocking_send(Err(e));
+            return;
+        }
+    };
+    if let Err(e) = dcc.attach(stop) {
+        let _ = tx.blocking_send(Err(e));
+        return;
+    }
+
+    let mut open = true;
+    while open && !stop.is_cancelled() {
+        open = tx.blocking_send(dcc.next_record()).is_ok();
+    }
+    if open {
+        for record in dcc.drain() {
+            if tx.blocking_send(Ok(record)).is_err() {
+                break;
+            }
+        }
+    }
+    let _ = dcc.restore(false);
+}
+
+/// Write `records` to `out` in the same `timestamp: value` text format as the command line
+/// tool, stopping at the first error.  Returns the number of records written.
+pub async fn write_text<S, W>(records: S, mut out: W) -> Result<u64, DccError>
+where
+    S: Stream<Item = Result<Record, DccError>>,
+    W: AsyncWrite + Unpin,
+{
+    tokio::pin!(records);
+    let mut written = 0;
+    while let Some(record) = records.next().await {
+        let record = record?;
+        out.write_all(format!("{}: {:x}\n", record.timestamp, record.value).as_bytes())
+            .await
+            .map_err(|e| DccError::Io("write".to_string(), e))?;
+        written += 1;
+    }
+    out.flush().await.map_err(|e| DccError::Io("write".to_string(), e))?;
+    Ok(written)
+}
@@ -0,0 +1,146 @@
+//! `dcc-stream bench`: read at a range of batch sizes and report which gives the most
+//! throughput through this cable to this target
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use dcc_stream::{parse_duration, parse_u32, Arch, CancelToken, DccError, DccStreamBuilder, MAX_QUEUE_SIZE};
+
+#[derive(Parser, Debug)]
+#[command(bin_name = "dcc-stream bench", about = "Measure read throughput at a range of --queue-size values")]
+pub struct BenchArgs {
+    #[arg(short, long, env = "DCC_CABLE")]
+    cable: String,
+    #[arg(short, long, env = "DCC_BAUD")]
+    baud: u32,
+    #[arg(short, long, default_value_t = 0, env = "DCC_TAP_INDEX")]
+    /// Which JTAG TAP to use
+    tap_index: usize,
+    #[arg(short, long, default_value_t = 1, env = "DCC_AP_NUM")]
+    /// Which access port to use
+    ap_num: u32,
+    #[arg(long, value_enum, default_value_t = Arch::Armv7, env = "DCC_ARCH")]
+    /// Debug architecture of the core
+    arch: Arch,
+    #[arg(long, default_value_t = false)]
+    /// Read DSCR with each DTRTX read, as for streaming with --txfull
+    txfull: bool,
+    #[arg(long, value_delimiter = ',', default_value = "1,2,4,8,16,32,64,128,256,512,1024")]
+    /// Batch sizes to try
+    sizes: Vec<usize>,
+    #[arg(long, default_value = "2s", value_parser = parse_duration)]
+    /// How long to read at each size
+    time: Duration,
+    #[arg(long, default_value_t = false)]
+    /// The target is writing an incrementing counter, so words it wrote that weren't read can
+    /// be counted
+    generator: bool,
+    #[arg(value_parser = parse_u32, env = "DCC_DEBUG_BASE")]
+    /// CPU debug base address, prefix with 0x for hexadecimal
+    debug_base: u32,
+}
+
+/// What one batch size achieved
+struct Run {
+    size: usize,
+    reads: u64,
+    fresh: u64,
+    missed: u64,
+    errors: u64,
+    secs: f64,
+}
+
+impl Run {
+    /// Words per second that were worth reading, or DTRTX reads per second while the target
+    /// is quiet
+    fn score(&self) -> f64 {
+        let n = if self.fresh > 0 { self.fresh } else { self.reads };
+        n as f64 / self.secs
+    }
+}
+
+pub fn run(args: &BenchArgs, stop: &CancelToken) -> Result<(), DccError> {
+    if let Some(size) = args.sizes.iter().find(|&&s| s == 0 || s > MAX_QUEUE_SIZE) {
+        return Err(DccError::InvalidConfig(format!(
+            "bench size {} is not between 1 and {}",
+            size, MAX_QUEUE_SIZE
+        )));
+    }
+    let mut dcc = DccStreamBuilder::new(args.cable.clone(), args.debug_base)
+        .baud(args.baud)
+        .tap_index(args.tap_index)
+        .ap_num(args.ap_num)
+        .arch(args.arch)
+        .txfull(args.txfull)
+        .build()?;
+    dcc.attach(stop)?;
+
+    println!("{:>6} {:>12} {:>12} {:>8} {:>8}", "queue", "reads/s", "new/s", "missed", "errors");
+    let mut best: Option<Run> = None;
+    for &size in &args.sizes {
+        if stop.is_cancelled() {
+            break;
+        }
+        let mut run = Run {
+            size,
+            reads: 0,
+            fresh: 0,
+            missed: 0,
+            errors: 0,
+            secs: 0.0,
+        };
+        let mut last = None;
+        let start = Instant::now();
+        while start.elapsed() < args.time && !stop.is_cancelled() {
+            let words = match dcc.read(size) {
+                Ok(words) => words,
+                Err(_) => {
+                    run.errors += 1;
+                    continue;
+                }
+            };
+            run.reads += size as u64;
+            for word in words {
+                if args.txfull || last != Some(word) {
+                    run.fresh += 1;
+                    if let Some(prev) = last.filter(|_| args.generator) {
+                        run.missed += word.wrapping_sub(prev).wrapping_sub(1) as u64;
+                    }
+                }
+                last = Some(word);
+            }
+        }
+        run.secs = start.elapsed().as_secs_f64();
+        println!(
+            "{:>6} {:>12.0} {:>12.0} {:>8} {:>8}",
+            size,
+            run.reads as f64 / run.secs,
+            run.fresh as f64 / run.secs,
+            run.missed,
+            run.errors
+        );
+        // With a generator, a size that loses words can't be the best one
+        let lossless = run.missed == 0 || !args.generator;
+        if lossless && best.as_ref().is_none_or(|b| run.score() > b.score()) {
+            best = Some(run);
+        }
+    }
+    dcc.restore(false)?;
+    if stop.is_cancelled() {
+        return Err(DccError::Interrupted);
+    }
+
+    match best {
+        Some(best) => {
+            println!(
+                "Best --queue-size for {} at {} baud: {} ({:.0} words/s)",
+                args.cable,
+                args.baud,
+                best.size,
+                best.score()
+            );
+            Ok(())
+        }
+        None => Err(DccError::Other("no batch size read without losing words".to_string())),
+    }
+}
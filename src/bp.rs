@@ -0,0 +1,109 @@
+//! `dcc-stream bp`: set and clear the core's hardware breakpoints, so a capture can be bounded
+//! by code locations without a debugger taking the cable.  With --cable share:SOCKET this can
+//! run alongside the capture.
+use clap::{Parser, Subcommand};
+
+use dcc_stream::run_control;
+use dcc_stream::{parse_u32, Arch, DccError, DccStream, DccStreamBuilder};
+
+use crate::lock;
+
+#[derive(Parser, Debug)]
+#[command(bin_name = "dcc-stream bp", about = "Set, clear and list the core's hardware breakpoints")]
+pub struct BpArgs {
+    #[arg(short, long, env = "DCC_CABLE")]
+    cable: String,
+    #[arg(short, long, env = "DCC_BAUD")]
+    baud: u32,
+    #[arg(long, default_value_t = false, env = "DCC_RTCK")]
+    /// Clock TCK from the target's RTCK, falling back to --baud
+    rtck: bool,
+    #[arg(short, long, default_value_t = 0, env = "DCC_TAP_INDEX")]
+    /// Which JTAG TAP to use
+    tap_index: usize,
+    #[arg(short, long, default_value_t = 1, env = "DCC_AP_NUM")]
+    /// Which access port to use
+    ap_num: u32,
+    #[arg(long, value_enum, default_value_t = Arch::Armv7, env = "DCC_ARCH")]
+    /// Debug architecture of the core
+    arch: Arch,
+    #[arg(short, long, value_parser = parse_u32, env = "DCC_DEBUG_BASE")]
+    /// CPU debug base address, prefix with 0x for hexadecimal
+    debug_base: u32,
+    #[arg(long, default_value_t = false)]
+    /// Don't take the cable lock
+    no_lock: bool,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Halt the core when it executes the instruction at ADDR
+    Set {
+        #[arg(value_parser = parse_u32)]
+        addr: u32,
+        #[arg(long)]
+        /// Which breakpoint to use, by default the first free one
+        index: Option<usize>,
+    },
+    /// Clear one breakpoint, or all of them
+    Clear {
+        #[arg(long)]
+        index: Option<usize>,
+    },
+    /// Show the breakpoints that are set
+    List,
+}
+
+pub fn run(args: &BpArgs) -> Result<(), DccError> {
+    // The server of a shared cable holds its lock
+    let shared = args.cable.starts_with("share:");
+    let _lock = if args.no_lock || shared {
+        None
+    } else {
+        Some(lock::lock(&args.cable)?)
+    };
+    let mut dcc = DccStreamBuilder::new(args.cable.clone(), args.debug_base)
+        .baud(args.baud)
+        .rtck(args.rtck)
+        .tap_index(args.tap_index)
+        .ap_num(args.ap_num)
+        .arch(args.arch)
+        .build()?;
+    dcc.check_powered()?;
+    dcc.clear_os_lock()?;
+    match args.action {
+        Action::Set { addr, index } => {
+            let index = match index {
+                Some(index) => index,
+                None => run_control::breakpoints(&mut dcc)?
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or_else(|| DccError::Other("every hardware breakpoint is in use".to_string()))?,
+            };
+            run_control::set_breakpoint(&mut dcc, index, addr)?;
+            println!("breakpoint {} at 0x{:x}", index, addr);
+        }
+        Action::Clear { index: Some(index) } => run_control::clear_breakpoint(&mut dcc, index)?,
+        Action::Clear { index: None } => {
+            for index in 0..run_control::breakpoint_count(&mut dcc)? {
+                run_control::clear_breakpoint(&mut dcc, index)?;
+            }
+        }
+        Action::List => list(&mut dcc)?,
+    }
+    Ok(())
+}
+
+fn list(dcc: &mut DccStream) -> Result<(), DccError> {
+    let breakpoints = run_control::breakpoints(dcc)?;
+    for (index, addr) in breakpoints.iter().enumerate() {
+        if let Some(addr) = addr {
+            println!("breakpoint {} at 0x{:x}", index, addr);
+        }
+    }
+    let free = breakpoints.iter().filter(|addr| addr.is_none()).count();
+    println!("{} of {} free", free, breakpoints.len());
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::clock::SharedClock;
+use crate::error::DccError;
+use crate::init::Step;
+use crate::jtag::JtagPort;
+#[cfg(unix)]
+use crate::share::{self, SharedPort};
+use crate::sim::{self, SimPort};
+use crate::stream::{DccStream, Target};
+use crate::transport::{DebugPort, RetryPolicy};
+
+/// Batches much larger than this only delay output
+pub const MAX_QUEUE_SIZE: usize = 4096;
+
+/// Debug architecture of the core, which decides how it is prepared for streaming
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Arch {
+    /// ARMv7 debug: DSCR.ExtDCCmode is set to stall mode so reads wait for the target
+    #[default]
+    Armv7,
+    /// ARMv8 external debug, which has no stall mode so DTRTX is simply polled
+    Armv8,
+}
+
+impl Arch {
+    pub fn has_stall_mode(self) -> bool {
+        self == Arch::Armv7
+    }
+}
+
+/// Configuration for a `DccStream`, checked before anything is opened
+///
+/// ```no_run
+/// use dcc_stream::DccStreamBuilder;
+///
+/// let mut dcc = DccStreamBuilder::new("jlink", 0x80010000)
+///     .baud(4_000_000)
+///     .queue_size(64)
+///     .nodups(true)
+///     .build()?;
+/// # Ok::<(), dcc_stream::DccError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct DccStreamBuilder {
+    target: Target,
+    arch: Arch,
+    queue_size: usize,
+    nodups: bool,
+    txfull: bool,
+    attach_only: bool,
+    dscr_timeout: Option<Duration>,
+    idcodes: Vec<u32>,
+    init: Vec<Step>,
+    /// AP and debug base of each core streamed alongside the first by `build_all`
+    cores: Vec<(u32, u32)>,
+}
+
+impl DccStreamBuilder {
+    /// Stream from the core whose debug registers are at `debug_base`, through `cable`.  The
+    /// rest defaults to 1MHz, TAP 0, AP 1, ARMv7, 16 word batches and keeping duplicates.
+    pub fn new(cable: impl Into<String>, debug_base: u32) -> Self {
+        Self {
+            target: Target {
+                cable: cable.into(),
+                baud: 1_000_000,
+                tap_index: 0,
+                ap_num: 1,
+                debug_base,
+                retry: RetryPolicy::default(),
+                rtck: false,
+                clock: None,
+            },
+            arch: Arch::default(),
+            queue_size: 16,
+            nodups: false,
+            txfull: false,
+            attach_only: false,
+            dscr_timeout: None,
+            idcodes: vec![],
+            init: vec![],
+            cores: vec![],
+        }
+    }
+
+    pub fn baud(mut self, baud: u32) -> Self {
+        self.target.baud = baud;
+        self
+    }
+
+    pub fn tap_index(mut self, tap_index: usize) -> Self {
+        self.target.tap_index = tap_index;
+        self
+    }
+
+    pub fn ap_num(mut self, ap_num: u32) -> Self {
+        self.target.ap_num = ap_num;
+        self
+    }
+
+    /// Ask for adaptive clocking from the target's RTCK.  Cables that can't do it, and targets
+    /// whose IDCODE can't be read with it, are clocked at the baud instead; see
+    /// `DccStream::adaptive_clocking`.
+    pub fn rtck(mut self, rtck: bool) -> Self {
+        self.target.rtck = rtck;
+        self
+    }
+
+    /// How the transport retries WAIT acknowledgements
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.target.retry = retry;
+        self
+    }
+
+    /// Take time from `clock` rather than the wall clock, for record timestamps and the
+    /// simulator, see `VirtualClock`
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.target.clock = Some(clock);
+        self
+    }
+
+    pub fn arch(mut self, arch: Arch) -> Self {
+        self.arch = arch;
+        self
+    }
+
+    /// Words read per batch
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Skip words equal to the one before them
+    pub fn nodups(mut self, nodups: bool) -> Self {
+        self.nodups = nodups;
+        self
+    }
+
+    /// Check DSCR.TXfull rather than relying on stall mode or repeats, see
+    /// `DccStream::set_txfull`
+    pub fn txfull(mut self, txfull: bool) -> Self {
+        self.txfull = txfull;
+        self
+    }
+
+    /// Leave the core's configuration alone, see `DccStream::set_attach_only`.  Init steps
+    /// can't be used with it.
+    pub fn attach_only(mut self, attach_only: bool) -> Self {
+        self.attach_only = attach_only;
+        self
+    }
+
+    /// How long attaching keeps trying to read DSCR before giving up, see
+    /// `DccStream::set_dscr_timeout`
+    pub fn dscr_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.dscr_timeout = timeout;
+        self
+    }
+
+    /// Refuse to open a debug port whose IDCODE isn't one of `idcodes`, e.g.
+    /// `ARM_DAP_IDCODES`.  Empty, the default, accepts any.
+    pub fn expect_idcodes(mut self, idcodes: Vec<u32>) -> Self {
+        self.idcodes = idcodes;
+        self
+    }
+
+    /// Steps run before every attach, see the `init` module
+    pub fn init(mut self, steps: Vec<Step>) -> Self {
+        self.init = steps;
+        self
+    }
+
+    /// Also stream from the core whose debug registers are at `debug_base` behind `ap_num`,
+    /// through the same cable.  Only `build_all` opens these.
+    pub fn core(mut self, ap_num: u32, debug_base: u32) -> Self {
+        self.cores.push((ap_num, debug_base));
+        self
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    /// AP and debug base of each core added with `core`
+    pub fn cores(&self) -> &[(u32, u32)] {
+        &self.cores
+    }
+
+    pub fn validate(&self) -> Result<(), DccError> {
+        if self.target.cable.is_empty() {
+            return Err(DccError::InvalidConfig("no cable given".to_string()));
+        }
+        if self.target.baud == 0 {
+            return Err(DccError::InvalidConfig("baud must be above 0".to_string()));
+        }
+        let mut bases = std::iter::once(self.target.debug_base).chain(self.cores.iter().map(|(_, base)| *base));
+        if let Some(base) = bases.find(|base| base & 0xfff != 0) {
+            return Err(DccError::InvalidConfig(format!("debug base 0x{:x} is not 4KB aligned", base)));
+        }
+        if self.attach_only && !self.init.is_empty() {
+            return Err(DccError::InvalidConfig(
+                "init steps write to the target, which attaching only may not".to_string(),
+            ));
+        }
+        if self.queue_size == 0 || self.queue_size > MAX_QUEUE_SIZE {
+            return Err(DccError::InvalidConfig(format!(
+                "queue size must be between 1 and {}",
+                MAX_QUEUE_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration and open the stream.  The core isn't touched until
+    /// `DccStream::attach`.
+    pub fn build(self) -> Result<DccStream, DccError> {
+        self.validate()?;
+        if sim::is_sim(&self.target.cable) {
+            let port = self.check_idcode(SimPort::open(&self.target)?)?;
+            return Ok(self.build_with_port(Box::new(port)));
+        }
+        #[cfg(unix)]
+        if share::is_shared(&self.target.cable) {
+            let port = self.check_idcode(SharedPort::open(&self.target)?)?;
+            return Ok(self.build_with_port(Box::new(port)));
+        }
+        let port = self.check_idcode(JtagPort::open(&self.target)?)?;
+        Ok(self.build_with_port(Box::new(port)))
+    }
+
+    fn check_idcode<P: DebugPort>(&self, port: P) -> Result<P, DccError> {
+        if !self.idcodes.is_empty() && !self.idcodes.contains(&port.idcode()) {
+            return Err(DccError::IdcodeMismatch(port.idcode()));
+        }
+        Ok(port)
+    }
+
+    /// Validate the configuration and open a stream for the first core and each one added with
+    /// `core`, in that order.  They share the cable, so they must be used from one thread.
+    pub fn build_all(self) -> Result<Vec<DccStream>, DccError> {
+        self.validate()?;
+        #[cfg(unix)]
+        let shared = share::is_shared(&self.target.cable);
+        #[cfg(not(unix))]
+        let shared = false;
+        // Each simulated core runs its own copy of the scenario, and each core of a shared
+        // cable has a connection of its own
+        if sim::is_sim(&self.target.cable) || shared {
+            let mut streams = vec![self.clone().build()?];
+            for &(ap_num, debug_base) in &self.cores {
+                let mut builder = self.clone();
+                builder.target.ap_num = ap_num;
+                builder.target.debug_base = debug_base;
+                streams.push(builder.build()?);
+            }
+            return Ok(streams);
+        }
+        let port = self.check_idcode(JtagPort::open(&self.target)?)?;
+        let mut streams = Vec::with_capacity(self.cores.len() + 1);
+        for &(ap_num, debug_base) in &self.cores {
+            let port = port.with_ap(ap_num)?;
+            let mut builder = self.clone();
+            builder.target.debug_base = debug_base;
+            streams.push(builder.build_with_port(Box::new(port)));
+        }
+        streams.insert(0, self.build_with_port(Box::new(port)));
+        Ok(streams)
+    }
+
+    /// Stream through `port` instead of opening the cable.  Only the debug base, arch, queue
+    /// size, dedup, txfull, attach-only, DSCR timeout and init settings apply.
+    pub fn build_with_port(self, port: Box<dyn DebugPort>) -> DccStream {
+        let mut dcc = DccStream::new(port, self.target.debug_base, self.arch);
+        dcc.set_queue_size(self.queue_size);
+        dcc.set_nodups(self.nodups);
+        dcc.set_txfull(self.txfull);
+        dcc.set_attach_only(self.attach_only);
+        dcc.set_dscr_timeout(self.dscr_timeout);
+        dcc.set_init(self.init);
+        if let Some(clock) = self.target.clock {
+            dcc.set_clock(clock);
+        }
+        dcc
+    }
+}
@@ -0,0 +1,91 @@
+//! --capabilities-json: what this build of dcc-stream can do, as one JSON object on stdout, for
+//! tools driving several lab hosts whose builds differ in features and platform.
+//!
+//! ```json
+//! {"version":"0.1.2","features":{"tui":true,"net":true,"async":false,"tracing":false},
+//!  "subcommands":["replay",...],"transports":["jtag","sim",...],"cables":["jlink",...],
+//!  "arch":["armv7","armv8"],"formats":["text",...],"decoders":["raw",...],
+//!  "destinations":["file","stdout",...],"file_writers":["buffered",...],
+//!  "plugin_abi":1,"max_queue_size":4096}
+//! ```
+//!
+//! Lists only grow between versions, so a tool can look for what it needs and ignore the rest.
+use clap::ValueEnum;
+
+use dcc_stream::decode::DecoderKind;
+use dcc_stream::sink::OutputFormat;
+use dcc_stream::{known_cables, plugin, Arch, MAX_QUEUE_SIZE};
+
+use crate::report::json_string;
+
+/// The capabilities as a JSON object, without a trailing newline
+pub fn json() -> String {
+    let features = [
+        ("tui", cfg!(feature = "tui")),
+        ("net", cfg!(feature = "net")),
+        ("async", cfg!(feature = "async")),
+        ("tracing", cfg!(feature = "tracing")),
+    ];
+    let features: Vec<String> = features.iter().map(|(name, on)| format!("{}:{}", json_string(name), on)).collect();
+
+    let mut subcommands = vec!["replay", "decode", "bench", "selftest"];
+    if cfg!(unix) {
+        subcommands.push("serve");
+    }
+    subcommands.extend(["bp", "mem", "probes", "config"]);
+
+    // The debug paths --cable, --rtt and --mailbox select between
+    let mut transports = vec!["jtag", "sim"];
+    if cfg!(unix) {
+        transports.push("share");
+    }
+    transports.extend(["rtt", "mailbox"]);
+
+    let mut destinations = vec!["file", "stdout"];
+    if cfg!(feature = "net") {
+        destinations.push("tcp");
+        if cfg!(unix) {
+            destinations.push("unix");
+        }
+    }
+    if cfg!(windows) {
+        destinations.push("pipe");
+    }
+    destinations.push("plugin");
+
+    let mut writers = vec!["buffered"];
+    if cfg!(unix) {
+        writers.push("mmap");
+    }
+    writers.push("ping-pong");
+
+    format!(
+        "{{\"version\":{},\"features\":{{{}}},\"subcommands\":{},\"transports\":{},\"cables\":{},\"arch\":{},\"formats\":{},\
+         \"decoders\":{},\"destinations\":{},\"file_writers\":{},\"plugin_abi\":{},\"max_queue_size\":{}}}",
+        json_string(env!("CARGO_PKG_VERSION")),
+        features.join(","),
+        list(&subcommands),
+        list(&transports),
+        list(&known_cables()),
+        list(&names::<Arch>()),
+        list(&names::<OutputFormat>()),
+        list(&names::<DecoderKind>()),
+        list(&destinations),
+        list(&writers),
+        plugin::ABI_VERSION,
+        MAX_QUEUE_SIZE
+    )
+}
+
+/// The command line names of an option's values
+fn names<T: ValueEnum>() -> Vec<String> {
+    T::value_variants()
+        .iter()
+        .filter_map(|v| v.to_possible_value().map(|v| v.get_name().to_string()))
+        .collect()
+}
+
+fn list<S: AsRef<str>>(items: &[S]) -> String {
+    let items: Vec<String> = items.iter().map(|item| json_string(item.as_ref())).collect();
+    format!("[{}]", items.join(","))
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+/// --on-change and --debounce: hold back words that repeat the last one output.  Unlike
+/// --nodups this looks at what was output, after the filters, rather than at what was read,
+/// so it also applies to --txfull words.
+pub struct ChangeGate {
+    on_change: bool,
+    debounce: Option<Duration>,
+    /// The last word let through and its timestamp in microseconds
+    last: Option<(u32, u128)>,
+}
+
+impl ChangeGate {
+    pub fn new(on_change: bool, debounce: Option<Duration>) -> Self {
+        Self {
+            on_change,
+            debounce,
+            last: None,
+        }
+    }
+
+    /// Apply reloaded settings, keeping the last word output
+    pub fn update(&mut self, on_change: bool, debounce: Option<Duration>) {
+        self.on_change = on_change;
+        self.debounce = debounce;
+    }
+
+    /// Whether `val` read at `ts` should be output.  With --debounce a value that doesn't
+    /// change is output again once each window.
+    pub fn allow(&mut self, ts: u128, val: u32) -> bool {
+        let allow = match self.last {
+            Some((last, _)) if self.on_change => val != last,
+            Some((last, at)) => match self.debounce {
+                Some(window) => val != last || ts.saturating_sub(at) >= window.as_micros(),
+                None => true,
+            },
+            None => true,
+        };
+        if allow {
+            self.last = Some((val, ts));
+        }
+        allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Microseconds in a millisecond and a second, for the timestamps
+    const MS: u128 = 1_000;
+    const SECS: u128 = 1_000_000;
+
+    #[test]
+    fn passes_everything_by_default() {
+        let mut gate = ChangeGate::new(false, None);
+        for (i, val) in [1, 1, 1, 2].into_iter().enumerate() {
+            assert!(gate.allow(i as u128 * MS, val));
+        }
+    }
+
+    #[test]
+    fn on_change_drops_repeats() {
+        let mut gate = ChangeGate::new(true, None);
+        let mut out = vec![];
+        for (i, val) in [1, 1, 2, 2, 2, 1, 3, 3].into_iter().enumerate() {
+            if gate.allow(i as u128 * 10 * SECS, val) {
+                out.push(val);
+            }
+        }
+        assert_eq!(out, [1, 2, 1, 3]);
+    }
+
+    #[test]
+    fn debounce_repeats_once_a_window() {
+        let mut gate = ChangeGate::new(false, Some(Duration::from_millis(100)));
+        assert!(gate.allow(0, 7));
+        assert!(!gate.allow(99 * MS, 7));
+        // A change always gets through, and starts the window again
+        assert!(gate.allow(99 * MS, 8));
+        assert!(!gate.allow(159 * MS, 8));
+        assert!(gate.allow(199 * MS, 8));
+        assert!(!gate.allow(199 * MS, 8));
+    }
+
+    #[test]
+    fn update_keeps_the_last_word() {
+        let mut gate = ChangeGate::new(false, None);
+        assert!(gate.allow(0, 5));
+        gate.update(true, None);
+        assert!(!gate.allow(0, 5));
+        assert!(gate.allow(0, 6));
+    }
+}
@@ -0,0 +1,89 @@
+//! Where time comes from.  Timestamps, intervals and the simulator's timing all go through a
+//! `Clock`, so a `VirtualClock` can stand in for the wall clock and make a simulated capture
+//! reproducible: time then only moves as the simulated accesses and sleeps move it.
+use std::fmt;
+use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync + RefUnwindSafe + fmt::Debug {
+    /// Time since the clock started
+    fn now(&self) -> Duration;
+
+    /// Wait for `duration` to pass on this clock
+    fn sleep(&self, duration: Duration);
+
+    /// Time since `since`, a value of `now`
+    fn since(&self, since: Duration) -> Duration {
+        self.now().saturating_sub(since)
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The wall clock, counting from when it was made
+#[derive(Debug)]
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self { epoch: Instant::now() }
+    }
+
+    pub fn shared() -> SharedClock {
+        Arc::new(Self::new())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Time that passes only when it is slept through or advanced, starting at 0.  Sleeping
+/// returns at once, so a simulated capture runs as fast as the host allows.
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    nanos: AtomicU64,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared() -> SharedClock {
+        Arc::new(Self::new())
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.nanos.fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+        // Let the other threads see the time move
+        thread::yield_now();
+    }
+}
@@ -0,0 +1,138 @@
+//! Config file support.  The config file is TOML whose top level keys are the long names of the
+//! command line options, e.g. `cable = "jtagkey"` or `stats-interval = "10s"`.  Tables are left
+//! for sections that don't map onto a single option.
+use std::fs;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+
+use dcc_stream::init::{self, Step};
+
+use crate::policy::SinkPolicy;
+
+pub fn load(path: &Path) -> Result<Table, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("read config {}: {}", path.display(), e))?;
+    text.parse::<Table>().map_err(|e| format!("parse config {}: {}", path.display(), e))
+}
+
+/// Normalize a config key to the clap argument id, which uses underscores
+pub fn arg_id(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+/// Render a single config value the way it would be written on the command line
+pub fn scalar(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Datetime(d) => Ok(d.to_string()),
+        _ => Err(format!("config key {} has an unsupported value", key)),
+    }
+}
+
+/// Convert the option `key` and its value into command line arguments
+pub fn to_args(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    let flag = format!("--{}", key.replace('_', "-"));
+    match value {
+        Value::Boolean(true) => Ok(vec![flag]),
+        Value::Boolean(false) => Ok(vec![]),
+        Value::Array(items) => {
+            let mut args = vec![];
+            for item in items {
+                args.push(flag.clone());
+                args.push(scalar(key, item)?);
+            }
+            Ok(args)
+        }
+        value => Ok(vec![flag, scalar(key, value)?]),
+    }
+}
+
+/// Steps from the `[init]` table
+pub fn init_steps(table: &Table) -> Result<Vec<Step>, String> {
+    let Some(section) = table.get("init") else {
+        return Ok(vec![]);
+    };
+    let steps = section
+        .get("steps")
+        .and_then(Value::as_array)
+        .ok_or("config [init] needs a steps array")?;
+    steps
+        .iter()
+        .map(|step| match step.as_str() {
+            Some(line) => init::parse_step(line).map_err(|e| format!("config [init]: {}", e)),
+            None => Err("config [init] steps must be strings".to_string()),
+        })
+        .collect()
+}
+
+/// Outputs from the `[[sink]]` tables
+pub fn sinks(table: &Table) -> Result<Vec<SinkPolicy>, String> {
+    let Some(section) = table.get("sink") else {
+        return Ok(vec![]);
+    };
+    let sinks = section.as_array().ok_or("config sink must be [[sink]] tables")?;
+    sinks
+        .iter()
+        .enumerate()
+        .map(|(i, sink)| {
+            let sink = sink.as_table().ok_or("config sink must be [[sink]] tables")?;
+            SinkPolicy::from_table(sink).map_err(|e| format!("config [[sink]] {}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+/// Whether `value` is a section rather than an option, a table or an array of them
+pub fn is_section(value: &Value) -> bool {
+    match value {
+        Value::Table(_) => true,
+        Value::Array(items) => !items.is_empty() && items.iter().all(Value::is_table),
+        _ => false,
+    }
+}
+
+/// Every option `matches` has a value for, one `key = value` line each in config file form,
+/// marking those left at their default or taken from the environment
+pub fn effective(cmd: &Command, matches: &ArgMatches) -> String {
+    let mut out = String::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(arg.get_action(), ArgAction::Help | ArgAction::Version) {
+            continue;
+        }
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+        let values: Vec<Value> = raw.map(|v| value(&v.to_string_lossy())).collect();
+        let value = match arg.get_action() {
+            ArgAction::Append => Value::Array(values),
+            _ => match values.into_iter().next() {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        out.push_str(&format!("{} = {}", id.replace('_', "-"), value));
+        match matches.value_source(id) {
+            Some(ValueSource::DefaultValue) => out.push_str("  # default"),
+            Some(ValueSource::EnvVariable) => out.push_str("  # environment"),
+            _ => {}
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// A command line value as the config file would write it
+fn value(s: &str) -> Value {
+    match s {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => match s.parse() {
+            Ok(i) => Value::Integer(i),
+            Err(_) => Value::String(s.to_string()),
+        },
+    }
+}
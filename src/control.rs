@@ -0,0 +1,310 @@
+//! Control API.  Clients connect to a Unix socket, or a named pipe on Windows, and send one
+//! command per line; each command is answered with a single line starting with `ok` or `error`.
+//! Stream events such as markers are pushed to every connected client as lines starting with
+//! `event`.
+//!
+//! `mem read ADDR [COUNT]` and `mem write ADDR VALUE` reach the target's memory through the
+//! debug AP without stopping the capture: the reader carries out one between each polling
+//! round, so neither they nor the DCC can hold the other up for long.  Reads answer with the
+//! words in hexadecimal, at most `MEM_MAX_WORDS` of them.
+//!
+//! `baud HZ` and `queue WORDS` change TCK and the batch size without detaching, for trading
+//! throughput against errors while watching `stats`.  A marker says when the new clock is in
+//! use.
+#[cfg(unix)]
+use std::fs;
+#[cfg(windows)]
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+#[cfg(unix)]
+use std::time::Duration;
+#[cfg(windows)]
+use std::{mem, ptr};
+
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::{ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE};
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX};
+#[cfg(windows)]
+use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+
+use dcc_stream::{parse_u32, MAX_QUEUE_SIZE};
+
+/// The most words one `mem read` returns
+pub const MEM_MAX_WORDS: usize = 256;
+
+/// A memory access, carried out by the reader between batches
+#[derive(Clone, Copy, Debug)]
+pub enum MemOp {
+    /// COUNT words from ADDR
+    Read(u32, usize),
+    Write(u32, u32),
+}
+
+impl MemOp {
+    fn parse(args: &str) -> Result<Self, String> {
+        let words: Vec<&str> = args.split_whitespace().collect();
+        let num = |i: usize| {
+            words
+                .get(i)
+                .ok_or_else(|| "mem needs more arguments".to_string())
+                .and_then(|w| parse_u32(w))
+        };
+        match words.first().copied() {
+            Some("read") => {
+                let count = if words.len() > 2 { num(2)? as usize } else { 1 };
+                if !(1..=MEM_MAX_WORDS).contains(&count) {
+                    return Err(format!("mem read COUNT must be 1 to {}", MEM_MAX_WORDS));
+                }
+                Ok(MemOp::Read(num(1)?, count))
+            }
+            Some("write") => Ok(MemOp::Write(num(1)?, num(2)?)),
+            _ => Err("mem needs read ADDR [COUNT] or write ADDR VALUE".to_string()),
+        }
+    }
+}
+
+pub enum Command {
+    /// Report the current statistics as JSON
+    Stats,
+    /// Insert a marker into the stream
+    Marker(String),
+    /// Re-read the config file, like SIGHUP
+    Reload,
+    /// End the capture
+    Stop,
+    /// Stop outputting words until resumed
+    Pause,
+    Resume,
+    /// Read or write the target's memory
+    Mem(MemOp),
+    /// Change TCK
+    Baud(u32),
+    /// Change the words per batch
+    Queue(usize),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match cmd {
+            "stats" => Ok(Command::Stats),
+            "marker" => Ok(Command::Marker(rest.trim().to_string())),
+            "reload" => Ok(Command::Reload),
+            "stop" => Ok(Command::Stop),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            "mem" => MemOp::parse(rest).map(Command::Mem),
+            "baud" => match parse_u32(rest.trim())? {
+                0 => Err("baud must be above 0".to_string()),
+                baud => Ok(Command::Baud(baud)),
+            },
+            "queue" => match parse_u32(rest.trim())? as usize {
+                size if (1..=MAX_QUEUE_SIZE).contains(&size) => Ok(Command::Queue(size)),
+                _ => Err(format!("queue must be 1 to {}", MAX_QUEUE_SIZE)),
+            },
+            _ => Err(format!("unknown command: {}", cmd)),
+        }
+    }
+}
+
+/// A command from a client, waiting for the streaming loop to act on it
+pub struct Request {
+    pub command: Command,
+    reply: Sender<String>,
+}
+
+impl Request {
+    pub fn ok(self, msg: &str) {
+        let line = if msg.is_empty() { "ok".to_string() } else { format!("ok {}", msg) };
+        let _ = self.reply.send(line);
+    }
+
+    pub fn error(self, msg: &str) {
+        let _ = self.reply.send(format!("error {}", msg));
+    }
+}
+
+/// A connected client
+#[cfg(unix)]
+type Stream = UnixStream;
+#[cfg(windows)]
+type Stream = File;
+
+pub struct ControlServer {
+    /// The socket to remove again
+    #[cfg(unix)]
+    path: PathBuf,
+    requests: Receiver<Request>,
+    clients: Arc<Mutex<Vec<Stream>>>,
+}
+
+fn serve_client(stream: Stream, requests: Sender<Request>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match Command::parse(&line) {
+            Ok(command) => {
+                let (reply, rx) = mpsc::channel();
+                if requests.send(Request { command, reply }).is_err() {
+                    return Ok(());
+                }
+                match rx.recv() {
+                    Ok(response) => response,
+                    Err(_) => return Ok(()),
+                }
+            }
+            Err(e) => format!("error {}", e),
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+impl ControlServer {
+    /// Listen on `path`, replacing any stale socket left behind by an earlier run.  On Windows
+    /// `path` names a pipe, as `\\.\pipe\NAME` or just `NAME`.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let mut listener = Listener::bind(path)?;
+        let (tx, requests) = mpsc::channel();
+        let clients = Arc::new(Mutex::new(Vec::new()));
+
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            while let Some(stream) = listener.accept() {
+                if let Ok(events) = stream.try_clone() {
+                    accepted.lock().unwrap_or_else(|e| e.into_inner()).push(events);
+                }
+                let tx = tx.clone();
+                thread::spawn(move || serve_client(stream, tx));
+            }
+        });
+
+        Ok(Self {
+            #[cfg(unix)]
+            path: path.to_path_buf(),
+            requests,
+            clients,
+        })
+    }
+
+    /// Return the next pending request, if any.  This never blocks.
+    pub fn poll(&self) -> Option<Request> {
+        self.requests.try_recv().ok()
+    }
+
+    /// Send an event line to every connected client, dropping clients that have gone away
+    pub fn event(&self, msg: &str) {
+        let line = format!("event {}\n", msg);
+        self.clients
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+    }
+}
+
+// A pipe goes away with its last handle
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+struct Listener(UnixListener);
+
+#[cfg(unix)]
+impl Listener {
+    fn bind(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(Self(UnixListener::bind(path)?))
+    }
+
+    fn accept(&mut self) -> Option<Stream> {
+        let stream = self.0.incoming().flatten().next()?;
+        // A client that stops reading must not stall the stream
+        let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+        Some(stream)
+    }
+}
+
+/// A named pipe server.  Each client connects to an instance of its own, made ready before it
+/// arrives.  Pipes have no write timeout, so a client that stops reading stalls the events.
+#[cfg(windows)]
+struct Listener {
+    name: Vec<u16>,
+    /// The instance waiting for the next client
+    next: OwnedHandle,
+}
+
+#[cfg(windows)]
+impl Listener {
+    fn bind(path: &Path) -> io::Result<Self> {
+        let path = path.to_string_lossy();
+        let name = if path.starts_with(PIPE_PREFIX) {
+            path.to_string()
+        } else {
+            format!("{}{}", PIPE_PREFIX, path)
+        };
+        let name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+        // The first instance fails if another process already serves the pipe
+        let next = instance(&name, FILE_FLAG_FIRST_PIPE_INSTANCE)?;
+        Ok(Self { name, next })
+    }
+
+    /// The next client, or `None` once no more instances can be made
+    fn accept(&mut self) -> Option<Stream> {
+        loop {
+            let connected = unsafe { ConnectNamedPipe(self.next.as_raw_handle(), ptr::null_mut()) } != 0
+                // The client got in between the instance being made and it being waited on
+                || io::Error::last_os_error().raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32);
+            let next = instance(&self.name, 0).ok()?;
+            let waited = mem::replace(&mut self.next, next);
+            if connected {
+                return Some(File::from(waited));
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+const PIPE_PREFIX: &str = r"\\.\pipe\";
+
+#[cfg(windows)]
+fn instance(name: &[u16], flags: u32) -> io::Result<OwnedHandle> {
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            PIPE_ACCESS_DUPLEX | flags,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+            PIPE_UNLIMITED_INSTANCES,
+            4096,
+            4096,
+            0,
+            ptr::null(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(handle) })
+}
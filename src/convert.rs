@@ -0,0 +1,214 @@
+//! `dcc-stream decode`: run a capture written with `--format raw` through the decoders, filters
+//! and output formats, without a target
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+
+use dcc_stream::decode::DecoderKind;
+use dcc_stream::display::{Radix, ValueFormat};
+use dcc_stream::framing::{Deframer, Item};
+use dcc_stream::sink::{self, FileWriter, OutputFormat, Sink, Timestamps};
+use dcc_stream::{CancelToken, DccError, Record};
+
+use crate::filter::{self, Filter};
+use crate::output::Output;
+use crate::record;
+
+#[derive(Parser, Debug)]
+#[command(bin_name = "dcc-stream decode", about = "Decode a raw capture file as the capture would have")]
+pub struct DecodeArgs {
+    #[arg(long, value_enum, default_value_t = DecoderKind::Raw)]
+    /// How to decode the words before output
+    decode: DecoderKind,
+    #[arg(long, default_value_t = false)]
+    /// The target interleaves threads, each run of words preceded by a context switch record
+    /// of 0x58544344, the thread ID and CONTEXTIDR.  Each thread is decoded apart and its
+    /// output tagged with the thread.
+    context_ids: bool,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    /// How the words are written to the outputs
+    format: OutputFormat,
+    #[arg(long, value_enum, default_value_t = Radix::Hex)]
+    /// Number base used to display values
+    radix: Radix,
+    #[arg(long)]
+    /// Multiply values by this before display, e.g. 0.001 for millivolts to volts
+    scale: Option<f64>,
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    /// Add this to values after scaling
+    offset: f64,
+    #[arg(long)]
+    /// Unit to show after displayed values
+    unit: Option<String>,
+    #[arg(long)]
+    /// Only output values matching "value [& MASK] (==|!=) VALUE", may be given more than once
+    filter: Vec<Filter>,
+    #[arg(long, default_value_t = false)]
+    /// Ignore duplicate values
+    nodups: bool,
+    #[arg(long, default_value_t = false)]
+    /// Start text lines with the word's position in the file, since raw captures keep no times
+    index: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "index")]
+    /// The capture was written with --sync-interval: start at its first sync frame, find the
+    /// next one after corruption and start text lines with the time of the last one
+    synced: bool,
+    #[arg(long, default_value_t = false, requires = "synced")]
+    /// Write the times of words after the first since a sync frame, which only have the
+    /// frame's, as `TIMESTAMP~:`
+    mark_interpolated: bool,
+    #[arg(long, value_parser = record::parse_offset, requires = "synced")]
+    /// Start at the first sync frame this far into the capture, as HH:MM:SS or a duration such
+    /// as 90s.  Raw captures have no index, so what comes before it is still read through.
+    from: Option<Duration>,
+    #[arg(long, value_parser = record::parse_offset, requires = "synced")]
+    /// Stop at the first sync frame after this far into the capture, as for --from
+    to: Option<Duration>,
+    #[arg(short, long)]
+    /// Write to DEST instead of stdout, as for the capture's --output.  May be given more than
+    /// once.
+    output: Vec<String>,
+    /// The raw capture, "-" for stdin
+    file: PathBuf,
+}
+
+/// Words read from the file at a time
+const CHUNK: usize = 4096;
+
+pub fn run(args: &DecodeArgs, stop: &CancelToken) -> Result<(), DccError> {
+    if args.context_ids && !matches!(args.format, OutputFormat::Text | OutputFormat::Trace32) {
+        return Err(DccError::InvalidConfig("--context-ids needs --format text or trace32".to_string()));
+    }
+    let mut input: Box<dyn Read> = if args.file.as_os_str() == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        let file = File::open(&args.file).map_err(|e| DccError::Io(format!("open {}", args.file.display()), e))?;
+        Box::new(BufReader::new(file))
+    };
+
+    let format = ValueFormat {
+        radix: args.radix,
+        scale: args.scale,
+        offset: args.offset,
+        unit: args.unit.clone(),
+    };
+    let dests = if args.output.is_empty() {
+        vec!["-".to_string()]
+    } else {
+        args.output.clone()
+    };
+    let timestamps = match (args.index || args.synced, args.mark_interpolated) {
+        (false, _) => Timestamps::Off,
+        (true, false) => Timestamps::On,
+        (true, true) => Timestamps::Marked,
+    };
+    let mut sinks: Vec<(String, Box<dyn Sink>)> = vec![];
+    for dest in dests {
+        let sink = sink::open(&dest, args.format, format.clone(), FileWriter::Buffered, timestamps, None)
+            .map_err(|e| DccError::Io(format!("open output {}", dest), e))?;
+        sinks.push((dest, sink));
+    }
+    let decoder = if args.context_ids {
+        args.decode.build_contexts()
+    } else {
+        args.decode.build()
+    };
+    let mut output = Output::new(None, None, sinks, decoder, format);
+
+    let mut buf = vec![0; CHUNK * 4];
+    // Bytes of a word split across reads
+    let mut held = 0;
+    let mut index = 0;
+    let mut last = None;
+    let mut deframer = args.synced.then(Deframer::new);
+    let mut items = vec![];
+    // The time of the last sync frame, for --synced, and whether the word after it is still to
+    // come: only its time is known, the rest take the frame's
+    let mut synced_at = 0;
+    let mut measured = false;
+    let from = args.from.map_or(0, |from| from.as_micros());
+    let to = args.to.map(|to| to.as_micros());
+    // Whether --to ended the decode before the end of the file
+    let mut cut = false;
+    'read: while !stop.is_cancelled() {
+        let n = match input.read(&mut buf[held..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(DccError::Io(format!("read {}", args.file.display()), e)),
+        };
+        let len = held + n;
+        if let Some(deframer) = deframer.as_mut() {
+            deframer.push(&buf[..len], &mut items);
+            held = 0;
+        } else {
+            items.extend(
+                buf[..len - len % 4]
+                    .chunks_exact(4)
+                    .map(|w| Item::Word(u32::from_le_bytes(w.try_into().unwrap()))),
+            );
+            held = len % 4;
+            buf.copy_within(len - held..len, 0);
+        }
+        for item in items.drain(..) {
+            let val = match item {
+                Item::Word(val) => val,
+                Item::Sync {
+                    seq,
+                    timestamp,
+                    expected,
+                    actual,
+                } => {
+                    // The first frame read can't know how many words came before it
+                    if seq > 0 && index > 0 && expected != actual {
+                        output.marker(timestamp, &format!("sync {}: {} words expected, {} read", seq, expected, actual));
+                    }
+                    synced_at = timestamp;
+                    measured = true;
+                    if to.is_some_and(|to| timestamp > to) {
+                        cut = true;
+                        break 'read;
+                    }
+                    continue;
+                }
+                Item::Skipped(bytes) => {
+                    output.marker(synced_at, &format!("skipped {} bytes looking for a sync frame", bytes));
+                    continue;
+                }
+            };
+            let record = Record {
+                timestamp: if args.synced { synced_at } else { index },
+                value: val,
+                interpolated: args.synced && !std::mem::take(&mut measured),
+            };
+            index += 1;
+            if args.synced && synced_at < from {
+                continue;
+            }
+            if args.nodups && last == Some(val) {
+                continue;
+            }
+            last = Some(val);
+            if filter::any_match(&args.filter, val) {
+                output.record(record);
+            }
+        }
+        output.flush_due();
+    }
+    let held = deframer.as_ref().map_or(held, Deframer::pending);
+    if held != 0 && !cut && !stop.is_cancelled() {
+        output.warn(&format!(
+            "{} ends with {} bytes of a partial word, ignored",
+            args.file.display(),
+            held
+        ));
+    }
+    output.close();
+    if stop.is_cancelled() {
+        return Err(DccError::Interrupted);
+    }
+    Ok(())
+}
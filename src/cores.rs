@@ -0,0 +1,38 @@
+use std::str::FromStr;
+
+use crate::parse_u32;
+
+/// Another core to stream from through the same cable, written as `AP:BASE=DEST`, or just
+/// `AP:BASE` with --merge-cores
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoreSpec {
+    pub ap_num: u32,
+    pub debug_base: u32,
+    /// Where its stream is written, as for --output
+    pub output: Option<String>,
+}
+
+impl FromStr for CoreSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (core, output) = match s.split_once('=') {
+            Some((core, output)) => (core, Some(output)),
+            None => (s, None),
+        };
+        let (ap, base) = core.split_once(':').ok_or_else(|| format!("core {} must be AP:BASE=DEST", s))?;
+        if output.is_some_and(str::is_empty) {
+            return Err(format!("core {} has an empty output", s));
+        }
+        Ok(CoreSpec {
+            ap_num: parse_u32(ap.trim())?,
+            debug_base: parse_u32(base.trim())?,
+            output: output.map(str::to_string),
+        })
+    }
+}
+
+/// How a core is named in markers and statistics
+pub fn name(ap_num: u32, debug_base: u32) -> String {
+    format!("ap{}@0x{:x}", ap_num, debug_base)
+}
@@ -0,0 +1,227 @@
+//! --cycle-marker: targets that write their own cycle counter into the stream, e.g. PMCCNTR or
+//! DWT_CYCCNT, as this word followed by the counter value, time the words after it far more
+//! closely than polling can.  The two words are taken out of the stream and the word after
+//! them is timestamped by the count.  Target time is laid on the capture's clock at the first
+//! stamp, so it lines up with the markers and the other cores.
+use dcc_stream::Record;
+
+/// What `CycleStamps::check` made of a word
+#[derive(Debug, PartialEq, Eq)]
+pub enum Stamped {
+    /// A data word, retimed once there has been a stamp
+    Data(Record),
+    /// The marker, with the count still to come
+    Marker,
+    /// A stamp, the count extended to 64 bits, and whether it was the first
+    Stamp { ticks: u64, first: bool },
+    /// A count that doesn't fit the host time since the last stamp, as when a reset clears
+    /// the counter, so target time is laid on the host's clock again from here
+    Restarted { ticks: u64 },
+}
+
+/// How far apart the first and last stamps must be to measure drift, in microseconds
+const MIN_SPAN_US: u128 = 1_000_000;
+
+pub struct CycleStamps {
+    marker: u32,
+    hz: f64,
+    /// The next word is a count
+    awaiting: bool,
+    /// Host time and count of the first stamp and of the latest
+    first: Option<(u128, u64)>,
+    last: Option<(u128, u64)>,
+    /// Target time of the latest stamp, and the host time of the word it timed once it arrived
+    stamp: Option<(u128, Option<u128>)>,
+    /// The last time given out, so a stamp never takes the output back in time
+    mapped: u128,
+}
+
+impl CycleStamps {
+    pub fn new(marker: u32, hz: u64) -> Self {
+        Self {
+            marker,
+            hz: hz as f64,
+            awaiting: false,
+            first: None,
+            last: None,
+            stamp: None,
+            mapped: 0,
+        }
+    }
+
+    pub fn check(&mut self, record: Record) -> Stamped {
+        if self.awaiting {
+            self.awaiting = false;
+            return self.stamp(record.timestamp, record.value);
+        }
+        if record.value == self.marker {
+            self.awaiting = true;
+            return Stamped::Marker;
+        }
+        let Some((target, timed)) = self.stamp.as_mut() else {
+            return Stamped::Data(record);
+        };
+        // Words after the first one a stamp times are as far on from it as the host saw
+        let host = *timed.get_or_insert(record.timestamp);
+        let timestamp = (*target + record.timestamp.saturating_sub(host)).max(self.mapped);
+        self.mapped = timestamp;
+        Stamped::Data(Record {
+            timestamp,
+            value: record.value,
+            interpolated: record.timestamp != host,
+        })
+    }
+
+    /// The counter read `count` at `host`.  Wraps of the 32-bit counter are counted by how much
+    /// host time went by since the last stamp.
+    fn stamp(&mut self, host: u128, count: u32) -> Stamped {
+        let Some((h1, t1)) = self.last else {
+            return self.anchor(
+                host,
+                count as u64,
+                Stamped::Stamp {
+                    ticks: count as u64,
+                    first: true,
+                },
+            );
+        };
+        let delta = count.wrapping_sub(t1 as u32) as u64;
+        let expected = (host - h1) as f64 * self.hz / 1e6;
+        let wraps = ((expected - delta as f64) / 4294967296.0).round().max(0.0) as u64;
+        let elapsed = delta + (wraps << 32);
+        // Polling times the stamps to within much less than this
+        if (elapsed as f64 - expected).abs() > expected.max(self.hz / 10.0) {
+            return self.anchor(host, count as u64, Stamped::Restarted { ticks: count as u64 });
+        }
+        let ticks = t1 + elapsed;
+        let (h0, t0) = self.first.unwrap_or((host, ticks));
+        self.last = Some((host, ticks));
+        self.stamp = Some((h0 + ((ticks - t0) as f64 * 1e6 / self.hz) as u128, None));
+        Stamped::Stamp { ticks, first: false }
+    }
+
+    /// Lay target time on the host's clock at `host`
+    fn anchor(&mut self, host: u128, ticks: u64, stamped: Stamped) -> Stamped {
+        self.first = Some((host, ticks));
+        self.last = Some((host, ticks));
+        self.stamp = Some((host, None));
+        stamped
+    }
+
+    /// How far the counter runs from the host's clock, in parts per million, once measured
+    pub fn drift_ppm(&self) -> Option<f64> {
+        match (self.first, self.last) {
+            (Some((h0, t0)), Some((h1, t1))) if h1 - h0 >= MIN_SPAN_US => {
+                let rate = (t1 - t0) as f64 / (h1 - h0) as f64;
+                Some((rate * 1e6 / self.hz - 1.0) * 1e6)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKER: u32 = 0xc7c1_e500;
+
+    fn record(timestamp: u128, value: u32) -> Record {
+        Record {
+            timestamp,
+            value,
+            interpolated: false,
+        }
+    }
+
+    /// The marker and `count`, arriving at `host`
+    fn stamp(stamps: &mut CycleStamps, host: u128, count: u32) -> Stamped {
+        assert_eq!(stamps.check(record(host, MARKER)), Stamped::Marker);
+        stamps.check(record(host, count))
+    }
+
+    fn timestamp(stamped: Stamped) -> (u128, bool) {
+        match stamped {
+            Stamped::Data(record) => (record.timestamp, record.interpolated),
+            other => panic!("{:?} isn't data", other),
+        }
+    }
+
+    #[test]
+    fn counts_become_host_time() {
+        let mut stamps = CycleStamps::new(MARKER, 2_000_000);
+        // Nothing to go on before the first stamp
+        assert_eq!(stamps.check(record(500, 1)), Stamped::Data(record(500, 1)));
+        assert_eq!(
+            stamp(&mut stamps, 1_000, 10_000),
+            Stamped::Stamp {
+                ticks: 10_000,
+                first: true
+            }
+        );
+        assert_eq!(timestamp(stamps.check(record(1_010, 2))), (1_000, false));
+        assert_eq!(timestamp(stamps.check(record(1_030, 3))), (1_020, true));
+        // 3000 ticks are 1500us at 2MHz, though the host saw the stamp 1600us on
+        assert_eq!(
+            stamp(&mut stamps, 2_600, 13_000),
+            Stamped::Stamp {
+                ticks: 13_000,
+                first: false
+            }
+        );
+        assert_eq!(timestamp(stamps.check(record(2_650, 4))), (2_500, false));
+        assert_eq!(timestamp(stamps.check(record(2_660, 5))), (2_510, true));
+        assert_eq!(stamps.drift_ppm(), None);
+        // 2_000_200 ticks in a second
+        stamp(&mut stamps, 1_001_000, 2_010_200);
+        assert!((stamps.drift_ppm().unwrap() - 100.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn time_never_goes_back() {
+        let mut stamps = CycleStamps::new(MARKER, 1_000_000);
+        stamp(&mut stamps, 1_000, 0);
+        assert_eq!(timestamp(stamps.check(record(1_000, 1))), (1_000, false));
+        assert_eq!(timestamp(stamps.check(record(1_500, 2))), (1_500, true));
+        // The counter says less time went by than the host saw before the stamp
+        stamp(&mut stamps, 1_600, 400);
+        assert_eq!(timestamp(stamps.check(record(1_600, 3))), (1_500, false));
+        assert_eq!(timestamp(stamps.check(record(1_700, 4))), (1_500, true));
+        assert_eq!(timestamp(stamps.check(record(1_800, 5))), (1_600, true));
+    }
+
+    #[test]
+    fn counter_wraparound() {
+        // 2^32 ticks at 100MHz are about 43s
+        let mut stamps = CycleStamps::new(MARKER, 100_000_000);
+        let start = 0xffff_0000_u32;
+        stamp(&mut stamps, 1_000_000, start);
+        // Across one wrap 10ms later
+        let ticks = start as u64 + 1_000_000;
+        assert_eq!(stamp(&mut stamps, 1_010_000, ticks as u32), Stamped::Stamp { ticks, first: false });
+        // Two wraps and then some, 100s on
+        let ticks = ticks + 10_000_000_000;
+        assert_eq!(
+            stamp(&mut stamps, 101_010_000, ticks as u32),
+            Stamped::Stamp { ticks, first: false }
+        );
+        assert_eq!(timestamp(stamps.check(record(101_010_000, 1))), (101_010_000, false));
+        assert_eq!(stamps.drift_ppm(), Some(0.0));
+    }
+
+    #[test]
+    fn reset_counter_restarts() {
+        let mut stamps = CycleStamps::new(MARKER, 1_000_000);
+        stamp(&mut stamps, 1_000, 5_000_000);
+        assert_eq!(stamp(&mut stamps, 2_001_000, 100), Stamped::Restarted { ticks: 100 });
+        assert_eq!(timestamp(stamps.check(record(2_001_010, 1))), (2_001_000, false));
+        assert_eq!(
+            stamp(&mut stamps, 3_001_000, 1_000_100),
+            Stamped::Stamp {
+                ticks: 1_000_100,
+                first: false
+            }
+        );
+        assert_eq!(stamps.drift_ppm(), Some(0.0));
+    }
+}
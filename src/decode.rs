@@ -0,0 +1,496 @@
+//! Decoders turn the raw DCC words into frames for the sinks, e.g. reassembling text or
+//! packets that the target has packed into consecutive words.  Bytes are unpacked from each
+//! word least significant first, matching a little-endian target writing a byte buffer.
+use clap::ValueEnum;
+
+use crate::display::ValueFormat;
+use crate::stream::Record;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameData {
+    /// A DCC word, unchanged
+    Word(u32),
+    /// A line of text without its line ending
+    Text(String),
+    /// A binary packet
+    Bytes(Vec<u8>),
+    /// Data the decoder couldn't make sense of, with the reason
+    Invalid(String),
+    /// A frame from one thread of a target that says which, see `Contexts`
+    Context {
+        thread: u32,
+        contextidr: u32,
+        data: Box<FrameData>,
+    },
+}
+
+impl FrameData {
+    fn to_text(&self, format: &ValueFormat) -> String {
+        match self {
+            FrameData::Word(val) => format.format(*val),
+            FrameData::Text(text) => text.clone(),
+            FrameData::Bytes(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            FrameData::Invalid(msg) => format!("# decode error: {}", msg),
+            FrameData::Context { thread, contextidr, data } => {
+                format!("[{} 0x{:x}] {}", thread, contextidr, data.to_text(format))
+            }
+        }
+    }
+}
+
+/// Decoder output.  The timestamp is that of the word which completed the frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub timestamp: u128,
+    pub data: FrameData,
+    /// The timestamp is interpolated, see `Record::interpolated`
+    pub interpolated: bool,
+}
+
+impl Frame {
+    /// Render as a line of text output, without the timestamp
+    pub fn to_text(&self, format: &ValueFormat) -> String {
+        self.data.to_text(format)
+    }
+}
+
+pub trait Decoder {
+    /// Feed one record, appending any frames it completes to `out`
+    fn push(&mut self, record: &Record, out: &mut Vec<Frame>);
+
+    /// Frame whatever is left over when the capture ends
+    fn finish(&mut self, _out: &mut Vec<Frame>) {}
+}
+
+/// Longest line or packet a decoder buffers.  Target output that never delimits a frame would
+/// otherwise grow the buffer without end.
+pub const MAX_FRAME: usize = 64 * 1024;
+
+/// The built-in decoders, for selecting one on the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DecoderKind {
+    /// One frame per word
+    #[default]
+    Raw,
+    /// Newline separated text; NUL bytes are padding and skipped
+    Text,
+    /// COBS encoded packets separated by zero bytes
+    Cobs,
+}
+
+impl DecoderKind {
+    pub fn build(self) -> Box<dyn Decoder> {
+        match self {
+            DecoderKind::Raw => Box::new(Raw),
+            DecoderKind::Text => Box::<Text>::default(),
+            DecoderKind::Cobs => Box::<Cobs>::default(),
+        }
+    }
+
+    /// The decoder for targets that interleave threads, see `Contexts`
+    pub fn build_contexts(self) -> Box<dyn Decoder> {
+        Box::new(Contexts::new(self))
+    }
+}
+
+/// Passes words through as they are
+pub struct Raw;
+
+impl Decoder for Raw {
+    fn push(&mut self, record: &Record, out: &mut Vec<Frame>) {
+        out.push(Frame {
+            timestamp: record.timestamp,
+            data: FrameData::Word(record.value),
+            interpolated: record.interpolated,
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct Text {
+    line: Vec<u8>,
+    /// The timestamp of the last word and whether it was interpolated
+    last: (u128, bool),
+}
+
+impl Text {
+    fn take_line(&mut self, (timestamp, interpolated): (u128, bool), out: &mut Vec<Frame>) {
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        let text = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        out.push(Frame {
+            timestamp,
+            data: FrameData::Text(text),
+            interpolated,
+        });
+    }
+}
+
+impl Decoder for Text {
+    fn push(&mut self, record: &Record, out: &mut Vec<Frame>) {
+        self.last = (record.timestamp, record.interpolated);
+        for byte in record.value.to_le_bytes() {
+            match byte {
+                0 => {}
+                b'\n' => self.take_line(self.last, out),
+                byte => {
+                    self.line.push(byte);
+                    // Break overlong lines rather than buffer them
+                    if self.line.len() == MAX_FRAME {
+                        self.take_line(self.last, out);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<Frame>) {
+        if !self.line.is_empty() {
+            self.take_line(self.last, out);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Cobs {
+    packet: Vec<u8>,
+    /// The packet grew past `MAX_FRAME`, so the rest of it up to the delimiter is dropped
+    discarding: bool,
+}
+
+/// Undo COBS encoding of one packet, without its zero delimiter
+fn cobs_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        let end = i + code;
+        if code == 0 || end > data.len() {
+            return Err(format!("bad COBS code {} at offset {}", code, i));
+        }
+        decoded.extend_from_slice(&data[i + 1..end]);
+        if code < 0xff && end < data.len() {
+            decoded.push(0);
+        }
+        i = end;
+    }
+    Ok(decoded)
+}
+
+impl Decoder for Cobs {
+    fn push(&mut self, record: &Record, out: &mut Vec<Frame>) {
+        for byte in record.value.to_le_bytes() {
+            if byte != 0 {
+                if self.discarding {
+                    continue;
+                }
+                self.packet.push(byte);
+                if self.packet.len() > MAX_FRAME {
+                    self.packet.clear();
+                    self.discarding = true;
+                    out.push(Frame {
+                        timestamp: record.timestamp,
+                        data: FrameData::Invalid(format!("COBS packet longer than {} bytes", MAX_FRAME)),
+                        interpolated: record.interpolated,
+                    });
+                }
+                continue;
+            }
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+            // Back to back delimiters, or padding after a packet
+            if self.packet.is_empty() {
+                continue;
+            }
+            let data = match cobs_decode(&self.packet) {
+                Ok(packet) => FrameData::Bytes(packet),
+                Err(e) => FrameData::Invalid(e),
+            };
+            self.packet.clear();
+            out.push(Frame {
+                timestamp: record.timestamp,
+                data,
+                interpolated: record.interpolated,
+            });
+        }
+    }
+}
+
+/// Starts a context switch record: "DCTX" in little-endian bytes
+pub const CONTEXT_MAGIC: u32 = 0x5854_4344;
+/// Threads a `Contexts` keeps a decoder for, the least recently used being finished first
+pub const MAX_CONTEXTS: usize = 256;
+
+/// Where `Contexts` is in a context switch record
+enum ContextState {
+    Data,
+    Magic,
+    Thread(u32),
+}
+
+/// For targets that interleave several threads' output: `CONTEXT_MAGIC, THREAD, CONTEXTIDR`
+/// says the words after it, up to the next such record, come from that thread while that
+/// CONTEXTIDR was current.  A data word equal to `CONTEXT_MAGIC` is written twice.  Each
+/// thread gets its own decoder, so its lines and packets are reassembled apart from the
+/// others', and its frames are tagged with the thread.  Words before the first record are
+/// thread 0.
+pub struct Contexts {
+    kind: DecoderKind,
+    state: ContextState,
+    current: (u32, u32),
+    /// Each thread's CONTEXTIDR and decoder, most recently used last
+    decoders: Vec<(u32, u32, Box<dyn Decoder>)>,
+    frames: Vec<Frame>,
+}
+
+impl Contexts {
+    pub fn new(kind: DecoderKind) -> Self {
+        Self {
+            kind,
+            state: ContextState::Data,
+            current: (0, 0),
+            decoders: vec![],
+            frames: vec![],
+        }
+    }
+
+    fn data(&mut self, record: &Record, out: &mut Vec<Frame>) {
+        let (thread, contextidr) = self.current;
+        let at = match self.decoders.iter().position(|(t, _, _)| *t == thread) {
+            Some(at) => at,
+            None => {
+                if self.decoders.len() == MAX_CONTEXTS {
+                    let (thread, contextidr, mut decoder) = self.decoders.remove(0);
+                    decoder.finish(&mut self.frames);
+                    tag(thread, contextidr, &mut self.frames, out);
+                }
+                self.decoders.push((thread, contextidr, self.kind.build()));
+                self.decoders.len() - 1
+            }
+        };
+        let mut entry = self.decoders.remove(at);
+        entry.1 = contextidr;
+        entry.2.push(record, &mut self.frames);
+        self.decoders.push(entry);
+        tag(thread, contextidr, &mut self.frames, out);
+    }
+}
+
+/// Move `frames` to `out` tagged with the thread they came from
+fn tag(thread: u32, contextidr: u32, frames: &mut Vec<Frame>, out: &mut Vec<Frame>) {
+    out.extend(frames.drain(..).map(|frame| Frame {
+        timestamp: frame.timestamp,
+        data: FrameData::Context {
+            thread,
+            contextidr,
+            data: Box::new(frame.data),
+        },
+        interpolated: frame.interpolated,
+    }));
+}
+
+impl Decoder for Contexts {
+    fn push(&mut self, record: &Record, out: &mut Vec<Frame>) {
+        self.state = match self.state {
+            ContextState::Data if record.value == CONTEXT_MAGIC => ContextState::Magic,
+            ContextState::Magic if record.value == CONTEXT_MAGIC => {
+                self.data(record, out);
+                ContextState::Data
+            }
+            ContextState::Magic => ContextState::Thread(record.value),
+            ContextState::Thread(thread) => {
+                self.current = (thread, record.value);
+                ContextState::Data
+            }
+            ContextState::Data => {
+                self.data(record, out);
+                ContextState::Data
+            }
+        };
+    }
+
+    fn finish(&mut self, out: &mut Vec<Frame>) {
+        for (thread, contextidr, mut decoder) in self.decoders.drain(..) {
+            decoder.finish(&mut self.frames);
+            tag(thread, contextidr, &mut self.frames, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `bytes` packed into words least significant first, the last padded with zeros
+    fn records(bytes: &[u8]) -> Vec<Record> {
+        bytes
+            .chunks(4)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                Record {
+                    timestamp: i as u128,
+                    value: u32::from_le_bytes(word),
+                    interpolated: false,
+                }
+            })
+            .collect()
+    }
+
+    fn decode(kind: DecoderKind, bytes: &[u8]) -> Vec<FrameData> {
+        let mut decoder = kind.build();
+        let mut frames = vec![];
+        for record in records(bytes) {
+            decoder.push(&record, &mut frames);
+        }
+        decoder.finish(&mut frames);
+        frames.into_iter().map(|frame| frame.data).collect()
+    }
+
+    /// COBS encoding of `data`, without the delimiter
+    fn cobs_encode(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0];
+        let mut code = 0;
+        for &byte in data {
+            if byte != 0 {
+                out.push(byte);
+            }
+            if byte == 0 || out.len() - code == 0xff {
+                out[code] = (out.len() - code) as u8;
+                code = out.len();
+                out.push(0);
+            }
+        }
+        out[code] = (out.len() - code) as u8;
+        out
+    }
+
+    #[test]
+    fn cobs_vectors() {
+        let vectors: &[(&[u8], &[u8])] = &[
+            (&[0x01, 0x01], &[0x00]),
+            (&[0x01, 0x01, 0x01], &[0x00, 0x00]),
+            (&[0x03, 0x11, 0x22, 0x02, 0x33], &[0x11, 0x22, 0x00, 0x33]),
+            (&[0x02, 0x11, 0x01, 0x01], &[0x11, 0x00, 0x00]),
+            (&[0x05, 0x11, 0x22, 0x33, 0x44], &[0x11, 0x22, 0x33, 0x44]),
+        ];
+        for (encoded, decoded) in vectors {
+            let mut bytes = encoded.to_vec();
+            bytes.push(0);
+            assert_eq!(
+                decode(DecoderKind::Cobs, &bytes),
+                vec![FrameData::Bytes(decoded.to_vec())],
+                "{:02x?}",
+                encoded
+            );
+        }
+    }
+
+    #[test]
+    fn cobs_packets_across_words() {
+        let packets: Vec<Vec<u8>> = vec![(1..=254).collect(), (0..=255).cycle().take(1000).collect(), vec![0; 7], vec![]];
+        let mut bytes = vec![0, 0];
+        for packet in &packets {
+            bytes.extend(cobs_encode(packet));
+            bytes.push(0);
+        }
+        let expected: Vec<FrameData> = packets.into_iter().map(FrameData::Bytes).collect();
+        assert_eq!(decode(DecoderKind::Cobs, &bytes), expected);
+    }
+
+    #[test]
+    fn cobs_overlong_packets_are_dropped_to_the_delimiter() {
+        // The longest packet that is kept, then one that isn't
+        let data: Vec<u8> = (1..=255).cycle().take(MAX_FRAME - MAX_FRAME / 255 - 1).collect();
+        let longest = cobs_encode(&data);
+        assert_eq!(longest.len(), MAX_FRAME);
+        let mut bytes = longest.clone();
+        bytes.push(0);
+        bytes.extend(vec![0x42; MAX_FRAME * 3]);
+        bytes.extend([0, 0x03, 0x11, 0x22, 0x02, 0x33, 0]);
+        assert_eq!(
+            decode(DecoderKind::Cobs, &bytes),
+            vec![
+                FrameData::Bytes(data),
+                FrameData::Invalid(format!("COBS packet longer than {} bytes", MAX_FRAME)),
+                FrameData::Bytes(vec![0x11, 0x22, 0x00, 0x33]),
+            ]
+        );
+    }
+
+    #[test]
+    fn cobs_unterminated_packet_is_not_framed() {
+        assert_eq!(decode(DecoderKind::Cobs, &[0x04, 0x11, 0x22, 0x33]), vec![]);
+        assert_eq!(decode(DecoderKind::Cobs, &[0x42; MAX_FRAME * 2]).len(), 1);
+    }
+
+    #[test]
+    fn cobs_bad_codes_are_invalid() {
+        // A code past the end, and a zero inside a packet, which ends it early
+        assert_eq!(
+            decode(DecoderKind::Cobs, &[0x05, 0x11, 0x22, 0, 0x03, 0x11, 0, 0x22, 0, 0x01, 0]),
+            vec![
+                FrameData::Invalid("bad COBS code 5 at offset 0".to_string()),
+                FrameData::Invalid("bad COBS code 3 at offset 0".to_string()),
+                FrameData::Invalid("bad COBS code 34 at offset 0".to_string()),
+                FrameData::Bytes(vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_lines() {
+        assert_eq!(
+            decode(DecoderKind::Text, b"one\n\0\0two\r\nab\0\0cd\n\ntail"),
+            ["one", "two", "abcd", "", "tail"].map(|line| FrameData::Text(line.to_string()))
+        );
+        assert_eq!(
+            decode(DecoderKind::Text, b"\xff\xfeok\n"),
+            vec![FrameData::Text("\u{fffd}\u{fffd}ok".to_string())]
+        );
+    }
+
+    #[test]
+    fn text_overlong_lines_are_broken() {
+        let mut bytes = vec![b'x'; MAX_FRAME * 2 + 5];
+        bytes.push(b'\n');
+        let expected = [MAX_FRAME, MAX_FRAME, 5].map(|len| FrameData::Text("x".repeat(len)));
+        assert_eq!(decode(DecoderKind::Text, &bytes), expected);
+    }
+
+    /// Neither decoder panics or frames more than `MAX_FRAME` on arbitrary words, as the fuzz
+    /// targets check, here with words biased towards delimiters
+    #[test]
+    fn arbitrary_words() {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for kind in [DecoderKind::Text, DecoderKind::Cobs] {
+            for _ in 0..8 {
+                let bytes: Vec<u8> = (0..1 << 18)
+                    .map(|_| match next() % 64 {
+                        0 => 0,
+                        1 => b'\n',
+                        r => (r as u8).wrapping_mul(next() as u8) | 1,
+                    })
+                    .collect();
+                for frame in decode(kind, &bytes) {
+                    match frame {
+                        FrameData::Text(text) => assert!(text.len() <= MAX_FRAME * 3),
+                        FrameData::Bytes(bytes) => assert!(bytes.len() <= MAX_FRAME),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Radix {
+    #[default]
+    Hex,
+    Dec,
+    Bin,
+}
+
+/// How DCC words are rendered in text output, plain hex by default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValueFormat {
+    pub radix: Radix,
+    /// Linear transform to engineering units, `value * scale + offset`
+    pub scale: Option<f64>,
+    pub offset: f64,
+    pub unit: Option<String>,
+}
+
+impl ValueFormat {
+    pub fn format(&self, val: u32) -> String {
+        let mut text = if self.scale.is_some() || self.offset != 0.0 {
+            let scaled = val as f64 * self.scale.unwrap_or(1.0) + self.offset;
+            scaled.to_string()
+        } else {
+            match self.radix {
+                Radix::Hex => format!("{:x}", val),
+                Radix::Dec => val.to_string(),
+                Radix::Bin => format!("{:b}", val),
+            }
+        };
+        if let Some(unit) = &self.unit {
+            text.push(' ');
+            text.push_str(unit);
+        }
+        text
+    }
+}
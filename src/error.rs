@@ -0,0 +1,57 @@
+use thiserror::Error;
+
+/// Reasons dcc-stream can stop, each with its own process exit code so that wrapper scripts can
+/// react without parsing messages.  clap already uses 2 for usage errors.
+#[derive(Debug, Error)]
+pub enum DccError {
+    /// The cable could not be opened
+    #[error("cable not found: {0}")]
+    CableNotFound(String),
+    /// Another process holds the cable lock
+    #[error("cable busy: {0}")]
+    CableBusy(String),
+    /// The selected TAP is not the expected ARM DAP
+    #[error("unexpected idcode {0:x}")]
+    IdcodeMismatch(u32),
+    /// The core reports that it is powered down, with the EDPRSR value
+    #[error("core is powered down (EDPRSR 0x{0:x})")]
+    PoweredDown(u32),
+    /// A debug register access failed
+    #[error("debug access fault: {0}")]
+    AccessFault(String),
+    /// A debug register access was still answered with WAIT after the retries allowed
+    #[error("debug access still waiting after retries: {0}")]
+    Wait(String),
+    /// Stopped by SIGINT
+    #[error("interrupted")]
+    Interrupted,
+    /// The configuration doesn't make sense
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// An I/O error, with what we were doing at the time
+    #[error("{0}: {1}")]
+    Io(String, #[source] std::io::Error),
+    /// Anything else
+    #[error("{0}")]
+    Other(String),
+}
+
+impl DccError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DccError::Other(_) | DccError::Io(..) => 1,
+            DccError::InvalidConfig(_) => 2,
+            DccError::CableNotFound(_) => 10,
+            DccError::CableBusy(_) => 14,
+            DccError::IdcodeMismatch(_) => 11,
+            DccError::PoweredDown(_) => 12,
+            DccError::AccessFault(_) | DccError::Wait(_) => 13,
+            DccError::Interrupted => 130,
+        }
+    }
+
+    /// Build an `AccessFault` from a jtag_adi error code and a description of what failed
+    pub fn access(what: &str, ack: u8) -> Self {
+        DccError::AccessFault(format!("{}: ack {}", what, ack))
+    }
+}
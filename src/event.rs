@@ -0,0 +1,14 @@
+/// Something that happened to the session, passed to the `DccStream::on_event` callbacks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The core was prepared for streaming, by `attach` or `reattach`
+    Attached,
+    /// `session_lost` found the core powered down, reset or unreachable, with the reason
+    SessionLost(String),
+    /// `attach_powered` found the core powered down and is waiting for it, with EDPRSR
+    PoweredDown(u32),
+    /// `check_config` found stall mode cleared and set it again, with the DSCR it found
+    Reconfigured(u32),
+    /// DSCR showed DCC data was lost, with the DSCR value.  The sticky bits have been cleared.
+    Overrun(u32),
+}
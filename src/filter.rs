@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+use crate::parse_u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+}
+
+/// A mask/compare test on DCC words, written as `value [& MASK] (==|!=) VALUE`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    mask: u32,
+    op: Op,
+    value: u32,
+}
+
+impl Filter {
+    pub fn matches(&self, word: u32) -> bool {
+        match self.op {
+            Op::Eq => word & self.mask == self.value,
+            Op::Ne => word & self.mask != self.value,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (lhs, op, rhs) = if let Some((lhs, rhs)) = s.split_once("==") {
+            (lhs, Op::Eq, rhs)
+        } else if let Some((lhs, rhs)) = s.split_once("!=") {
+            (lhs, Op::Ne, rhs)
+        } else {
+            return Err(format!("filter {} needs == or !=", s));
+        };
+
+        let lhs = lhs.trim();
+        let rest = lhs
+            .strip_prefix("value")
+            .ok_or_else(|| format!("filter {} must start with \"value\"", s))?
+            .trim();
+        let mask = if rest.is_empty() {
+            u32::MAX
+        } else {
+            let mask = rest
+                .strip_prefix('&')
+                .ok_or_else(|| format!("filter {}: expected & after value", s))?;
+            parse_u32(mask.trim())?
+        };
+
+        Ok(Filter {
+            mask,
+            op,
+            value: parse_u32(rhs.trim())?,
+        })
+    }
+}
+
+/// Returns true if `word` should be output.  With no filters everything passes, otherwise any
+/// one filter matching is enough.
+pub fn any_match(filters: &[Filter], word: u32) -> bool {
+    filters.is_empty() || filters.iter().any(|f| f.matches(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_match() {
+        let filter: Filter = "value == 0x10".parse().unwrap();
+        assert!(filter.matches(0x10));
+        assert!(!filter.matches(0x110));
+
+        let filter: Filter = "value&0xff00==0x1200".parse().unwrap();
+        assert_eq!(
+            filter,
+            Filter {
+                mask: 0xff00,
+                op: Op::Eq,
+                value: 0x1200
+            }
+        );
+        assert!(filter.matches(0xab12cd));
+        assert!(!filter.matches(0x1300));
+
+        let filter: Filter = " value & 1 != 0 ".parse().unwrap();
+        assert!(filter.matches(3));
+        assert!(!filter.matches(2));
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        for (s, error) in [
+            ("value", "filter value needs == or !="),
+            ("value = 1", "filter value = 1 needs == or !="),
+            ("word == 1", "filter word == 1 must start with \"value\""),
+            ("value | 1 == 1", "filter value | 1 == 1: expected & after value"),
+            ("value & == 1", "invalid number : cannot parse integer from empty string"),
+            ("value == 0xg", "invalid number 0xg: invalid digit found in string"),
+            ("value == 1 == 1", "invalid number 1 == 1: invalid digit found in string"),
+            (
+                "value == 0x100000000",
+                "invalid number 0x100000000: number too large to fit in target type",
+            ),
+        ] {
+            assert_eq!(s.parse::<Filter>(), Err(error.to_string()), "{}", s);
+        }
+    }
+
+    #[test]
+    fn any_filter_passes() {
+        assert!(any_match(&[], 0));
+        let filters: Vec<Filter> = ["value == 1", "value & 0xf0 == 0x20"].iter().map(|s| s.parse().unwrap()).collect();
+        assert!(any_match(&filters, 1));
+        assert!(any_match(&filters, 0x2f));
+        assert!(!any_match(&filters, 2));
+    }
+}
@@ -0,0 +1,344 @@
+//! Sync frames for raw word streams, so a reader that joins part way through or loses bytes
+//! can find the word boundaries again and know the time.  Words are written little-endian as
+//! usual, with a sync frame every so often:
+//!
+//! ```text
+//! SYNC_MAGIC, 1, SEQ, COUNT, TIME_LO, TIME_HI, CHECK
+//! ```
+//!
+//! SEQ counts the sync frames from 0, COUNT is the number of data words since the previous
+//! one, TIME is the timestamp of the next data word in microseconds since the capture started
+//! and CHECK is the XOR of SYNC_MAGIC and the four words before it.  A data word that happens
+//! to equal SYNC_MAGIC is written as `SYNC_MAGIC, 0`.
+use std::time::Duration;
+
+/// Starts a sync frame or an escaped data word: "DCCS" in little-endian bytes
+pub const SYNC_MAGIC: u32 = 0x5343_4344;
+const KIND_ESCAPE: u32 = 0;
+const KIND_SYNC: u32 = 1;
+/// Words in a sync frame
+const SYNC_LEN: usize = 7;
+
+/// Writes words with a sync frame at least every `interval` of their timestamps
+pub struct Framer {
+    interval: u128,
+    next: Option<u128>,
+    seq: u32,
+    count: u32,
+}
+
+impl Framer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval: interval.as_micros(),
+            next: None,
+            seq: 0,
+            count: 0,
+        }
+    }
+
+    /// Append `words`, read at `timestamp`, to `out` as bytes.  A sync frame goes first if one
+    /// is due, so the stream starts with one.
+    pub fn encode(&mut self, timestamp: u128, words: &[u32], out: &mut Vec<u8>) {
+        if words.is_empty() {
+            return;
+        }
+        if self.next.is_none_or(|next| timestamp >= next) {
+            let (lo, hi) = (timestamp as u32, (timestamp >> 32) as u32);
+            let check = SYNC_MAGIC ^ self.seq ^ self.count ^ lo ^ hi;
+            for word in [SYNC_MAGIC, KIND_SYNC, self.seq, self.count, lo, hi, check] {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            self.seq = self.seq.wrapping_add(1);
+            self.count = 0;
+            self.next = Some(timestamp + self.interval);
+        }
+        for &word in words {
+            out.extend_from_slice(&word.to_le_bytes());
+            if word == SYNC_MAGIC {
+                out.extend_from_slice(&KIND_ESCAPE.to_le_bytes());
+            }
+        }
+        self.count = self.count.wrapping_add(words.len() as u32);
+    }
+}
+
+/// What `Deframer::push` found in the stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    Word(u32),
+    /// A sync frame, with `expected` the data words since the previous one going by the frame
+    /// and `actual` the number seen.  They differ when words were lost or corrupted, or for
+    /// the first frame after joining part way through.
+    Sync {
+        seq: u32,
+        timestamp: u128,
+        expected: u32,
+        actual: u32,
+    },
+    /// Bytes skipped looking for a sync frame, reported with the frame that ends the search
+    Skipped(usize),
+}
+
+/// Splits a framed stream back into words and sync frames.  Nothing is returned until the
+/// first sync frame, and after anything that doesn't parse the reader looks for the next one.
+#[derive(Default)]
+pub struct Deframer {
+    pending: Vec<u8>,
+    synced: bool,
+    actual: u32,
+    skipped: usize,
+}
+
+fn word_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+/// The sync frame at the start of `bytes`: None if it isn't one, Some(None) if it might be
+/// but more bytes are needed
+fn sync_at(bytes: &[u8]) -> Option<Option<[u32; SYNC_LEN]>> {
+    let words = (bytes.len() / 4).min(SYNC_LEN);
+    let mut frame = [0; SYNC_LEN];
+    for (i, word) in frame.iter_mut().enumerate().take(words) {
+        *word = word_at(bytes, i);
+    }
+    if (words > 0 && frame[0] != SYNC_MAGIC) || (words > 1 && frame[1] != KIND_SYNC) {
+        return None;
+    }
+    if words < SYNC_LEN {
+        return Some(None);
+    }
+    if frame[2..SYNC_LEN - 1].iter().fold(SYNC_MAGIC, |x, w| x ^ w) != frame[SYNC_LEN - 1] {
+        return None;
+    }
+    Some(Some(frame))
+}
+
+impl Deframer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next bytes of the stream, appending what they complete to `out`
+    pub fn push(&mut self, bytes: &[u8], out: &mut Vec<Item>) {
+        self.pending.extend_from_slice(bytes);
+        let mut at = 0;
+        loop {
+            let rest = &self.pending[at..];
+            if !self.synced {
+                match sync_at(rest) {
+                    Some(Some(frame)) => {
+                        if self.skipped > 0 {
+                            out.push(Item::Skipped(self.skipped));
+                            self.skipped = 0;
+                        }
+                        self.sync(frame, out);
+                        self.synced = true;
+                        at += SYNC_LEN * 4;
+                    }
+                    Some(None) => break,
+                    // Not at a frame, so try a byte further on
+                    None if rest.len() >= 4 => {
+                        at += 1;
+                        self.skipped += 1;
+                    }
+                    None => break,
+                }
+                continue;
+            }
+            if rest.len() < 4 {
+                break;
+            }
+            let word = word_at(rest, 0);
+            if word != SYNC_MAGIC {
+                out.push(Item::Word(word));
+                self.actual = self.actual.wrapping_add(1);
+                at += 4;
+                continue;
+            }
+            if rest.len() < 8 {
+                break;
+            }
+            if word_at(rest, 1) == KIND_ESCAPE {
+                out.push(Item::Word(SYNC_MAGIC));
+                self.actual = self.actual.wrapping_add(1);
+                at += 8;
+                continue;
+            }
+            match sync_at(rest) {
+                Some(Some(frame)) => {
+                    self.sync(frame, out);
+                    at += SYNC_LEN * 4;
+                }
+                Some(None) => break,
+                // Corrupt, so look for the next good frame
+                None => self.synced = false,
+            }
+        }
+        self.pending.drain(..at);
+    }
+
+    fn sync(&mut self, frame: [u32; SYNC_LEN], out: &mut Vec<Item>) {
+        out.push(Item::Sync {
+            seq: frame[2],
+            timestamp: (frame[5] as u128) << 32 | frame[4] as u128,
+            expected: frame[3],
+            actual: self.actual,
+        });
+        self.actual = 0;
+    }
+
+    /// Bytes held back waiting for the rest of a word or frame, or skipped since the last one
+    pub fn pending(&self) -> usize {
+        self.pending.len() + self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Batches of words from 1 up, the nth read at n ms, framed every 2ms
+    fn framed(batches: &[usize]) -> Vec<u8> {
+        let mut framer = Framer::new(Duration::from_millis(2));
+        let mut out = vec![];
+        let mut next = 1;
+        for (i, &n) in batches.iter().enumerate() {
+            let words: Vec<u32> = (next..next + n as u32).collect();
+            framer.encode(i as u128 * 1000, &words, &mut out);
+            next += n as u32;
+        }
+        out
+    }
+
+    fn deframe(bytes: &[u8], chunk: usize) -> Vec<Item> {
+        let mut deframer = Deframer::new();
+        let mut items = vec![];
+        for chunk in bytes.chunks(chunk) {
+            deframer.push(chunk, &mut items);
+        }
+        items
+    }
+
+    fn sync(seq: u32, ms: u128, expected: u32, actual: u32) -> Item {
+        Item::Sync {
+            seq,
+            timestamp: ms * 1000,
+            expected,
+            actual,
+        }
+    }
+
+    fn words(items: &[Item]) -> Vec<u32> {
+        items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Word(w) => Some(*w),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = framed(&[2, 1, 3]);
+        let expected = vec![
+            sync(0, 0, 0, 0),
+            Item::Word(1),
+            Item::Word(2),
+            Item::Word(3),
+            sync(1, 2, 3, 3),
+            Item::Word(4),
+            Item::Word(5),
+            Item::Word(6),
+        ];
+        assert_eq!(deframe(&bytes, bytes.len()), expected);
+        // However the bytes arrive
+        for chunk in [1, 3, 5, 28] {
+            assert_eq!(deframe(&bytes, chunk), expected, "in chunks of {}", chunk);
+        }
+        let mut deframer = Deframer::new();
+        deframer.push(&bytes, &mut vec![]);
+        assert_eq!(deframer.pending(), 0);
+    }
+
+    #[test]
+    fn empty_batches_write_nothing() {
+        let mut framer = Framer::new(Duration::from_millis(2));
+        let mut out = vec![];
+        framer.encode(0, &[], &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn escaped_words_that_look_like_frames() {
+        // A sync frame as data, check word and all
+        let fake = [SYNC_MAGIC, KIND_SYNC, 9, 0, 0, 0, SYNC_MAGIC ^ 9];
+        let mut framer = Framer::new(Duration::from_secs(1));
+        let mut bytes = vec![];
+        framer.encode(0, &fake, &mut bytes);
+        framer.encode(1, &[SYNC_MAGIC, KIND_ESCAPE], &mut bytes);
+        let items = deframe(&bytes, 2);
+        assert_eq!(items[0], sync(0, 0, 0, 0));
+        assert_eq!(words(&items), [&fake[..], &[SYNC_MAGIC, KIND_ESCAPE]].concat());
+        assert_eq!(items.len(), 1 + fake.len() + 2);
+    }
+
+    #[test]
+    fn joins_part_way_through() {
+        let bytes = framed(&[2, 1, 3]);
+        // Into the middle of the first frame, off the word boundaries
+        let items = deframe(&bytes[6..], 4);
+        let skipped = SYNC_LEN * 4 + 3 * 4 - 6;
+        assert_eq!(
+            items,
+            [
+                Item::Skipped(skipped),
+                sync(1, 2, 3, 0),
+                Item::Word(4),
+                Item::Word(5),
+                Item::Word(6)
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_before_the_first_frame() {
+        let bytes = framed(&[2]);
+        let mut deframer = Deframer::new();
+        let mut items = vec![];
+        deframer.push(&bytes[..SYNC_LEN * 4 - 1], &mut items);
+        assert!(items.is_empty());
+        assert_eq!(deframer.pending(), SYNC_LEN * 4 - 1);
+        deframer.push(&bytes[SYNC_LEN * 4 - 1..], &mut items);
+        assert_eq!(items, [sync(0, 0, 0, 0), Item::Word(1), Item::Word(2)]);
+    }
+
+    #[test]
+    fn resyncs_after_a_corrupt_frame() {
+        let mut bytes = framed(&[2, 0, 3, 0, 1]);
+        // The second frame's SEQ, so its check fails
+        let second = SYNC_LEN * 4 + 2 * 4;
+        bytes[second + 8] ^= 0x40;
+        let items = deframe(&bytes, 7);
+        let skipped = items
+            .iter()
+            .position(|item| matches!(item, Item::Skipped(_)))
+            .expect("skipped the bad frame");
+        assert_eq!(items[..skipped], [sync(0, 0, 0, 0), Item::Word(1), Item::Word(2)]);
+        // The bad frame and the words after it, which can't be trusted until the next one
+        assert_eq!(items[skipped], Item::Skipped(SYNC_LEN * 4 + 3 * 4));
+        assert!(matches!(items[skipped + 1], Item::Sync { seq: 2, expected: 3, .. }));
+        assert_eq!(items[skipped + 2..], [Item::Word(6)]);
+    }
+
+    #[test]
+    fn counts_lost_words() {
+        let bytes = framed(&[2, 0, 3]);
+        // Drop the first data word after the first frame
+        let at = SYNC_LEN * 4;
+        let bytes = [&bytes[..at], &bytes[at + 4..]].concat();
+        let items = deframe(&bytes, 4);
+        assert_eq!(items[2], sync(1, 2, 2, 1));
+    }
+}
@@ -0,0 +1,74 @@
+//! Liveness tracking for targets that send a heartbeat word every so often.  Heartbeat words
+//! are taken out of the stream, and once none has arrived for the timeout the loss is reported
+//! and the heartbeat-lost hooks run.
+use std::time::Duration;
+
+use dcc_stream::SharedClock;
+
+/// A change in whether the target is alive, from `Heartbeat::poll`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Liveness {
+    /// No heartbeat for this long
+    Lost(Duration),
+    Resumed,
+}
+
+pub struct Heartbeat {
+    word: u32,
+    /// Bits of the word that identify it, leaving the rest free for a counter
+    mask: u32,
+    timeout: Duration,
+    clock: SharedClock,
+    last: Duration,
+    /// Heartbeats seen so far
+    pub count: u64,
+    lost: bool,
+}
+
+impl Heartbeat {
+    pub fn new(word: u32, mask: u32, timeout: Duration, clock: SharedClock) -> Self {
+        Self {
+            word,
+            mask,
+            timeout,
+            last: clock.now(),
+            clock,
+            count: 0,
+            lost: false,
+        }
+    }
+
+    /// Whether `word` is a heartbeat, noting when it arrived if so
+    pub fn check(&mut self, word: u32) -> bool {
+        if word & self.mask != self.word & self.mask {
+            return false;
+        }
+        self.last = self.clock.now();
+        self.count += 1;
+        true
+    }
+
+    /// Time since the last heartbeat, or since the capture started before the first one
+    pub fn age(&self) -> Duration {
+        self.clock.since(self.last)
+    }
+
+    /// Start the timeout again, for when the words aren't being looked at
+    pub fn reset(&mut self) {
+        self.last = self.clock.now();
+    }
+
+    /// Report the target going quiet or coming back, once each time it happens
+    pub fn poll(&mut self) -> Option<Liveness> {
+        let age = self.age();
+        if age >= self.timeout && !self.lost {
+            self.lost = true;
+            Some(Liveness::Lost(age))
+        } else if age < self.timeout && self.lost {
+            self.lost = false;
+            Some(Liveness::Resumed)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! --hook: shell commands run when something happens to the capture, so lab automation can
+//! react at once, e.g. power-cycle the board when the heartbeat is lost.  What happened is in
+//! the environment:
+//!
+//! - `DCC_EVENT`: the event, as given to --hook
+//! - `DCC_TIMESTAMP`: microseconds since the capture started
+//! - `DCC_CORE`: the core, as the stats name it, e.g. `ap1@0x80010000`
+//! - `DCC_MESSAGE`: the marker written to the output for it
+//!
+//! and per event `DCC_TRIGGER` (start or stop) and `DCC_VALUE` for a trigger, `DCC_REASON` for
+//! a reset and `DCC_HEARTBEAT_AGE` in seconds for a lost heartbeat.
+use std::io;
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HookEvent {
+    /// The start or stop trigger fired
+    Trigger,
+    /// The core was reset or powered down under the capture, or set the OS lock again
+    Reset,
+    /// --heartbeat stopped arriving
+    HeartbeatLost,
+}
+
+/// A command for an event, written as `EVENT=COMMAND`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+    pub event: HookEvent,
+    pub command: String,
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (event, command) = s.split_once('=').ok_or_else(|| format!("hook {} must be EVENT=COMMAND", s))?;
+        let event = HookEvent::from_str(event.trim(), true).map_err(|_| {
+            let events: Vec<String> = HookEvent::value_variants()
+                .iter()
+                .filter_map(|e| e.to_possible_value().map(|v| v.get_name().to_string()))
+                .collect();
+            format!("hook {}: unknown event {}, expected one of {}", s, event, events.join(", "))
+        })?;
+        if command.trim().is_empty() {
+            return Err(format!("hook {} has an empty command", s));
+        }
+        Ok(Hook {
+            event,
+            command: command.to_string(),
+        })
+    }
+}
+
+impl HookEvent {
+    fn name(self) -> String {
+        self.to_possible_value().map_or(String::new(), |v| v.get_name().to_string())
+    }
+}
+
+/// Run every hook for `event`, with `vars` added to the environment.  The commands aren't
+/// waited for, so a slow one can't hold up the capture.  Returns the commands that couldn't be
+/// started, with why.
+pub fn run(
+    hooks: &[Hook],
+    event: HookEvent,
+    timestamp: u128,
+    core: &str,
+    message: &str,
+    vars: &[(&str, String)],
+) -> Vec<(String, io::Error)> {
+    let mut failed = vec![];
+    for hook in hooks.iter().filter(|h| h.event == event) {
+        let mut cmd = shell(&hook.command);
+        cmd.env("DCC_EVENT", event.name())
+            .env("DCC_TIMESTAMP", timestamp.to_string())
+            .env("DCC_CORE", core)
+            .env("DCC_MESSAGE", message);
+        for (name, value) in vars {
+            cmd.env(name, value);
+        }
+        match cmd.spawn() {
+            // Reaped in the background so it doesn't linger as a zombie
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => failed.push((hook.command.clone(), e)),
+        }
+    }
+    failed
+}
+
+#[cfg(unix)]
+fn shell(cmd: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(cmd);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(cmd: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(cmd);
+    shell
+}
@@ -0,0 +1,63 @@
+//! Target-specific initialization run after attaching and before streaming, e.g. vendor debug
+//! enables or clock setup.  A script is a list of steps, one per line in a file given by
+//! --init-script or one per string in the `steps` array of the config file's `[init]` table:
+//!
+//! ```text
+//! # Enable the debug clock
+//! write 0x40001000 0x1
+//! delay 10ms
+//! ```
+//!
+//! Addresses are accessed through the selected MEM-AP.
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::DccError;
+use crate::stream::DccStream;
+
+#[derive(Clone, Debug)]
+pub enum Step {
+    Write { addr: u32, value: u32 },
+    Delay(Duration),
+}
+
+pub fn parse_step(line: &str) -> Result<Step, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["write", addr, value] => Ok(Step::Write {
+            addr: crate::parse_u32(addr)?,
+            value: crate::parse_u32(value)?,
+        }),
+        ["delay", time] => Ok(Step::Delay(crate::parse_duration(time)?)),
+        _ => Err(format!("bad init step '{}'", line)),
+    }
+}
+
+/// Parse a script, skipping blank lines and `#` comments
+pub fn parse(text: &str) -> Result<Vec<Step>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| parse_step(line).map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect()
+}
+
+pub fn load(path: &Path) -> Result<Vec<Step>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("read init script {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("init script {}: {}", path.display(), e))
+}
+
+pub(crate) fn run(dcc: &mut DccStream, steps: &[Step]) -> Result<(), DccError> {
+    for step in steps {
+        match *step {
+            Step::Write { addr, value } => {
+                dcc.write_mem(addr, value)?;
+            }
+            Step::Delay(time) => thread::sleep(time),
+        }
+    }
+    Ok(())
+}
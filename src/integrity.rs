@@ -0,0 +1,124 @@
+/// What `Integrity::check` made of a word
+#[derive(Debug, PartialEq, Eq)]
+pub enum Check {
+    /// An ordinary word, to be output
+    Data,
+    /// The checkpoint marker, with the CRC still to come
+    Marker,
+    /// The CRC matched the `count` words since the last checkpoint
+    Passed { count: u64 },
+    /// The CRC didn't match
+    Failed { expected: u32, actual: u32, count: u64 },
+}
+
+/// Verifies the checkpoints the target sends: the marker word, then the CRC-32 of the words
+/// it sent since the previous checkpoint, each taken as 4 little-endian bytes.  The marker and
+/// CRC words themselves aren't covered.
+pub struct Integrity {
+    marker: u32,
+    crc: u32,
+    count: u64,
+    /// The next word is a CRC
+    awaiting: bool,
+}
+
+impl Integrity {
+    pub fn new(marker: u32) -> Self {
+        Self {
+            marker,
+            crc: !0,
+            count: 0,
+            awaiting: false,
+        }
+    }
+
+    pub fn check(&mut self, word: u32) -> Check {
+        if self.awaiting {
+            self.awaiting = false;
+            let actual = !self.crc;
+            let count = self.count;
+            self.crc = !0;
+            self.count = 0;
+            return if word == actual {
+                Check::Passed { count }
+            } else {
+                Check::Failed {
+                    expected: word,
+                    actual,
+                    count,
+                }
+            };
+        }
+        if word == self.marker {
+            self.awaiting = true;
+            return Check::Marker;
+        }
+        self.crc = word.to_le_bytes().iter().fold(self.crc, |crc, &b| crc32_byte(crc, b));
+        self.count += 1;
+        Check::Data
+    }
+}
+
+// Reflected polynomial of the IEEE 802.3 CRC-32, as used by zlib
+const CRC32_POLY: u32 = 0xedb88320;
+
+fn crc32_byte(crc: u32, byte: u8) -> u32 {
+    (0..8).fold(
+        crc ^ byte as u32,
+        |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLY
+            } else {
+                crc >> 1
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKER: u32 = 0xc4c4_c4c4;
+    // "12345678" as little-endian words, and its CRC-32
+    const WORDS: [u32; 2] = [0x3433_3231, 0x3837_3635];
+    const CRC: u32 = 0x9ae0_daaf;
+
+    #[test]
+    fn clean_checkpoints_pass() {
+        let mut integrity = Integrity::new(MARKER);
+        for word in WORDS {
+            assert_eq!(integrity.check(word), Check::Data);
+        }
+        assert_eq!(integrity.check(MARKER), Check::Marker);
+        assert_eq!(integrity.check(CRC), Check::Passed { count: 2 });
+        // Each checkpoint covers the words since the last one, here none
+        assert_eq!(integrity.check(MARKER), Check::Marker);
+        assert_eq!(integrity.check(0), Check::Passed { count: 0 });
+        // A CRC that looks like the marker is still taken as the CRC
+        for word in WORDS {
+            integrity.check(word);
+        }
+        integrity.check(MARKER);
+        assert_eq!(integrity.check(CRC), Check::Passed { count: 2 });
+    }
+
+    #[test]
+    fn corrupted_word_fails() {
+        let mut integrity = Integrity::new(MARKER);
+        integrity.check(WORDS[0]);
+        integrity.check(WORDS[1] ^ 0x100);
+        integrity.check(MARKER);
+        let Check::Failed { expected, actual, count } = integrity.check(CRC) else {
+            panic!("corruption not detected");
+        };
+        assert_eq!((expected, count), (CRC, 2));
+        assert_ne!(actual, CRC);
+        // The next checkpoint starts afresh
+        for word in WORDS {
+            integrity.check(word);
+        }
+        integrity.check(MARKER);
+        assert_eq!(integrity.check(CRC), Check::Passed { count: 2 });
+    }
+}
@@ -0,0 +1,470 @@
+//! The built-in `DebugPort`: jtag_adi over a jtag_taps cable
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::thread;
+
+use jtag_taps::cable::{self, Cable};
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+use jtag_adi::{ArmDebugInterface, DPReg, MemAP, Port};
+use rusb::UsbContext;
+
+use crate::error::DccError;
+#[cfg(unix)]
+use crate::share::is_shared;
+use crate::sim;
+use crate::stream::Target;
+use crate::transport::{AckCounts, DebugPort, RetryPolicy};
+
+/// IDCODE of the ARM JTAG-DP
+pub const ARM_DAP_IDCODE: u32 = 0x4ba00477;
+
+/// IDCODEs of ARM JTAG-DPs across families: Cortex-M3/M4, CoreSight SoC-400 as on most
+/// Cortex-A and R parts, later SoC-400 revisions and SoC-600
+pub const ARM_DAP_IDCODES: &[u32] = &[0x3ba00477, ARM_DAP_IDCODE, 0x5ba00477, 0x6ba00477];
+
+// jtag_adi's error for a WAIT acknowledgement; anything else is a FAULT or sticky error
+const ACK_WAIT: u8 = 1;
+
+// The J-Link speed that selects adaptive clocking, 0xffff kHz, in the Hz jtag_taps takes.  The
+// other jtag_taps cables only have a fixed TCK.
+const JLINK_ADAPTIVE: u32 = 0xffff * 1000;
+
+// Number of IDCODE and DSCR reads that must all succeed for a baud rate to be considered stable
+const AUTO_BAUD_TRIALS: usize = 20;
+
+type Adi = Rc<RefCell<ArmDebugInterface<Box<dyn Cable>>>>;
+
+type JtagTaps = Taps<Box<dyn Cable>>;
+
+/// Open the cable clocked from RTCK, if it can be, and check the IDCODE comes back.  Without
+/// RTCK from the target, TCK stalls or runs unsynchronised and the IDCODE reads as garbage.
+fn open_adaptive(target: &Target) -> Option<(JtagTaps, CableSlot, u32)> {
+    if target.cable != "jlink" {
+        return None;
+    }
+    let (mut taps, slot) = open_taps(target, JLINK_ADAPTIVE).ok()?;
+    let idcode = read_idcode(&mut taps).ok()?;
+    // Bit 0 of every IDCODE is set
+    if idcode & 1 == 0 || idcode == u32::MAX || read_idcode(&mut taps).ok()? != idcode {
+        return None;
+    }
+    Some((taps, slot, idcode))
+}
+
+/// The cable under the taps.  jtag_taps can neither change an open cable's clock nor give the
+/// cable back, so the taps get a `Swappable` and the cable itself is kept here, where
+/// `JtagPort::set_baud` can replace it with one opened at another clock.
+type CableSlot = Rc<RefCell<Option<Box<dyn Cable>>>>;
+
+/// Passes everything on to the cable in its slot
+struct Swappable(CableSlot);
+
+impl Swappable {
+    fn with<T>(&self, f: impl FnOnce(&mut dyn Cable) -> T) -> T {
+        f(self.0.borrow_mut().as_deref_mut().expect("the cable failed to reopen"))
+    }
+}
+
+impl Cable for Swappable {
+    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
+        self.with(|cable| cable.change_mode(tms, tdo))
+    }
+
+    fn read_data(&mut self, bits: usize) -> Vec<u8> {
+        self.with(|cable| cable.read_data(bits))
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) {
+        self.with(|cable| cable.write_data(data, bits, pause_after))
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+        self.with(|cable| cable.read_write_data(data, bits, pause_after))
+    }
+
+    fn flush(&mut self) {
+        self.with(|cable| cable.flush())
+    }
+
+    fn queue_read(&mut self, bits: usize) -> bool {
+        self.with(|cable| cable.queue_read(bits))
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
+        self.with(|cable| cable.queue_read_write(data, bits, pause_after))
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Vec<u8> {
+        self.with(|cable| cable.finish_read(bits))
+    }
+}
+
+/// Open `name` clocked at `baud`
+fn open_cable(name: &str, baud: u32) -> Result<Box<dyn Cable>, DccError> {
+    // The cable drivers panic when the adapter is missing
+    panic::catch_unwind(|| cable::new_from_string(name, baud))
+        .map_err(|_| DccError::CableNotFound(name.to_string()))?
+        .map_err(DccError::CableNotFound)
+}
+
+/// Open the cable at `baud` and select the TAP with the IDCODE instruction loaded
+fn open_taps(target: &Target, baud: u32) -> Result<(JtagTaps, CableSlot), DccError> {
+    let slot = Rc::new(RefCell::new(Some(open_cable(&target.cable, baud)?)));
+    let jtag = JtagSM::new(Box::new(Swappable(slot.clone())) as Box<dyn Cable>);
+    let mut taps = Taps::new(jtag);
+    guard("detect taps", || {
+        taps.detect();
+
+        // IDCODE instruction
+        let ir = vec![14];
+        taps.select_tap(target.tap_index, &ir);
+    })?;
+    Ok((taps, slot))
+}
+
+fn read_idcode(taps: &mut JtagTaps) -> Result<u32, DccError> {
+    let dr = guard("read idcode", || taps.read_dr(32))?;
+    let dr = dr
+        .try_into()
+        .map_err(|dr: Vec<u8>| DccError::AccessFault(format!("idcode read returned {} bytes", dr.len())))?;
+    Ok(u32::from_le_bytes(dr))
+}
+
+/// Run a transport operation, turning a panic in the cable driver or jtag_adi into an
+/// `AccessFault` so that callers can retry instead of unwinding out of the capture
+fn guard<T>(what: &str, f: impl FnOnce() -> T) -> Result<T, DccError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|_| DccError::AccessFault(format!("{}: transport panic", what)))
+}
+
+// USB IDs of the adapters the jtag_taps cable drivers open, with the cable name that opens them.
+// jtagkey opens any FT2232H by its description, so it is listed at FTDI's default ID.
+const KNOWN_PROBES: &[(u16, u16, &str)] = &[
+    (0x1366, 0x0105, "jlink"),
+    (0x16c0, 0x06ad, "usbblaster"),
+    (0x0403, 0x8738, "ef3"),
+    (0x0403, 0x6010, "jtagkey"),
+];
+
+/// The --cable names of the adapters `list_probes` recognises
+pub fn known_cables() -> Vec<&'static str> {
+    KNOWN_PROBES.iter().map(|&(_, _, cable)| cable).collect()
+}
+
+/// An attached USB adapter one of the cable drivers can open
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Probe {
+    /// The --cable name for it
+    pub cable: &'static str,
+    pub bus: u8,
+    pub address: u8,
+    pub vid: u16,
+    pub pid: u16,
+}
+
+/// The attached adapters the cable drivers know, found by their USB IDs.  Enumerating needs no
+/// access to the devices, so it also lists adapters another process is using, and on Windows
+/// adapters without the WinUSB driver.
+pub fn list_probes() -> Result<Vec<Probe>, DccError> {
+    // The global libusb context panics where there is no USB at all, e.g. in containers
+    let devices = rusb::Context::new()
+        .and_then(|usb| usb.devices())
+        .map_err(|e| DccError::Other(format!("list USB devices: {}", e)))?;
+    let mut probes = vec![];
+    for device in devices.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
+        };
+        let known = KNOWN_PROBES
+            .iter()
+            .find(|(vid, pid, _)| *vid == desc.vendor_id() && *pid == desc.product_id());
+        if let Some(&(vid, pid, cable)) = known {
+            probes.push(Probe {
+                cable,
+                bus: device.bus_number(),
+                address: device.address(),
+                vid,
+                pid,
+            });
+        }
+    }
+    Ok(probes)
+}
+
+// Shared cables need Unix sockets
+#[cfg(not(unix))]
+fn is_shared(_cable: &str) -> bool {
+    false
+}
+
+/// Returns true if IDCODE and DSCR reads are reliable at `baud`.  The server of a shared cable
+/// owns its clock, so any baud will do for one.
+pub fn probe_baud(target: &Target, baud: u32) -> bool {
+    if sim::is_sim(&target.cable) || is_shared(&target.cable) {
+        return true;
+    }
+    // jtag_adi panics on transport errors, which are expected while probing too fast
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let (mut taps, _) = open_taps(target, baud)?;
+        // Bit errors show up as the IDCODE changing between reads
+        let first = read_idcode(&mut taps)?;
+        for _ in 1..AUTO_BAUD_TRIALS {
+            let idcode = read_idcode(&mut taps)?;
+            if idcode != first || idcode == 0 || idcode == u32::MAX {
+                return Err(DccError::IdcodeMismatch(idcode));
+            }
+        }
+
+        let adi = Rc::new(RefCell::new(ArmDebugInterface::new(taps)));
+        let mut debug = MemAP::new(adi, target.ap_num);
+        for _ in 0..AUTO_BAUD_TRIALS {
+            debug.read(target.debug_base + 0x88).map_err(|e| DccError::access("read dscr", e))?;
+        }
+        Ok::<(), DccError>(())
+    }));
+    matches!(result, Ok(Ok(())))
+}
+
+/// Returns true if the cable can be opened, or an error if it never will be
+pub fn probe_present(target: &Target) -> Result<bool, DccError> {
+    if sim::is_sim(&target.cable) || is_shared(&target.cable) {
+        return Ok(true);
+    }
+    // The cable drivers panic when the adapter is missing
+    match panic::catch_unwind(|| cable::new_from_string(&target.cable, target.baud)) {
+        Ok(Ok(_)) => Ok(true),
+        Ok(Err(e)) => Err(DccError::CableNotFound(e)),
+        Err(_) => Ok(false),
+    }
+}
+
+/// A MEM-AP reached through jtag_adi
+pub struct JtagPort {
+    adi: Adi,
+    cable: CableSlot,
+    /// The cable's name and the clock it was opened at, for reopening it
+    cable_name: String,
+    baud: u32,
+    debug: MemAP<Box<dyn Cable>>,
+    ap_num: u32,
+    idcode: u32,
+    adaptive_clocking: bool,
+    retry: RetryPolicy,
+    acks: AckCounts,
+}
+
+impl JtagPort {
+    /// Open the cable and select the TAP and AP given by `target`
+    pub fn open(target: &Target) -> Result<Self, DccError> {
+        let adaptive = if target.rtck { open_adaptive(target) } else { None };
+        let adaptive_clocking = adaptive.is_some();
+        let (taps, cable, idcode) = match adaptive {
+            Some(opened) => opened,
+            None => {
+                let (mut taps, cable) = open_taps(target, target.baud)?;
+                let idcode = read_idcode(&mut taps)?;
+                (taps, cable, idcode)
+            }
+        };
+        let adi = Rc::new(RefCell::new(ArmDebugInterface::new(taps)));
+        let debug = MemAP::new(adi.clone(), target.ap_num);
+        Ok(Self {
+            adi,
+            cable,
+            cable_name: target.cable.clone(),
+            baud: if adaptive_clocking { JLINK_ADAPTIVE } else { target.baud },
+            debug,
+            ap_num: target.ap_num,
+            idcode,
+            adaptive_clocking,
+            retry: target.retry,
+            acks: AckCounts::default(),
+        })
+    }
+
+    /// Another MEM-AP in the same JTAG session, so cores behind different APs share one cable
+    pub fn with_ap(&self, ap_num: u32) -> Result<Self, DccError> {
+        let debug = guard("open AP", || MemAP::new(self.adi.clone(), ap_num))?;
+        Ok(Self {
+            adi: self.adi.clone(),
+            cable: self.cable.clone(),
+            cable_name: self.cable_name.clone(),
+            baud: self.baud,
+            debug,
+            ap_num,
+            idcode: self.idcode,
+            adaptive_clocking: self.adaptive_clocking,
+            retry: self.retry,
+            acks: AckCounts::default(),
+        })
+    }
+
+    /// Run a MEM-AP transaction, retrying it with backoff while the DAP answers WAIT.  After a
+    /// FAULT the sticky errors are cleared so the next transaction can go through.
+    fn transact<T>(&mut self, what: &str, mut f: impl FnMut(&mut Self) -> Result<T, u8>) -> Result<T, DccError> {
+        let mut backoff = self.retry.wait_backoff;
+        let mut retries = 0;
+        loop {
+            match guard(what, || f(self))? {
+                Ok(value) => return Ok(value),
+                Err(ACK_WAIT) => {
+                    self.acks.waits += 1;
+                    if retries == self.retry.wait_retries {
+                        return Err(DccError::Wait(what.to_string()));
+                    }
+                    retries += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(ack) => {
+                    self.acks.faults += 1;
+                    let _ = self.clear_errors();
+                    return Err(DccError::access(what, ack));
+                }
+            }
+        }
+    }
+}
+
+impl DebugPort for JtagPort {
+    fn read(&mut self, addr: u32) -> Result<u32, DccError> {
+        self.transact(&format!("read 0x{:x}", addr), |port| port.debug.read(addr))
+    }
+
+    fn write(&mut self, addr: u32, value: u32) -> Result<(), DccError> {
+        self.transact(&format!("write 0x{:x}", addr), |port| port.debug.write(addr, value))
+    }
+
+    fn read_repeated(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        self.transact("DCC read", |port| port.debug.read_multi(addr, count, false, false))
+    }
+
+    /// Split at the 1KB boundaries where the MEM-AP's address auto-increment may stop
+    fn read_block(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        let mut words = Vec::with_capacity(count);
+        while words.len() < count {
+            let at = addr + 4 * words.len() as u32;
+            let n = (count - words.len()).min(((0x400 - (at & 0x3ff)) / 4) as usize);
+            let chunk = self.transact(&format!("read block 0x{:x}", at), |port| {
+                let chunk = port.debug.read_block(at, n, true)?;
+                // jtag_adi drops reads answered with WAIT, which would leave a hole here, so
+                // retry the whole chunk as for a WAIT
+                if chunk.len() != n {
+                    return Err(ACK_WAIT);
+                }
+                Ok(chunk)
+            })?;
+            // jtag_adi doesn't always track where auto-increment left TAR, so read the MEM-AP
+            // state back before the next access relies on it
+            self.debug = guard("reopen AP", || MemAP::new(self.adi.clone(), self.ap_num))?;
+            words.extend(chunk);
+        }
+        Ok(words)
+    }
+
+    /// Registers in the same 16 byte block are read through the MEM-AP's banked data registers,
+    /// so the whole batch is one pipelined transaction
+    fn read_pairs(&mut self, first: u32, second: u32, count: usize) -> Result<Vec<(u32, u32)>, DccError> {
+        let block = first & !0xf;
+        if count == 0 || second & !0xf != block {
+            return (0..count).map(|_| Ok((self.read(first)?, self.read(second)?))).collect();
+        }
+        // BD0-BD3 are registers 4-7 of the MEM-AP
+        let banked = |addr: u32| 4 + ((addr & 0xf) >> 2) as u8;
+        let regs: Vec<u8> = (0..count).flat_map(|_| [banked(first), banked(second)]).collect();
+        self.transact("DCC read", |port| {
+            // Point TAR at the block; the read itself has no side effects
+            port.debug.read(block)?;
+            let results = port.adi.borrow_mut().read_adi_pipelined(port.ap_num, Port::AP, &regs);
+            let mut pairs = Vec::with_capacity(count);
+            for pair in results.chunks_exact(2) {
+                match (pair[0], pair[1]) {
+                    (Ok(a), Ok(b)) => pairs.push((a, b)),
+                    // A WAIT drops that pair
+                    (Err(ACK_WAIT), _) | (_, Err(ACK_WAIT)) => continue,
+                    (Err(e), _) | (_, Err(e)) => return Err(e),
+                }
+            }
+            Ok(pairs)
+        })
+    }
+
+    fn clear_errors(&mut self) -> Result<(), DccError> {
+        guard("clear sticky errors", || {
+            self.adi
+                .borrow_mut()
+                .write_adi_nobank(Port::DP, DPReg::CtrlStat as u8, 1 << 30 | 1 << 28 | 1 << 24 | 1 << 5 | 1 << 1, true)
+        })?
+        .map_err(|e| DccError::access("clear sticky errors", e))
+    }
+
+    /// Abort whatever the DAP was stuck on, clear the sticky errors and reread the MEM-AP
+    /// state jtag_adi caches.  The cable stays open, since other cores may share it.
+    fn reinit(&mut self) -> Result<(), DccError> {
+        guard("abort", || {
+            self.adi.borrow_mut().write_adi_nobank(Port::DP, DPReg::Abort as u8, 1, true)
+        })?
+        .map_err(|e| DccError::access("abort", e))?;
+        self.clear_errors()?;
+        self.debug = guard("reopen AP", || MemAP::new(self.adi.clone(), self.ap_num))?;
+        Ok(())
+    }
+
+    fn read_ap_idr(&mut self, ap_num: u32) -> Result<Option<u32>, DccError> {
+        // IDR is at 0xfc, register 0x3f in the banks jtag_adi counts in
+        self.transact(&format!("read AP{} IDR", ap_num), |port| {
+            port.adi.borrow_mut().read_adi(ap_num, Port::AP, 0x3f)
+        })
+        .map(Some)
+    }
+
+    /// Close the cable and open it again at `baud`; the TAPs and the DAP keep their state
+    /// while TCK is stopped.  If it won't open at `baud` it is opened at the old clock again.
+    fn set_baud(&mut self, baud: u32) -> Result<(), DccError> {
+        if self.adaptive_clocking {
+            return Err(DccError::InvalidConfig("TCK follows RTCK, so there is no clock to set".to_string()));
+        }
+        // The adapter can only be open once
+        *self.cable.borrow_mut() = None;
+        let result = match open_cable(&self.cable_name, baud) {
+            Ok(cable) => {
+                *self.cable.borrow_mut() = Some(cable);
+                self.baud = baud;
+                Ok(())
+            }
+            Err(e) => {
+                *self.cable.borrow_mut() = Some(open_cable(&self.cable_name, self.baud)?);
+                Err(e)
+            }
+        };
+        self.reinit()?;
+        result
+    }
+
+    fn read_csw(&mut self) -> Result<Option<u32>, DccError> {
+        let ap_num = self.ap_num;
+        self.transact(&format!("read AP{} CSW", ap_num), |port| {
+            port.adi.borrow_mut().read_adi(ap_num, Port::AP, 0)
+        })
+        .map(Some)
+    }
+
+    fn write_csw(&mut self, csw: u32) -> Result<(), DccError> {
+        // Through the MemAP, which keeps its own copy of CSW
+        self.transact(&format!("write AP{} CSW", self.ap_num), |port| port.debug.write_csw(csw))
+    }
+
+    fn ack_counts(&self) -> AckCounts {
+        self.acks
+    }
+
+    fn adaptive_clocking(&self) -> bool {
+        self.adaptive_clocking
+    }
+
+    fn idcode(&self) -> u32 {
+        self.idcode
+    }
+}
@@ -0,0 +1,121 @@
+//! Capture the ARM debug communications channel over JTAG.
+//!
+//! [`DccStream`] owns the connection to one core: it opens the cable, prepares the core for
+//! streaming and reads batches of words from DTRTX.  The `dcc-stream` binary adds output,
+//! filtering and the rest of the command line on top.
+//!
+//! ```no_run
+//! use dcc_stream::{CancelToken, DccStreamBuilder};
+//!
+//! let stop = CancelToken::new();
+//! let mut dcc = DccStreamBuilder::new("jlink", 0x80010000).build()?;
+//! dcc.attach(&stop)?;
+//! for record in dcc.iter().take(100) {
+//!     let record = record?;
+//!     println!("{}: {:x}", record.timestamp, record.value);
+//! }
+//! dcc.restore(false)?;
+//! # Ok::<(), dcc_stream::DccError>(())
+//! ```
+//!
+//! Embedders that would rather be called than poll can register callbacks with
+//! [`DccStream::on_record`] and [`DccStream::on_event`].
+//!
+//! The cable drivers and jtag_adi panic on some transport errors, e.g. when the adapter is
+//! unplugged, so callers that need to survive that should use `std::panic::catch_unwind`.
+use std::time::Duration;
+
+mod builder;
+mod cancel;
+pub mod decode;
+pub mod display;
+pub use cancel::CancelToken;
+pub mod clock;
+pub use builder::{Arch, DccStreamBuilder, MAX_QUEUE_SIZE};
+pub use clock::{Clock, SharedClock, SystemClock, VirtualClock};
+mod error;
+pub use error::DccError;
+mod event;
+pub use event::Event;
+pub mod framing;
+pub mod init;
+pub mod jtag;
+pub mod mailbox;
+#[cfg(unix)]
+pub mod mmap;
+pub mod pingpong;
+pub mod plugin;
+pub mod rtt;
+pub mod run_control;
+#[cfg(unix)]
+pub mod share;
+pub use jtag::{known_cables, list_probes, probe_baud, probe_present, Probe, ARM_DAP_IDCODE, ARM_DAP_IDCODES};
+pub mod sim;
+pub mod sink;
+pub mod state;
+mod stream;
+pub use stream::{DccStream, Record, Records, Target};
+#[cfg(feature = "async")]
+pub mod async_api;
+#[cfg(feature = "tracing")]
+pub mod tracing_api;
+pub mod transport;
+
+/// Parse a number in decimal or, with a 0x prefix, hex
+pub fn parse_u32(s: &str) -> Result<u32, String> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).map_err(|e| format!("invalid number {}: {}", s, e))
+    } else {
+        s.parse().map_err(|e| format!("invalid number {}: {}", s, e))
+    }
+}
+
+/// Parse a duration with an optional ms, s or m suffix, defaulting to seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1.0)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        (mins, 60.0)
+    } else {
+        (s, 1.0)
+    };
+    let num: f64 = num.parse().map_err(|_| format!("invalid duration: {}", s))?;
+    if !num.is_finite() {
+        return Err(format!("invalid duration: {}", s));
+    }
+    Duration::try_from_secs_f64(num * scale).map_err(|_| format!("invalid duration: {}", s))
+}
+
+/// Parse a duration as `parse_duration` does, for something done that often, which can't be 0
+pub fn parse_period(s: &str) -> Result<Duration, String> {
+    match parse_duration(s)? {
+        Duration::ZERO => Err(format!("{} is too short, it must be more than 0", s)),
+        period => Ok(period),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+        for s in ["nan", "inf", "-infs", "1e30", "-1", "1h", ""] {
+            assert_eq!(parse_duration(s), Err(format!("invalid duration: {}", s)));
+        }
+    }
+
+    #[test]
+    fn periods() {
+        assert_eq!(parse_period("5s"), Ok(Duration::from_secs(5)));
+        assert!(parse_period("0").is_err());
+        assert!(parse_period("0ms").is_err());
+        assert!(parse_period("nan").is_err());
+    }
+}
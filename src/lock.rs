@@ -0,0 +1,55 @@
+//! Advisory lock on the cable, so two processes don't drive the same adapter at once.  Other
+//! tools can opt in by taking an flock on the same file.
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use dcc_stream::DccError;
+
+/// Held for as long as we own the cable
+pub struct CableLock {
+    _file: File,
+}
+
+pub fn lock_path(cable: &str) -> PathBuf {
+    let name: String = cable.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    std::env::temp_dir().join(format!("dcc-stream-{}.lock", name))
+}
+
+pub fn lock(cable: &str) -> Result<CableLock, DccError> {
+    let path = lock_path(cable);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| DccError::Io(format!("open lock file {}", path.display()), e))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut owner = String::new();
+            let _ = file.read_to_string(&mut owner);
+            let owner = match owner.trim() {
+                "" => "another process".to_string(),
+                pid => format!("pid {}", pid),
+            };
+            return Err(DccError::CableBusy(format!(
+                "{} is in use by {} (lock file {})",
+                cable,
+                owner,
+                path.display()
+            )));
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(DccError::Io(format!("lock {}", path.display()), e));
+        }
+    }
+
+    // Record who holds the lock, for the message above
+    let _ = file.set_len(0);
+    let _ = file.seek(SeekFrom::Start(0));
+    let _ = writeln!(file, "{}", std::process::id());
+    Ok(CableLock { _file: file })
+}
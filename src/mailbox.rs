@@ -0,0 +1,133 @@
+//! A word mailbox in target RAM, read through the MEM-AP instead of the DCC, for targets that
+//! can spare a little RAM and produce more than DTRTX polling can keep up with.  The target
+//! writes words into a ring of `WORDS` words and then advances the producer index; the host
+//! reads from the consumer index up to it and writes the consumer index back.  Both count
+//! words from the start of the data area, and the ring is empty when they are equal, so it
+//! holds at most `WORDS - 1`.
+//!
+//! ```c
+//! volatile uint32_t dcc_mailbox_producer, dcc_mailbox_consumer;
+//! volatile uint32_t dcc_mailbox_data[1024];
+//! ```
+//!
+//! The three are found at given addresses or from those symbols of the firmware's ELF file,
+//! the data area's size being that of its symbol.
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error::DccError;
+use crate::parse_u32;
+use crate::rtt;
+use crate::stream::DccStream;
+
+const PRODUCER: &[u8] = b"dcc_mailbox_producer";
+const CONSUMER: &[u8] = b"dcc_mailbox_consumer";
+const DATA: &[u8] = b"dcc_mailbox_data";
+
+/// Where to find the mailbox, written as `PRODUCER:CONSUMER:DATA:WORDS` or `elf:PATH`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MailboxLocation {
+    Addresses(Mailbox),
+    Elf(PathBuf),
+}
+
+impl FromStr for MailboxLocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("elf:") {
+            return Ok(MailboxLocation::Elf(PathBuf::from(path)));
+        }
+        let fields: Vec<&str> = s.split(':').collect();
+        let [producer, consumer, data, words] = fields[..] else {
+            return Err(format!("mailbox {} must be PRODUCER:CONSUMER:DATA:WORDS or elf:PATH", s));
+        };
+        Mailbox::new(parse_u32(producer)?, parse_u32(consumer)?, parse_u32(data)?, parse_u32(words)?).map(MailboxLocation::Addresses)
+    }
+}
+
+/// The mailbox's indices and data area, shown as `PRODUCER:CONSUMER:DATA:WORDS`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mailbox {
+    producer: u32,
+    consumer: u32,
+    data: u32,
+    words: u32,
+}
+
+impl Mailbox {
+    pub fn new(producer: u32, consumer: u32, data: u32, words: u32) -> Result<Self, String> {
+        if [producer, consumer, data].iter().any(|addr| addr & 3 != 0) {
+            return Err("mailbox addresses must be word aligned".to_string());
+        }
+        if words < 2 {
+            return Err("a mailbox needs at least 2 words of data".to_string());
+        }
+        if data as u64 + words as u64 * 4 > 1 << 32 {
+            return Err(format!("mailbox data must fit below 4GB, {} words from 0x{:x} don't", words, data));
+        }
+        Ok(Self {
+            producer,
+            consumer,
+            data,
+            words,
+        })
+    }
+
+    /// The mailbox at `location`
+    pub fn locate(location: &MailboxLocation) -> Result<Self, DccError> {
+        let path = match location {
+            MailboxLocation::Addresses(mailbox) => return Ok(mailbox.clone()),
+            MailboxLocation::Elf(path) => path,
+        };
+        let elf = fs::read(path).map_err(|e| DccError::Io(format!("read {}", path.display()), e))?;
+        let symbol = |name: &[u8]| {
+            rtt::elf_symbol(&elf, name)
+                .ok_or_else(|| DccError::InvalidConfig(format!("no {} symbol in {}", String::from_utf8_lossy(name), path.display())))
+        };
+        let (producer, _) = symbol(PRODUCER)?;
+        let (consumer, _) = symbol(CONSUMER)?;
+        let (data, size) = symbol(DATA)?;
+        Self::new(producer, consumer, data, (size / 4).min(u32::MAX as u64) as u32)
+            .map_err(|e| DccError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// The producer and consumer indices, checked against the size
+    pub fn indices(&self, dcc: &mut DccStream) -> Result<(u32, u32), DccError> {
+        let producer = dcc.read_mem(self.producer)?;
+        let consumer = dcc.read_mem(self.consumer)?;
+        if producer >= self.words || consumer >= self.words {
+            return Err(DccError::Other(format!(
+                "mailbox indices out of range: producer {} consumer {} for {} words",
+                producer, consumer, self.words
+            )));
+        }
+        Ok((producer, consumer))
+    }
+
+    /// Take up to `max` of the words the target has written since the last read, marking
+    /// them read
+    pub fn read(&mut self, dcc: &mut DccStream, max: u32) -> Result<Vec<u32>, DccError> {
+        let (producer, consumer) = self.indices(dcc)?;
+        let count = ((producer + self.words - consumer) % self.words).min(max);
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        // Up to the end of the data area, then on from its start if the words wrap
+        let first = count.min(self.words - consumer);
+        let mut words = dcc.read_block(self.data + consumer * 4, first as usize)?;
+        if count > first {
+            words.extend(dcc.read_block(self.data, (count - first) as usize)?);
+        }
+        dcc.write_mem(self.consumer, (consumer + count) % self.words)?;
+        Ok(words)
+    }
+}
+
+impl fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:x}:0x{:x}:0x{:x}:{}", self.producer, self.consumer, self.data, self.words)
+    }
+}
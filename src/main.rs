@@ -1,136 +1,2240 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::time::SystemTime;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::builder::ArgPredicate;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, Parser, ValueEnum};
+use toml::{Table, Value};
 
-use jtag_taps::cable;
-use jtag_taps::statemachine::JtagSM;
-use jtag_taps::taps::Taps;
+use dcc_stream::decode::{Decoder, DecoderKind};
+use dcc_stream::display::{Radix, ValueFormat};
+use dcc_stream::mailbox::{Mailbox, MailboxLocation};
+use dcc_stream::pingpong::Accounting;
+use dcc_stream::plugin::Plugins;
+use dcc_stream::rtt::RttLocation;
+use dcc_stream::run_control::{Access, Vector};
+use dcc_stream::sink::{self, FileWriter, OutputFormat, Sink, Timestamps};
+use dcc_stream::state::StateFile;
+use dcc_stream::transport::RetryPolicy;
+use dcc_stream::{
+    init, parse_duration, parse_period, parse_u32, Arch, CancelToken, DccError, DccStreamBuilder, Record, ARM_DAP_IDCODE, ARM_DAP_IDCODES,
+    MAX_QUEUE_SIZE,
+};
+use dcc_stream::{SharedClock, SystemClock, VirtualClock};
 
-use jtag_adi::{ArmDebugInterface, MemAP};
+mod adaptive;
+use adaptive::{AdaptiveQueue, Tuning};
+mod bench;
+mod bp;
+mod capabilities;
+mod config;
+mod control;
+mod convert;
+use control::{Command, ControlServer};
+mod change;
+use change::ChangeGate;
+mod cores;
+use cores::CoreSpec;
+mod cycles;
+use cycles::{CycleStamps, Stamped};
+mod filter;
+use filter::Filter;
+mod probes;
+mod progress;
+use progress::Progress;
+mod ratelimit;
+use ratelimit::RateLimit;
+mod signals;
+use signals::Signals;
+mod stats;
+mod status;
+use stats::{CoreCounts, Stats, StatsFormat};
+use status::StatusLine;
+mod heartbeat;
+mod hooks;
+use heartbeat::{Heartbeat, Liveness};
+use hooks::{Hook, HookEvent};
+mod integrity;
+use integrity::{Check, Integrity};
+mod lock;
+mod mem;
+mod merge;
+mod output;
+mod policy;
+use output::Output;
+use policy::SinkPolicy;
+mod reader;
+use reader::{FullPolicy, Msg, OsLockPolicy, Reader, Shared};
+mod record;
+use record::{Recorder, Recording};
+mod report;
+use report::Report;
+mod ring;
+mod selftest;
+mod sequence;
+#[cfg(unix)]
+mod serve;
+use sequence::{Sequence, SequenceMode};
+mod syslog;
+mod target_time;
+use target_time::TargetClock;
+mod template;
+mod trigger;
+use trigger::{Gate, StartTrigger, StopTrigger};
+#[cfg_attr(not(feature = "tui"), path = "tui_disabled.rs")]
+mod tui;
+use tui::Tui;
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None, after_help = "See `dcc-stream bench --help` for finding the best --queue-size.\n\n\
+`dcc-stream replay FILE [OPTIONS]` feeds a --record recording back through the output options, \
+taking the session settings from the recording, and `dcc-stream decode --help` covers decoding a \
+--format raw capture.  `dcc-stream selftest --help` checks the debug path stage by stage and `dcc-stream probes` lists \
+the attached adapters.  `dcc-stream serve --help` shares one cable between several dcc-stream processes, and `dcc-stream bp --help` \
+sets hardware breakpoints.  `dcc-stream mem --help` reads and writes target memory, alongside a capture through a shared cable.  `dcc-stream config check [OPTIONS]` checks the options and the config file without a target \
+and prints what they resolve to.")]
 struct Args {
-    #[arg(short, long)]
+    #[arg(long, default_value_t = false, exclusive = true)]
+    /// Print what this build supports as JSON, its features, transports, formats, decoders
+    /// and destinations among them, and exit
+    capabilities_json: bool,
+    #[arg(long, env = "DCC_CONFIG")]
+    /// Read options from a TOML config file, options on the command line take precedence.  The
+    /// file is re-read on SIGHUP.
+    config: Option<PathBuf>,
+    #[arg(short, long, env = "DCC_CABLE")]
+    /// The JTAG cable, sim:SCENARIO.toml for a simulated target, or share:SOCKET for a cable
+    /// owned by `dcc-stream serve`, whose baud and TAP settings then apply
     cable: String,
-    #[arg(short, long)]
+    #[arg(short, long, env = "DCC_BAUD")]
     baud: u32,
-    #[arg(short, long, default_value_t = 0)]
+    #[arg(long, default_value_t = false, conflicts_with = "auto_baud", env = "DCC_RTCK")]
+    /// Clock TCK from the target's RTCK (adaptive clocking), falling back to --baud when the
+    /// cable or the target can't.  Only jlink cables support it.
+    rtck: bool,
+    #[arg(short, long, default_value_t = 0, env = "DCC_TAP_INDEX")]
     /// Which JTAG TAP to use
     tap_index: usize,
-    #[arg(short, long, default_value_t = 1)]
+    #[arg(short, long, default_value_t = 1, env = "DCC_AP_NUM")]
     /// Which access port to use
     ap_num: u32,
-    #[arg(short, long, default_value_t = 16)]
-    /// Number of reads to queue per batch
+    #[arg(long)]
+    /// Also stream from the core at debug base BASE behind access port AP, written to DEST, as
+    /// AP:BASE=DEST.  May be given more than once; the cores are read in turn.  Triggers,
+    /// --count and the TUI follow the first core only.
+    core: Vec<CoreSpec>,
+    #[arg(long, default_value_t = false)]
+    /// Also write every --core to the main output, in time order with each line tagged with
+    /// its core.  DEST may then be left off.  Needs --format text.
+    merge_cores: bool,
+    #[arg(long, value_parser = parse_duration)]
+    /// Mark each core's stream when it sends nothing new for this long, and when it resumes
+    core_stall: Option<Duration>,
+    #[arg(long, value_enum, default_value_t = Arch::Armv7, env = "DCC_ARCH")]
+    /// Debug architecture of the core
+    arch: Arch,
+    #[arg(long, value_parser = parse_u32)]
+    /// Accept a debug port with this IDCODE instead of the known ARM ones, may be given more
+    /// than once
+    expect_idcode: Vec<u32>,
+    #[arg(long, default_value_t = false)]
+    /// Carry on with a debug port whatever its IDCODE
+    force: bool,
+    #[arg(short, long, default_value_t = 16, env = "DCC_QUEUE_SIZE")]
+    /// Number of reads to queue per batch, the starting size with --adaptive-queue
     queue_size: u32,
     #[arg(long, default_value_t = false)]
+    /// Grow the batch while the target keeps it filled and shrink it while reads are mostly
+    /// stale, between --queue-min and --queue-max
+    adaptive_queue: bool,
+    #[arg(long, value_enum, default_value_t = Tuning::Stale)]
+    /// What --adaptive-queue sizes batches by
+    tune_by: Tuning,
+    #[arg(long, default_value_t = 1)]
+    /// Smallest batch for --adaptive-queue
+    queue_min: usize,
+    #[arg(long, default_value_t = 1024)]
+    /// Largest batch for --adaptive-queue
+    queue_max: usize,
+    #[arg(long, default_value_t = false)]
     /// Ignore duplicate values
     nodups: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "debounce")]
+    /// Only output a word when it differs from the last word output, for status words that
+    /// change slowly
+    on_change: bool,
+    #[arg(long, value_parser = parse_duration)]
+    /// Hold back a word that repeats the last word output until this long after it, e.g. 10ms
+    debounce: Option<Duration>,
+    #[arg(long, value_enum)]
+    /// Check the rolling counter the target embeds in the stream and report lost words
+    sequence: Option<SequenceMode>,
+    #[arg(long, value_parser = parse_u32)]
+    /// Verify the checkpoints the target sends: this word, then the CRC-32 of the words since
+    /// the last one.  Repeated words count as stale, so use --txfull if the data can repeat.
+    crc_marker: Option<u32>,
+    #[arg(long, value_parser = parse_u32, requires = "cycle_hz", conflicts_with_all = ["target_time", "no_timestamps"])]
+    /// Take the target's own timestamps out of the stream: this word, then its 32-bit cycle
+    /// counter.  The words after a stamp are timed by the counter, laid on the host's clock at
+    /// the first stamp, with the later ones interpolated from the host's timing.
+    cycle_marker: Option<u32>,
+    #[arg(long, requires = "cycle_marker", value_parser = clap::value_parser!(u64).range(1..))]
+    /// The frequency of the --cycle-marker counter, e.g. the core clock for PMCCNTR
+    cycle_hz: Option<u64>,
+    #[arg(long, default_value_t = false)]
+    /// Read DSCR with each DTRTX read and only keep words the target wrote, so repeated values
+    /// are real.  Stall mode is left off.
+    txfull: bool,
+    #[arg(long, value_parser = parse_u32)]
+    /// Treat a word matching this under --burst-mask as a header announcing a burst, and read
+    /// exactly the rest of the burst next: as many words as the bits outside the mask, after
+    /// the header.  The header stays in the stream.  Counting needs every word read once, so
+    /// the DCC must be in stall mode or read with --txfull.
+    burst_header: Option<u32>,
+    #[arg(long, value_parser = parse_u32, default_value = "0xffff0000", requires = "burst_header")]
+    /// Bits of a word that must match --burst-header, the rest hold the burst length
+    burst_mask: u32,
+    #[arg(long, value_parser = parse_duration)]
+    /// Sleep between batches while the target sends nothing new, doubling from 1ms up to this
+    /// long, e.g. 20ms
+    idle_backoff: Option<Duration>,
+    #[arg(long, default_value_t = 256)]
+    /// Number of batches the reader thread may get ahead of the output
+    channel_depth: usize,
+    #[arg(long, value_enum, default_value_t = FullPolicy::Block)]
+    /// What the reader does with a batch when the output is --channel-depth batches behind
+    on_full: FullPolicy,
+    #[arg(long)]
+    /// Only output values matching "value [& MASK] (==|!=) VALUE", may be given more than once
+    filter: Vec<Filter>,
+    #[arg(short, long)]
+    /// Write the stream to DEST instead of stdout: a file, "-" for stdout, tcp:host:port or
+    /// unix:path, or plugin:NAME[:ARG] for a sink from --plugin-dir.  May be given more than
+    /// once.  A file name can be a template such as "cap-{profile}-{date}-{seq}.bin", filled in
+    /// when it is opened: {profile} is the --config file's name, {date} and {time} are in UTC
+    /// and {seq} numbers the files from 001.  Outputs with their own format, filter or rate
+    /// limit go in [[sink]] sections of the config file.
+    output: Vec<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    /// How the stream is written to the outputs
+    format: OutputFormat,
+    #[arg(long, value_parser = parse_period, conflicts_with = "no_timestamps")]
+    /// Put a sync frame with the time and a sequence number in --format raw or base64 output
+    /// at least this often, e.g. 1s, so a reader can join part way through or recover from
+    /// corruption.  Data words that look like one are escaped; `dcc-stream decode --synced`
+    /// reads it back.
+    sync_interval: Option<Duration>,
+    #[arg(long, default_value_t = false, conflicts_with = "adaptive_queue")]
+    /// Don't time the words or write timestamps, for the most throughput when only the values
+    /// matter.  Latency and gap statistics go unmeasured.
+    no_timestamps: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "no_timestamps")]
+    /// Write timestamps that were spread over a batch rather than measured as `TIMESTAMP~:` in
+    /// --format text.  Only the first read of each batch is timed; the words after it are
+    /// placed by which read returned them.
+    mark_interpolated: bool,
+    #[arg(long)]
+    /// Also record every word read, with its timing and the session settings, for `dcc-stream
+    /// replay`
+    record: Option<PathBuf>,
+    #[arg(long, default_value_t = false)]
+    /// When replaying, output the words at the pace they were recorded instead of as fast as
+    /// possible
+    realtime: bool,
+    #[arg(long)]
+    /// When replaying, output the words this many times faster than they were recorded, e.g.
+    /// 0.5 or 10.  Implies --realtime.
+    speed: Option<f64>,
+    #[arg(long, value_parser = record::parse_offset)]
+    /// When replaying, start this far into the recording, as HH:MM:SS or a duration such as 90s.
+    /// A recording from a capture that ended normally is indexed, so this is found without
+    /// reading what comes before it.
+    from: Option<Duration>,
+    #[arg(long, value_parser = record::parse_offset)]
+    /// When replaying, stop this far into the recording, as for --from
+    to: Option<Duration>,
+    /// The recording being replayed
+    #[arg(skip)]
+    replay: Option<PathBuf>,
+    #[arg(long, conflicts_with_all = ["core", "txfull", "burst_header", "adaptive_queue", "check", "auto_baud"])]
+    /// Read a SEGGER RTT up-buffer through the MEM-AP instead of the DCC, finding the control
+    /// block at an address, by scanning with scan:START:LEN or from the _SEGGER_RTT symbol with
+    /// elf:PATH.  --ap-num must be an AP that reaches the target's RAM, and the debug base can
+    /// be left out.
+    rtt: Option<RttLocation>,
+    #[arg(long, default_value_t = 0, requires = "rtt")]
+    /// Which RTT up-buffer to read
+    rtt_channel: u32,
+    #[arg(
+        long,
+        conflicts_with_all = ["rtt", "core", "txfull", "burst_header", "adaptive_queue", "check", "auto_baud"]
+    )]
+    /// Read words from a ring buffer in target RAM through the MEM-AP instead of the DCC, given
+    /// as PRODUCER:CONSUMER:DATA:WORDS, the addresses of the two index words and of the data
+    /// area and its size in words, or as elf:PATH to take them from the dcc_mailbox_producer,
+    /// dcc_mailbox_consumer and dcc_mailbox_data symbols.  The indices count words into the
+    /// data area; the target advances the producer index as it writes and the consumer index
+    /// is written back as words are read.  --ap-num and the debug base are as for --rtt.
+    mailbox: Option<MailboxLocation>,
+    #[arg(long, default_value_t = false)]
+    /// Write output files through a memory mapping, allocated 64MB at a time, for long high
+    /// rate captures
+    mmap: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "mmap")]
+    /// Write output files from two 8MB buffers in turn, one filling while a thread of its own
+    /// writes the other, for sustained high rate captures on slow disks.  Nothing is dropped:
+    /// if the disk falls behind the capture waits, and how long for is reported at the end.
+    ping_pong: bool,
+    /// What the --ping-pong writers waited for
+    #[arg(skip)]
+    disk: Accounting,
+    #[arg(long, value_enum, default_value_t = DecoderKind::Raw)]
+    /// How to decode the words before output
+    decode: DecoderKind,
+    #[arg(long, requires = "plugin_dir", conflicts_with = "context_ids")]
+    /// Decode the words with a decoder from --plugin-dir instead, given as NAME[:ARG]
+    decode_plugin: Option<String>,
+    #[arg(long, env = "DCC_PLUGIN_DIR")]
+    /// Load the sink and decoder plugins in this directory, shared libraries exporting
+    /// dcc_stream_plugin (Unix only)
+    plugin_dir: Option<PathBuf>,
+    #[arg(long, default_value_t = false)]
+    /// The target interleaves threads, each run of words preceded by a context switch record
+    /// of 0x58544344, the thread ID and CONTEXTIDR.  Each thread is decoded apart and its
+    /// output tagged with the thread.
+    context_ids: bool,
+    #[arg(long, value_enum, default_value_t = Radix::Hex)]
+    /// Number base used to display values
+    radix: Radix,
+    #[arg(long)]
+    /// Multiply values by this before display, e.g. 0.001 for millivolts to volts
+    scale: Option<f64>,
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    /// Add this to values after scaling
+    offset: f64,
+    #[arg(long)]
+    /// Unit to show after displayed values
+    unit: Option<String>,
+    #[arg(long, default_value_t = false)]
+    /// Stop polling the target while paused, instead of reading and discarding words
+    pause_polling: bool,
+    #[arg(long)]
+    /// Stop after capturing this many words
+    count: Option<u64>,
+    #[arg(long, value_parser = parse_duration)]
+    /// Stop after capturing for this long, e.g. 30s or 10m
+    duration: Option<Duration>,
+    #[arg(long, value_parser = parse_duration)]
+    /// Alert if no new word arrives for this long, e.g. 10s
+    expect_data_within: Option<Duration>,
+    #[arg(long, value_enum, default_value_t = NoDataAction::Warn)]
+    /// What the --expect-data-within alert does
+    on_no_data: NoDataAction,
+    #[arg(long, value_parser = parse_u32)]
+    /// Treat this word as the target's heartbeat: take it out of the stream and alert when it
+    /// stops.  Repeated words count as stale, so vary the bits outside --heartbeat-mask or use
+    /// --txfull.
+    heartbeat: Option<u32>,
+    #[arg(long, value_parser = parse_u32, default_value = "0xffffffff", requires = "heartbeat")]
+    /// Bits of a word that must match --heartbeat
+    heartbeat_mask: u32,
+    #[arg(long, value_parser = parse_period, default_value = "5s", requires = "heartbeat")]
+    /// How long without a heartbeat before the target counts as lost
+    heartbeat_timeout: Duration,
+    #[arg(long, requires = "heartbeat")]
+    /// Shell command to run when the heartbeat is lost, with the seconds since the last one in
+    /// DCC_HEARTBEAT_AGE.  The same as --hook heartbeat-lost=COMMAND.
+    on_heartbeat_lost: Option<String>,
+    #[arg(long)]
+    /// Run a shell command when something happens, given as EVENT=COMMAND with an EVENT of
+    /// trigger, reset or heartbeat-lost.  The details are in DCC_EVENT, DCC_TIMESTAMP,
+    /// DCC_CORE, DCC_MESSAGE and, by event, DCC_TRIGGER, DCC_VALUE, DCC_REASON and
+    /// DCC_HEARTBEAT_AGE.  May be given more than once.
+    hook: Vec<Hook>,
+    #[arg(long)]
+    /// Maximum number of words per second to output, the rest are counted and dropped
+    max_rate: Option<u32>,
+    #[arg(long, value_parser = parse_u32)]
+    /// Discard everything until this word is seen
+    trigger_start: Option<u32>,
+    #[arg(long, default_value_t = false)]
+    /// Output the start trigger word itself
+    include_trigger: bool,
+    #[arg(long, default_value_t = 0)]
+    /// Number of words from before the start trigger to output when it fires
+    pre_trigger: usize,
+    #[arg(long, value_parser = parse_u32)]
+    /// End the capture when this word is seen
+    trigger_stop: Option<u32>,
+    #[arg(long, default_value_t = 0)]
+    /// Number of words to capture after the stop trigger
+    post_trigger: u64,
+    #[arg(long, value_parser = parse_u32, conflicts_with_all = ["rtt", "mailbox"])]
+    /// Discard everything until the first core accesses the word at this address, caught
+    /// with a hardware watchpoint.  The core halts briefly while the watchpoint is handled.
+    watch_start: Option<u32>,
+    #[arg(long, value_parser = parse_u32, conflicts_with_all = ["rtt", "mailbox"])]
+    /// End the capture when the first core accesses the word at this address, after
+    /// --watch-start if given
+    watch_stop: Option<u32>,
+    #[arg(long, value_enum, default_value_t = Access::Store)]
+    /// Which accesses --watch-start and --watch-stop trigger on
+    watch_access: Access,
     #[arg(long, default_value_t = false)]
     /// Show periodic statistics
     stats: bool,
+    #[arg(long, default_value = "5s", value_parser = parse_period, env = "DCC_STATS_INTERVAL")]
+    /// How often to print statistics, e.g. 500ms, 5s or 1m
+    stats_interval: Duration,
+    #[arg(long, value_enum, env = "DCC_STATS_FORMAT")]
+    /// Format of statistics records, implies --stats
+    stats_format: Option<StatsFormat>,
+    #[arg(long, env = "DCC_STATS_OUTPUT")]
+    /// Write statistics to a file, tcp:host:port or unix:path instead of stderr, implies --stats
+    stats_output: Option<String>,
+    #[arg(long)]
+    /// On exit, write the session totals and the throughput in each --stats-interval to this
+    /// file, as CSV if it ends in .csv and JSON otherwise
+    stats_out: Option<PathBuf>,
+    #[arg(long)]
+    /// On exit, write a JSON report of the run to this file: the session and attach settings,
+    /// the outputs, how the run ended, the session stats and every marker and warning with its
+    /// timestamp, such as triggers, reattaches and integrity failures
+    report: Option<PathBuf>,
+    #[arg(long, default_value_t = false)]
+    /// Verify the debug path is alive and exit without streaming
+    check: bool,
+    #[arg(long, env = "DCC_STATE_DIR")]
+    /// Where each board profile's state file goes, by default $XDG_STATE_HOME/dcc-stream or
+    /// ~/.local/state/dcc-stream.  Before attaching to a core its DSCR, OS lock and debug AP CSW
+    /// are saved there, unless a capture since the last --restore-state already did.
+    state_dir: Option<PathBuf>,
+    #[arg(long, default_value_t = false, conflicts_with_all = ["check", "attach_only"])]
+    /// Put back the saved debug state of every core and exit without streaming
+    restore_state: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "attach_only")]
+    /// Put back the saved debug state of every core when the capture ends, as --restore-state
+    /// does, rather than only undoing this capture's changes
+    restore_state_on_exit: bool,
+    #[arg(long, default_value_t = false)]
+    /// Lower the clock from --baud until the debug path is stable, then use that rate
+    auto_baud: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "tui")]
+    /// Keep a line on stderr updated with a sparkline of the throughput, the duplicate rate and
+    /// the error count, in place of the progress bar
+    status: bool,
+    #[arg(long, default_value_t = false)]
+    /// Show the stream in an interactive full screen view
+    tui: bool,
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    /// Give up attaching if DSCR can't be read for this long, 0 to keep trying
+    dscr_timeout: Duration,
+    #[arg(long, default_value_t = 4)]
+    /// Times a debug access answered with WAIT is retried
+    wait_retries: u32,
+    #[arg(long, default_value = "0.1ms", value_parser = parse_duration)]
+    /// Sleep before the first WAIT retry, doubled for each retry after it
+    wait_backoff: Duration,
+    #[arg(long, default_value_t = false)]
+    /// End the capture when reads keep failing after the transport is reinitialised, instead of
+    /// reattaching to the core
+    no_reattach: bool,
+    #[arg(long, value_enum, default_value_t = OsLockPolicy::Clear)]
+    /// What to do when the target sets the OS lock again while streaming, e.g. after a warm reset
+    os_lock: OsLockPolicy,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["init_script", "catch_reset", "vector_catch", "watch_start", "watch_stop", "halt_on", "relock", "check", "rtt", "mailbox"]
+    )]
+    /// Stream without changing anything on the target, for a core set up by another debugger
+    /// or by firmware that must not be disturbed: the OS lock isn't cleared, so it must be
+    /// clear at the start and is waited for if the target sets it later, stall mode is left as
+    /// it is and overruns go unchecked, since clearing them is a write.  Use --txfull or
+    /// --nodups unless the core is already in stall mode.  Opening the debug port still
+    /// requests debug power, as every debugger's must.
+    attach_only: bool,
+    #[arg(long, default_value_t = false)]
+    /// Set the OS lock again on exit
+    relock: bool,
+    #[arg(long, default_value_t = false, conflicts_with_all = ["rtt", "mailbox"])]
+    /// Warm reset the first core with reset catch on, then let it run once attached, so the
+    /// capture starts with the first word it sends
+    catch_reset: bool,
+    #[arg(long, value_parser = parse_u32)]
+    /// Where the ARMv8 core's CTI is, used to restart it after --catch-reset and watchpoints.
+    /// By default 64KB above the debug base.
+    cti_base: Option<u32>,
+    #[arg(long, value_enum, value_delimiter = ',')]
+    /// Halt the cores when they take these exceptions, e.g. undef,pabort,dabort, and report it.
+    /// A core that halts is left halted for inspection.  ARMv8 can only catch every exception,
+    /// or resets.
+    vector_catch: Vec<Vector>,
+    #[arg(long, default_value_t = false)]
+    /// End the capture when a core halts, e.g. at a breakpoint set with `dcc-stream bp`, and
+    /// leave a core halted with --halt-on halted
+    stop_on_halt: bool,
+    #[arg(long, conflicts_with_all = ["rtt", "mailbox"])]
+    /// Halt the core the first time it sends a word matching "value [& MASK] (==|!=) VALUE",
+    /// and output its registers.  It is let run again unless --stop-on-halt.  May be given more
+    /// than once.
+    halt_on: Vec<Filter>,
+    #[arg(long, value_parser = parse_u32, requires = "target_clock_hz", conflicts_with_all = ["rtt", "mailbox", "no_timestamps"])]
+    /// Read the target's memory-mapped generic timer counter, CNTCV, at this address once a
+    /// second and keep a mapping from host to target time.  The first sample is marked in the
+    /// output.  It must be reachable through --ap-num.
+    target_clock: Option<u32>,
+    #[arg(long)]
+    /// The counter's frequency, as in CNTFRQ, used until the mapping has measured it
+    target_clock_hz: Option<u64>,
+    #[arg(long, default_value_t = false, requires = "target_clock")]
+    /// Timestamp the words in target time, microseconds of the --target-clock counter, rather
+    /// than since the capture started
+    target_time: bool,
+    #[arg(long, default_value_t = false)]
+    /// Run a sim: cable on simulated time, which moves only as the simulated accesses and
+    /// waits do, so the same scenario gives the same capture every time and runs as fast as
+    /// the host can.  Timestamps, stats intervals, --duration and heartbeat timeouts all follow
+    /// it.
+    virtual_time: bool,
+    #[arg(long, default_value_t = false)]
+    /// Wait for the cable to be plugged in, and wait again if it goes away
+    wait_for_probe: bool,
+    #[arg(long, default_value_t = false)]
+    /// Don't take the advisory lock that stops two processes sharing a cable
+    no_lock: bool,
+    #[arg(long, env = "DCC_INIT_SCRIPT")]
+    /// Run the register writes and delays in this file after attaching, before streaming.  See
+    /// also the [init] section of the config file.
+    init_script: Option<PathBuf>,
+    /// Init steps from the config file
+    #[arg(skip)]
+    init: Vec<init::Step>,
+    /// Outputs from the config file's [[sink]] tables
+    #[arg(skip)]
+    sinks: Vec<SinkPolicy>,
+    #[arg(long, default_value_t = false)]
+    /// Detach from the terminal, log to syslog and enable the control socket
+    daemon: bool,
+    #[arg(long, env = "DCC_PIDFILE")]
+    /// Write the process ID to this file, removed again on exit
+    pidfile: Option<PathBuf>,
+    #[arg(long, env = "DCC_CONTROL_SOCKET")]
+    /// Accept control commands on this Unix socket
+    control_socket: Option<PathBuf>,
+    #[arg(
+        value_parser = parse_u32,
+        env = "DCC_DEBUG_BASE",
+        required = false,
+        required_unless_present_any = ["rtt", "mailbox"],
+        default_value_if("rtt", ArgPredicate::IsPresent, "0"),
+        default_value_if("mailbox", ArgPredicate::IsPresent, "0")
+    )]
     /// CPU debug base address, prefix with 0x for hexadecimal
-    debug_base: String,
+    debug_base: u32,
 }
 
-fn main() {
-    let args = Args::parse();
-    let cable = cable::new_from_string(&args.cable, args.baud).expect("cable");
-    let jtag = JtagSM::new(cable);
-    let mut taps = Taps::new(jtag);
-    taps.detect();
-
-    // IDCODE instruction
-    let ir = vec![14];
-    taps.select_tap(args.tap_index, &ir);
-    let dr = taps.read_dr(32);
-    let idcode = u32::from_le_bytes(dr.try_into().unwrap());
-
-    // Verify ARM ID code
-    if idcode != 0x4ba00477 {
-        eprintln!("Warning: unexpected idcode {:x}", idcode);
+const AUTO_BAUD_MIN: u32 = 10_000;
+
+/// What happens when --expect-data-within passes without a new word
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum NoDataAction {
+    /// Warn and emit a marker, which control clients also see, then keep capturing
+    Warn,
+    /// End the capture with an error
+    Exit,
+}
+
+/// Parse `cli`, filling in any options it and the DCC_* environment variables don't set from
+/// the config file given by --config.  For `replay FILE`, the settings in the recording are
+/// filled in the same way, taking precedence over the config file.
+fn parse_args(cli: &[OsString]) -> Result<Args, clap::Error> {
+    resolve(cli).map(|(args, _)| args)
+}
+
+/// `parse_args`, also returning the command line with the config file and recorded settings
+/// filled in
+fn resolve(cli: &[OsString]) -> Result<(Args, Vec<OsString>), clap::Error> {
+    let mut cmd = Args::command();
+    let (cli, recorded) = match cli.get(1) {
+        Some(sub) if sub == "replay" => {
+            let Some(path) = cli.get(2).map(PathBuf::from) else {
+                return Err(cmd.error(ErrorKind::MissingRequiredArgument, "replay needs a recording to replay"));
+            };
+            let (_, settings) =
+                Recording::open(&path).map_err(|e| cmd.error(ErrorKind::Io, format!("open recording {}: {}", path.display(), e)))?;
+            let argv: Vec<OsString> = cli[..1].iter().chain(&cli[3..]).cloned().collect();
+            (argv, Some((path, settings)))
+        }
+        _ => (cli.to_vec(), None),
+    };
+    let cli = cli.as_slice();
+    let matches = Args::command().ignore_errors(true).try_get_matches_from(cli)?;
+    let mut table = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(config::load(path).map_err(|e| cmd.error(ErrorKind::Io, e))?),
+        None => None,
+    };
+    if let Some((_, settings)) = &recorded {
+        table.get_or_insert_with(Table::new).extend(settings.clone());
+    }
+    let (mut args, argv) = match table {
+        None => (Args::try_parse_from(cli)?, cli.to_vec()),
+        Some(table) => {
+            let mut argv = cli[..1].to_vec();
+            let mut positional = vec![];
+            for (key, value) in &table {
+                if config::is_section(value) {
+                    continue;
+                }
+                let id = config::arg_id(key);
+                let arg = cmd.get_arguments().find(|a| a.get_id() == id.as_str());
+                // Precedence is command line, then environment, then the config file
+                let source = arg.and_then(|_| matches.value_source(&id));
+                if matches!(source, Some(ValueSource::CommandLine | ValueSource::EnvVariable)) {
+                    continue;
+                }
+                if arg.is_some_and(|a| a.is_positional()) {
+                    positional.push(
+                        config::scalar(key, value)
+                            .map_err(|e| cmd.error(ErrorKind::InvalidValue, e))?
+                            .into(),
+                    );
+                } else {
+                    let opts = config::to_args(key, value).map_err(|e| cmd.error(ErrorKind::InvalidValue, e))?;
+                    argv.extend(opts.into_iter().map(OsString::from));
+                }
+            }
+            argv.extend_from_slice(&cli[1..]);
+            argv.extend(positional);
+            let mut args = Args::try_parse_from(&argv)?;
+            args.init = config::init_steps(&table).map_err(|e| cmd.error(ErrorKind::InvalidValue, e))?;
+            args.sinks = config::sinks(&table).map_err(|e| cmd.error(ErrorKind::InvalidValue, e))?;
+            (args, argv)
+        }
+    };
+    args.replay = recorded.map(|(path, _)| path);
+    args.stats |= args.stats_format.is_some() || args.stats_output.is_some();
+    if args.tui && !cfg!(feature = "tui") {
+        return Err(cmd.error(ErrorKind::ArgumentConflict, "--tui needs a build with the tui feature"));
+    }
+    Ok((args, argv))
+}
+
+/// Whether batches can go straight to the sinks: raw output with nothing that needs to see
+/// each word
+fn raw_path(args: &Args) -> bool {
+    matches!(args.format, OutputFormat::Raw | OutputFormat::Base64)
+        && args.decode == DecoderKind::Raw
+        && !args.context_ids
+        && !args.nodups
+        && !args.on_change
+        && args.debounce.is_none()
+        && args.filter.is_empty()
+        && args.trigger_start.is_none()
+        && args.trigger_stop.is_none()
+        && args.watch_start.is_none()
+        && args.watch_stop.is_none()
+        && args.halt_on.is_empty()
+        && args.max_rate.is_none()
+        && args.count.is_none()
+        && args.sequence.is_none()
+        && args.crc_marker.is_none()
+        && args.cycle_marker.is_none()
+        && args.heartbeat.is_none()
+        && args
+            .sinks
+            .iter()
+            .all(|s| s.format.is_none_or(|f| matches!(f, OutputFormat::Raw | OutputFormat::Base64)))
+}
+
+/// `dest` with its template filled in, see `template`
+fn output_path(args: &Args, dest: &str) -> Result<String, DccError> {
+    if !template::is_template(dest) || dest.starts_with("plugin:") {
+        return Ok(dest.to_string());
+    }
+    let path = template::expand(dest, args.config.as_deref()).map_err(DccError::InvalidConfig)?;
+    println!("Writing {}", path);
+    Ok(path)
+}
+
+fn file_writer(args: &Args) -> FileWriter {
+    if args.mmap {
+        FileWriter::Mmap
+    } else if args.ping_pong {
+        FileWriter::PingPong(args.disk.clone())
+    } else {
+        FileWriter::Buffered
+    }
+}
+
+fn disk_stalls(args: &Args) -> (u64, Duration) {
+    let disk = args.disk.get();
+    (disk.count, disk.time)
+}
+
+fn timestamps(args: &Args) -> Timestamps {
+    match (args.no_timestamps, args.mark_interpolated) {
+        (true, _) => Timestamps::Off,
+        (false, true) => Timestamps::Marked,
+        (false, false) => Timestamps::On,
     }
+}
 
-    let adi = Rc::new(RefCell::new(ArmDebugInterface::new(taps)));
-    let mut debug = MemAP::new(adi.clone(), args.ap_num);
+/// Open the sink for the output `dest`, a plugin's or one of `sink::open`'s
+fn open_sink(args: &Args, plugins: &Plugins, dest: &str, kind: OutputFormat, format: &ValueFormat) -> Result<Box<dyn Sink>, DccError> {
+    match dest.strip_prefix("plugin:") {
+        Some(spec) => plugins.open_sink(spec),
+        None => sink::open(dest, kind, format.clone(), file_writer(args), timestamps(args), args.sync_interval)
+            .map_err(|e| DccError::Io(format!("open output {}", dest), e)),
+    }
+}
 
-    let base = if args.debug_base.starts_with("0x") {
-        let len = args.debug_base.len();
-        u32::from_str_radix(&args.debug_base[2..len], 16).expect("failed to parse debug base")
+fn decoder(args: &Args, plugins: &Plugins) -> Result<Box<dyn Decoder>, DccError> {
+    if let Some(spec) = &args.decode_plugin {
+        plugins.decoder(spec)
+    } else if args.context_ids {
+        Ok(args.decode.build_contexts())
     } else {
-        str::parse(&args.debug_base).expect("failed to parse debug base")
+        Ok(args.decode.build())
+    }
+}
+
+fn load_plugins(args: &Args) -> Result<Plugins, DccError> {
+    match &args.plugin_dir {
+        Some(dir) => Plugins::load_dir(dir),
+        None => Ok(Plugins::default()),
+    }
+}
+
+fn value_format(args: &Args) -> ValueFormat {
+    ValueFormat {
+        radix: args.radix,
+        scale: args.scale,
+        offset: args.offset,
+        unit: args.unit.clone(),
+    }
+}
+
+/// Apply the options from a reloaded config that can change without reattaching
+fn reload(
+    args: &mut Args,
+    new: Args,
+    stats: &mut Stats,
+    output: &mut Output,
+    start: &mut StartTrigger,
+    stop: &mut StopTrigger,
+    clock: &SharedClock,
+) {
+    if new.cable != args.cable
+        || new.baud != args.baud
+        || new.rtck != args.rtck
+        || new.tap_index != args.tap_index
+        || new.ap_num != args.ap_num
+        || new.core != args.core
+        || new.merge_cores != args.merge_cores
+        || new.debug_base != args.debug_base
+        || new.arch != args.arch
+        || new.txfull != args.txfull
+        || new.burst_header != args.burst_header
+        || new.burst_mask != args.burst_mask
+        || new.attach_only != args.attach_only
+        || new.state_dir != args.state_dir
+        || new.restore_state_on_exit != args.restore_state_on_exit
+        || new.catch_reset != args.catch_reset
+        || new.cti_base != args.cti_base
+        || new.vector_catch != args.vector_catch
+        || new.watch_start != args.watch_start
+        || new.watch_stop != args.watch_stop
+        || new.watch_access != args.watch_access
+        || new.stop_on_halt != args.stop_on_halt
+        || new.virtual_time != args.virtual_time
+        || new.target_clock != args.target_clock
+        || new.target_clock_hz != args.target_clock_hz
+        || new.target_time != args.target_time
+        || new.sequence != args.sequence
+        || new.crc_marker != args.crc_marker
+        || new.cycle_marker != args.cycle_marker
+        || new.cycle_hz != args.cycle_hz
+        || new.heartbeat != args.heartbeat
+        || new.heartbeat_mask != args.heartbeat_mask
+        || new.heartbeat_timeout != args.heartbeat_timeout
+        || new.on_heartbeat_lost != args.on_heartbeat_lost
+        || new.output != args.output
+        || new.sinks != args.sinks
+        || new.format != args.format
+        || new.sync_interval != args.sync_interval
+        || new.mmap != args.mmap
+        || new.ping_pong != args.ping_pong
+        || new.no_timestamps != args.no_timestamps
+        || new.decode != args.decode
+        || new.decode_plugin != args.decode_plugin
+        || new.plugin_dir != args.plugin_dir
+        || new.context_ids != args.context_ids
+    {
+        output.warn("cable, baud, TAP, AP, debug base, --core, arch, --txfull, --burst-header, --attach-only, state file, run control, target clock, --sequence, --crc-marker, --cycle-marker, heartbeat, output, format, timestamp and decoder changes take effect on restart");
+    }
+
+    args.queue_size = new.queue_size;
+    args.nodups = new.nodups;
+    args.on_change = new.on_change;
+    args.debounce = new.debounce;
+    args.filter = new.filter;
+    args.hook = new.hook;
+    args.halt_on = new.halt_on;
+    args.radix = new.radix;
+    args.scale = new.scale;
+    args.offset = new.offset;
+    args.unit = new.unit;
+    output.set_format(value_format(args));
+    if new.max_rate != args.max_rate {
+        args.max_rate = new.max_rate;
+        output.limit = args.max_rate.map(|rate| RateLimit::new(rate, clock.clone()));
+    }
+    args.trigger_start = new.trigger_start;
+    args.include_trigger = new.include_trigger;
+    args.pre_trigger = new.pre_trigger;
+    start.update(args.trigger_start, args.include_trigger, args.pre_trigger);
+    args.trigger_stop = new.trigger_stop;
+    args.post_trigger = new.post_trigger;
+    stop.update(args.trigger_stop, args.post_trigger);
+    args.core_stall = new.core_stall;
+    args.stats = new.stats;
+    args.stats_interval = new.stats_interval;
+    args.stats_format = new.stats_format;
+    stats.set_interval(args.stats_interval);
+    stats.set_format(args.stats_format.unwrap_or(StatsFormat::Text));
+}
+
+/// The IDCODEs a debug port may have without --force
+fn expected_idcodes(args: &Args) -> Vec<u32> {
+    if args.expect_idcode.is_empty() {
+        ARM_DAP_IDCODES.to_vec()
+    } else {
+        args.expect_idcode.clone()
+    }
+}
+
+/// Run the --hook commands for `event` of `core`, whose marker is `msg`, warning about any that
+/// couldn't be started
+fn run_hooks(args: &Args, out: &mut Output, event: HookEvent, core: &str, timestamp: u128, msg: &str, vars: &[(&str, String)]) {
+    let legacy = args.on_heartbeat_lost.iter().map(|command| Hook {
+        event: HookEvent::HeartbeatLost,
+        command: command.clone(),
+    });
+    let hooks: Vec<Hook> = args.hook.iter().cloned().chain(legacy).collect();
+    for (command, e) in hooks::run(&hooks, event, timestamp, core, msg, vars) {
+        out.warn(&format!("hook {}: {}", command, e));
+    }
+}
+
+/// How messages about `core` start, so they can be told apart when streaming from several
+/// cores
+fn core_prefix(stats: &Stats, core: usize) -> String {
+    if stats.cores.len() < 2 {
+        return String::new();
+    }
+    format!("{}: ", stats.cores[core].name)
+}
+
+/// The settings a recording keeps, under the config file keys so that a replay can use them
+fn session_settings(args: &Args, idcode: u32) -> Table {
+    let mut settings = Table::new();
+    settings.insert("cable".to_string(), args.cable.clone().into());
+    settings.insert("baud".to_string(), Value::Integer(args.baud.into()));
+    settings.insert("tap-index".to_string(), Value::Integer(args.tap_index as i64));
+    settings.insert("ap-num".to_string(), Value::Integer(args.ap_num.into()));
+    if let Some(arch) = args.arch.to_possible_value() {
+        settings.insert("arch".to_string(), arch.get_name().into());
+    }
+    settings.insert("txfull".to_string(), args.txfull.into());
+    settings.insert("no-timestamps".to_string(), args.no_timestamps.into());
+    settings.insert("debug-base".to_string(), Value::Integer(args.debug_base.into()));
+
+    let mut session = Table::new();
+    session.insert("version".to_string(), env!("CARGO_PKG_VERSION").into());
+    session.insert("idcode".to_string(), Value::Integer(idcode.into()));
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    session.insert("started".to_string(), Value::Integer(started as i64));
+    let cores = args.core.iter().map(|c| format!("{}:0x{:x}", c.ap_num, c.debug_base).into());
+    session.insert("cores".to_string(), Value::Array(cores.collect()));
+    settings.insert("session".to_string(), session.into());
+    settings
+}
+
+fn builder(args: &Args) -> DccStreamBuilder {
+    let builder = DccStreamBuilder::new(args.cable.clone(), args.debug_base)
+        .baud(args.baud)
+        .rtck(args.rtck)
+        .tap_index(args.tap_index)
+        .ap_num(args.ap_num)
+        .arch(args.arch)
+        .retry(RetryPolicy {
+            wait_retries: args.wait_retries,
+            wait_backoff: args.wait_backoff,
+        })
+        .queue_size(args.queue_size as usize)
+        .txfull(args.txfull)
+        .attach_only(args.attach_only)
+        .dscr_timeout(Some(args.dscr_timeout).filter(|t| !t.is_zero()))
+        .expect_idcodes(if args.force { vec![] } else { expected_idcodes(args) })
+        .nodups(args.nodups)
+        .init(args.init.clone());
+    args.core
+        .iter()
+        .fold(builder, |builder, core| builder.core(core.ap_num, core.debug_base))
+}
+
+/// Find the highest rate at or below --baud where the debug path is stable
+fn auto_baud(args: &Args) -> Option<u32> {
+    let builder = builder(args);
+    quiet_panics(|| {
+        let mut baud = args.baud;
+        while baud >= AUTO_BAUD_MIN {
+            eprintln!("Trying baud {}", baud);
+            if dcc_stream::probe_baud(builder.target(), baud) {
+                return Some(baud);
+            }
+            baud = baud / 4 * 3;
+        }
+        None
+    })
+}
+
+/// Run `f` without printing messages for panics it catches
+fn quiet_panics<T>(f: impl FnOnce() -> T) -> T {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = f();
+    panic::set_hook(hook);
+    result
+}
+
+/// Whether the cable's adapter has gone away
+fn probe_lost(args: &Args) -> bool {
+    quiet_panics(|| dcc_stream::probe_present(builder(args).target())).is_ok_and(|present| !present)
+}
+
+/// What a panic said, if it was a message
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => panic.downcast_ref::<String>().map_or("panic", String::as_str),
+    }
+}
+
+/// Block until the cable can be opened
+fn wait_for_probe(args: &Args, stop: &CancelToken) -> Result<(), DccError> {
+    let builder = builder(args);
+    let mut waiting = false;
+    quiet_panics(|| {
+        while !stop.is_cancelled() {
+            if dcc_stream::probe_present(builder.target())? {
+                return Ok(());
+            }
+            if !waiting {
+                eprintln!("Waiting for {} probe", args.cable);
+                waiting = true;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        Err(DccError::Interrupted)
+    })
+}
+
+/// `dcc-stream config check [OPTIONS]`: resolve the options as a capture would, from the
+/// command line, the environment and --config, check them and the files they name, and print
+/// the result.  The target is left alone.
+fn config_check(cli: &[OsString]) -> Result<(), DccError> {
+    if cli.get(2).is_none_or(|a| a != "check") {
+        return Err(DccError::InvalidConfig("usage: dcc-stream config check [OPTIONS]".to_string()));
+    }
+    let argv: Vec<OsString> = cli[..1].iter().chain(&cli[3..]).cloned().collect();
+    let (args, argv) = resolve(&argv).unwrap_or_else(|e| e.exit());
+    validate(&args)?;
+    let mut steps = args.init.len();
+    if let Some(path) = &args.init_script {
+        steps += init::load(path).map_err(DccError::InvalidConfig)?.len();
+    }
+    if let Some(RttLocation::Elf(path)) = &args.rtt {
+        fs::metadata(path).map_err(|e| DccError::Io(format!("--rtt ELF {}", path.display()), e))?;
+    }
+    if let Some(location) = &args.mailbox {
+        Mailbox::locate(location)?;
+    }
+    if let Some(scenario) = args.cable.strip_prefix("sim:") {
+        dcc_stream::sim::Scenario::load(scenario)?;
+    }
+    let outputs = args.output.iter().chain(args.core.iter().filter_map(|c| c.output.as_ref()));
+    let plugins = load_plugins(&args)?;
+    for (name, sink, decoder) in plugins.list() {
+        let provides = [(sink, "sink"), (decoder, "decoder")]
+            .iter()
+            .filter(|p| p.0)
+            .map(|p| p.1)
+            .collect::<Vec<_>>();
+        println!(
+            "# plugin {}: {}",
+            name,
+            if provides.is_empty() {
+                "nothing".to_string()
+            } else {
+                provides.join(" and ")
+            }
+        );
+    }
+    if let Some(spec) = &args.decode_plugin {
+        plugins.check_decoder(spec)?;
+    }
+    for spec in outputs.clone().filter_map(|d| d.strip_prefix("plugin:")) {
+        plugins.check_sink(spec)?;
+    }
+    for dest in outputs.filter(|d| *d != "-" && !d.starts_with("tcp:") && !d.starts_with("unix:") && !d.starts_with("plugin:")) {
+        let path = template::expand(dest, args.config.as_deref()).map_err(DccError::InvalidConfig)?;
+        let dir = Path::new(&path)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if !dir.is_dir() {
+            return Err(DccError::InvalidConfig(format!("output {}: no directory {}", dest, dir.display())));
+        }
+    }
+    let matches = Args::command()
+        .try_get_matches_from(&argv)
+        .map_err(|e| DccError::InvalidConfig(e.to_string()))?;
+    print!("{}", config::effective(&Args::command(), &matches));
+    println!("# {} init steps", steps);
+    println!("Config OK");
+    Ok(())
+}
+
+/// Run the bring-up and report each step, for --check
+fn check(args: &Args) -> Result<(), DccError> {
+    let mut dcc = builder(args).build()?;
+    let idcode = dcc.idcode();
+    println!("IDCODE: 0x{:x}", idcode);
+
+    if !args.init.is_empty() {
+        dcc.run_init()?;
+        println!("Init script: {} steps", args.init.len());
+    }
+    let edprsr = dcc.check_powered()?;
+    println!("EDPRSR: 0x{:x}", edprsr);
+    dcc.clear_os_lock()?;
+    println!("OS lock cleared");
+    let dscr = dcc.read_dscr()?;
+    println!("DSCR: 0x{:x}", dscr);
+    Ok(())
+}
+
+/// Ask the reader to change TCK, for the control API and the TUI.  It says with
+/// `Msg::Reclocked` once it has.
+fn set_baud(args: &Args, shared: &Shared, baud: u32) -> Result<(), String> {
+    if args.replay.is_some() {
+        return Err("a replay has no target".to_string());
+    }
+    shared.baud.store(baud, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Change the words per batch, for the control API and the TUI, returning the marker for it
+fn set_queue_size(args: &mut Args, shared: &Shared, size: usize) -> Result<String, String> {
+    if args.adaptive_queue {
+        return Err("--adaptive-queue sizes the batches".to_string());
+    }
+    if args.rtt.is_some() || args.mailbox.is_some() {
+        return Err("--rtt and --mailbox take whatever the target has written, not batches of a size".to_string());
+    }
+    args.queue_size = size as u32;
+    shared.queue_size.store(size, Ordering::SeqCst);
+    Ok(format!("queue size now {}", size))
+}
+
+/// The state file for the --config profile, or None with --attach-only, which changes nothing
+/// that would need restoring
+fn state_file(args: &Args) -> Option<StateFile> {
+    let dir = args.state_dir.clone().or_else(StateFile::default_dir)?;
+    (!args.attach_only).then(|| StateFile::new(&dir, &template::profile(args.config.as_deref())))
+}
+
+/// Put back the saved state of each core, for --restore-state
+fn restore_state(args: &Args) -> Result<(), DccError> {
+    let file =
+        state_file(args).ok_or_else(|| DccError::InvalidConfig("no home directory for the state file, give --state-dir".to_string()))?;
+    let names: Vec<String> = std::iter::once(cores::name(args.ap_num, args.debug_base))
+        .chain(args.core.iter().map(|c| cores::name(c.ap_num, c.debug_base)))
+        .collect();
+    let mut streams = builder(args).build_all()?;
+    for (dcc, name) in streams.iter_mut().zip(&names) {
+        match file.restore(name, dcc)? {
+            Some(state) => {
+                let dscr = state.dscr.map_or("unread".to_string(), |dscr| format!("0x{:x}", dscr));
+                let csw = state.csw.map_or(String::new(), |csw| format!(", CSW 0x{:x}", csw));
+                let lock = if state.os_locked { "set" } else { "clear" };
+                println!("{}: restored DSCR {}, OS lock {}{}", name, dscr, lock, csw);
+            }
+            None => println!("{}: no saved state in {}", name, file.path().display()),
+        }
+    }
+    Ok(())
+}
+
+/// Check the options go together, without touching the target
+fn validate(args: &Args) -> Result<(), DccError> {
+    builder(args).validate()?;
+    if args.adaptive_queue && (args.queue_min == 0 || args.queue_min > args.queue_max || args.queue_max > MAX_QUEUE_SIZE) {
+        return Err(DccError::InvalidConfig(format!(
+            "--queue-min and --queue-max must be in order between 1 and {}",
+            MAX_QUEUE_SIZE
+        )));
+    }
+    if let Some(header) = args.burst_header {
+        if args.burst_mask == 0 || header & !args.burst_mask != 0 {
+            return Err(DccError::InvalidConfig(format!(
+                "--burst-header 0x{:x} must only set bits of a non-zero --burst-mask 0x{:x}",
+                header, args.burst_mask
+            )));
+        }
+    }
+    if let Some(core) = args.core.iter().find(|c| c.output.is_none() && !args.merge_cores) {
+        return Err(DccError::InvalidConfig(format!(
+            "--core {}:0x{:x} needs =DEST for its output, or --merge-cores",
+            core.ap_num, core.debug_base
+        )));
+    }
+    if args.sync_interval.is_some() && (!matches!(args.format, OutputFormat::Raw | OutputFormat::Base64) || args.decode != DecoderKind::Raw)
+    {
+        return Err(DccError::InvalidConfig(
+            "--sync-interval needs --format raw or base64 and --decode raw".to_string(),
+        ));
+    }
+    let mut outputs = args.output.iter().chain(args.core.iter().filter_map(|c| c.output.as_ref()));
+    if let Some(dest) = outputs.find(|d| d.starts_with("plugin:") && args.plugin_dir.is_none()) {
+        return Err(DccError::InvalidConfig(format!("output {} needs --plugin-dir", dest)));
+    }
+    if args.context_ids && !matches!(args.format, OutputFormat::Text | OutputFormat::Trace32) {
+        return Err(DccError::InvalidConfig("--context-ids needs --format text or trace32".to_string()));
+    }
+    if args.virtual_time && !args.cable.starts_with("sim:") && args.replay.is_none() {
+        return Err(DccError::InvalidConfig("--virtual-time needs a sim: cable or a replay".to_string()));
+    }
+    if args.target_clock_hz == Some(0) {
+        return Err(DccError::InvalidConfig("--target-clock-hz must be above 0".to_string()));
+    }
+    if args.merge_cores && args.format != OutputFormat::Text {
+        return Err(DccError::InvalidConfig("--merge-cores needs --format text".to_string()));
+    }
+    if args.merge_cores && args.sinks.iter().any(|s| s.format.is_some_and(|f| f != OutputFormat::Text)) {
+        return Err(DccError::InvalidConfig(
+            "--merge-cores needs every [[sink]] in text format".to_string(),
+        ));
+    }
+    if args.sinks.iter().any(|s| !s.filter.is_empty()) && (args.decode != DecoderKind::Raw || args.decode_plugin.is_some()) {
+        return Err(DccError::InvalidConfig("[[sink]] filters need --decode raw".to_string()));
+    }
+    if args.speed.is_some_and(|s| !(s > 0.0 && s.is_finite())) {
+        return Err(DccError::InvalidConfig("--speed must be above 0".to_string()));
+    }
+    if (args.from.is_some() || args.to.is_some()) && args.replay.is_none() {
+        return Err(DccError::InvalidConfig("--from and --to are for replay".to_string()));
+    }
+    if args.from.zip(args.to).is_some_and(|(from, to)| to < from) {
+        return Err(DccError::InvalidConfig("--to is before --from".to_string()));
+    }
+    if args.replay.is_some() && (args.check || args.auto_baud || args.wait_for_probe) {
+        return Err(DccError::InvalidConfig(
+            "--check, --auto-baud and --wait-for-probe need a target, not a recording".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn run(mut args: Args, cli: &[OsString], signals: &Signals) -> Result<(), DccError> {
+    let stop = &signals.stop;
+    let hup = &signals.reload;
+    let pause_req = &signals.pause;
+    let resume_req = &signals.resume;
+    validate(&args)?;
+    // The server of a shared cable holds its lock
+    let shared = args.cable.starts_with("share:");
+    let _lock = if args.no_lock || args.replay.is_some() || shared {
+        None
+    } else {
+        Some(lock::lock(&args.cable)?)
     };
 
-    let queue_size = args.queue_size;
+    if args.auto_baud {
+        match auto_baud(&args) {
+            Some(baud) => {
+                eprintln!("Using baud {}", baud);
+                args.baud = baud;
+            }
+            None => {
+                return Err(DccError::Other(format!("no stable baud found at or below {}", args.baud)));
+            }
+        }
+    }
 
-    println!("Using debug base 0x{:x}", base);
+    if let Some(path) = &args.init_script {
+        let steps = init::load(path).map_err(DccError::Other)?;
+        args.init.extend(steps);
+    }
 
-    // Make sure the CPU is powered up
-    let edprsr = debug.read(base + 0x314).expect("read edprsr");
-    assert!(edprsr & 1 == 1);
+    if args.restore_state {
+        return restore_state(&args);
+    }
 
-    // Clear OS lock
-    debug.write(base + 0x300, 0).expect("write oslar");
+    if args.check {
+        return match check(&args) {
+            Ok(()) => {
+                println!("Check passed");
+                Ok(())
+            }
+            Err(e) => {
+                println!("Check failed: {}", e);
+                Err(e)
+            }
+        };
+    }
 
-    loop {
-        if let Ok(dscr) = debug.read(base + 0x88) {
-            // Enable "stall" mode
-            debug.write(base + 0x88, dscr | (1 << 20)).expect("write dscr");
-            break;
+    let stats_out = stats::open_output(args.stats_output.as_deref()).map_err(|e| DccError::Io("open stats output".to_string(), e))?;
+    if args.wait_for_probe {
+        wait_for_probe(&args, stop)?;
+    }
+    let mailbox = args
+        .mailbox
+        .as_ref()
+        .filter(|_| args.replay.is_none())
+        .map(Mailbox::locate)
+        .transpose()?;
+    match (&args.replay, &args.rtt, &mailbox) {
+        (Some(path), _, _) => println!("Replaying {}", path.display()),
+        (None, Some(_), _) => println!("Reading RTT channel {} through AP {}", args.rtt_channel, args.ap_num),
+        (None, None, Some(mailbox)) => println!("Reading the mailbox at {} through AP {}", mailbox, args.ap_num),
+        (None, None, None) => println!("Using debug base 0x{:x}", args.debug_base),
+    }
+    // Timestamps count from here
+    let clock: SharedClock = if args.virtual_time {
+        VirtualClock::shared()
+    } else {
+        SystemClock::shared()
+    };
+    let options = reader::Options {
+        queue_size: args.queue_size as usize,
+        adaptive: args
+            .adaptive_queue
+            .then(|| AdaptiveQueue::new(args.tune_by, args.queue_min, args.queue_max, args.txfull)),
+        depth: args.channel_depth,
+        policy: args.on_full,
+        idle_backoff: args.idle_backoff.filter(|d| !d.is_zero()),
+        txfull: args.txfull,
+        burst_header: args.burst_header.map(|header| (header, args.burst_mask)),
+        timestamps: !args.no_timestamps,
+        reattach: !args.no_reattach,
+        os_lock: args.os_lock,
+        relock: args.relock,
+        catch_reset: args.catch_reset,
+        cti_base: args.cti_base,
+        resume_after_halt: !args.stop_on_halt,
+        watch: [args.watch_start, args.watch_stop]
+            .into_iter()
+            .flatten()
+            .map(|addr| (addr, args.watch_access))
+            .collect(),
+        vector_catch: args.vector_catch.clone(),
+        target_clock: args.target_clock,
+        state: state_file(&args),
+        restore_state: args.restore_state_on_exit,
+    };
+    let (reader, idcode) = match &args.replay {
+        Some(path) => {
+            let (mut recording, settings) =
+                Recording::open(path).map_err(|e| DccError::Io(format!("open recording {}", path.display()), e))?;
+            let from = args.from.map_or(0, |from| from.as_micros());
+            recording
+                .range(from, args.to.map(|to| to.as_micros()))
+                .map_err(|e| DccError::Io(format!("seek recording {}", path.display()), e))?;
+            let session = record::session(&settings);
+            let idcode = session.and_then(|s| s.get("idcode")).and_then(Value::as_integer);
+            let recorded_cores = session.and_then(|s| s.get("cores")).and_then(Value::as_array).map_or(0, Vec::len);
+            if recorded_cores > args.core.len() {
+                eprintln!(
+                    "Warning: the recording has {} more cores, give --core for each to replay them",
+                    recorded_cores - args.core.len()
+                );
+            }
+            let speed = args.speed.or(args.realtime.then_some(1.0));
+            let reader = Reader::replay(recording, args.core.len() + 1, speed, args.channel_depth, clock.clone());
+            (reader, idcode.map_or(ARM_DAP_IDCODE, |i| i as u32))
         }
+        None => match (&args.rtt, mailbox) {
+            (Some(location), _) => Reader::spawn_rtt(
+                builder(&args).clock(clock.clone()),
+                location.clone(),
+                args.rtt_channel,
+                args.decode == DecoderKind::Text && args.decode_plugin.is_none(),
+                options,
+                clock.clone(),
+            )?,
+            (None, Some(mailbox)) => Reader::spawn_mailbox(builder(&args).clock(clock.clone()), mailbox, options, clock.clone())?,
+            (None, None) => Reader::spawn(builder(&args).clock(clock.clone()), stop.clone(), options, clock.clone())?,
+        },
+    };
+
+    // Only reachable with an unexpected IDCODE when forced
+    if !expected_idcodes(&args).contains(&idcode) {
+        eprintln!("Warning: unexpected idcode {:x}", idcode);
     }
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+    let mut stats = Stats::new(
+        args.stats_interval,
+        args.stats_format.unwrap_or(StatsFormat::Text),
+        stats_out,
+        clock.clone(),
+    );
+    stats.cores = std::iter::once(cores::name(args.ap_num, args.debug_base))
+        .chain(args.core.iter().map(|c| cores::name(c.ap_num, c.debug_base)))
+        .map(CoreCounts::new)
+        .collect();
+    // Stats on stderr would scribble over the TUI
+    let mut print_stats = args.stats && (!args.tui || args.stats_output.is_some());
+    let tui = if args.tui {
+        Some(Tui::new().map_err(|e| DccError::Io("start tui".to_string(), e))?)
+    } else {
+        None
+    };
+    let control = match &args.control_socket {
+        Some(path) => Some(ControlServer::bind(path).map_err(|e| DccError::Io(format!("bind control socket {}", path.display()), e))?),
+        None => None,
+    };
+    let plugins = load_plugins(&args)?;
+    let format = value_format(&args);
+    let mut sinks: Vec<(String, Box<dyn Sink>)> = vec![];
+    // The [[sink]] tables' filters and rate limits, for each of `sinks`
+    let mut policers = vec![];
+    let mut opened = vec![];
+    for dest in &args.output {
+        let dest = &output_path(&args, dest)?;
+        opened.push(dest.clone());
+        let sink = open_sink(&args, &plugins, dest, args.format, &format)?;
+        sinks.push((dest.clone(), sink));
+        policers.push(None);
+    }
+    for policy in &args.sinks {
+        let dest = &output_path(&args, &policy.dest)?;
+        opened.push(dest.clone());
+        let sink = open_sink(&args, &plugins, dest, policy.format.unwrap_or(args.format), &format)?;
+        sinks.push((dest.clone(), sink));
+        policers.push(policy.policer(&clock));
+    }
+    if args.output.is_empty() && args.sinks.is_empty() && tui.is_none() {
+        let sink = sink::open(
+            "-",
+            args.format,
+            format.clone(),
+            FileWriter::Buffered,
+            timestamps(&args),
+            args.sync_interval,
+        )
+        .map_err(|e| DccError::Io("open stdout".to_string(), e))?;
+        sinks.push(("stdout".to_string(), sink));
+        policers.push(None);
+    }
+    // With --merge-cores the main sinks are shared, each core tagging what it writes
+    let merged = if args.merge_cores {
+        merge::tag(sinks, &stats.cores.iter().map(|c| c.name.clone()).collect::<Vec<_>>(), &format)
+    } else {
+        vec![sinks]
+    };
+    // Filtered before the tagging turns the words into text
+    let mut merged = merged
+        .into_iter()
+        .map(|sinks| {
+            sinks
+                .into_iter()
+                .zip(&policers)
+                .map(|((name, sink), policer)| match policer {
+                    Some(policer) => (name, policer.apply(sink)),
+                    None => (name, sink),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+    let sinks = merged.next().unwrap_or_default();
+    let mut others = vec![];
+    for core in &args.core {
+        let mut sinks = merged.next().unwrap_or_default();
+        if let Some(dest) = &core.output {
+            let dest = &output_path(&args, dest)?;
+            opened.push(dest.clone());
+            let sink = open_sink(&args, &plugins, dest, args.format, &format)?;
+            sinks.push((dest.clone(), sink));
+        }
+        others.push(Output::new(None, None, sinks, decoder(&args, &plugins)?, format.clone()));
+    }
+    let settings = session_settings(&args, idcode);
+    let mut recorder = match &args.record {
+        Some(path) => Some(Recorder::create(path, &settings).map_err(|e| DccError::Io(format!("create recording {}", path.display()), e))?),
+        None => None,
+    };
+    let mut output = Output::new(tui, control, sinks, decoder(&args, &plugins)?, format);
+    output.limit = args.max_rate.map(|rate| RateLimit::new(rate, clock.clone()));
+    output.report = args.report.as_ref().map(|_| Report::new(clock.clone()));
+    let mut start_trigger = StartTrigger::new(args.trigger_start, args.include_trigger, args.pre_trigger);
+    let mut stop_trigger = StopTrigger::new(args.trigger_stop, args.post_trigger);
+    if args.watch_start.is_some() {
+        start_trigger.arm();
+    }
+    let mut finished = false;
+    // Why the reader stopped by itself, if it did
+    let mut failure = None;
+    let mut pause = None;
+    let mut paused = 0;
+    let mut captured = 0;
+    let mut halt_requested = false;
+    let mut target_clock = args.target_clock_hz.filter(|_| args.target_clock.is_some()).map(TargetClock::new);
+    let mut status = if args.status { StatusLine::new() } else { None };
+    let mut progress = if output.tui.is_none() && status.is_none() {
+        Progress::new(args.count, args.duration)
+    } else {
+        None
+    };
+    let capture_start = clock.now();
+    // The last word from each core, and when its last word that wasn't a repeat arrived
+    let mut last = vec![0; args.core.len() + 1];
+    let mut last_new = vec![None; args.core.len() + 1];
+    // When a new word last arrived from any core, for --expect-data-within
+    let mut last_data = clock.now();
+    let mut no_data = false;
+    // When each core last sent a new word, and whether it is marked as stalled, for --core-stall
+    let mut core_data = vec![clock.now(); args.core.len() + 1];
+    let mut stalled = vec![false; args.core.len() + 1];
+    let mut sequences: Vec<Option<Sequence>> = (0..=args.core.len()).map(|_| args.sequence.map(Sequence::new)).collect();
+    let mut integrity: Vec<Option<Integrity>> = (0..=args.core.len()).map(|_| args.crc_marker.map(Integrity::new)).collect();
+    let mut cycles: Vec<Option<CycleStamps>> = (0..=args.core.len())
+        .map(|_| {
+            args.cycle_marker
+                .zip(args.cycle_hz)
+                .map(|(marker, hz)| CycleStamps::new(marker, hz))
+        })
+        .collect();
+    let mut changes: Vec<ChangeGate> = (0..=args.core.len())
+        .map(|_| ChangeGate::new(args.on_change, args.debounce))
+        .collect();
+    let mut heartbeat = args
+        .heartbeat
+        .map(|word| Heartbeat::new(word, args.heartbeat_mask, args.heartbeat_timeout, clock.clone()));
+    while !finished {
+        if stop.is_cancelled() {
+            // Keep going until the reader has stopped and everything it read is written out
+            reader.stop();
+        }
+        if let Some(t) = output.tui.as_mut() {
+            t.set_tuning(reader.shared.queue_size.load(Ordering::SeqCst), args.baud);
+            match t.update(&stats) {
+                Ok(Some(tui::Action::Quit)) => stop.cancel(),
+                Ok(Some(tui::Action::Queue(grow))) => {
+                    let size = reader.shared.queue_size.load(Ordering::SeqCst);
+                    let size = if grow { (size * 2).min(MAX_QUEUE_SIZE) } else { (size / 2).max(1) };
+                    match set_queue_size(&mut args, &reader.shared, size) {
+                        Ok(msg) => output.marker(clock.now().as_micros(), &msg),
+                        Err(e) => output.warn(&e),
+                    }
+                }
+                Ok(Some(tui::Action::Clock(faster))) => {
+                    let baud = if faster {
+                        args.baud.saturating_mul(2)
+                    } else {
+                        (args.baud / 2).max(1)
+                    };
+                    if let Err(e) = set_baud(&args, &reader.shared, baud) {
+                        output.warn(&e);
+                    }
+                }
+                Ok(Some(tui::Action::TogglePause)) => {
+                    let flag = if pause.is_some() { resume_req } else { pause_req };
+                    flag.store(true, Ordering::SeqCst);
+                }
+                Ok(Some(tui::Action::Marker)) => output.marker(clock.now().as_micros(), "user marker"),
+                Ok(None) => {}
+                Err(e) => return Err(DccError::Io("tui".to_string(), e)),
+            }
+        }
 
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    }).expect("set handler");
+        while let Some(req) = output.control.as_ref().and_then(|c| c.poll()) {
+            match &req.command {
+                Command::Stats => req.ok(&stats.snapshot()),
+                Command::Marker(text) => {
+                    let text = if text.is_empty() { "user marker" } else { text.as_str() };
+                    output.marker(clock.now().as_micros(), text);
+                    req.ok("");
+                }
+                Command::Reload if args.config.is_none() => req.error("no config file"),
+                Command::Reload => {
+                    hup.store(true, Ordering::SeqCst);
+                    req.ok("");
+                }
+                Command::Stop => {
+                    stop.cancel();
+                    req.ok("");
+                }
+                Command::Pause => {
+                    pause_req.store(true, Ordering::SeqCst);
+                    req.ok("");
+                }
+                Command::Resume => {
+                    resume_req.store(true, Ordering::SeqCst);
+                    req.ok("");
+                }
+                Command::Baud(baud) => match set_baud(&args, &reader.shared, *baud) {
+                    Ok(()) => req.ok(""),
+                    Err(e) => req.error(&e),
+                },
+                Command::Queue(size) => match set_queue_size(&mut args, &reader.shared, *size) {
+                    Ok(msg) => {
+                        output.marker(clock.now().as_micros(), &msg);
+                        req.ok("");
+                    }
+                    Err(e) => req.error(&e),
+                },
+                Command::Mem(_) if args.replay.is_some() => req.error("a replay has no target"),
+                Command::Mem(op) => {
+                    let op = *op;
+                    reader.shared.mem(op, req);
+                }
+            }
+        }
 
-    let mut dup = 0;
-    let mut total = 0;
-    let mut last = 0;
-    let now = SystemTime::now();
-    while running.load(Ordering::SeqCst) {
-        let start = now.elapsed().expect("elapsed").as_micros();
-        let result = debug.read_multi(base + 0x8c, queue_size as usize, false, false).expect("read dcc");
-        let done = now.elapsed().expect("elapsed").as_micros();
+        if pause_req.swap(false, Ordering::SeqCst) && pause.is_none() {
+            let ts = clock.now().as_micros();
+            output.marker(ts, "paused");
+            pause = Some(ts);
+            paused = 0;
+        }
+        if resume_req.swap(false, Ordering::SeqCst) {
+            if let Some(since) = pause.take() {
+                let ts = clock.now().as_micros();
+                let msg = if args.pause_polling {
+                    format!("resumed, polling suspended for {}us", ts - since)
+                } else {
+                    format!("resumed, {} words not output", paused)
+                };
+                output.marker(ts, &msg);
+            }
+        }
+        if let Some(tui) = output.tui.as_mut() {
+            tui.set_paused(pause.is_some());
+        }
+
+        if hup.swap(false, Ordering::SeqCst) {
+            match parse_args(cli) {
+                Ok(new) => {
+                    reload(
+                        &mut args,
+                        new,
+                        &mut stats,
+                        &mut output,
+                        &mut start_trigger,
+                        &mut stop_trigger,
+                        &clock,
+                    );
+                    changes.iter_mut().for_each(|c| c.update(args.on_change, args.debounce));
+                    others.iter_mut().for_each(|o| o.set_format(value_format(&args)));
+                    reader.shared.queue_size.store(args.queue_size as usize, Ordering::SeqCst);
+                    print_stats = args.stats && (!args.tui || args.stats_output.is_some());
+                    output.marker(clock.now().as_micros(), "configuration reloaded");
+                }
+                Err(e) => output.warn(&format!("config reload failed: {}", e)),
+            }
+        }
+
+        stats.waits = reader.shared.waits.load(Ordering::Relaxed);
+        stats.disk_stalls = args.ping_pong.then(|| disk_stalls(&args));
+        stats.faults = reader.shared.faults.load(Ordering::Relaxed);
+        let suspend = pause.is_some() && args.pause_polling;
+        reader.shared.suspend.store(suspend, Ordering::SeqCst);
 
-        for (i, val) in result.iter().enumerate() {
-            total += 1;
+        let (core, start, done, result, (reads, slots)) = match reader.recv(Duration::from_millis(20)) {
+            Ok(Msg::Batch {
+                core,
+                start,
+                done,
+                words,
+                reads,
+                slots,
+                overflow,
+                rate,
+            }) => {
+                if !args.no_timestamps {
+                    stats.latency.record((done - start) as u64);
+                    stats.set_rate_estimate(core, rate);
+                }
+                if overflow > 0 {
+                    stats.overflow += overflow;
+                    stats.cores[core].overflow += overflow;
+                    output.marker(start, &format!("output fell behind, {} words discarded", overflow));
+                }
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder
+                        .batch(core, start, done, &words)
+                        .map_err(|e| DccError::Io("write recording".to_string(), e))?;
+                }
+                match target_clock.as_mut().filter(|_| args.target_time) {
+                    Some(clock) => (
+                        core,
+                        clock.map(start).unwrap_or(start),
+                        clock.map(done).unwrap_or(done),
+                        words,
+                        (reads, slots),
+                    ),
+                    None => (core, start, done, words, (reads, slots)),
+                }
+            }
+            Ok(Msg::Error(core, e)) => {
+                stats.errors += 1;
+                stats.cores[core].errors += 1;
+                output.warn(&format!("{}{}", core_prefix(&stats, core), e));
+                continue;
+            }
+            Ok(Msg::Reinitialized(core)) => {
+                let msg = format!("{}read failures, transport reinitialised", core_prefix(&stats, core));
+                output.marker(clock.now().as_micros(), &msg);
+                continue;
+            }
+            Ok(Msg::GaveUp(core, e)) => {
+                output.marker(clock.now().as_micros(), &format!("{}giving up: {}", core_prefix(&stats, core), e));
+                failure = Some(e);
+                continue;
+            }
+            Ok(Msg::PoweredDown(core)) => {
+                let msg = format!("{}core powered down, waiting for power-up", core_prefix(&stats, core));
+                output.marker(clock.now().as_micros(), &msg);
+                continue;
+            }
+            Ok(Msg::PoweredUp(core)) => {
+                output.marker(clock.now().as_micros(), &format!("{}core powered up", core_prefix(&stats, core)));
+                continue;
+            }
+            Ok(Msg::OsLocked(core)) => {
+                let action = if args.os_lock == OsLockPolicy::Wait || args.attach_only {
+                    "waiting for it to be cleared"
+                } else {
+                    "clearing it"
+                };
+                let msg = format!("{}target set the OS lock, {}", core_prefix(&stats, core), action);
+                let ts = clock.now().as_micros();
+                output.marker(ts, &msg);
+                let reason = [("DCC_REASON", "OS lock set".to_string())];
+                run_hooks(&args, &mut output, HookEvent::Reset, &stats.cores[core].name, ts, &msg, &reason);
+                continue;
+            }
+            Ok(Msg::OsUnlocked(core)) => {
+                output.marker(
+                    clock.now().as_micros(),
+                    &format!("{}OS lock clear, reattached", core_prefix(&stats, core)),
+                );
+                continue;
+            }
+            Ok(Msg::Overrun(core, dscr)) => {
+                stats.overruns += 1;
+                stats.cores[core].overruns += 1;
+                let what = match (dscr & 1 << 27 != 0, dscr & 1 << 26 != 0) {
+                    (true, true) => "DTRRX overrun and DTRTX underrun",
+                    (true, false) => "DTRRX overrun",
+                    _ => "DTRTX underrun",
+                };
+                let msg = format!("{}DCC data lost: {} (DSCR 0x{:x}), cleared", core_prefix(&stats, core), what, dscr);
+                output.marker(clock.now().as_micros(), &msg);
+                continue;
+            }
+            Ok(Msg::Halted(core, reason)) => {
+                let msg = format!("{}core halted: {}", core_prefix(&stats, core), reason);
+                output.marker(clock.now().as_micros(), &msg);
+                if args.stop_on_halt {
+                    finished = true;
+                    break;
+                }
+                continue;
+            }
+            // The watchpoints fire in turn, so the start one first if there is one
+            Ok(Msg::Watchpoint(core, addr)) => {
+                let ts = clock.now().as_micros();
+                if Some(addr) == args.watch_start && start_trigger.fire() {
+                    for record in start_trigger.take_history() {
+                        if filter::any_match(&args.filter, record.value) && changes[0].allow(record.timestamp, record.value) {
+                            captured += 1;
+                            if !output.record(record) {
+                                stats.dropped += 1;
+                            }
+                        }
+                    }
+                    let msg = format!("{}start trigger: watchpoint at 0x{:x}", core_prefix(&stats, core), addr);
+                    output.marker(ts, &msg);
+                    let vars = [("DCC_TRIGGER", "start".to_string()), ("DCC_VALUE", format!("0x{:x}", addr))];
+                    run_hooks(&args, &mut output, HookEvent::Trigger, &stats.cores[core].name, ts, &msg, &vars);
+                } else if stop_trigger.fire() {
+                    let msg = format!("{}stop trigger: watchpoint at 0x{:x}", core_prefix(&stats, core), addr);
+                    output.marker(ts, &msg);
+                    let vars = [("DCC_TRIGGER", "stop".to_string()), ("DCC_VALUE", format!("0x{:x}", addr))];
+                    run_hooks(&args, &mut output, HookEvent::Trigger, &stats.cores[core].name, ts, &msg, &vars);
+                    if stop_trigger.done() {
+                        finished = true;
+                        break;
+                    }
+                }
+                continue;
+            }
+            Ok(Msg::Registers(core, registers)) => {
+                let registers: Vec<String> = registers.iter().map(|(name, value)| format!("{}=0x{:x}", name, value)).collect();
+                let msg = format!("{}registers: {}", core_prefix(&stats, core), registers.join(" "));
+                output.marker(clock.now().as_micros(), &msg);
+                continue;
+            }
+            Ok(Msg::Clock { host, ticks }) => {
+                if let Some(clock) = target_clock.as_mut() {
+                    let first = !clock.sampled();
+                    clock.sample(host, ticks);
+                    if first {
+                        let timestamp = if args.target_time { clock.map(host).unwrap_or(host) } else { host };
+                        output.marker(timestamp, &format!("target clock at {} ticks", ticks));
+                    }
+                }
+                continue;
+            }
+            Ok(Msg::Resumed(core)) => {
+                output.marker(clock.now().as_micros(), &format!("{}core running again", core_prefix(&stats, core)));
+                continue;
+            }
+            Ok(Msg::Reclocked(baud)) => {
+                args.baud = baud;
+                output.marker(clock.now().as_micros(), &format!("TCK now {} Hz", baud));
+                continue;
+            }
+            Ok(Msg::Reconfigured(core, dscr)) => {
+                let msg = format!(
+                    "{}stall mode was cleared (DSCR 0x{:x}), set it again",
+                    core_prefix(&stats, core),
+                    dscr
+                );
+                output.marker(clock.now().as_micros(), &msg);
+                continue;
+            }
+            Ok(Msg::SessionLost(core, reason)) => {
+                let msg = format!("{}session lost: {}, reattaching", core_prefix(&stats, core), reason);
+                let ts = clock.now().as_micros();
+                output.marker(ts, &msg);
+                run_hooks(
+                    &args,
+                    &mut output,
+                    HookEvent::Reset,
+                    &stats.cores[core].name,
+                    ts,
+                    &msg,
+                    &[("DCC_REASON", reason)],
+                );
+                continue;
+            }
+            Ok(Msg::Reattached(core)) => {
+                stats.reattaches += 1;
+                output.marker(clock.now().as_micros(), &format!("{}reattached", core_prefix(&stats, core)));
+                continue;
+            }
+            // Nothing read yet, but the time limit and stats still need checking
+            Err(RecvTimeoutError::Timeout) => {
+                output.flush();
+                others.iter_mut().for_each(Output::flush);
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.flush().map_err(|e| DccError::Io("write recording".to_string(), e))?;
+                }
+                (0, 0, 0, vec![], (0, vec![]))
+            }
+            // The end of a recording is the end of the capture
+            Err(RecvTimeoutError::Disconnected) => {
+                finished = args.replay.is_some() && !stop.is_cancelled();
+                break;
+            }
+        };
+        let out = if core == 0 { &mut output } else { &mut others[core - 1] };
+
+        if raw_path(&args) && out.tui.is_none() && pause.is_none() {
+            // Nothing looks at the words one at a time, so hand the batch over as it is.  The
+            // duplicate count and word gaps go unmeasured.
+            stats.total += result.len() as u64;
+            stats.cores[core].total += result.len() as u64;
+            captured += result.len() as u64;
+            if args.expect_data_within.is_some() || args.core_stall.is_some() {
+                if result.iter().any(|w| args.txfull || *w != last[core]) {
+                    last_data = clock.now();
+                    core_data[core] = last_data;
+                }
+                last[core] = result.last().copied().unwrap_or(last[core]);
+            }
+            out.words(start, &result);
+        } else {
+            for (i, val) in result.iter().enumerate() {
+                stats.total += 1;
+                stats.cores[core].total += 1;
+
+                // Each word is timed by the start of the read that returned it, spread over the
+                // batch, which only measured the start of the first
+                let slot = slots.get(i).map_or(i, |&slot| slot as usize);
+                let ts = start + (done - start) * slot as u128 / reads.max(result.len()) as u128;
+                let mut record = Record {
+                    timestamp: ts,
+                    value: *val,
+                    interpolated: slot != 0,
+                };
+
+                // Words read with --txfull are never stale
+                let dup = !args.txfull && *val == last[core];
+                if dup {
+                    stats.dup += 1;
+                    stats.cores[core].dup += 1;
+                    if args.nodups {
+                        continue;
+                    }
+                } else {
+                    last_data = clock.now();
+                    core_data[core] = last_data;
+                    if !args.no_timestamps {
+                        if let Some(prev) = last_new[core] {
+                            stats.gap.record((ts - prev) as u64);
+                        }
+                        last_new[core] = Some(ts);
+                    }
+                }
+                last[core] = *val;
+
+                if let Some(seq) = sequences[core].as_mut().filter(|_| !dup) {
+                    let (data, lost) = seq.check(*val);
+                    if lost > 0 {
+                        stats.lost += lost;
+                        stats.cores[core].lost += lost;
+                        let msg = format!("{}sequence gap before 0x{:x}, {} words lost", core_prefix(&stats, core), val, lost);
+                        out.marker(ts, &msg);
+                    }
+                    if !data {
+                        continue;
+                    }
+                }
+                if let Some(check) = integrity[core].as_mut().filter(|_| !dup) {
+                    match check.check(*val) {
+                        Check::Data => {}
+                        Check::Marker => continue,
+                        Check::Passed { .. } => {
+                            stats.checkpoints += 1;
+                            continue;
+                        }
+                        Check::Failed { expected, actual, count } => {
+                            stats.checkpoints += 1;
+                            stats.crc_failures += 1;
+                            let msg = format!(
+                                "{}integrity check failed: CRC 0x{:08x} from the target, 0x{:08x} over the {} words received",
+                                core_prefix(&stats, core),
+                                expected,
+                                actual,
+                                count
+                            );
+                            out.marker(ts, &msg);
+                            continue;
+                        }
+                    }
+                }
+
+                if let Some(beat) = heartbeat.as_mut().filter(|_| !dup) {
+                    if beat.check(*val) {
+                        stats.heartbeats = beat.count;
+                        continue;
+                    }
+                }
+
+                if let Some(stamps) = cycles[core].as_mut().filter(|_| !dup) {
+                    match stamps.check(record) {
+                        Stamped::Data(retimed) => record = retimed,
+                        Stamped::Marker => continue,
+                        Stamped::Stamp { ticks, first } => {
+                            if first {
+                                out.marker(ts, &format!("{}cycle counter at {} cycles", core_prefix(&stats, core), ticks));
+                            }
+                            continue;
+                        }
+                        Stamped::Restarted { ticks } => {
+                            out.marker(
+                                ts,
+                                &format!("{}cycle counter restarted at {} cycles", core_prefix(&stats, core), ticks),
+                            );
+                            continue;
+                        }
+                    }
+                }
+                let ts = record.timestamp;
 
-            if *val == last {
-                dup += 1;
-                if args.nodups {
+                if pause.is_some() {
+                    paused += 1;
                     continue;
                 }
+
+                if core == 0 {
+                    match start_trigger.check(record) {
+                        Gate::Waiting => continue,
+                        Gate::Fired => {
+                            for record in start_trigger.take_history() {
+                                if filter::any_match(&args.filter, record.value) && changes[core].allow(record.timestamp, record.value) {
+                                    captured += 1;
+                                    if !out.record(record) {
+                                        stats.dropped += 1;
+                                    }
+                                }
+                            }
+                            let msg = format!("start trigger 0x{:x}", val);
+                            out.marker(ts, &msg);
+                            let vars = [("DCC_TRIGGER", "start".to_string()), ("DCC_VALUE", format!("0x{:x}", val))];
+                            run_hooks(&args, out, HookEvent::Trigger, &stats.cores[core].name, ts, &msg, &vars);
+                            if !start_trigger.include() {
+                                continue;
+                            }
+                        }
+                        Gate::Open => {}
+                    }
+
+                    if stop_trigger.check(*val) {
+                        let msg = format!("stop trigger 0x{:x}", val);
+                        out.marker(ts, &msg);
+                        let vars = [("DCC_TRIGGER", "stop".to_string()), ("DCC_VALUE", format!("0x{:x}", val))];
+                        run_hooks(&args, out, HookEvent::Trigger, &stats.cores[core].name, ts, &msg, &vars);
+                    }
+                }
+
+                if filter::any_match(&args.filter, *val) && changes[core].allow(ts, *val) {
+                    captured += 1;
+                    if !out.record(record) {
+                        stats.dropped += 1;
+                    }
+                }
+
+                if !halt_requested && args.halt_on.iter().any(|f| f.matches(*val)) {
+                    halt_requested = true;
+                    reader.shared.halt.store(core + 1, Ordering::SeqCst);
+                    out.marker(ts, &format!("halting on 0x{:x}", val));
+                }
+
+                if stop_trigger.done() || args.count.is_some_and(|c| captured >= c) {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+
+        if let Some(window) = args.expect_data_within {
+            let quiet = clock.since(last_data);
+            if pause.is_some() {
+                last_data = clock.now();
+            } else if quiet >= window && !no_data {
+                no_data = true;
+                let msg = format!("no new data for {:?}", quiet);
+                output.marker(clock.now().as_micros(), &msg);
+                output.warn(&msg);
+                if args.on_no_data == NoDataAction::Exit {
+                    failure = Some(DccError::Other(msg));
+                    reader.stop();
+                }
+            } else if quiet < window && no_data {
+                no_data = false;
+                output.marker(clock.now().as_micros(), "data resumed");
             }
-            last = *val;
+        }
 
-            let delta = done - start;
-            let ts = start + delta * i as u128 / result.len() as u128;
-            println!("{}: {:x}", ts, val);
+        if let Some(window) = args.core_stall {
+            for core in 0..stalled.len() {
+                let quiet = clock.since(core_data[core]);
+                let out = if core == 0 { &mut output } else { &mut others[core - 1] };
+                if pause.is_some() {
+                    core_data[core] = clock.now();
+                } else if quiet >= window && !stalled[core] {
+                    stalled[core] = true;
+                    out.marker(
+                        clock.now().as_micros(),
+                        &format!("{}stalled, no new data for {:?}", core_prefix(&stats, core), quiet),
+                    );
+                } else if quiet < window && stalled[core] {
+                    stalled[core] = false;
+                    out.marker(clock.now().as_micros(), &format!("{}resumed", core_prefix(&stats, core)));
+                }
+            }
+        }
 
-            let elapsed = now.elapsed().expect("elapsed");
-            if args.stats && total % 100 == 0 {
-                eprintln!(
-                    "STATS: total: {} duplicate: {} kbps: {}",
-                    total, dup, (total * 32) * 1024 / elapsed.as_micros()
-                );
+        if let Some(beat) = heartbeat.as_mut() {
+            if pause.is_some() {
+                beat.reset();
             }
+            match beat.poll() {
+                Some(Liveness::Lost(age)) => {
+                    let msg = format!("heartbeat lost, none for {:?}", age);
+                    let ts = clock.now().as_micros();
+                    output.marker(ts, &msg);
+                    output.warn(&msg);
+                    let age = [("DCC_HEARTBEAT_AGE", format!("{:.3}", age.as_secs_f64()))];
+                    run_hooks(&args, &mut output, HookEvent::HeartbeatLost, &stats.cores[0].name, ts, &msg, &age);
+                }
+                Some(Liveness::Resumed) => output.marker(clock.now().as_micros(), "heartbeat resumed"),
+                None => {}
+            }
+            stats.heartbeat_age = Some(beat.age().as_secs_f64());
+        }
+
+        if args.duration.is_some_and(|d| clock.since(capture_start) >= d) {
+            finished = true;
+        }
+        output.flush_due();
+        others.iter_mut().for_each(Output::flush_due);
+        if let Some(p) = progress.as_mut() {
+            p.update(captured);
+        }
+        if let Some(s) = status.as_mut() {
+            s.update(&stats);
+        }
+
+        stats.sample();
+        if print_stats && stats.due() {
+            stats.report();
         }
     }
 
-    if args.stats {
-        let elapsed = now.elapsed().expect("elapsed");
-        eprintln!(
-            "STATS: total: {} duplicate: {} kbps: {}",
-            total, dup, (total * 32) * 1024 / elapsed.as_micros()
+    if let Some(p) = &progress {
+        p.finish(captured);
+    }
+    if let Some(s) = &status {
+        s.finish();
+    }
+    if !finished {
+        // Everything the reader had read when we were asked to stop has been processed, so the
+        // only words not written out are those the rate limit dropped or the reader discarded
+        let msg = format!(
+            "stopped after {} words, {} dropped by the rate limit, {} discarded by the reader",
+            captured, stats.dropped, stats.overflow
+        );
+        output.marker(clock.now().as_micros(), &msg);
+    }
+    if let Some(ppm) = target_clock.as_ref().and_then(TargetClock::drift_ppm) {
+        output.marker(
+            clock.now().as_micros(),
+            &format!("target clock runs {:+.1} ppm from the host's", ppm),
         );
     }
+    for (core, stamps) in cycles.iter().enumerate() {
+        if let Some(ppm) = stamps.as_ref().and_then(CycleStamps::drift_ppm) {
+            let msg = format!("{}cycle counter runs {:+.1} ppm from the host's", core_prefix(&stats, core), ppm);
+            output.marker(clock.now().as_micros(), &msg);
+        }
+    }
+    if let Err(e) = reader.join() {
+        output.warn(&format!("failed to restore target state: {}", e));
+    }
+    if let Some(path) = &args.stats_out {
+        if let Err(e) = stats.export(path) {
+            output.warn(&format!("write stats file {}: {}", path.display(), e));
+        }
+    }
+    if let Some(Err(e)) = recorder.as_mut().map(Recorder::finish) {
+        output.warn(&format!("write recording: {}", e));
+    }
+    let mut report = output.report.take();
+    output.close();
+    drop(output);
+    others.iter_mut().for_each(Output::close);
+    let _ = io::stdout().flush();
+    // The --ping-pong writers are finished once their sinks are closed
+    if args.ping_pong {
+        stats.disk_stalls = Some(disk_stalls(&args));
+        let disk = args.disk.get();
+        let fell_behind = (disk.count > 0).then(|| {
+            format!(
+                "the disk fell behind {} times, holding up the capture for {:.1}s in all",
+                disk.count,
+                disk.time.as_secs_f64()
+            )
+        });
+        for msg in disk.error.iter().chain(&fell_behind) {
+            eprintln!("Warning: {}", msg);
+            if let Some(report) = &mut report {
+                report.warning(msg);
+            }
+        }
+    }
+    if args.stats || finished {
+        stats.summary();
+    }
+    if let (Some(path), Some(report)) = (&args.report, &report) {
+        let result = match &failure {
+            Some(e) => e.to_string(),
+            None if finished => "ok".to_string(),
+            None => "interrupted".to_string(),
+        };
+        if let Err(e) = report.write(path, &settings, &opened, &stats.summary_json(), &result) {
+            eprintln!("Warning: write report {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(e) = failure {
+        Err(e)
+    } else if finished {
+        Ok(())
+    } else {
+        // Otherwise the loop only ends when a signal or the control socket sets `stop`
+        Err(DccError::Interrupted)
+    }
+}
+
+#[cfg(unix)]
+fn default_control_socket() -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").unwrap_or_else(|| "/tmp".into());
+    PathBuf::from(dir).join("dcc-stream.sock")
+}
+
+/// Detach from the controlling terminal.  The working directory and stdio are kept so relative
+/// paths still work and the stream can be redirected to a file.
+#[cfg(unix)]
+fn daemonize(args: &mut Args) {
+    if args.tui {
+        eprintln!("Error: --tui can't be used with --daemon");
+        std::process::exit(1);
+    }
+    if unsafe { libc::daemon(1, 1) } != 0 {
+        eprintln!("Error: daemon: {}", std::io::Error::last_os_error());
+        std::process::exit(1);
+    }
+    if let Err(e) = syslog::open() {
+        eprintln!("Warning: syslog unavailable: {}", e);
+    }
+    if args.control_socket.is_none() {
+        args.control_socket = Some(default_control_socket());
+    }
+}
+
+/// Windows programs run in the background as services, which dcc-stream doesn't implement
+#[cfg(not(unix))]
+fn daemonize(_args: &mut Args) {
+    eprintln!("Error: --daemon needs a Unix system, on Windows run dcc-stream with --control-socket from a service wrapper");
+    std::process::exit(1);
+}
+
+/// Run subcommand `name` if it is the one on the command line, then exit with its result
+fn dispatch(cli: &[OsString], name: &str, run: impl FnOnce() -> Result<(), DccError>) {
+    if cli.get(1).is_none_or(|a| a != name) {
+        return;
+    }
+    // jtag_adi panics on faults it can't handle itself, as in `main`
+    let result =
+        panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|_| Err(DccError::AccessFault("panic in debug transport".to_string())));
+    let _ = io::stdout().flush();
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+    std::process::exit(0);
+}
+
+/// The signal handlers, exiting if they can't be installed
+fn signals() -> Signals {
+    Signals::install().unwrap_or_else(|e| {
+        eprintln!("Error: install signal handlers: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// --capabilities-json
+fn print_capabilities(cli: &[OsString]) -> Result<(), DccError> {
+    // Only the matches: the options it excludes include required ones
+    Args::command().try_get_matches_from(cli).unwrap_or_else(|e| e.exit());
+    println!("{}", capabilities::json());
+    Ok(())
+}
+
+fn main() {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    dispatch(&cli, "bench", || {
+        bench::run(&bench::BenchArgs::parse_from(&cli[1..]), &signals().stop)
+    });
+    dispatch(&cli, "selftest", || {
+        selftest::run(&selftest::SelftestArgs::parse_from(&cli[1..]), &signals().stop)
+    });
+    #[cfg(unix)]
+    dispatch(&cli, "serve", || {
+        serve::run(&serve::ServeArgs::parse_from(&cli[1..]), &signals().stop)
+    });
+    dispatch(&cli, "bp", || bp::run(&bp::BpArgs::parse_from(&cli[1..])));
+    dispatch(&cli, "mem", || mem::run(&mem::MemArgs::parse_from(&cli[1..])));
+    dispatch(&cli, "probes", || probes::run(&probes::ProbesArgs::parse_from(&cli[1..])));
+    dispatch(&cli, "decode", || {
+        convert::run(&convert::DecodeArgs::parse_from(&cli[1..]), &signals().stop)
+    });
+    dispatch(&cli, "--capabilities-json", || print_capabilities(&cli));
+    dispatch(&cli, "config", || config_check(&cli));
+    let mut args = parse_args(&cli).unwrap_or_else(|e| e.exit());
+    let check = args.check;
+
+    if args.daemon {
+        daemonize(&mut args);
+    }
+    let pidfile = args.pidfile.clone();
+    if let Some(path) = &pidfile {
+        if let Err(e) = fs::write(path, format!("{}\n", std::process::id())) {
+            eprintln!("Error: write pidfile {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    let signals = signals();
+    let result = loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| run(args.clone(), &cli, &signals))) {
+            // The transport fails when the adapter goes away, which the probe then shows
+            Ok(Err(_)) if args.wait_for_probe && !signals.stopping() && probe_lost(&args) => {
+                let msg = "lost the probe, waiting for it to come back";
+                if syslog::enabled() {
+                    syslog::log(syslog::Severity::Warning, msg);
+                } else {
+                    eprintln!("Warning: {}", msg);
+                }
+            }
+            Ok(result) => break result,
+            // Panics in jtag_adi and the cable drivers are errors by the time they get here, so
+            // this is a bug, which the panic hook has already printed
+            Err(panic) => {
+                if syslog::enabled() {
+                    syslog::log(syslog::Severity::Err, &format!("internal error: {}", panic_message(&*panic)));
+                }
+                if let Some(path) = &pidfile {
+                    let _ = fs::remove_file(path);
+                }
+                std::process::exit(101);
+            }
+        }
+    };
+
+    if let Some(path) = &pidfile {
+        let _ = fs::remove_file(path);
+    }
+
+    match result {
+        Ok(()) => {}
+        Err(DccError::Interrupted) => std::process::exit(DccError::Interrupted.exit_code()),
+        Err(e) => {
+            if syslog::enabled() {
+                syslog::log(syslog::Severity::Err, &e.to_string());
+            } else if !check {
+                eprintln!("Error: {}", e);
+            }
+            std::process::exit(e.exit_code());
+        }
+    }
 }
@@ -0,0 +1,91 @@
+//! `dcc-stream mem`: read and write the target's memory through an access port.  With --cable
+//! share:SOCKET this runs alongside a capture, taking turns with it on the cable; a capture's
+//! control socket offers the same through its `mem` command.
+use clap::{Parser, Subcommand};
+
+use dcc_stream::{parse_u32, Arch, DccError, DccStreamBuilder};
+
+use crate::lock;
+
+/// Words read per access, so a long read doesn't keep a shared cable from the capture
+const CHUNK: usize = 256;
+
+#[derive(Parser, Debug)]
+#[command(bin_name = "dcc-stream mem", about = "Read and write the target's memory through an access port")]
+pub struct MemArgs {
+    #[arg(short, long, env = "DCC_CABLE")]
+    cable: String,
+    #[arg(short, long, env = "DCC_BAUD")]
+    baud: u32,
+    #[arg(long, default_value_t = false, env = "DCC_RTCK")]
+    /// Clock TCK from the target's RTCK, falling back to --baud
+    rtck: bool,
+    #[arg(short, long, default_value_t = 0, env = "DCC_TAP_INDEX")]
+    /// Which JTAG TAP to use
+    tap_index: usize,
+    #[arg(short, long, default_value_t = 1, env = "DCC_AP_NUM")]
+    /// Which access port to use
+    ap_num: u32,
+    #[arg(long, value_enum, default_value_t = Arch::Armv7, env = "DCC_ARCH")]
+    /// Debug architecture of the core
+    arch: Arch,
+    #[arg(short, long, value_parser = parse_u32, env = "DCC_DEBUG_BASE")]
+    /// CPU debug base address, prefix with 0x for hexadecimal.  With a shared cable it names
+    /// the core whose session the accesses join.
+    debug_base: u32,
+    #[arg(long, default_value_t = false)]
+    /// Don't take the cable lock
+    no_lock: bool,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Print COUNT words from ADDR
+    Read {
+        #[arg(value_parser = parse_u32)]
+        addr: u32,
+        #[arg(default_value_t = 1)]
+        count: usize,
+    },
+    /// Write VALUE to the word at ADDR
+    Write {
+        #[arg(value_parser = parse_u32)]
+        addr: u32,
+        #[arg(value_parser = parse_u32)]
+        value: u32,
+    },
+}
+
+pub fn run(args: &MemArgs) -> Result<(), DccError> {
+    // The server of a shared cable holds its lock
+    let shared = args.cable.starts_with("share:");
+    let _lock = if args.no_lock || shared {
+        None
+    } else {
+        Some(lock::lock(&args.cable)?)
+    };
+    let mut dcc = DccStreamBuilder::new(args.cable.clone(), args.debug_base)
+        .baud(args.baud)
+        .rtck(args.rtck)
+        .tap_index(args.tap_index)
+        .ap_num(args.ap_num)
+        .arch(args.arch)
+        .build()?;
+    match args.action {
+        Action::Read { addr, count } => {
+            let mut done = 0;
+            while done < count {
+                let n = CHUNK.min(count - done);
+                let at = addr.wrapping_add(4 * done as u32);
+                for (i, word) in dcc.read_block(at, n)?.into_iter().enumerate() {
+                    println!("0x{:08x}: 0x{:08x}", at.wrapping_add(4 * i as u32), word);
+                }
+                done += n;
+            }
+        }
+        Action::Write { addr, value } => dcc.write_mem(addr, value)?,
+    }
+    Ok(())
+}
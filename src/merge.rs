@@ -0,0 +1,92 @@
+//! --merge-cores: every core writes to the same sinks, each line tagged with the core.  The
+//! cores are read in turn on one thread, so their words already arrive in time order.
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use dcc_stream::decode::{Frame, FrameData};
+use dcc_stream::display::ValueFormat;
+use dcc_stream::sink::Sink;
+use dcc_stream::Record;
+
+type Shared = Rc<RefCell<Box<dyn Sink>>>;
+
+/// One core's view of a shared sink
+pub struct Tagged {
+    sink: Shared,
+    tag: String,
+    format: ValueFormat,
+    /// Closed the shared sink, as the last core holding it
+    closed: bool,
+}
+
+/// Share each of `sinks` between the cores named in `cores`, returning the sinks for each
+pub fn tag(sinks: Vec<(String, Box<dyn Sink>)>, cores: &[String], format: &ValueFormat) -> Vec<Vec<(String, Box<dyn Sink>)>> {
+    let shared: Vec<(String, Shared)> = sinks.into_iter().map(|(name, sink)| (name, Rc::new(RefCell::new(sink)))).collect();
+    cores
+        .iter()
+        .map(|core| {
+            shared
+                .iter()
+                .map(|(name, sink)| {
+                    let tagged = Tagged {
+                        sink: sink.clone(),
+                        tag: core.clone(),
+                        format: format.clone(),
+                        closed: false,
+                    };
+                    (name.clone(), Box::new(tagged) as Box<dyn Sink>)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+impl Sink for Tagged {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.write_frame(&Frame {
+            timestamp: record.timestamp,
+            data: FrameData::Word(record.value),
+            interpolated: record.interpolated,
+        })
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.sink.borrow_mut().write_frame(&Frame {
+            timestamp: frame.timestamp,
+            data: FrameData::Text(format!("{}: {}", self.tag, frame.to_text(&self.format))),
+            interpolated: frame.interpolated,
+        })
+    }
+
+    // Markers about a core already say which
+    fn write_marker(&mut self, timestamp: u128, msg: &str) -> io::Result<()> {
+        self.sink.borrow_mut().write_marker(timestamp, msg)
+    }
+
+    fn set_format(&mut self, format: &ValueFormat) {
+        self.format = format.clone();
+        self.sink.borrow_mut().set_format(format);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.borrow_mut().flush()
+    }
+
+    // The last core to let go closes the sink, so its errors are reported
+    fn close(&mut self) -> io::Result<()> {
+        if Rc::strong_count(&self.sink) == 1 {
+            self.closed = true;
+            return self.sink.borrow_mut().close();
+        }
+        self.flush()
+    }
+}
+
+impl Drop for Tagged {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.sink) == 1 && !self.closed {
+            let _ = self.sink.borrow_mut().close();
+        }
+    }
+}
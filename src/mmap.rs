@@ -0,0 +1,163 @@
+//! A file writer backed by a shared memory mapping, for captures too fast for a `write` per
+//! buffer.  Space is allocated a whole extent ahead, so the file doesn't fragment as it grows,
+//! and cut back to what was written when the writer is dropped.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+/// How much of the file is allocated and mapped at a time
+pub const EXTENT: usize = 64 << 20;
+
+// How often `flush` waits for the data to reach the disk rather than just scheduling it
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct MmapWriter {
+    file: File,
+    /// Mapping of the extent being written
+    map: *mut u8,
+    /// File offset of the mapped extent
+    extent: u64,
+    /// Bytes written into the mapped extent
+    pos: usize,
+    /// Start of the data in the extent not yet passed to msync
+    synced: usize,
+    last_sync: Instant,
+}
+
+// The mapping is only reached through `&mut self`
+unsafe impl Send for MmapWriter {}
+
+impl MmapWriter {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let mut writer = Self {
+            file,
+            map: ptr::null_mut(),
+            extent: 0,
+            pos: 0,
+            synced: 0,
+            last_sync: Instant::now(),
+        };
+        writer.map_extent()?;
+        Ok(writer)
+    }
+
+    /// Allocate and map the extent at `self.extent`
+    fn map_extent(&mut self) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        allocate(&self.file, self.extent, EXTENT)?;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                EXTENT,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                self.extent as libc::off_t,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        self.map = map as *mut u8;
+        self.pos = 0;
+        self.synced = 0;
+        Ok(())
+    }
+
+    fn unmap(&mut self) -> io::Result<()> {
+        if self.map.is_null() {
+            return Ok(());
+        }
+        self.sync(libc::MS_ASYNC)?;
+        let ret = unsafe { libc::munmap(self.map as *mut libc::c_void, EXTENT) };
+        self.map = ptr::null_mut();
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// msync the part of the extent written since the last call.  msync needs a page aligned
+    /// start, so the range is rounded down to a page.
+    fn sync(&mut self, flags: libc::c_int) -> io::Result<()> {
+        if self.pos == self.synced {
+            return Ok(());
+        }
+        let page = page_size();
+        let start = self.synced / page * page;
+        let ret = unsafe { libc::msync(self.map.add(start) as *mut libc::c_void, self.pos - start, flags) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.synced = self.pos;
+        Ok(())
+    }
+
+    /// Unmap the file and cut it back to the data written
+    fn finish(&mut self) -> io::Result<()> {
+        if self.map.is_null() {
+            return Ok(());
+        }
+        let len = self.extent + self.pos as u64;
+        self.unmap()?;
+        self.file.set_len(len)
+    }
+}
+
+impl Write for MmapWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.map.is_null() {
+            return Err(io::Error::other("mapping closed after an earlier error"));
+        }
+        if self.pos == EXTENT {
+            self.unmap()?;
+            self.extent += EXTENT as u64;
+            self.map_extent()?;
+        }
+        let n = buf.len().min(EXTENT - self.pos);
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.map.add(self.pos), n) };
+        self.pos += n;
+        Ok(n)
+    }
+
+    /// Schedule the new data to be written back, waiting for it once a second
+    fn flush(&mut self) -> io::Result<()> {
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.last_sync = Instant::now();
+            self.sync(libc::MS_SYNC)
+        } else {
+            self.sync(libc::MS_ASYNC)
+        }
+    }
+}
+
+impl Drop for MmapWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Reserve disk blocks for `len` bytes at `offset`, extending the file
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, offset: u64, len: usize) -> io::Result<()> {
+    let ret = unsafe { libc::posix_fallocate(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+/// Extend the file to cover `len` bytes at `offset`.  Without posix_fallocate the blocks are
+/// allocated as the pages are written.
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &File, offset: u64, len: usize) -> io::Result<()> {
+    file.set_len(offset + len as u64)
+}
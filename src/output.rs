@@ -0,0 +1,154 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use dcc_stream::decode::{Decoder, Frame};
+use dcc_stream::display::ValueFormat;
+use dcc_stream::sink::Sink;
+use dcc_stream::Record;
+
+use crate::control::ControlServer;
+use crate::ratelimit::RateLimit;
+use crate::report::Report;
+use crate::syslog::{self, Severity};
+use crate::tui::Tui;
+
+// Longest a line written to a sink waits in its buffer while the stream is busy
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Where stream lines, markers and warnings end up
+pub struct Output {
+    pub tui: Option<Tui>,
+    pub control: Option<ControlServer>,
+    /// Named destinations for records and markers, alongside the TUI if there is one
+    pub sinks: Vec<(String, Box<dyn Sink>)>,
+    /// Turns records into the frames given to the sinks
+    decoder: Box<dyn Decoder>,
+    /// Limit on the rate of records written out
+    pub limit: Option<RateLimit>,
+    /// Markers and warnings kept for --report
+    pub report: Option<Report>,
+    format: ValueFormat,
+    last_flush: Instant,
+}
+
+impl Output {
+    pub fn new(
+        tui: Option<Tui>,
+        control: Option<ControlServer>,
+        sinks: Vec<(String, Box<dyn Sink>)>,
+        decoder: Box<dyn Decoder>,
+        format: ValueFormat,
+    ) -> Self {
+        Self {
+            tui,
+            control,
+            sinks,
+            decoder,
+            limit: None,
+            report: None,
+            format,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn set_format(&mut self, format: ValueFormat) {
+        for (_, sink) in &mut self.sinks {
+            sink.set_format(&format);
+        }
+        self.format = format;
+    }
+
+    /// Output a DCC word.  Returns false if it was dropped by the rate limit.
+    pub fn record(&mut self, record: Record) -> bool {
+        if let Some(limit) = &mut self.limit {
+            if !limit.allow() {
+                return false;
+            }
+        }
+        let mut frames = vec![];
+        self.decoder.push(&record, &mut frames);
+        self.frames(frames);
+        true
+    }
+
+    /// Output a batch of words read at `ts` without decoding, timestamping or rate limiting
+    /// each one
+    pub fn words(&mut self, ts: u128, words: &[u32]) {
+        self.each_sink(|sink| sink.write_words(ts, words));
+    }
+
+    fn frames(&mut self, frames: Vec<Frame>) {
+        for frame in frames {
+            if let Some(tui) = &mut self.tui {
+                tui.push(format!("{}: {}", frame.timestamp, frame.to_text(&self.format)));
+            }
+            self.each_sink(|sink| sink.write_frame(&frame));
+        }
+    }
+
+    /// Emit an out-of-band event into the output stream and to control clients
+    pub fn marker(&mut self, ts: u128, msg: &str) {
+        if let Some(tui) = &mut self.tui {
+            tui.push(format!("{}: # {}", ts, msg));
+        }
+        self.each_sink(|sink| sink.write_marker(ts, msg));
+        if let Some(control) = &self.control {
+            control.event(&format!("{} {}", ts, msg));
+        }
+        syslog::log(Severity::Info, msg);
+        if let Some(report) = &mut self.report {
+            report.marker(ts, msg);
+        }
+    }
+
+    pub fn warn(&mut self, msg: &str) {
+        if let Some(report) = &mut self.report {
+            report.warning(msg);
+        }
+        if syslog::enabled() {
+            syslog::log(Severity::Warning, msg);
+        } else if let Some(tui) = &mut self.tui {
+            tui.push(format!("Warning: {}", msg));
+        } else {
+            eprintln!("Warning: {}", msg);
+        }
+    }
+
+    /// Write out whatever the sinks have buffered
+    pub fn flush(&mut self) {
+        self.each_sink(|sink| sink.flush());
+        self.last_flush = Instant::now();
+    }
+
+    /// Flush if the sinks haven't been flushed for a while, for calling once per batch
+    pub fn flush_due(&mut self) {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Close all the sinks, reporting any that fail to flush
+    pub fn close(&mut self) {
+        let mut frames = vec![];
+        self.decoder.finish(&mut frames);
+        self.frames(frames);
+        self.each_sink(|sink| sink.close());
+        self.sinks.clear();
+    }
+
+    /// Run `f` on each sink, dropping any that fail so one broken destination doesn't stop the
+    /// others
+    fn each_sink(&mut self, mut f: impl FnMut(&mut dyn Sink) -> io::Result<()>) {
+        let mut failed = vec![];
+        self.sinks.retain_mut(|(name, sink)| match f(sink.as_mut()) {
+            Ok(()) => true,
+            Err(e) => {
+                failed.push(format!("output {} failed, closing it: {}", name, e));
+                false
+            }
+        });
+        for msg in failed {
+            self.warn(&msg);
+        }
+    }
+}
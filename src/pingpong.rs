@@ -0,0 +1,204 @@
+//! A file writer for multi-hour captures at several MB/s onto disks too slow to keep up with
+//! every burst: data goes into one of two preallocated buffers while a thread of its own
+//! writes out the other.  Nothing is dropped.  If the disk is still busy with one buffer when
+//! the other fills, the writer waits for it, and counts how often and for how long so the
+//! stall can be reported rather than passing unnoticed as a gap in the capture.  The counts,
+//! and the last error, go to an `Accounting` that outlives the writer.
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Size of each of the two buffers
+pub const BUFFER: usize = 8 << 20;
+
+// How long a partly filled buffer waits before `flush` hands it to the I/O thread
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the writers sharing it had to wait for, kept where it can be read while they run and
+/// once the sinks writing through them have closed
+#[derive(Clone, Debug, Default)]
+pub struct Accounting(Arc<Mutex<Stalls>>);
+
+/// `Accounting`'s counts
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stalls {
+    /// Times a writer waited for the disk, and for how long in all
+    pub count: u64,
+    pub time: Duration,
+    /// The last write that failed, with the file's name
+    pub error: Option<String>,
+}
+
+impl Accounting {
+    pub fn get(&self) -> Stalls {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Stalls)) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+pub struct PingPongWriter {
+    /// The buffer being filled
+    active: Vec<u8>,
+    /// Full buffers to the I/O thread, and the written ones back.  The sender is dropped to
+    /// end the thread.
+    full: Option<SyncSender<Vec<u8>>>,
+    empty: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    last_handoff: Instant,
+    /// Times the writer waited for the disk, and for how long in all
+    stalls: u64,
+    stalled: Duration,
+    accounting: Accounting,
+    path: String,
+}
+
+impl PingPongWriter {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::create(path)?;
+        let (full, to_write) = mpsc::sync_channel::<Vec<u8>>(1);
+        let (written, empty) = mpsc::sync_channel(2);
+        // The second buffer starts out waiting to be filled
+        written.send(Vec::with_capacity(BUFFER)).expect("the receiver is held here");
+        let thread = thread::spawn(move || {
+            for mut buffer in to_write {
+                file.write_all(&buffer)?;
+                buffer.clear();
+                // The writer may be gone if it is closing
+                let _ = written.send(buffer);
+            }
+            file.sync_all()
+        });
+        Ok(Self {
+            active: Vec::with_capacity(BUFFER),
+            full: Some(full),
+            empty,
+            thread: Some(thread),
+            last_handoff: Instant::now(),
+            stalls: 0,
+            stalled: Duration::ZERO,
+            accounting: Accounting::default(),
+            path: path.display().to_string(),
+        })
+    }
+
+    /// Count the stalls and errors in `accounting` too, which may be shared with other writers
+    pub fn accounting(mut self, accounting: Accounting) -> Self {
+        self.accounting = accounting;
+        self
+    }
+
+    /// Times the writer had to wait for the disk, and for how long in all
+    pub fn stalls(&self) -> (u64, Duration) {
+        (self.stalls, self.stalled)
+    }
+
+    /// Pass the active buffer to the I/O thread and take the other, waiting for it if it is
+    /// still being written
+    fn swap(&mut self) -> io::Result<()> {
+        let buffer = match self.empty.try_recv() {
+            Ok(buffer) => buffer,
+            Err(TryRecvError::Empty) => {
+                let waited = Instant::now();
+                let buffer = self.empty.recv().map_err(|_| self.failed())?;
+                let waited = waited.elapsed();
+                self.stalls += 1;
+                self.stalled += waited;
+                self.accounting.update(|stalls| {
+                    stalls.count += 1;
+                    stalls.time += waited;
+                });
+                buffer
+            }
+            Err(TryRecvError::Disconnected) => return Err(self.failed()),
+        };
+        self.hand_off(buffer)
+    }
+
+    /// Send the active buffer to be written, filling `buffer` next
+    fn hand_off(&mut self, buffer: Vec<u8>) -> io::Result<()> {
+        let full = mem::replace(&mut self.active, buffer);
+        let sent = self.full.as_ref().is_some_and(|sender| sender.send(full).is_ok());
+        if !sent {
+            return Err(self.failed());
+        }
+        self.last_handoff = Instant::now();
+        Ok(())
+    }
+
+    /// The error that ended the I/O thread
+    fn failed(&mut self) -> io::Error {
+        self.full = None;
+        let e = match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => e,
+            Some(Err(_)) => io::Error::other("the I/O thread panicked"),
+            _ => return io::Error::other("writer closed after an earlier error"),
+        };
+        self.record(&e);
+        e
+    }
+
+    fn record(&self, e: &io::Error) {
+        let error = format!("write {}: {}", self.path, e);
+        self.accounting.update(|stalls| stalls.error = Some(error));
+    }
+
+    /// Write out what is buffered and wait for the I/O thread to finish.  Dropping the writer
+    /// does this too, leaving any error to the accounting.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.full.is_none() {
+            return Ok(());
+        }
+        if !self.active.is_empty() {
+            self.swap()?;
+        }
+        self.full = None;
+        let result = match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("the I/O thread panicked")),
+            None => Ok(()),
+        };
+        if let Err(e) = &result {
+            self.record(e);
+        }
+        result
+    }
+}
+
+impl Write for PingPongWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.active.len() == BUFFER {
+            self.swap()?;
+        }
+        let n = buf.len().min(BUFFER - self.active.len());
+        self.active.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    /// Hand a partly filled buffer to the I/O thread once a second, if it isn't busy, so the
+    /// file keeps up with a slow stream
+    fn flush(&mut self) -> io::Result<()> {
+        if self.active.is_empty() || self.last_handoff.elapsed() < FLUSH_INTERVAL {
+            return Ok(());
+        }
+        match self.empty.try_recv() {
+            Ok(buffer) => self.hand_off(buffer),
+            Err(TryRecvError::Empty) => Ok(()),
+            Err(TryRecvError::Disconnected) => Err(self.failed()),
+        }
+    }
+}
+
+impl Drop for PingPongWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,407 @@
+//! Sink and decoder plugins: shared libraries in a plugin directory that add site-specific
+//! outputs or decoders without rebuilding dcc-stream.  Each library is loaded at start-up and
+//! exports one C function returning a table of what it provides:
+//!
+//! ```c
+//! typedef void (*DccFrameCallback)(void *ctx, uint64_t timestamp_us, uint32_t kind,
+//!                                  const uint8_t *data, size_t len);
+//!
+//! struct DccPluginV1 {
+//!     uint32_t abi_version;   /* DCC_PLUGIN_ABI_VERSION, 1 */
+//!     const char *name;       /* what plugin:NAME and --decode-plugin NAME select */
+//!
+//!     /* A sink, or all NULL.  The functions return 0 on success. */
+//!     void *(*sink_open)(const char *arg);
+//!     int32_t (*sink_record)(void *sink, uint64_t timestamp_us, uint32_t value);
+//!     int32_t (*sink_marker)(void *sink, uint64_t timestamp_us, const char *msg); /* may be NULL */
+//!     int32_t (*sink_flush)(void *sink);
+//!     int32_t (*sink_close)(void *sink);
+//!
+//!     /* A decoder, or all NULL.  Frames are passed to emit(ctx, ...) before returning. */
+//!     void *(*decoder_new)(const char *arg);
+//!     void (*decoder_push)(void *decoder, uint64_t timestamp_us, uint32_t value,
+//!                          DccFrameCallback emit, void *ctx);
+//!     void (*decoder_finish)(void *decoder, DccFrameCallback emit, void *ctx);
+//!     void (*decoder_free)(void *decoder);
+//! };
+//!
+//! const struct DccPluginV1 *dcc_stream_plugin(void);
+//! ```
+//!
+//! `arg` is whatever followed the name and a colon, or an empty string; `*_open` and `*_new`
+//! return NULL if they can't make sense of it.  A frame's `kind` is `FRAME_WORD` with the word
+//! as 4 little-endian bytes, `FRAME_TEXT` with a line of UTF-8, `FRAME_BYTES` with a packet or
+//! `FRAME_INVALID` with why the data made no sense.  Everything is called from one thread.
+//!
+//! Libraries are only loaded on Unix systems.
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::decode::{Decoder, Frame, FrameData};
+use crate::error::DccError;
+use crate::sink::Sink;
+use crate::stream::Record;
+
+/// The version of `PluginV1` this build understands
+pub const ABI_VERSION: u32 = 1;
+/// The symbol every plugin exports
+const ENTRY: &CStr = c"dcc_stream_plugin";
+
+/// Frame kinds for `FrameCallback`
+pub const FRAME_WORD: u32 = 0;
+pub const FRAME_TEXT: u32 = 1;
+pub const FRAME_BYTES: u32 = 2;
+pub const FRAME_INVALID: u32 = 3;
+
+pub type FrameCallback = extern "C" fn(ctx: *mut c_void, timestamp_us: u64, kind: u32, data: *const u8, len: usize);
+
+/// The table a plugin returns, `struct DccPluginV1` above
+#[repr(C)]
+pub struct PluginV1 {
+    pub abi_version: u32,
+    pub name: *const c_char,
+    pub sink_open: Option<unsafe extern "C" fn(arg: *const c_char) -> *mut c_void>,
+    pub sink_record: Option<unsafe extern "C" fn(sink: *mut c_void, timestamp_us: u64, value: u32) -> i32>,
+    pub sink_marker: Option<unsafe extern "C" fn(sink: *mut c_void, timestamp_us: u64, msg: *const c_char) -> i32>,
+    pub sink_flush: Option<unsafe extern "C" fn(sink: *mut c_void) -> i32>,
+    pub sink_close: Option<unsafe extern "C" fn(sink: *mut c_void) -> i32>,
+    pub decoder_new: Option<unsafe extern "C" fn(arg: *const c_char) -> *mut c_void>,
+    pub decoder_push:
+        Option<unsafe extern "C" fn(decoder: *mut c_void, timestamp_us: u64, value: u32, emit: FrameCallback, ctx: *mut c_void)>,
+    pub decoder_finish: Option<unsafe extern "C" fn(decoder: *mut c_void, emit: FrameCallback, ctx: *mut c_void)>,
+    pub decoder_free: Option<unsafe extern "C" fn(decoder: *mut c_void)>,
+}
+
+/// A loaded library, unloaded when the last sink or decoder made from it is gone
+struct Library {
+    handle: *mut c_void,
+    table: &'static PluginV1,
+    name: String,
+    path: PathBuf,
+}
+
+// The handle and table are only read, and plugins are called from one thread at a time
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        dl::close(self.handle);
+    }
+}
+
+/// The plugins found in a directory
+#[derive(Default)]
+pub struct Plugins {
+    loaded: Vec<Arc<Library>>,
+}
+
+impl Plugins {
+    /// Load every shared library in `dir`, in name order
+    pub fn load_dir(dir: &Path) -> Result<Self, DccError> {
+        let entries = fs::read_dir(dir).map_err(|e| DccError::Io(format!("read plugin directory {}", dir.display()), e))?;
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == dl::EXTENSION))
+            .collect();
+        paths.sort();
+        let mut plugins = Self::default();
+        for path in paths {
+            let library = load(&path)?;
+            if let Some(other) = plugins.loaded.iter().find(|l| l.name == library.name) {
+                return Err(DccError::InvalidConfig(format!(
+                    "plugin {} is in both {} and {}",
+                    library.name,
+                    other.path.display(),
+                    path.display()
+                )));
+            }
+            plugins.loaded.push(Arc::new(library));
+        }
+        Ok(plugins)
+    }
+
+    /// The names of the plugins and whether each provides a sink and a decoder
+    pub fn list(&self) -> Vec<(&str, bool, bool)> {
+        self.loaded
+            .iter()
+            .map(|l| (l.name.as_str(), l.table.sink_open.is_some(), l.table.decoder_new.is_some()))
+            .collect()
+    }
+
+    /// Open the sink named by `spec`, `NAME[:ARG]`
+    pub fn open_sink(&self, spec: &str) -> Result<Box<dyn Sink>, DccError> {
+        let (library, arg) = self.find(spec, "sink", |t| t.sink_open.is_some() && t.sink_record.is_some())?;
+        let open = library.table.sink_open.expect("checked by find");
+        let handle = unsafe { open(arg.as_ptr()) };
+        if handle.is_null() {
+            return Err(DccError::InvalidConfig(format!("plugin sink {}: refused {:?}", library.name, arg)));
+        }
+        Ok(Box::new(PluginSink { library, handle }))
+    }
+
+    /// Make the decoder named by `spec`, `NAME[:ARG]`
+    pub fn decoder(&self, spec: &str) -> Result<Box<dyn Decoder>, DccError> {
+        let (library, arg) = self.find(spec, "decoder", |t| t.decoder_new.is_some() && t.decoder_push.is_some())?;
+        let new = library.table.decoder_new.expect("checked by find");
+        let handle = unsafe { new(arg.as_ptr()) };
+        if handle.is_null() {
+            return Err(DccError::InvalidConfig(format!(
+                "plugin decoder {}: refused {:?}",
+                library.name, arg
+            )));
+        }
+        Ok(Box::new(PluginDecoder { library, handle }))
+    }
+
+    /// Check a sink of `spec` can be opened, without opening it
+    pub fn check_sink(&self, spec: &str) -> Result<(), DccError> {
+        self.find(spec, "sink", |t| t.sink_open.is_some() && t.sink_record.is_some())
+            .map(|_| ())
+    }
+
+    /// Check a decoder of `spec` can be made, without making it
+    pub fn check_decoder(&self, spec: &str) -> Result<(), DccError> {
+        self.find(spec, "decoder", |t| t.decoder_new.is_some() && t.decoder_push.is_some())
+            .map(|_| ())
+    }
+
+    fn find(&self, spec: &str, what: &str, provides: impl Fn(&PluginV1) -> bool) -> Result<(Arc<Library>, CString), DccError> {
+        let (name, arg) = spec.split_once(':').unwrap_or((spec, ""));
+        let library = self
+            .loaded
+            .iter()
+            .find(|l| l.name == name)
+            .ok_or_else(|| DccError::InvalidConfig(format!("no plugin {} in the plugin directory", name)))?;
+        if !provides(library.table) {
+            return Err(DccError::InvalidConfig(format!("plugin {} has no {}", name, what)));
+        }
+        let arg = CString::new(arg).map_err(|_| DccError::InvalidConfig(format!("plugin {}: NUL in its argument", name)))?;
+        Ok((library.clone(), arg))
+    }
+}
+
+fn load(path: &Path) -> Result<Library, DccError> {
+    let fail = |msg: String| DccError::InvalidConfig(format!("plugin {}: {}", path.display(), msg));
+    let handle = dl::open(path).map_err(fail)?;
+    // Closed again by the drop if anything below fails
+    let mut library = Library {
+        handle,
+        table: &EMPTY,
+        name: String::new(),
+        path: path.to_path_buf(),
+    };
+    let entry = dl::symbol(handle, ENTRY).map_err(fail)?;
+    let entry: unsafe extern "C" fn() -> *const PluginV1 = unsafe { std::mem::transmute(entry) };
+    let table = unsafe { entry().as_ref() }.ok_or_else(|| fail("dcc_stream_plugin returned NULL".to_string()))?;
+    if table.abi_version != ABI_VERSION {
+        return Err(fail(format!(
+            "ABI version {}, this dcc-stream needs {}",
+            table.abi_version, ABI_VERSION
+        )));
+    }
+    if table.name.is_null() {
+        return Err(fail("no name".to_string()));
+    }
+    library.name = unsafe { CStr::from_ptr(table.name) }.to_string_lossy().into_owned();
+    library.table = table;
+    Ok(library)
+}
+
+static EMPTY: PluginV1 = PluginV1 {
+    abi_version: 0,
+    name: std::ptr::null(),
+    sink_open: None,
+    sink_record: None,
+    sink_marker: None,
+    sink_flush: None,
+    sink_close: None,
+    decoder_new: None,
+    decoder_push: None,
+    decoder_finish: None,
+    decoder_free: None,
+};
+
+// The table only holds pointers to the library's constants and functions
+unsafe impl Sync for PluginV1 {}
+
+fn status(library: &Library, what: &str, ret: i32) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        ret => Err(io::Error::other(format!("plugin {} {} returned {}", library.name, what, ret))),
+    }
+}
+
+struct PluginSink {
+    library: Arc<Library>,
+    /// Null once closed
+    handle: *mut c_void,
+}
+
+impl Sink for PluginSink {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let write = self.library.table.sink_record.expect("checked when opened");
+        status(&self.library, "write", unsafe {
+            write(self.handle, record.timestamp as u64, record.value)
+        })
+    }
+
+    fn write_marker(&mut self, timestamp: u128, msg: &str) -> io::Result<()> {
+        let Some(marker) = self.library.table.sink_marker else {
+            return Ok(());
+        };
+        let msg = CString::new(msg.replace('\0', " ")).expect("NULs replaced");
+        status(&self.library, "marker", unsafe {
+            marker(self.handle, timestamp as u64, msg.as_ptr())
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.library.table.sink_flush {
+            Some(flush) => status(&self.library, "flush", unsafe { flush(self.handle) }),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if self.handle.is_null() {
+            return Ok(());
+        }
+        let handle = std::mem::replace(&mut self.handle, std::ptr::null_mut());
+        match self.library.table.sink_close {
+            Some(close) => status(&self.library, "close", unsafe { close(handle) }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Errors are for an explicit `close` to report; the library doesn't print
+impl Drop for PluginSink {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+struct PluginDecoder {
+    library: Arc<Library>,
+    handle: *mut c_void,
+}
+
+/// Passed to plugins as `emit`, with `ctx` the `Vec<Frame>` to add to
+extern "C" fn collect(ctx: *mut c_void, timestamp_us: u64, kind: u32, data: *const u8, len: usize) {
+    let out = unsafe { &mut *(ctx as *mut Vec<Frame>) };
+    let bytes = if data.is_null() {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(data, len) }
+    };
+    let data = match kind {
+        FRAME_WORD if bytes.len() == 4 => FrameData::Word(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        FRAME_TEXT => FrameData::Text(String::from_utf8_lossy(bytes).into_owned()),
+        FRAME_BYTES => FrameData::Bytes(bytes.to_vec()),
+        FRAME_INVALID => FrameData::Invalid(String::from_utf8_lossy(bytes).into_owned()),
+        kind => FrameData::Invalid(format!("plugin frame of unknown kind {} and {} bytes", kind, len)),
+    };
+    out.push(Frame {
+        timestamp: timestamp_us as u128,
+        data,
+        interpolated: false,
+    });
+}
+
+impl Decoder for PluginDecoder {
+    fn push(&mut self, record: &Record, out: &mut Vec<Frame>) {
+        let push = self.library.table.decoder_push.expect("checked when made");
+        let from = out.len();
+        unsafe {
+            push(
+                self.handle,
+                record.timestamp as u64,
+                record.value,
+                collect,
+                out as *mut Vec<Frame> as *mut c_void,
+            )
+        };
+        // Frames stamped with the word's time have its timestamp's quality too
+        for frame in out[from..].iter_mut().filter(|f| f.timestamp == record.timestamp) {
+            frame.interpolated = record.interpolated;
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<Frame>) {
+        if let Some(finish) = self.library.table.decoder_finish {
+            unsafe { finish(self.handle, collect, out as *mut Vec<Frame> as *mut c_void) };
+        }
+    }
+}
+
+impl Drop for PluginDecoder {
+    fn drop(&mut self) {
+        if let Some(free) = self.library.table.decoder_free {
+            unsafe { free(self.handle) };
+        }
+    }
+}
+
+#[cfg(unix)]
+mod dl {
+    use std::ffi::{c_void, CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[cfg(target_os = "macos")]
+    pub const EXTENSION: &str = "dylib";
+    #[cfg(not(target_os = "macos"))]
+    pub const EXTENSION: &str = "so";
+
+    fn error() -> String {
+        let msg = unsafe { libc::dlerror() };
+        if msg.is_null() {
+            "unknown dlopen error".to_string()
+        } else {
+            unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned()
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<*mut c_void, String> {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| "NUL in the path".to_string())?;
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(error());
+        }
+        Ok(handle)
+    }
+
+    pub fn symbol(handle: *mut c_void, name: &CStr) -> Result<*mut c_void, String> {
+        let sym = unsafe { libc::dlsym(handle, name.as_ptr()) };
+        if sym.is_null() {
+            return Err(format!("no {} symbol: {}", name.to_string_lossy(), error()));
+        }
+        Ok(sym)
+    }
+
+    pub fn close(handle: *mut c_void) {
+        unsafe { libc::dlclose(handle) };
+    }
+}
+
+#[cfg(not(unix))]
+mod dl {
+    use std::ffi::{c_void, CStr};
+    use std::path::Path;
+
+    pub const EXTENSION: &str = "dll";
+
+    pub fn open(_path: &Path) -> Result<*mut c_void, String> {
+        Err("plugins need a Unix system".to_string())
+    }
+
+    pub fn symbol(_handle: *mut c_void, _name: &CStr) -> Result<*mut c_void, String> {
+        unreachable!("nothing is opened")
+    }
+
+    pub fn close(_handle: *mut c_void) {}
+}
@@ -0,0 +1,332 @@
+//! Outputs from the config file's `[[sink]]` tables, each with its own format, filter and
+//! rate limit on top of the capture's, e.g. every word raw to a file while the console only
+//! shows some of them:
+//!
+//! ```toml
+//! [[sink]]
+//! dest = "capture.bin"
+//! format = "raw"
+//!
+//! [[sink]]
+//! dest = "-"
+//! filter = ["value & 0xff000000 == 0x01000000"]
+//! max-rate = 50
+//! ```
+//!
+//! `dest` is as for --output, and `format` one of --format's, that option's by default.  The
+//! filters test words, so they need --decode raw.  Markers always get through.
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use clap::ValueEnum;
+use toml::{Table, Value};
+
+use dcc_stream::decode::{Frame, FrameData};
+use dcc_stream::display::ValueFormat;
+use dcc_stream::sink::{OutputFormat, Sink};
+use dcc_stream::{Record, SharedClock};
+
+use crate::filter::{self, Filter};
+use crate::ratelimit::RateLimit;
+
+/// One `[[sink]]` table
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkPolicy {
+    pub dest: String,
+    /// None for --format
+    pub format: Option<OutputFormat>,
+    pub filter: Vec<Filter>,
+    pub max_rate: Option<u32>,
+}
+
+impl SinkPolicy {
+    pub fn from_table(table: &Table) -> Result<Self, String> {
+        for key in table.keys() {
+            if !["dest", "format", "filter", "max-rate"].contains(&key.as_str()) {
+                return Err(format!("unknown key {}", key));
+            }
+        }
+        let dest = match table.get("dest") {
+            Some(Value::String(dest)) => dest.clone(),
+            Some(_) => return Err("dest must be a string".to_string()),
+            None => return Err("needs a dest".to_string()),
+        };
+        let format = match table.get("format") {
+            None => None,
+            Some(Value::String(format)) => Some(OutputFormat::from_str(format, true).map_err(|_| format!("unknown format {}", format))?),
+            Some(_) => return Err("format must be a string".to_string()),
+        };
+        let filter = match table.get("filter") {
+            None => vec![],
+            Some(Value::String(filter)) => vec![filter.parse()?],
+            Some(Value::Array(filters)) => filters
+                .iter()
+                .map(|f| f.as_str().ok_or("filter must be strings".to_string())?.parse())
+                .collect::<Result<_, String>>()?,
+            Some(_) => return Err("filter must be a string or an array of them".to_string()),
+        };
+        let max_rate = match table.get("max-rate") {
+            None => None,
+            Some(Value::Integer(rate)) if (1..=u32::MAX as i64).contains(rate) => Some(*rate as u32),
+            Some(_) => return Err("max-rate must be a number of words per second above 0".to_string()),
+        };
+        Ok(Self {
+            dest,
+            format,
+            filter,
+            max_rate,
+        })
+    }
+
+    /// The table's filter and rate limit, if it has either
+    pub fn policer(&self, clock: &SharedClock) -> Option<Policer> {
+        if self.filter.is_empty() && self.max_rate.is_none() {
+            return None;
+        }
+        Some(Policer {
+            filter: self.filter.clone(),
+            limit: self.max_rate.map(|rate| Rc::new(RefCell::new(RateLimit::new(rate, clock.clone())))),
+        })
+    }
+}
+
+/// A table's filter and rate limit.  With --merge-cores each core's view of the sink is
+/// wrapped in a clone, ahead of the tagging, so the filter sees the words and the cores share
+/// the limit.
+#[derive(Clone)]
+pub struct Policer {
+    filter: Vec<Filter>,
+    limit: Option<Rc<RefCell<RateLimit>>>,
+}
+
+impl Policer {
+    /// Wrap `sink`, opened for `dest`
+    pub fn apply(&self, sink: Box<dyn Sink>) -> Box<dyn Sink> {
+        Box::new(Policed {
+            sink,
+            filter: self.filter.clone(),
+            limit: self.limit.clone(),
+        })
+    }
+}
+
+/// A sink that only gets the words its filter and rate limit let through
+struct Policed {
+    sink: Box<dyn Sink>,
+    filter: Vec<Filter>,
+    limit: Option<Rc<RefCell<RateLimit>>>,
+}
+
+impl Policed {
+    /// Whether to write a frame holding `word`, if it is one
+    fn allow(&mut self, word: Option<u32>) -> bool {
+        word.is_none_or(|w| filter::any_match(&self.filter, w)) && self.limit.as_ref().is_none_or(|limit| limit.borrow_mut().allow())
+    }
+}
+
+/// The word a frame holds, looking inside --context-ids frames
+fn word(data: &FrameData) -> Option<u32> {
+    match data {
+        FrameData::Word(value) => Some(*value),
+        FrameData::Context { data, .. } => word(data),
+        _ => None,
+    }
+}
+
+impl Sink for Policed {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        if !self.allow(Some(record.value)) {
+            return Ok(());
+        }
+        self.sink.write_record(record)
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.allow(word(&frame.data)) {
+            return Ok(());
+        }
+        self.sink.write_frame(frame)
+    }
+
+    fn write_words(&mut self, timestamp: u128, words: &[u32]) -> io::Result<()> {
+        let kept: Vec<u32> = words.iter().copied().filter(|&w| self.allow(Some(w))).collect();
+        if kept.is_empty() {
+            return Ok(());
+        }
+        self.sink.write_words(timestamp, &kept)
+    }
+
+    fn write_marker(&mut self, timestamp: u128, msg: &str) -> io::Result<()> {
+        self.sink.write_marker(timestamp, msg)
+    }
+
+    fn set_format(&mut self, format: &ValueFormat) {
+        self.sink.set_format(format)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.sink.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use dcc_stream::VirtualClock;
+
+    fn policy(toml: &str) -> Result<SinkPolicy, String> {
+        SinkPolicy::from_table(&toml.parse::<Table>().unwrap())
+    }
+
+    /// What a sink behind a policer was given
+    #[derive(Clone, Default)]
+    struct Written(Rc<RefCell<Vec<String>>>);
+
+    impl Sink for Written {
+        fn write_record(&mut self, record: &Record) -> io::Result<()> {
+            self.0.borrow_mut().push(format!("record {:x}", record.value));
+            Ok(())
+        }
+
+        fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+            self.0.borrow_mut().push(format!("frame {:?}", frame.data));
+            Ok(())
+        }
+
+        fn write_words(&mut self, _timestamp: u128, words: &[u32]) -> io::Result<()> {
+            self.0.borrow_mut().push(format!("words {:x?}", words));
+            Ok(())
+        }
+
+        fn write_marker(&mut self, _timestamp: u128, msg: &str) -> io::Result<()> {
+            self.0.borrow_mut().push(format!("marker {}", msg));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(value: u32) -> Record {
+        Record {
+            timestamp: 0,
+            value,
+            interpolated: false,
+        }
+    }
+
+    fn frame(data: FrameData) -> Frame {
+        Frame {
+            timestamp: 0,
+            data,
+            interpolated: false,
+        }
+    }
+
+    #[test]
+    fn tables() {
+        assert_eq!(
+            policy("dest = \"-\""),
+            Ok(SinkPolicy {
+                dest: "-".to_string(),
+                format: None,
+                filter: vec![],
+                max_rate: None,
+            })
+        );
+        let full = policy("dest = \"capture.bin\"\nformat = \"RAW\"\nfilter = [\"value == 1\", \"value == 2\"]\nmax-rate = 50").unwrap();
+        assert_eq!(full.format, Some(OutputFormat::Raw));
+        assert_eq!(full.filter, vec!["value == 1".parse().unwrap(), "value == 2".parse().unwrap()]);
+        assert_eq!(full.max_rate, Some(50));
+        assert_eq!(policy("dest = \"-\"\nfilter = \"value == 1\"").unwrap().filter.len(), 1);
+    }
+
+    #[test]
+    fn bad_tables() {
+        for (toml, error) in [
+            ("format = \"raw\"", "needs a dest"),
+            ("dest = 1", "dest must be a string"),
+            ("dest = \"-\"\nrate = 1", "unknown key rate"),
+            ("dest = \"-\"\nformat = \"morse\"", "unknown format morse"),
+            ("dest = \"-\"\nformat = 1", "format must be a string"),
+            ("dest = \"-\"\nfilter = \"value\"", "filter value needs == or !="),
+            ("dest = \"-\"\nfilter = [1]", "filter must be strings"),
+            ("dest = \"-\"\nfilter = 1", "filter must be a string or an array of them"),
+            (
+                "dest = \"-\"\nmax-rate = 0",
+                "max-rate must be a number of words per second above 0",
+            ),
+            (
+                "dest = \"-\"\nmax-rate = 4294967296",
+                "max-rate must be a number of words per second above 0",
+            ),
+            (
+                "dest = \"-\"\nmax-rate = 1.5",
+                "max-rate must be a number of words per second above 0",
+            ),
+        ] {
+            assert_eq!(policy(toml), Err(error.to_string()), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn filter_lets_markers_through() {
+        let clock = VirtualClock::shared();
+        assert!(policy("dest = \"-\"").unwrap().policer(&clock).is_none());
+        let written = Written::default();
+        let policer = policy("dest = \"-\"\nfilter = \"value & 0xf == 1\"")
+            .unwrap()
+            .policer(&clock)
+            .unwrap();
+        let mut sink = policer.apply(Box::new(written.clone()));
+        sink.write_record(&record(0x21)).unwrap();
+        sink.write_record(&record(0x22)).unwrap();
+        sink.write_words(0, &[1, 2, 3, 0x11]).unwrap();
+        sink.write_words(0, &[2, 3]).unwrap();
+        sink.write_frame(&frame(FrameData::Word(2))).unwrap();
+        sink.write_frame(&frame(FrameData::Context {
+            thread: 1,
+            contextidr: 1,
+            data: Box::new(FrameData::Word(0x31)),
+        }))
+        .unwrap();
+        sink.write_frame(&frame(FrameData::Text("hi".to_string()))).unwrap();
+        sink.write_marker(0, "mark").unwrap();
+        assert_eq!(
+            *written.0.borrow(),
+            [
+                "record 21",
+                "words [1, 11]",
+                "frame Context { thread: 1, contextidr: 1, data: Word(49) }",
+                "frame Text(\"hi\")",
+                "marker mark"
+            ]
+        );
+    }
+
+    #[test]
+    fn clones_share_the_rate_limit() {
+        let clock = VirtualClock::shared();
+        let policer = policy("dest = \"-\"\nmax-rate = 3").unwrap().policer(&clock).unwrap();
+        let (a, b) = (Written::default(), Written::default());
+        let mut first = policer.apply(Box::new(a.clone()));
+        let mut second = policer.clone().apply(Box::new(b.clone()));
+        first.write_words(0, &[1, 2]).unwrap();
+        second.write_words(0, &[3, 4]).unwrap();
+        first.write_marker(0, "mark").unwrap();
+        assert_eq!(*a.0.borrow(), ["words [1, 2]", "marker mark"]);
+        assert_eq!(*b.0.borrow(), ["words [3]"]);
+        clock.sleep(Duration::from_secs(1));
+        second.write_record(&record(5)).unwrap();
+        assert_eq!(b.0.borrow().last().unwrap(), "record 5");
+    }
+}
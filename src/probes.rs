@@ -0,0 +1,23 @@
+//! `dcc-stream probes`: list the attached adapters and the --cable name for each
+use clap::Parser;
+
+use dcc_stream::{list_probes, DccError};
+
+#[derive(Parser, Debug)]
+#[command(bin_name = "dcc-stream probes", about = "List the attached JTAG adapters that --cable can open")]
+pub struct ProbesArgs {}
+
+pub fn run(_args: &ProbesArgs) -> Result<(), DccError> {
+    let probes = list_probes()?;
+    if probes.is_empty() {
+        println!("No known adapters attached");
+        return Ok(());
+    }
+    for probe in probes {
+        println!(
+            "{:<12} bus {:03} device {:03}  {:04x}:{:04x}",
+            probe.cable, probe.bus, probe.address, probe.vid, probe.pid
+        );
+    }
+    Ok(())
+}
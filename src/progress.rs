@@ -0,0 +1,86 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Progress bar on stderr for captures with a known length
+pub struct Progress {
+    count: Option<u64>,
+    duration: Option<Duration>,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    /// Returns `None` if the capture is unbounded or stderr isn't a terminal
+    pub fn new(count: Option<u64>, duration: Option<Duration>) -> Option<Self> {
+        if (count.is_none() && duration.is_none()) || !io::stderr().is_terminal() {
+            return None;
+        }
+        Some(Self {
+            count,
+            duration,
+            start: Instant::now(),
+            last_draw: None,
+        })
+    }
+
+    /// Fraction complete and estimated seconds remaining, whichever bound is closer
+    fn estimate(&self, words: u64) -> (f64, f64) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut fraction: f64 = 0.0;
+        let mut eta = f64::INFINITY;
+        if let Some(count) = self.count {
+            let f = words as f64 / count.max(1) as f64;
+            fraction = fraction.max(f);
+            if words > 0 {
+                eta = eta.min(elapsed * (count.saturating_sub(words)) as f64 / words as f64);
+            }
+        }
+        if let Some(duration) = self.duration {
+            let total = duration.as_secs_f64();
+            fraction = fraction.max(elapsed / total);
+            eta = eta.min((total - elapsed).max(0.0));
+        }
+        (fraction.min(1.0), eta)
+    }
+
+    pub fn update(&mut self, words: u64) {
+        if self.last_draw.is_some_and(|t| t.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        self.draw(words);
+    }
+
+    fn draw(&self, words: u64) {
+        let (fraction, eta) = self.estimate(words);
+        let filled = (fraction * WIDTH as f64) as usize;
+        let secs = self.start.elapsed().as_secs_f64();
+        let kbps = if secs > 0.0 { words as f64 * 32.0 / 1000.0 / secs } else { 0.0 };
+        let eta = if eta.is_finite() { format!("{:.0}s", eta) } else { "?".to_string() };
+        let count = match self.count {
+            Some(count) => format!("{}/{}", words, count),
+            None => words.to_string(),
+        };
+        let mut stderr = io::stderr();
+        let _ = write!(
+            stderr,
+            "\r[{}{}] {:3.0}% {} words ETA {} {:.1} kbps ",
+            "#".repeat(filled),
+            "-".repeat(WIDTH - filled),
+            fraction * 100.0,
+            count,
+            eta,
+            kbps
+        );
+        let _ = stderr.flush();
+    }
+
+    /// Draw the final state and move off the progress line
+    pub fn finish(&self, words: u64) {
+        self.draw(words);
+        eprintln!();
+    }
+}
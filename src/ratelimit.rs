@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use dcc_stream::SharedClock;
+
+/// Token bucket allowing `rate` events per second with bursts of up to one second's worth
+pub struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    clock: SharedClock,
+    last: Duration,
+}
+
+impl RateLimit {
+    pub fn new(rate: u32, clock: SharedClock) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: clock.now(),
+            clock,
+        }
+    }
+
+    /// Returns true if another event fits within the rate, consuming a token
+    pub fn allow(&mut self) -> bool {
+        let now = self.clock.now();
+        let refill = now.saturating_sub(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dcc_stream::VirtualClock;
+    use std::sync::Arc;
+
+    fn allowed(limit: &mut RateLimit, tries: usize) -> usize {
+        (0..tries).filter(|_| limit.allow()).count()
+    }
+
+    #[test]
+    fn allows_a_second_at_once() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut limit = RateLimit::new(100, clock.clone());
+        assert_eq!(allowed(&mut limit, 150), 100);
+        assert!(!limit.allow());
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut limit = RateLimit::new(100, clock.clone());
+        allowed(&mut limit, 100);
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(allowed(&mut limit, 100), 25);
+        clock.advance(Duration::from_millis(5));
+        assert!(!limit.allow());
+        clock.advance(Duration::from_millis(5));
+        assert!(limit.allow());
+    }
+
+    #[test]
+    fn bursts_are_capped_at_a_second() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut limit = RateLimit::new(10, clock.clone());
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(allowed(&mut limit, 100), 10);
+    }
+}
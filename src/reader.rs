@@ -0,0 +1,1268 @@
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::adaptive::{AdaptiveQueue, RateEstimate};
+use crate::control::{MemOp, Request};
+use crate::cores;
+use crate::record::Recording;
+use crate::ring::{self, Consumer, Producer, PushError};
+use crate::target_time;
+
+use dcc_stream::mailbox::Mailbox;
+use dcc_stream::rtt::{self, RttChannel, RttLocation};
+use dcc_stream::run_control::{self, Access, Vector};
+use dcc_stream::state::StateFile;
+use dcc_stream::{CancelToken, DccError, DccStream, DccStreamBuilder, Event, SharedClock, MAX_QUEUE_SIZE};
+
+// Consecutive failed DCC reads retried after clearing the sticky errors, before the transport
+// is reinitialised
+const READ_RETRIES: u32 = 3;
+// Consecutive failed DCC reads before the session is considered lost
+const REATTACH_FAILURES: u32 = 8;
+// How often EDPRSR is polled for power-down and reset events while streaming
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the reader does with a batch when the output thread has fallen a full channel behind
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FullPolicy {
+    /// Wait for the output to catch up, which in stall mode stalls the target
+    Block,
+    /// Discard the batch and count it, so the target never waits on the output
+    Drop,
+}
+
+/// What the reader does when the target sets the OS lock again while streaming
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OsLockPolicy {
+    /// Clear it and attach again
+    Clear,
+    /// Leave it alone and wait for the target to clear it
+    Wait,
+    /// End the capture
+    Stop,
+}
+
+/// Something from the reader thread.  `core` is the index of the core it concerns, in the
+/// order they were added to the builder.
+pub enum Msg {
+    /// Words read between `start` and `done`, in microseconds since the capture started, by
+    /// `reads` reads.  With --txfull `slots` says which read returned each word, otherwise it is
+    /// empty and the words came from the reads in turn.  `overflow` words were discarded since
+    /// the previous batch, and `rate` is the estimate of words per second the core is sending.
+    Batch {
+        core: usize,
+        start: u128,
+        done: u128,
+        words: Vec<u32>,
+        reads: usize,
+        slots: Vec<u32>,
+        overflow: u64,
+        rate: f64,
+    },
+    Error(usize, DccError),
+    /// The transport was reinitialised after `READ_RETRIES` failed reads in a row
+    Reinitialized(usize),
+    /// The core powered down, and the reader is waiting for it to power up
+    PoweredDown(usize),
+    /// The core powered up again and was reattached
+    PoweredUp(usize),
+    /// The target set the OS lock, which is being handled by the `OsLockPolicy`
+    OsLocked(usize),
+    /// The OS lock is clear again and the core was reattached
+    OsUnlocked(usize),
+    /// DSCR showed DCC data was lost, with the DSCR value
+    Overrun(usize, u32),
+    /// DSCR had lost stall mode and it was set again, with the DSCR found
+    Reconfigured(usize, u32),
+    SessionLost(usize, String),
+    Reattached(usize),
+    /// Reads kept failing and reattaching is off, so the reader has stopped
+    GaveUp(usize, DccError),
+    /// The core halted, with the reason, e.g. at a breakpoint or on a vector catch
+    Halted(usize, &'static str),
+    /// A halted core is running again
+    Resumed(usize),
+    /// The watchpoint at this address was hit and the core let run again
+    Watchpoint(usize, u32),
+    /// The registers of a core halted through `Shared::halt`, by name
+    Registers(usize, Vec<(String, u64)>),
+    /// The target's counter read `ticks` at `host`, in microseconds since the capture started
+    Clock {
+        host: u128,
+        ticks: u64,
+    },
+    /// TCK was changed to this, through `Shared::baud`
+    Reclocked(u32),
+}
+
+/// How the reader polls
+pub struct Options {
+    /// Words per batch to start with
+    pub queue_size: usize,
+    /// Tune the batch size as the reader goes, separately for each core
+    pub adaptive: Option<AdaptiveQueue>,
+    /// Batches queued for `recv` before `policy` applies
+    pub depth: usize,
+    pub policy: FullPolicy,
+    /// Longest sleep between batches while the target has sent nothing new
+    pub idle_backoff: Option<Duration>,
+    /// Batches only hold new words, see `DccStream::set_txfull`
+    pub txfull: bool,
+    /// A word that matches the value under the mask announces a burst of as many words as the
+    /// bits outside the mask say, and the next read is sized to the rest of it
+    pub burst_header: Option<(u32, u32)>,
+    /// Time each batch.  Without this batches are stamped 0 and the estimated rate is 0.
+    pub timestamps: bool,
+    /// Reattach to a core whose reads keep failing, rather than giving up
+    pub reattach: bool,
+    pub os_lock: OsLockPolicy,
+    /// Set the OS lock again when restoring the target
+    pub relock: bool,
+    /// Catch the first core coming out of a warm reset and let it run once every core is
+    /// attached
+    pub catch_reset: bool,
+    /// Where the first core's CTI is for restarting it, if not `run_control::CTI_OFFSET` above
+    /// the debug base
+    pub cti_base: Option<u32>,
+    /// Watchpoints for the first core, armed one at a time: when one is hit the next is armed
+    /// and the core let run again
+    pub watch: Vec<(u32, Access)>,
+    /// Halt every core on these exceptions, and report it when one does
+    pub vector_catch: Vec<Vector>,
+    /// Let a core halted through `Shared::halt` run again once its registers are read
+    pub resume_after_halt: bool,
+    /// Sample the first core's memory-mapped counter at this address at the start and with
+    /// each health check
+    pub target_clock: Option<u32>,
+    /// Save each core's debug state here before attaching, see `StateFile::save`
+    pub state: Option<StateFile>,
+    /// Put the saved state back when restoring the target, rather than only undoing what this
+    /// capture changed
+    pub restore_state: bool,
+}
+
+/// Settings the output thread can change while the reader runs
+pub struct Shared {
+    /// Words per batch, also updated by the reader from the first core when the queue is
+    /// adaptive
+    pub queue_size: AtomicUsize,
+    /// Stop polling the target, for --pause-polling
+    pub suspend: AtomicBool,
+    /// WAIT and FAULT acknowledgements seen by the transport, updated by the reader
+    pub waits: AtomicU64,
+    pub faults: AtomicU64,
+    /// Halt this core, plus one, and read its registers, for --halt-on.  0 for none.
+    pub halt: AtomicUsize,
+    /// Memory accesses from the control API, answered by the reader in turn with its batches
+    pub mem: Mutex<VecDeque<(MemOp, Request)>>,
+    /// Change TCK to this between batches.  0 for no change.
+    pub baud: AtomicU32,
+}
+
+impl Shared {
+    fn new(queue_size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            queue_size: AtomicUsize::new(queue_size),
+            suspend: AtomicBool::new(false),
+            waits: AtomicU64::new(0),
+            faults: AtomicU64::new(0),
+            halt: AtomicUsize::new(0),
+            mem: Mutex::new(VecDeque::new()),
+            baud: AtomicU32::new(0),
+        })
+    }
+
+    /// Queue a memory access for the reader, which answers `req`
+    pub fn mem(&self, op: MemOp, req: Request) {
+        self.mem.lock().unwrap_or_else(|e| e.into_inner()).push_back((op, req));
+    }
+}
+
+/// Polls the DCC on its own thread so that slow output can't hold up the target.  With more
+/// than one core, a batch is read from each in turn.
+pub struct Reader {
+    pub shared: Arc<Shared>,
+    rx: Consumer<Msg>,
+    stop: CancelToken,
+    thread: JoinHandle<Result<(), DccError>>,
+}
+
+impl Reader {
+    /// Open and attach to the cores on a new thread, which then reads from them until stopped.
+    /// `attach_stop` interrupts the attach and timestamps are taken from `clock`.  Returns
+    /// the reader and the IDCODE of the debug port.
+    pub fn spawn(
+        builder: DccStreamBuilder,
+        attach_stop: CancelToken,
+        options: Options,
+        clock: SharedClock,
+    ) -> Result<(Self, u32), DccError> {
+        let shared = Shared::new(options.queue_size);
+        let (tx, rx) = ring::ring(options.depth);
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let stop = CancelToken::new();
+        let rtck = builder.target().rtck;
+        let target = builder.target();
+        let names: Vec<String> = std::iter::once((target.ap_num, target.debug_base))
+            .chain(builder.cores().iter().copied())
+            .map(|(ap_num, debug_base)| cores::name(ap_num, debug_base))
+            .collect();
+        // The jtag_adi handles can't be sent between threads, so the stream is opened on the
+        // thread that reads from it
+        let thread = {
+            let shared = shared.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let attached = builder.build_all().and_then(|mut streams| {
+                    if rtck && !streams[0].adaptive_clocking() {
+                        eprintln!("Warning: adaptive clocking unavailable, using the fixed --baud");
+                    }
+                    for (dcc, name) in streams.iter_mut().zip(&names) {
+                        dcc.on_event(|event| {
+                            if let Event::PoweredDown(_) = event {
+                                eprintln!("Core powered down, waiting for it to power up");
+                            }
+                        });
+                        if let Some(Err(e)) = options.state.as_ref().map(|file| file.save(name, dcc)) {
+                            eprintln!("Warning: {}: debug state not saved: {}", name, e);
+                        }
+                        dcc.attach_powered(&attach_stop)?;
+                    }
+                    let cti = options.cti_base.unwrap_or(streams[0].debug_base() + run_control::CTI_OFFSET);
+                    if options.catch_reset {
+                        let dscr = run_control::catch_reset(&mut streams[0], &attach_stop)?;
+                        run_control::release(&mut streams[0], dscr, cti, &attach_stop)?;
+                        eprintln!("Caught the core at reset and let it run");
+                    }
+                    if let Some(&(addr, access)) = options.watch.first() {
+                        run_control::set_watchpoint(&mut streams[0], 0, addr, access)?;
+                    }
+                    // DSCR from before the vector catch, to put back when restoring
+                    let mut dscrs = vec![];
+                    if !options.vector_catch.is_empty() {
+                        for dcc in &mut streams {
+                            dscrs.push(run_control::set_vector_catch(dcc, &options.vector_catch)?);
+                        }
+                    }
+                    Ok((streams, dscrs))
+                });
+                let (streams, dscrs) = match attached {
+                    Ok(attached) => attached,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                let _ = ready_tx.send(Ok(streams[0].idcode()));
+                let relock = options.relock;
+                let state = options.state.clone().filter(|_| options.restore_state);
+                let mut watch = Watch {
+                    pending: options.watch.iter().copied().collect(),
+                    cti: options.cti_base.unwrap_or(streams[0].debug_base() + run_control::CTI_OFFSET),
+                };
+                let mut cores: Vec<Core> = streams.into_iter().map(|dcc| Core::new(dcc, &options)).collect();
+                read_loop(&mut cores, tx, &shared, &stop, options, &clock, &mut watch);
+                // Restore every core, even if one fails
+                let mut result = Ok(());
+                for (core, &dscr) in cores.iter_mut().zip(&dscrs) {
+                    result = result.and(run_control::clear_vector_catch(&mut core.dcc, dscr));
+                }
+                if !watch.pending.is_empty() {
+                    result = result.and(run_control::clear_watchpoint(&mut cores[0].dcc, 0));
+                }
+                for (core, name) in cores.iter_mut().zip(&names) {
+                    let restored = core.dcc.restore(relock);
+                    result = result.and(restored);
+                    if let Some(file) = &state {
+                        result = result.and(file.restore(name, &mut core.dcc).map(|_| ()));
+                    }
+                }
+                result
+            })
+        };
+        match ready_rx.recv() {
+            Ok(Ok(idcode)) => Ok((Self { shared, rx, stop, thread }, idcode)),
+            Ok(Err(e)) => Err(e),
+            // The thread panicked, pass that on to the caller
+            Err(_) => match thread.join() {
+                Err(panic) => panic::resume_unwind(panic),
+                Ok(_) => unreachable!("reader exited without reporting the attach"),
+            },
+        }
+    }
+
+    /// Send the batches in `recording` as though they were being read, for `dcc-stream replay`.
+    /// Batches from cores after the first `cores` are skipped.  With a `speed`, each batch is
+    /// held back until its time on `clock` comes round again, divided by `speed`.
+    pub fn replay(mut recording: Recording, cores: usize, speed: Option<f64>, depth: usize, clock: SharedClock) -> Self {
+        let shared = Shared::new(0);
+        let (tx, rx) = ring::ring(depth);
+        let stop = CancelToken::new();
+        let thread = {
+            let shared = shared.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                // Pacing starts from the first batch, which --from may have put well into the capture
+                let mut first = None;
+                while !stop.is_cancelled() {
+                    let batch = match recording.next_batch() {
+                        Ok(Some(batch)) => batch,
+                        Ok(None) => {
+                            if recording.verified() == Some(false) {
+                                let e = DccError::Other("the recording's checksum doesn't match, it is damaged".to_string());
+                                let _ = tx.push(Msg::Error(0, e));
+                            }
+                            break;
+                        }
+                        Err(e) => {
+                            let _ = tx.push(Msg::GaveUp(0, DccError::Io("read recording".to_string(), e)));
+                            break;
+                        }
+                    };
+                    if batch.core >= cores {
+                        continue;
+                    }
+                    while shared.suspend.load(Ordering::SeqCst) && !stop.is_cancelled() {
+                        thread::sleep(Duration::from_millis(20));
+                    }
+                    if let Some(speed) = speed {
+                        let first = *first.get_or_insert(batch.start);
+                        let due = Duration::from_micros((batch.done - first) as u64).div_f64(speed);
+                        if let Some(wait) = due.checked_sub(clock.now()) {
+                            clock.sleep(wait);
+                        }
+                    }
+                    let msg = Msg::Batch {
+                        core: batch.core,
+                        start: batch.start,
+                        done: batch.done,
+                        reads: batch.words.len(),
+                        words: batch.words,
+                        slots: vec![],
+                        overflow: 0,
+                        rate: 0.0,
+                    };
+                    if tx.push(msg).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            })
+        };
+        Self { shared, rx, stop, thread }
+    }
+
+    /// Read RTT up-buffer `channel` through the MEM-AP instead of the DCC, on a new thread.  The
+    /// core is left alone: nothing is attached or restored.  The bytes are sent as little-endian
+    /// words.  With `pad`, for text, which skips NULs, a word the target has only partly written
+    /// is padded with NULs once it has been waiting `RTT_PAD_AFTER`; otherwise it waits for the
+    /// rest, so the words stay aligned.  Returns the reader and the IDCODE of the debug port.
+    pub fn spawn_rtt(
+        builder: DccStreamBuilder,
+        location: RttLocation,
+        channel: u32,
+        pad: bool,
+        options: Options,
+        clock: SharedClock,
+    ) -> Result<(Self, u32), DccError> {
+        Self::spawn_ram(builder, options, clock, move |dcc| {
+            let control_block = rtt::locate(dcc, &location)?;
+            let up = RttChannel::open(dcc, control_block, channel)?;
+            eprintln!("Found the RTT control block at 0x{:x}", control_block);
+            Ok(RamSource::Rtt { up, pad })
+        })
+    }
+
+    /// Read the words of a RAM mailbox through the MEM-AP, on a new thread, leaving the core
+    /// alone as `spawn_rtt` does.  Returns the reader and the IDCODE of the debug port.
+    pub fn spawn_mailbox(
+        builder: DccStreamBuilder,
+        mailbox: Mailbox,
+        options: Options,
+        clock: SharedClock,
+    ) -> Result<(Self, u32), DccError> {
+        Self::spawn_ram(builder, options, clock, move |dcc| {
+            mailbox.indices(dcc)?;
+            Ok(RamSource::Mailbox(mailbox))
+        })
+    }
+
+    /// Poll the source `open` finds once the debug port is up
+    fn spawn_ram(
+        builder: DccStreamBuilder,
+        options: Options,
+        clock: SharedClock,
+        open: impl FnOnce(&mut DccStream) -> Result<RamSource, DccError> + Send + 'static,
+    ) -> Result<(Self, u32), DccError> {
+        let shared = Shared::new(0);
+        let (tx, rx) = ring::ring(options.depth);
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let stop = CancelToken::new();
+        let thread = {
+            let shared = shared.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let opened = builder.build().and_then(|mut dcc| {
+                    let source = open(&mut dcc)?;
+                    Ok((dcc, source))
+                });
+                let (mut dcc, mut source) = match opened {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                let _ = ready_tx.send(Ok(dcc.idcode()));
+                ram_loop(&mut dcc, &mut source, tx, &shared, &stop, options, &clock);
+                Ok(())
+            })
+        };
+        match ready_rx.recv() {
+            Ok(Ok(idcode)) => Ok((Self { shared, rx, stop, thread }, idcode)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => match thread.join() {
+                Err(panic) => panic::resume_unwind(panic),
+                Ok(_) => unreachable!("reader exited without reporting the attach"),
+            },
+        }
+    }
+
+    /// The next message.  `Disconnected` means the reader has stopped and everything it read
+    /// has been received.
+    pub fn recv(&self, timeout: Duration) -> Result<Msg, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+
+    /// Ask the reader to stop after the batch it is reading
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    /// Stop the reader, dropping anything not yet received, and restore the target.  A panic
+    /// in the transport is passed on.
+    pub fn join(self) -> Result<(), DccError> {
+        self.stop();
+        // Unblock a reader waiting for room in the channel
+        drop(self.rx);
+        self.thread.join().unwrap_or_else(|panic| panic::resume_unwind(panic))
+    }
+}
+
+/// What the reader keeps for each core
+struct Core {
+    dcc: DccStream,
+    estimate: RateEstimate,
+    adaptive: Option<AdaptiveQueue>,
+    backoff: Option<Backoff>,
+    /// Batch size when the queue is adaptive
+    size: usize,
+    failures: u32,
+    /// Seen halted by the last health check, e.g. at a breakpoint
+    halted: bool,
+    /// Words of the announced burst still to be read
+    burst: usize,
+    /// Words dropped because the channel was full, since the last batch that was sent
+    overflow: u64,
+}
+
+impl Core {
+    fn new(dcc: DccStream, options: &Options) -> Self {
+        Self {
+            dcc,
+            estimate: RateEstimate::new(options.txfull),
+            adaptive: options.adaptive.clone(),
+            backoff: options.idle_backoff.map(|max| Backoff::new(max, options.txfull)),
+            size: options.queue_size,
+            failures: 0,
+            halted: false,
+            burst: 0,
+            overflow: 0,
+        }
+    }
+}
+
+/// Read the counter at `addr`, timed by the middle of the read
+fn sample_clock(dcc: &mut DccStream, addr: u32, clock: &SharedClock) -> Msg {
+    let before = clock.now().as_micros();
+    match target_time::read_counter(dcc, addr) {
+        Ok(ticks) => Msg::Clock {
+            host: (before + clock.now().as_micros()) / 2,
+            ticks,
+        },
+        Err(e) => Msg::Error(0, e),
+    }
+}
+
+fn read_loop(
+    cores: &mut [Core],
+    tx: Producer<Msg>,
+    shared: &Shared,
+    stop: &CancelToken,
+    options: Options,
+    clock: &SharedClock,
+    watch: &mut Watch,
+) {
+    let mut last_health = clock.now();
+    if let Some(addr) = options.target_clock {
+        if tx.push(sample_clock(&mut cores[0].dcc, addr, clock)).is_err() {
+            return;
+        }
+    }
+    while !stop.is_cancelled() {
+        serve_mem(&mut cores[0].dcc, shared);
+        // Every core on the cable follows the first
+        let baud = shared.baud.swap(0, Ordering::SeqCst);
+        if baud != 0 {
+            let msg = match cores[0].dcc.set_baud(baud) {
+                Ok(()) => Msg::Reclocked(baud),
+                Err(e) => Msg::Error(0, e),
+            };
+            if tx.push(msg).is_err() {
+                return;
+            }
+        }
+        if shared.suspend.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(20));
+            continue;
+        }
+
+        let health = clock.since(last_health) >= HEALTH_INTERVAL;
+        if health {
+            last_health = clock.now();
+        }
+        // Sleep only as long as the busiest core allows
+        let mut idle = Duration::MAX;
+        for (i, core) in cores.iter_mut().enumerate() {
+            if i == 0 && !watch.pending.is_empty() {
+                let msg = match run_control::watchpoint_hit(&mut core.dcc) {
+                    Ok(true) => Some(match watch.next(&mut core.dcc, stop) {
+                        Ok(addr) => Msg::Watchpoint(i, addr),
+                        Err(e) => Msg::Error(i, e),
+                    }),
+                    Ok(false) => None,
+                    Err(e) => Some(Msg::Error(i, e)),
+                };
+                if let Some(msg) = msg {
+                    if tx.push(msg).is_err() {
+                        return;
+                    }
+                }
+            }
+            if shared.halt.compare_exchange(i + 1, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                let cti = if i == 0 {
+                    watch.cti
+                } else {
+                    core.dcc.debug_base() + run_control::CTI_OFFSET
+                };
+                let timestamp = if options.timestamps { clock.now().as_micros() } else { 0 };
+                for msg in halt_core(core, i, cti, options.resume_after_halt, timestamp, stop) {
+                    if tx.push(msg).is_err() {
+                        return;
+                    }
+                }
+            }
+            // Idle states power the core down; reads then fault until it comes back, which is
+            // waited for rather than treated as a lost session
+            if (health || core.failures > 0) && core.dcc.powered_down() {
+                if tx.push(Msg::PoweredDown(i)).is_err() {
+                    return;
+                }
+                // The OS lock is set again by the power-up, so the core is attached afresh
+                core.dcc.reattach(stop);
+                core.failures = 0;
+                if tx.push(Msg::PoweredUp(i)).is_err() {
+                    return;
+                }
+                idle = Duration::ZERO;
+                continue;
+            }
+            if (health || core.failures > 0) && core.dcc.os_locked() {
+                if options.os_lock == OsLockPolicy::Stop {
+                    let _ = tx.push(Msg::GaveUp(i, DccError::Other("the target set the OS lock".to_string())));
+                    return;
+                }
+                if tx.push(Msg::OsLocked(i)).is_err() {
+                    return;
+                }
+                if options.os_lock == OsLockPolicy::Wait {
+                    while core.dcc.os_locked() && !stop.is_cancelled() {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                }
+                // Whatever set the lock may also have reset DSCR, so attach rather than only
+                // clearing it
+                core.dcc.reattach(stop);
+                core.failures = 0;
+                if tx.push(Msg::OsUnlocked(i)).is_err() {
+                    return;
+                }
+                idle = Duration::ZERO;
+                continue;
+            }
+            let mut lost = if health { core.dcc.session_lost() } else { None };
+            if core.failures >= REATTACH_FAILURES {
+                if !options.reattach {
+                    let e = DccError::Other(format!("{} consecutive read failures", core.failures));
+                    let _ = tx.push(Msg::GaveUp(i, e));
+                    return;
+                }
+                lost = Some(format!("{} consecutive read failures", core.failures));
+            }
+            if lost.is_none() && health {
+                if let Ok(Some(dscr)) = core.dcc.check_overrun() {
+                    if tx.push(Msg::Overrun(i, dscr)).is_err() {
+                        return;
+                    }
+                }
+                if let Some(addr) = options.target_clock.filter(|_| i == 0) {
+                    if tx.push(sample_clock(&mut core.dcc, addr, clock)).is_err() {
+                        return;
+                    }
+                }
+                let msg = match run_control::halted_by(&mut core.dcc) {
+                    Ok(Some(reason)) if !core.halted => Some(Msg::Halted(i, reason)),
+                    Ok(None) if core.halted => Some(Msg::Resumed(i)),
+                    _ => None,
+                };
+                if let Some(msg) = msg {
+                    core.halted = matches!(msg, Msg::Halted(..));
+                    if tx.push(msg).is_err() {
+                        return;
+                    }
+                }
+                // Suspend and resume on the target can quietly clear stall mode
+                if let Ok(Some(dscr)) = core.dcc.check_config() {
+                    if tx.push(Msg::Reconfigured(i, dscr)).is_err() {
+                        return;
+                    }
+                }
+            }
+            if let Some(reason) = lost {
+                if tx.push(Msg::SessionLost(i, reason)).is_err() {
+                    return;
+                }
+                core.dcc.reattach(stop);
+                core.failures = 0;
+                if tx.push(Msg::Reattached(i)).is_err() {
+                    return;
+                }
+                idle = Duration::ZERO;
+                continue;
+            }
+
+            let start = if options.timestamps { clock.now().as_micros() } else { 0 };
+            let size = match core.adaptive {
+                _ if core.burst > 0 => core.burst.min(MAX_QUEUE_SIZE),
+                Some(_) => core.size,
+                None => shared.queue_size.load(Ordering::SeqCst),
+            };
+            let mut pace = Duration::ZERO;
+            let read = if options.txfull {
+                core.dcc
+                    .read_slots(size)
+                    .map(|words| words.into_iter().map(|(slot, word)| (slot as u32, word)).unzip())
+            } else {
+                core.dcc.read(size).map(|words| (vec![], words))
+            };
+            let msg = match read {
+                Ok((slots, words)) => {
+                    core.failures = 0;
+                    let done = if options.timestamps { clock.now().as_micros() } else { 0 };
+                    let rate = if options.timestamps {
+                        core.estimate.update(start, &words)
+                    } else {
+                        0.0
+                    };
+                    // A burst's size says nothing about the traffic around it
+                    let burst = core.burst > 0;
+                    if let Some(header) = options.burst_header {
+                        core.burst = burst_left(header, core.burst, &words);
+                    }
+                    if let Some(adaptive) = core.adaptive.as_mut().filter(|_| !burst) {
+                        let elapsed = Duration::from_micros((done - start) as u64);
+                        core.size = adaptive.update(size, &words, elapsed, rate);
+                        if i == 0 {
+                            shared.queue_size.store(core.size, Ordering::SeqCst);
+                        }
+                        pace = adaptive.poll_sleep(core.size, elapsed, rate);
+                    }
+                    Msg::Batch {
+                        core: i,
+                        start,
+                        done,
+                        words,
+                        reads: size,
+                        slots,
+                        overflow: core.overflow,
+                        rate,
+                    }
+                }
+                Err(e) => {
+                    core.failures += 1;
+                    // A glitched transaction usually leaves only the sticky errors behind
+                    if core.failures < READ_RETRIES {
+                        let _ = core.dcc.clear_sticky();
+                        Msg::Error(i, e)
+                    } else if core.failures == READ_RETRIES && core.dcc.reinit().is_ok() {
+                        if tx.push(Msg::Error(i, e)).is_err() {
+                            return;
+                        }
+                        Msg::Reinitialized(i)
+                    } else {
+                        Msg::Error(i, e)
+                    }
+                }
+            };
+            let batch = matches!(msg, Msg::Batch { .. });
+            let core_idle = match (&mut core.backoff, &msg) {
+                (Some(backoff), Msg::Batch { words, .. }) => backoff.after(words),
+                _ => Duration::ZERO,
+            };
+            idle = idle.min(core_idle.max(pace));
+            let sent = match options.policy {
+                FullPolicy::Block => tx.push(msg).map_err(|_| ()),
+                FullPolicy::Drop => match tx.try_push(msg) {
+                    Ok(()) => Ok(()),
+                    Err(PushError::Full(msg)) => {
+                        if let Msg::Batch { words, .. } = msg {
+                            core.overflow += words.len() as u64;
+                        }
+                        continue;
+                    }
+                    Err(PushError::Closed(_)) => Err(()),
+                },
+            };
+            if sent.is_err() {
+                return;
+            }
+            if batch {
+                core.overflow = 0;
+            }
+        }
+        let acks = cores.iter().map(|core| core.dcc.ack_counts());
+        let (waits, faults) = acks.fold((0, 0), |(w, f), acks| (w + acks.waits, f + acks.faults));
+        shared.waits.store(waits, Ordering::Relaxed);
+        shared.faults.store(faults, Ordering::Relaxed);
+        if idle != Duration::MAX && !idle.is_zero() {
+            clock.sleep(idle);
+        }
+    }
+}
+
+/// The words of a burst still to come after `words`, when `pending` were still to come before
+/// them.  A header inside a burst is data; one in the last word of a burst starts the next.
+fn burst_left((value, mask): (u32, u32), mut pending: usize, words: &[u32]) -> usize {
+    for &word in words {
+        if pending > 0 {
+            pending -= 1;
+        } else if word & mask == value {
+            pending = (word & !mask) as usize;
+        }
+    }
+    pending
+}
+
+/// Carry out the oldest memory access waiting in `Shared::mem`.  Only one is taken per polling
+/// round, so a client sending them back to back can't starve the DCC, and they still get a
+/// turn however busy the target is.  Polling suspended by --pause-polling leaves them the cable.
+fn serve_mem(dcc: &mut DccStream, shared: &Shared) {
+    let Some((op, req)) = shared.mem.lock().unwrap_or_else(|e| e.into_inner()).pop_front() else {
+        return;
+    };
+    let result = match op {
+        MemOp::Read(addr, 1) => dcc.read_mem(addr).map(|word| vec![word]),
+        MemOp::Read(addr, count) => dcc.read_block(addr, count),
+        MemOp::Write(addr, value) => dcc.write_mem(addr, value).map(|_| vec![]),
+    };
+    match result {
+        Ok(words) => {
+            let words: Vec<String> = words.iter().map(|w| format!("0x{:08x}", w)).collect();
+            req.ok(&words.join(" "));
+        }
+        Err(e) => {
+            // A fault leaves the sticky errors set, which would fail the next batch
+            let _ = dcc.clear_sticky();
+            req.error(&e.to_string());
+        }
+    }
+}
+
+/// Halt a core for `Shared::halt` and read its registers, returning the messages to send: the
+/// word the target left in DTRTX, as a batch read at `timestamp`, then the registers, then
+/// whether the core was let run again
+fn halt_core(core: &mut Core, i: usize, cti: u32, resume: bool, timestamp: u128, stop: &CancelToken) -> Vec<Msg> {
+    let mut msgs = vec![];
+    match run_control::halt(&mut core.dcc, cti, stop) {
+        Ok(Some(word)) => msgs.push(Msg::Batch {
+            core: i,
+            start: timestamp,
+            done: timestamp,
+            words: vec![word],
+            reads: 1,
+            slots: vec![],
+            overflow: 0,
+            rate: core.estimate.update(timestamp, &[word]),
+        }),
+        Ok(None) => {}
+        Err(e) => return vec![Msg::Error(i, e)],
+    }
+    msgs.push(match run_control::read_registers(&mut core.dcc, stop) {
+        Ok(registers) => Msg::Registers(i, registers),
+        Err(e) => Msg::Error(i, e),
+    });
+    if !resume {
+        core.halted = true;
+        msgs.push(Msg::Halted(i, "halt request"));
+        return msgs;
+    }
+    msgs.push(match run_control::restart(&mut core.dcc, cti, stop) {
+        Ok(()) => Msg::Resumed(i),
+        Err(e) => Msg::Error(i, e),
+    });
+    msgs
+}
+
+/// The first core's watchpoints, see `Options::watch`
+struct Watch {
+    /// The one armed, then the ones after it
+    pending: VecDeque<(u32, Access)>,
+    /// The CTI to restart the core through
+    cti: u32,
+}
+
+impl Watch {
+    /// Move on from the watchpoint that was hit: arm the next one and let the core run again.
+    /// Returns the address of the one that was hit.
+    fn next(&mut self, dcc: &mut DccStream, stop: &CancelToken) -> Result<u32, DccError> {
+        let (addr, _) = self.pending.pop_front().expect("a watchpoint is armed");
+        match self.pending.front() {
+            Some(&(next, access)) => run_control::set_watchpoint(dcc, 0, next, access)?,
+            None => run_control::clear_watchpoint(dcc, 0)?,
+        }
+        run_control::restart(dcc, self.cti, stop)?;
+        Ok(addr)
+    }
+}
+
+// Sleep between polls of target RAM that find nothing, without --idle-backoff
+const RAM_POLL: Duration = Duration::from_millis(1);
+// How long the start of a word waits in the RTT buffer for the rest before it is padded
+const RTT_PAD_AFTER: Duration = Duration::from_millis(100);
+
+/// What a reader polling target RAM through the MEM-AP reads
+enum RamSource {
+    Rtt { up: RttChannel, pad: bool },
+    Mailbox(Mailbox),
+}
+
+impl RamSource {
+    fn name(&self) -> &'static str {
+        match self {
+            RamSource::Rtt { .. } => "RTT",
+            RamSource::Mailbox(_) => "mailbox",
+        }
+    }
+}
+
+/// The whole little-endian words at the start of `held`, taking them out, and with `pad` the
+/// start of a word after them padded with NULs
+fn whole_words(held: &mut Vec<u8>, pad: bool) -> Vec<u32> {
+    if pad {
+        held.resize(held.len().next_multiple_of(4), 0);
+    }
+    let whole = held.len() - held.len() % 4;
+    held.drain(..whole)
+        .collect::<Vec<u8>>()
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect()
+}
+
+fn ram_loop(
+    dcc: &mut DccStream,
+    source: &mut RamSource,
+    tx: Producer<Msg>,
+    shared: &Shared,
+    stop: &CancelToken,
+    options: Options,
+    clock: &SharedClock,
+) {
+    let mut estimate = RateEstimate::new(true);
+    let mut backoff = Backoff::new(options.idle_backoff.unwrap_or(RAM_POLL), true);
+    let mut failures = 0;
+    let mut overflow = 0;
+    // Bytes of a word still being written, and when they arrived
+    let mut held: Vec<u8> = vec![];
+    let mut held_since = clock.now();
+    while !stop.is_cancelled() {
+        serve_mem(dcc, shared);
+        let baud = shared.baud.swap(0, Ordering::SeqCst);
+        if baud != 0 {
+            let msg = match dcc.set_baud(baud) {
+                Ok(()) => Msg::Reclocked(baud),
+                Err(e) => Msg::Error(0, e),
+            };
+            if tx.push(msg).is_err() {
+                return;
+            }
+        }
+        if shared.suspend.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(20));
+            continue;
+        }
+        let start = if options.timestamps { clock.now().as_micros() } else { 0 };
+        let read = match source {
+            RamSource::Rtt { up, pad } => up.read(dcc, MAX_QUEUE_SIZE as u32 * 4).map(|bytes| {
+                held.extend(bytes);
+                let words = whole_words(&mut held, *pad && clock.since(held_since) >= RTT_PAD_AFTER);
+                // Whatever is left arrived with this read
+                if !words.is_empty() || held.is_empty() {
+                    held_since = clock.now();
+                }
+                words
+            }),
+            RamSource::Mailbox(mailbox) => mailbox.read(dcc, MAX_QUEUE_SIZE as u32),
+        };
+        let msg = match read {
+            Ok(words) => {
+                failures = 0;
+                let done = if options.timestamps { clock.now().as_micros() } else { 0 };
+                let rate = if options.timestamps { estimate.update(start, &words) } else { 0.0 };
+                Msg::Batch {
+                    core: 0,
+                    start,
+                    done,
+                    reads: words.len(),
+                    words,
+                    slots: vec![],
+                    overflow,
+                    rate,
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                if failures >= REATTACH_FAILURES {
+                    let e = DccError::Other(format!("{} consecutive {} read failures", failures, source.name()));
+                    let _ = tx.push(Msg::GaveUp(0, e));
+                    return;
+                }
+                let _ = dcc.clear_sticky();
+                Msg::Error(0, e)
+            }
+        };
+        let idle = match &msg {
+            Msg::Batch { words, .. } => backoff.after(words),
+            _ => Duration::ZERO,
+        };
+        let sent = match options.policy {
+            FullPolicy::Block => tx.push(msg).map_err(|_| ()),
+            FullPolicy::Drop => match tx.try_push(msg) {
+                Ok(()) => {
+                    overflow = 0;
+                    Ok(())
+                }
+                Err(PushError::Full(msg)) => {
+                    if let Msg::Batch { words, .. } = msg {
+                        overflow += words.len() as u64;
+                    }
+                    Ok(())
+                }
+                Err(PushError::Closed(_)) => Err(()),
+            },
+        };
+        if sent.is_err() {
+            return;
+        }
+        let acks = dcc.ack_counts();
+        shared.waits.store(acks.waits, Ordering::Relaxed);
+        shared.faults.store(acks.faults, Ordering::Relaxed);
+        if !idle.is_zero() {
+            clock.sleep(idle);
+        }
+    }
+}
+
+// First sleep once the target goes quiet, doubled for each quiet batch after that
+const BACKOFF_START: Duration = Duration::from_millis(1);
+
+/// Sleeps between batches while the target is sending nothing, so an idle target doesn't keep
+/// a core and the USB bus busy
+struct Backoff {
+    max: Duration,
+    txfull: bool,
+    sleep: Duration,
+    last: Option<u32>,
+}
+
+impl Backoff {
+    fn new(max: Duration, txfull: bool) -> Self {
+        Self {
+            max,
+            txfull,
+            sleep: Duration::ZERO,
+            last: None,
+        }
+    }
+
+    /// How long to sleep after reading `words`.  Any new word ends the backoff.
+    fn after(&mut self, words: &[u32]) -> Duration {
+        let fresh = if self.txfull {
+            !words.is_empty()
+        } else {
+            let fresh = words.iter().any(|w| Some(*w) != self.last);
+            self.last = words.last().copied().or(self.last);
+            fresh
+        };
+        self.sleep = if fresh {
+            Duration::ZERO
+        } else {
+            (self.sleep * 2).clamp(BACKOFF_START.min(self.max), self.max)
+        };
+        self.sleep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Instant;
+
+    use dcc_stream::VirtualClock;
+
+    // How long a test waits in real time for the reader to do what it expects
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// A scenario file for a `sim:` cable
+    fn scenario(name: &str, toml: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dcc-stream-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, toml).unwrap();
+        path
+    }
+
+    fn options(depth: usize, policy: FullPolicy) -> Options {
+        Options {
+            queue_size: 16,
+            adaptive: None,
+            depth,
+            policy,
+            idle_backoff: None,
+            txfull: true,
+            burst_header: None,
+            timestamps: true,
+            reattach: true,
+            os_lock: OsLockPolicy::Clear,
+            relock: false,
+            catch_reset: false,
+            cti_base: None,
+            watch: vec![],
+            vector_catch: vec![],
+            resume_after_halt: true,
+            target_clock: None,
+            state: None,
+            restore_state: false,
+        }
+    }
+
+    fn builder(scenario: &Path, clock: &SharedClock) -> DccStreamBuilder {
+        DccStreamBuilder::new(format!("sim:{}", scenario.display()), 0x8001_0000).clock(clock.clone())
+    }
+
+    #[test]
+    fn overflow_is_counted_for_the_core_that_dropped() {
+        let path = scenario("overflow", "rate = 100000\ncount = 20000\n");
+        let clock = VirtualClock::shared();
+        let builder = builder(&path, &clock).core(1, 0x8001_2000);
+        let (reader, _) = Reader::spawn(builder, CancelToken::new(), options(1, FullPolicy::Drop), clock).unwrap();
+        // Each simulated core counts from 0, so the words dropped from one are the gap before
+        // its next batch
+        let mut next = [0u64; 2];
+        let mut dropped = [0u64; 2];
+        let deadline = Instant::now() + TIMEOUT;
+        while next != [20000; 2] {
+            assert!(Instant::now() < deadline, "only {:?} words accounted for", next);
+            if let Ok(Msg::Batch { core, words, overflow, .. }) = reader.recv(Duration::from_millis(10)) {
+                next[core] += overflow;
+                dropped[core] += overflow;
+                let expected: Vec<u32> = (next[core]..next[core] + words.len() as u64).map(|w| w as u32).collect();
+                assert_eq!(words, expected, "core {}", core);
+                next[core] += words.len() as u64;
+            }
+            // Fall behind so that the reader has to drop batches
+            thread::sleep(Duration::from_micros(200));
+        }
+        assert!(dropped.iter().all(|&d| d > 0), "dropped {:?}", dropped);
+        reader.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ram_reader_changes_tck() {
+        let path = scenario("ram-baud", "mailbox = 0x20000000\nrate = 100000\n");
+        let clock = VirtualClock::shared();
+        let mailbox = Mailbox::new(0x2000_0000, 0x2000_0004, 0x2000_0100, 256).unwrap();
+        let (reader, _) = Reader::spawn_mailbox(builder(&path, &clock), mailbox, options(64, FullPolicy::Block), clock).unwrap();
+        reader.shared.baud.store(2_000_000, Ordering::SeqCst);
+        let mut next = 0;
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            assert!(Instant::now() < deadline, "TCK wasn't changed");
+            match reader.recv(Duration::from_millis(10)) {
+                Ok(Msg::Reclocked(baud)) => {
+                    assert_eq!(baud, 2_000_000);
+                    break;
+                }
+                Ok(Msg::Batch { words, .. }) => {
+                    assert_eq!(words, (next..next + words.len() as u32).collect::<Vec<_>>());
+                    next += words.len() as u32;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(reader.shared.baud.load(Ordering::SeqCst), 0);
+        reader.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn partial_words_stay_aligned_unless_padded() {
+        let mut held = b"abcdef".to_vec();
+        assert_eq!(whole_words(&mut held, false), [u32::from_le_bytes(*b"abcd")]);
+        assert_eq!(held, b"ef");
+        held.extend(b"gh");
+        assert_eq!(whole_words(&mut held, false), [u32::from_le_bytes(*b"efgh")]);
+        assert!(held.is_empty());
+        held.extend(b"ij");
+        assert_eq!(whole_words(&mut held, true), [u32::from_le_bytes(*b"ij\0\0")]);
+        assert!(held.is_empty());
+        assert!(whole_words(&mut held, true).is_empty());
+    }
+
+    #[test]
+    fn rtt_scan_past_4gb_is_refused() {
+        let scenario = scenario("rtt-scan", "rtt = 0x20000000\n");
+        let clock = VirtualClock::shared();
+        let location = RttLocation::Scan {
+            start: 0xffff_ff00,
+            len: 0x200,
+        };
+        let e = Reader::spawn_rtt(
+            builder(&scenario, &clock),
+            location,
+            0,
+            false,
+            options(4, FullPolicy::Block),
+            clock.clone(),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(e, DccError::InvalidConfig(_)), "{}", e);
+        fs::remove_file(&scenario).unwrap();
+    }
+
+    #[test]
+    fn bursts_are_counted_down() {
+        const HEADER: (u32, u32) = (0xb000_0000, 0xffff_0000);
+        assert_eq!(burst_left(HEADER, 0, &[1, 2, 3]), 0);
+        assert_eq!(burst_left(HEADER, 0, &[1, 0xb000_0005, 2]), 4);
+        // Headers inside a burst are data
+        assert_eq!(burst_left(HEADER, 4, &[0xb000_0009, 1]), 2);
+        assert_eq!(burst_left(HEADER, 2, &[1, 2, 3]), 0);
+        // One straight after a burst starts the next
+        assert_eq!(burst_left(HEADER, 2, &[1, 2, 0xb000_0003, 1]), 2);
+        assert_eq!(burst_left(HEADER, 0, &[0xb000_0000, 0xb000_0001]), 1);
+        assert_eq!(burst_left(HEADER, 0, &[0xb000_ffff]), 0xffff);
+    }
+
+    #[test]
+    fn reads_are_sized_to_bursts() {
+        const HEADER: (u32, u32) = (0xb000_0000, 0xffff_0000);
+        // A burst longer than the longest read, a short one, then words outside any burst
+        let mut values = vec![0xb000_0000 | (MAX_QUEUE_SIZE as u32 + 904)];
+        values.extend(1..=MAX_QUEUE_SIZE as u32 + 904);
+        values.extend([0xb000_0003, 1, 2, 3]);
+        values.extend([7; 40]);
+        let list: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let toml = format!(
+            "rate = 1000000\npattern = \"values\"\nvalues = [{}]\ncount = {}\n",
+            list.join(", "),
+            values.len()
+        );
+        let path = scenario("burst", &toml);
+        let clock = VirtualClock::shared();
+        let mut options = options(64, FullPolicy::Block);
+        options.burst_header = Some(HEADER);
+        let (reader, _) = Reader::spawn(builder(&path, &clock), CancelToken::new(), options, clock).unwrap();
+        let mut received = vec![];
+        let mut pending = 0;
+        let mut sizes = vec![];
+        let deadline = Instant::now() + TIMEOUT;
+        // Until a read after the last word, which should be back to the queue size
+        while received.len() < values.len() || pending > 0 || sizes.last() != Some(&16) {
+            assert!(Instant::now() < deadline, "only {} words read", received.len());
+            if let Ok(Msg::Batch { words, reads, .. }) = reader.recv(Duration::from_millis(10)) {
+                let expected = if pending > 0 { pending.min(MAX_QUEUE_SIZE) } else { 16 };
+                assert_eq!(reads, expected, "after {} words", received.len());
+                pending = burst_left(HEADER, pending, &words);
+                sizes.push(reads);
+                received.extend(words);
+            }
+        }
+        assert_eq!(received, values);
+        assert!(sizes.contains(&MAX_QUEUE_SIZE), "read sizes {:?}", sizes);
+        reader.stop();
+        reader.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn halt_request_reads_registers_and_resumes() {
+        let path = scenario("halt", "rate = 100000\ncount = 4000\n");
+        let clock = VirtualClock::shared();
+        let builder = builder(&path, &clock).core(1, 0x8001_2000);
+        let (reader, _) = Reader::spawn(builder, CancelToken::new(), options(64, FullPolicy::Block), clock).unwrap();
+        reader.shared.halt.store(2, Ordering::SeqCst);
+        let mut next = [0u32; 2];
+        let (mut registers, mut resumed) = (false, false);
+        let deadline = Instant::now() + TIMEOUT;
+        // Nothing the second core sent is lost to the halt, including what it left in DTRTX
+        while next != [4000; 2] || !resumed {
+            assert!(Instant::now() < deadline, "only {:?} words read", next);
+            match reader.recv(Duration::from_millis(10)) {
+                Ok(Msg::Batch { core, words, .. }) => {
+                    assert_eq!(
+                        words,
+                        (next[core]..next[core] + words.len() as u32).collect::<Vec<_>>(),
+                        "core {}",
+                        core
+                    );
+                    next[core] += words.len() as u32;
+                }
+                Ok(Msg::Registers(core, regs)) => {
+                    assert_eq!(core, 1);
+                    assert!(!regs.is_empty());
+                    registers = true;
+                }
+                Ok(Msg::Resumed(core)) => {
+                    assert!(registers && core == 1);
+                    resumed = true;
+                }
+                Ok(Msg::Halted(core, reason)) => panic!("core {} left halted: {}", core, reason),
+                _ => {}
+            }
+        }
+        assert_eq!(reader.shared.halt.load(Ordering::SeqCst), 0);
+        reader.stop();
+        reader.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
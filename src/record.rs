@@ -0,0 +1,589 @@
+//! Session recordings, written with --record and read back by `dcc-stream replay`.  A
+//! recording starts with a magic line and the session settings as TOML, using the same keys as
+//! the config file so that they can fill in the options of the replay.  Then every batch the
+//! reader sent follows as it arrived, little-endian:
+//!
+//! ```text
+//! u32 core, u64 start, u64 done, u32 count, count * u32 words
+//! ```
+//!
+//! with `start` and `done` in microseconds since the capture started.  Between the batches are
+//! index blocks, each listing for up to 64 seconds of the capture where the first batch of each
+//! second starts in the file:
+//!
+//! ```text
+//! u32 0xffffffff, u32 count, count * (u64 start, u64 offset)
+//! ```
+//!
+//! and a capture that ends normally closes the file with a trailer listing the index blocks in
+//! the same way, the CRC-32 of everything before it, where it starts and an end marker:
+//!
+//! ```text
+//! u32 0xfffffffe, u32 count, count * (u64 start, u64 offset), u32 crc, u64 offset, "DCCIDX2\n"
+//! ```
+//!
+//! so that `replay --from` can go straight to a time in a long recording.  Recordings from
+//! before the index, and those cut short, are scanned instead.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use toml::{Table, Value};
+
+use dcc_stream::parse_duration;
+
+const MAGIC: &[u8] = b"DCCREC 2\n";
+/// Recordings without index blocks or a trailer
+const MAGIC_V1: &[u8] = b"DCCREC 1\n";
+const END: &[u8] = b"DCCIDX2\n";
+// Take the place of a batch's core, which is never near these
+const INDEX_TAG: u32 = 0xffff_ffff;
+const TRAILER_TAG: u32 = 0xffff_fffe;
+// Bigger counts mean the file is damaged, no reader batch gets near this
+const MAX_BATCH: u32 = 1 << 20;
+/// Capture time between index entries
+const INDEX_INTERVAL: u128 = 1_000_000;
+/// Entries per index block
+const INDEX_BLOCK: usize = 64;
+// Bigger index blocks or trailers mean the file is damaged: a trailer this long is a
+// recording of over a year
+const MAX_INDEX: u32 = 1 << 20;
+// A longer header means the file is damaged, the settings never get near this
+const MAX_HEADER: u32 = 1 << 20;
+
+/// One batch as it was recorded
+pub struct Batch {
+    pub core: usize,
+    pub start: u128,
+    pub done: u128,
+    pub words: Vec<u32>,
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    /// Bytes written so far, and their CRC-32
+    offset: u64,
+    crc: u32,
+    /// Index entries not yet written out in a block
+    entries: Vec<(u64, u64)>,
+    /// The first start and the offset of each index block written
+    blocks: Vec<(u64, u64)>,
+    /// The interval the last entry was in
+    indexed: Option<u128>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, settings: &Table) -> io::Result<Self> {
+        let mut recorder = Self {
+            out: BufWriter::new(File::create(path)?),
+            offset: 0,
+            crc: !0,
+            entries: vec![],
+            blocks: vec![],
+            indexed: None,
+        };
+        let header = settings.to_string();
+        recorder.write(MAGIC)?;
+        recorder.write(&(header.len() as u32).to_le_bytes())?;
+        recorder.write(header.as_bytes())?;
+        Ok(recorder)
+    }
+
+    pub fn batch(&mut self, core: usize, start: u128, done: u128, words: &[u32]) -> io::Result<()> {
+        if self.indexed != Some(start / INDEX_INTERVAL) {
+            self.indexed = Some(start / INDEX_INTERVAL);
+            self.entries.push((start as u64, self.offset));
+        }
+        self.write(&(core as u32).to_le_bytes())?;
+        self.write(&(start as u64).to_le_bytes())?;
+        self.write(&(done as u64).to_le_bytes())?;
+        self.write(&(words.len() as u32).to_le_bytes())?;
+        for word in words {
+            self.write(&word.to_le_bytes())?;
+        }
+        if self.entries.len() == INDEX_BLOCK {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Write the last index block and the trailer, once the capture has ended
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.entries.is_empty() {
+            self.write_block()?;
+        }
+        let trailer = self.offset;
+        let blocks = std::mem::take(&mut self.blocks);
+        self.write_index(TRAILER_TAG, &blocks)?;
+        let crc = !self.crc;
+        self.write(&crc.to_le_bytes())?;
+        self.write(&trailer.to_le_bytes())?;
+        self.write(END)?;
+        self.flush()
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let entries = std::mem::take(&mut self.entries);
+        self.blocks.push((entries[0].0, self.offset));
+        self.write_index(INDEX_TAG, &entries)
+    }
+
+    fn write_index(&mut self, tag: u32, entries: &[(u64, u64)]) -> io::Result<()> {
+        self.write(&tag.to_le_bytes())?;
+        self.write(&(entries.len() as u32).to_le_bytes())?;
+        for (start, offset) in entries {
+            self.write(&start.to_le_bytes())?;
+            self.write(&offset.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        self.crc = crc32(self.crc, bytes);
+        Ok(())
+    }
+}
+
+pub struct Recording {
+    input: BufReader<File>,
+    indexed: bool,
+    /// The CRC-32 of what has been read, while reading on from the start of the file
+    crc: Option<u32>,
+    /// Whether the trailer's CRC matched, once it has been read
+    verified: Option<bool>,
+    /// Batches that end before this, or start after the second, are skipped
+    from: u128,
+    to: Option<u128>,
+}
+
+impl Recording {
+    /// Open a recording, returning it and its session settings
+    pub fn open(path: &Path) -> io::Result<(Self, Table)> {
+        let mut recording = Self {
+            input: BufReader::new(File::open(path)?),
+            indexed: true,
+            crc: Some(!0),
+            verified: None,
+            from: 0,
+            to: None,
+        };
+        let mut magic = [0; MAGIC.len()];
+        recording.read_exact(&mut magic)?;
+        if magic == MAGIC_V1 {
+            recording.indexed = false;
+        } else if magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a dcc-stream recording"));
+        }
+        let len = recording.read_u32()?;
+        if len > MAX_HEADER {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("header of {} bytes", len)));
+        }
+        let mut header = vec![0; len as usize];
+        recording.read_exact(&mut header)?;
+        let settings = String::from_utf8(header)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+            .parse::<Table>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok((recording, settings))
+    }
+
+    /// Only give the batches between `from` and `to`, in microseconds since the capture
+    /// started.  Through the index, a recording with a trailer goes straight to `from`.
+    pub fn range(&mut self, from: u128, to: Option<u128>) -> io::Result<()> {
+        self.from = from;
+        self.to = to;
+        if from == 0 {
+            return Ok(());
+        }
+        // Only a read from the start covers everything the CRC does
+        self.crc = None;
+        if let Some(offset) = self.find(from)? {
+            self.input.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(())
+    }
+
+    /// The offset of the last indexed batch starting at or before `from`, if the recording has
+    /// a trailer.  Without one, the batches before `from` are read through and skipped.
+    fn find(&mut self, from: u128) -> io::Result<Option<u64>> {
+        if !self.indexed {
+            return Ok(None);
+        }
+        let here = self.input.stream_position()?;
+        let len = self.input.seek(SeekFrom::End(0))?;
+        let trailer = if len >= here + 12 + END.len() as u64 {
+            self.input.seek(SeekFrom::End(-8 - END.len() as i64))?;
+            let offset = read_u64(&mut self.input)?;
+            let mut end = [0; END.len()];
+            self.input.read_exact(&mut end)?;
+            Some(offset).filter(|&offset| end == END && (here..len).contains(&offset))
+        } else {
+            None
+        };
+        let Some(trailer) = trailer else {
+            self.input.seek(SeekFrom::Start(here))?;
+            return Ok(None);
+        };
+        self.input.seek(SeekFrom::Start(trailer))?;
+        let blocks = read_index(&mut self.input, TRAILER_TAG)?;
+        // The entries before the first block's are in it, so it is where a search before them starts
+        let at = blocks.partition_point(|&(start, _)| start as u128 <= from).saturating_sub(1);
+        let Some(&(_, block)) = blocks.get(at) else {
+            self.input.seek(SeekFrom::Start(here))?;
+            return Ok(None);
+        };
+        self.input.seek(SeekFrom::Start(block))?;
+        let entries = read_index(&mut self.input, INDEX_TAG)?;
+        let at = entries.partition_point(|&(start, _)| start as u128 <= from).saturating_sub(1);
+        Ok(entries.get(at).map(|&(_, offset)| offset))
+    }
+
+    /// Whether the recording's checksum matched, once it has been read to the end from the
+    /// start.  None if it hasn't, or has no trailer.
+    pub fn verified(&self) -> Option<bool> {
+        self.verified
+    }
+
+    /// The next batch, or `None` at the end of the recording or the range.  A recording cut
+    /// short by the capture being killed ends at its last whole batch.
+    pub fn next_batch(&mut self) -> io::Result<Option<Batch>> {
+        loop {
+            let batch = match self.read_batch() {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                batch => batch?,
+            };
+            match batch {
+                Some(batch) if batch.done < self.from => continue,
+                Some(batch) if self.to.is_some_and(|to| batch.start > to) => return Ok(None),
+                batch => return Ok(batch),
+            }
+        }
+    }
+
+    fn read_batch(&mut self) -> io::Result<Option<Batch>> {
+        loop {
+            let core = self.read_u32()?;
+            if self.indexed && core == INDEX_TAG {
+                let count = self.read_count()?;
+                let mut entries = vec![0; count as usize * 16];
+                self.read_exact(&mut entries)?;
+                continue;
+            }
+            if self.indexed && core == TRAILER_TAG {
+                self.check_trailer()?;
+                return Ok(None);
+            }
+            let start = self.read_u64()? as u128;
+            let done = self.read_u64()? as u128;
+            let count = self.read_u32()?;
+            if count > MAX_BATCH {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("batch of {} words", count)));
+            }
+            let mut words = vec![0; count as usize * 4];
+            self.read_exact(&mut words)?;
+            let words = words.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+            return Ok(Some(Batch {
+                core: core as usize,
+                start,
+                done,
+                words,
+            }));
+        }
+    }
+
+    fn check_trailer(&mut self) -> io::Result<()> {
+        let count = self.read_count()?;
+        let mut blocks = vec![0; count as usize * 16];
+        self.read_exact(&mut blocks)?;
+        let actual = self.crc.map(|crc| !crc);
+        let expected = read_u32(&mut self.input)?;
+        self.verified = actual.map(|actual| actual == expected);
+        Ok(())
+    }
+
+    fn read_count(&mut self) -> io::Result<u32> {
+        let count = self.read_u32()?;
+        if count > MAX_INDEX {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("index of {} entries", count)));
+        }
+        Ok(count)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.input.read_exact(buf)?;
+        if let Some(crc) = self.crc.as_mut() {
+            *crc = crc32(*crc, buf);
+        }
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// An index block or the trailer's list of them, which must start with `tag`
+fn read_index(input: &mut impl Read, tag: u32) -> io::Result<Vec<(u64, u64)>> {
+    if read_u32(input)? != tag {
+        return Err(io::Error::new(ErrorKind::InvalidData, "damaged index"));
+    }
+    let count = read_u32(input)?;
+    if count > MAX_INDEX {
+        return Err(io::Error::new(ErrorKind::InvalidData, "damaged index"));
+    }
+    (0..count).map(|_| Ok((read_u64(input)?, read_u64(input)?))).collect()
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// The IEEE 802.3 CRC-32, as zlib computes it, a byte at a time
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(crc, |crc, &b| CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// A time in the capture for --from and --to: HH:MM:SS or MM:SS, with a fraction of a second
+/// if need be, or a duration such as 90s
+pub fn parse_offset(s: &str) -> Result<Duration, String> {
+    if !s.contains(':') {
+        return parse_duration(s);
+    }
+    let fields: Vec<&str> = s.split(':').collect();
+    if fields.len() > 3 {
+        return Err(format!("invalid time: {}", s));
+    }
+    let mut secs = 0.0;
+    for (i, field) in fields.iter().enumerate() {
+        let value: f64 = field.parse().map_err(|_| format!("invalid time: {}", s))?;
+        let last = i == fields.len() - 1;
+        if !value.is_finite() || value < 0.0 || (!last && value.fract() != 0.0) || (i > 0 && value >= 60.0) {
+            return Err(format!("invalid time: {}", s));
+        }
+        secs = secs * 60.0 + value;
+    }
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("invalid time: {}", s))
+}
+
+/// What a recording says about the session beyond the options, in its `[session]` table
+pub fn session(settings: &Table) -> Option<&Table> {
+    settings.get("session").and_then(Value::as_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+
+    type Batches = Vec<(usize, u128, u128, Vec<u32>)>;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dcc-stream-{}-{}.rec", std::process::id(), name))
+    }
+
+    fn settings() -> Table {
+        "[session]\ncores = 2\n".parse().unwrap()
+    }
+
+    /// Two cores' batches every 250ms for 200s, enough for several index blocks
+    fn batches() -> Batches {
+        (0..800u32)
+            .map(|i| {
+                let start = i as u128 * 250_000;
+                ((i % 2) as usize, start, start + 1000, vec![0xdcc0_0000 | i, i])
+            })
+            .collect()
+    }
+
+    fn record(path: &Path, batches: &Batches, finish: bool) {
+        let mut recorder = Recorder::create(path, &settings()).unwrap();
+        for (core, start, done, words) in batches {
+            recorder.batch(*core, *start, *done, words).unwrap();
+        }
+        if finish {
+            recorder.finish().unwrap();
+        }
+    }
+
+    fn read(recording: &mut Recording) -> Batches {
+        let mut batches = vec![];
+        while let Some(batch) = recording.next_batch().unwrap() {
+            batches.push((batch.core, batch.start, batch.done, batch.words));
+        }
+        batches
+    }
+
+    fn between(batches: &Batches, from: u128, to: u128) -> Batches {
+        batches
+            .iter()
+            .filter(|(_, start, done, _)| *done >= from && *start <= to)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_offset("1:02:03.5"), Ok(Duration::from_secs_f64(3723.5)));
+        assert_eq!(parse_offset("90s"), Ok(Duration::from_secs(90)));
+        for s in ["1:nan", "inf:00", "1:60", "1.5:00", "1:2:3:4", "0:1e300"] {
+            assert_eq!(parse_offset(s), Err(format!("invalid time: {}", s)));
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = path("round-trip");
+        let batches = batches();
+        record(&path, &batches, true);
+        let (mut recording, read_settings) = Recording::open(&path).unwrap();
+        assert_eq!(read_settings, settings());
+        assert_eq!(recording.verified(), None);
+        assert_eq!(read(&mut recording), batches);
+        assert_eq!(recording.verified(), Some(true));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn range_through_the_index() {
+        let path = path("range");
+        let batches = batches();
+        record(&path, &batches, true);
+        for (from, to) in [
+            (100_100_000, 120_000_000),
+            (1, 500_000),
+            (199_000_000, u128::MAX),
+            (300_000_000, u128::MAX),
+        ] {
+            let (mut recording, _) = Recording::open(&path).unwrap();
+            recording.range(from, Some(to)).unwrap();
+            // Straight to the first batch of the second `from` is in
+            let first = recording.input.stream_position().unwrap();
+            assert_eq!(
+                recording.read_batch().unwrap().map(|batch| batch.start),
+                Some((from / 1_000_000).min(199) * 1_000_000)
+            );
+            recording.input.seek(SeekFrom::Start(first)).unwrap();
+            assert_eq!(read(&mut recording), between(&batches, from, to));
+            assert_eq!(recording.verified(), None);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_recording_is_scanned() {
+        let path = path("truncated");
+        let batches = batches();
+        record(&path, &batches, false);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+        // The last batch is cut short, and there is no trailer to find the index from
+        let kept = batches[..batches.len() - 1].to_vec();
+        let (mut recording, _) = Recording::open(&path).unwrap();
+        assert_eq!(read(&mut recording), kept);
+        assert_eq!(recording.verified(), None);
+        let (mut recording, _) = Recording::open(&path).unwrap();
+        recording.range(150_000_000, None).unwrap();
+        assert_eq!(read(&mut recording), between(&kept, 150_000_000, u128::MAX));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn v1_recording() {
+        let path = path("v1");
+        let batches = batches();
+        let header = settings().to_string();
+        let mut bytes = MAGIC_V1.to_vec();
+        bytes.extend((header.len() as u32).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        for (core, start, done, words) in &batches {
+            bytes.extend((*core as u32).to_le_bytes());
+            bytes.extend((*start as u64).to_le_bytes());
+            bytes.extend((*done as u64).to_le_bytes());
+            bytes.extend((words.len() as u32).to_le_bytes());
+            bytes.extend(words.iter().flat_map(|word| word.to_le_bytes()));
+        }
+        fs::write(&path, bytes).unwrap();
+        let (mut recording, read_settings) = Recording::open(&path).unwrap();
+        assert_eq!(read_settings, settings());
+        assert_eq!(read(&mut recording), batches);
+        assert_eq!(recording.verified(), None);
+        let (mut recording, _) = Recording::open(&path).unwrap();
+        recording.range(50_000_000, Some(60_000_000)).unwrap();
+        assert_eq!(read(&mut recording), between(&batches, 50_000_000, 60_000_000));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damaged_header_length() {
+        let path = path("header");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(u32::MAX.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        let e = Recording::open(&path).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), format!("header of {} bytes", u32::MAX));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_byte_fails_verification() {
+        let path = path("corrupted");
+        record(&path, &batches(), true);
+        let mut bytes = fs::read(&path).unwrap();
+        let word = (0xdcc0_0000u32 | 400).to_le_bytes();
+        let at = bytes.windows(4).position(|w| w == word).unwrap();
+        bytes[at] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        let (mut recording, _) = Recording::open(&path).unwrap();
+        assert_eq!(read(&mut recording).len(), 800);
+        assert_eq!(recording.verified(), Some(false));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+//! --report: one JSON document for the whole run, for CI to archive and diff.  It holds the
+//! session settings as a recording keeps them, the outputs, how the run ended, the session
+//! stats, and every marker and warning in order, which covers the triggers, reattaches,
+//! halts and integrity failures.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use toml::{Table, Value};
+
+use dcc_stream::SharedClock;
+
+pub struct Report {
+    /// The capture's clock, whose time 0 is the start
+    clock: SharedClock,
+    /// Microseconds since the capture started, "marker" or "warning", and the message
+    events: Vec<(u128, &'static str, String)>,
+}
+
+impl Report {
+    /// A report for a capture timestamped by `clock`
+    pub fn new(clock: SharedClock) -> Self {
+        Self { clock, events: vec![] }
+    }
+
+    pub fn marker(&mut self, timestamp: u128, msg: &str) {
+        self.events.push((timestamp, "marker", msg.to_string()));
+    }
+
+    /// Warnings aren't timestamped, so they are stamped as they arrive
+    pub fn warning(&mut self, msg: &str) {
+        self.events.push((self.clock.now().as_micros(), "warning", msg.to_string()));
+    }
+
+    /// Write the report to `path`.  `settings` are as `session_settings` makes them, `stats`
+    /// is the stats summary as a JSON object and `result` how the run ended: "ok",
+    /// "interrupted" or the error.
+    pub fn write(&self, path: &Path, settings: &Table, outputs: &[String], stats: &str, result: &str) -> io::Result<()> {
+        let ended = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let outputs: Vec<String> = outputs.iter().map(|o| json_string(o)).collect();
+        let events: Vec<String> = self
+            .events
+            .iter()
+            .map(|(ts, kind, msg)| format!("{{\"timestamp\":{},\"kind\":\"{}\",\"message\":{}}}", ts, kind, json_string(msg)))
+            .collect();
+        let text = format!(
+            "{{\"settings\":{},\"outputs\":[{}],\"ended\":{},\"elapsed\":{:.3},\"result\":{},\"stats\":{},\"events\":[{}]}}\n",
+            json_table(settings),
+            outputs.join(","),
+            ended,
+            self.clock.now().as_secs_f64(),
+            json_string(result),
+            stats,
+            events.join(",")
+        );
+        fs::write(path, text)
+    }
+}
+
+fn json_table(table: &Table) -> String {
+    let fields: Vec<String> = table
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_value(value)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => json_string(s),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) if f.is_finite() => f.to_string(),
+        Value::Float(_) => "null".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(d) => json_string(&d.to_string()),
+        Value::Array(items) => format!("[{}]", items.iter().map(json_value).collect::<Vec<_>>().join(",")),
+        Value::Table(table) => json_table(table),
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,269 @@
+//! Bounded single-producer single-consumer queue between the reader thread and the output,
+//! preallocated and without locks so neither side waits on the other at high rates.
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+// How long a producer waiting for room sleeps between checks
+const FULL_POLL: Duration = Duration::from_micros(100);
+
+struct Inner<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Count of items taken, only advanced by the consumer
+    head: AtomicUsize,
+    /// Count of items added, only advanced by the producer
+    tail: AtomicUsize,
+    /// Either side has gone away
+    closed: AtomicBool,
+    /// Woken when an item is added or the producer goes away
+    consumer: Thread,
+}
+
+// Each slot is only touched by one side at a time, as handed over by `head` and `tail`
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        for i in head..tail {
+            let slot = &mut self.slots[i % self.slots.len()];
+            unsafe { slot.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+pub struct Producer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+pub struct Consumer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// Whether a push failed because the ring is full or the consumer is gone, with the item
+pub enum PushError<T> {
+    Full(T),
+    Closed(T),
+}
+
+/// A ring holding up to `capacity` items.  The consumer must be used from the calling thread,
+/// which is the one woken when items arrive.
+pub fn ring<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1);
+    let inner = Arc::new(Inner {
+        slots: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        consumer: thread::current(),
+    });
+    (Producer { inner: inner.clone() }, Consumer { inner })
+}
+
+impl<T> Producer<T> {
+    pub fn try_push(&self, item: T) -> Result<(), PushError<T>> {
+        let inner = &*self.inner;
+        if inner.closed.load(Ordering::Acquire) {
+            return Err(PushError::Closed(item));
+        }
+        let tail = inner.tail.load(Ordering::Relaxed);
+        if tail - inner.head.load(Ordering::Acquire) == inner.slots.len() {
+            return Err(PushError::Full(item));
+        }
+        unsafe { (*inner.slots[tail % inner.slots.len()].get()).write(item) };
+        inner.tail.store(tail + 1, Ordering::Release);
+        inner.consumer.unpark();
+        Ok(())
+    }
+
+    /// Push, waiting for room.  Gives the item back if the consumer goes away.
+    pub fn push(&self, mut item: T) -> Result<(), T> {
+        loop {
+            match self.try_push(item) {
+                Ok(()) => return Ok(()),
+                Err(PushError::Closed(item)) => return Err(item),
+                Err(PushError::Full(back)) => {
+                    item = back;
+                    thread::sleep(FULL_POLL);
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self.inner.consumer.unpark();
+    }
+}
+
+impl<T> Consumer<T> {
+    fn try_pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let head = inner.head.load(Ordering::Relaxed);
+        if head == inner.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*inner.slots[head % inner.slots.len()].get()).assume_init_read() };
+        inner.head.store(head + 1, Ordering::Release);
+        Some(item)
+    }
+
+    /// The next item, waiting up to `timeout`.  `Disconnected` once the producer is gone and
+    /// everything it pushed has been taken.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Checked before looking for an item, so nothing pushed before closing is missed
+            let closed = self.inner.closed.load(Ordering::Acquire);
+            if let Some(item) = self.try_pop() {
+                return Ok(item);
+            }
+            if closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts its drops in the shared counter
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn pop<T>(rx: &Consumer<T>) -> Result<T, RecvTimeoutError> {
+        rx.recv_timeout(Duration::ZERO)
+    }
+
+    #[test]
+    fn wraps_around_in_order() {
+        let (tx, rx) = ring(3);
+        let mut next = 0;
+        for round in 0..10 {
+            // A different number each time, so the head and tail land on every slot
+            let n = round % 3 + 1;
+            for i in 0..n {
+                assert!(tx.try_push(next + i).is_ok());
+            }
+            for i in 0..n {
+                assert_eq!(pop(&rx), Ok(next + i));
+            }
+            next += n;
+        }
+        assert_eq!(pop(&rx), Err(RecvTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn full_gives_the_item_back() {
+        let (tx, rx) = ring(2);
+        assert!(tx.try_push(1).is_ok());
+        assert!(tx.try_push(2).is_ok());
+        assert!(matches!(tx.try_push(3), Err(PushError::Full(3))));
+        assert_eq!(pop(&rx), Ok(1));
+        assert!(tx.try_push(3).is_ok());
+        assert_eq!(pop(&rx), Ok(2));
+        assert_eq!(pop(&rx), Ok(3));
+    }
+
+    #[test]
+    fn zero_capacity_holds_one() {
+        let (tx, rx) = ring(0);
+        assert!(tx.try_push(1).is_ok());
+        assert!(matches!(tx.try_push(2), Err(PushError::Full(2))));
+        assert_eq!(pop(&rx), Ok(1));
+    }
+
+    #[test]
+    fn producer_closing_drains_first() {
+        let (tx, rx) = ring(4);
+        tx.try_push(1).ok();
+        tx.try_push(2).ok();
+        drop(tx);
+        assert_eq!(pop(&rx), Ok(1));
+        assert_eq!(pop(&rx), Ok(2));
+        assert_eq!(pop(&rx), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn consumer_closing_refuses_pushes() {
+        let (tx, rx) = ring(4);
+        drop(rx);
+        assert!(matches!(tx.try_push(1), Err(PushError::Closed(1))));
+        assert_eq!(tx.push(2), Err(2));
+    }
+
+    #[test]
+    fn times_out_while_open_and_empty() {
+        let (_tx, rx) = ring::<u32>(4);
+        assert_eq!(rx.recv_timeout(Duration::from_millis(5)), Err(RecvTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn drops_each_item_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = ring(3);
+        for _ in 0..3 {
+            assert!(tx.try_push(Counted(drops.clone())).is_ok());
+        }
+        // Given back and dropped here, not left in a slot
+        assert!(matches!(tx.try_push(Counted(drops.clone())), Err(PushError::Full(_))));
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        drop(pop(&rx));
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        // The two left in the ring, one of them wrapped around, go with it
+        assert!(tx.try_push(Counted(drops.clone())).is_ok());
+        drop(tx);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        drop(rx);
+        assert_eq!(drops.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn two_threads() {
+        let count: u64 = if cfg!(miri) { 2_000 } else { 200_000 };
+        let (tx, rx) = ring(64);
+        let producer = thread::spawn(move || {
+            for i in 0..count {
+                assert!(tx.push(i).is_ok());
+            }
+        });
+        let mut next = 0;
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(i) => {
+                    assert_eq!(i, next);
+                    next += 1;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => panic!("stalled at {}", next),
+            }
+        }
+        assert_eq!(next, count);
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! SEGGER RTT: reading the up-buffers of an RTT control block in target RAM through the MEM-AP,
+//! for firmware that already logs over RTT instead of the DCC.  The debug AP must be the one
+//! that reaches the RAM, usually the AHB-AP rather than the APB-AP the DCC is read through.
+//!
+//! The control block is found at a given address, by scanning a RAM range for its ID string or
+//! from the `_SEGGER_RTT` symbol of the firmware's ELF file.
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error::DccError;
+use crate::parse_u32;
+use crate::stream::DccStream;
+
+const ID: &[u8] = b"SEGGER RTT\0";
+const SYMBOL: &[u8] = b"_SEGGER_RTT";
+// Offsets in the control block and in each up-buffer descriptor that follows it
+const ID_LEN: u32 = 16;
+const UP_BUFFERS: u32 = ID_LEN + 8;
+const DESCRIPTOR_LEN: u32 = 24;
+const BUFFER_PTR: u32 = 4;
+const BUFFER_SIZE: u32 = 8;
+const WR_OFF: u32 = 12;
+const RD_OFF: u32 = 16;
+// Words read at a time while scanning for the control block
+const SCAN_CHUNK: usize = 256;
+// More up-buffers than this means the block isn't what it seems
+const MAX_UP_BUFFERS: u32 = 256;
+// Nor does a bigger up-buffer
+const MAX_BUFFER_SIZE: u32 = 1 << 24;
+
+/// Where to find the control block, written as an address, `scan:START:LEN` or `elf:PATH`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RttLocation {
+    Address(u32),
+    Scan { start: u32, len: u32 },
+    Elf(PathBuf),
+}
+
+impl FromStr for RttLocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("elf:") {
+            return Ok(RttLocation::Elf(PathBuf::from(path)));
+        }
+        if let Some(range) = s.strip_prefix("scan:") {
+            let (start, len) = range
+                .split_once(':')
+                .ok_or_else(|| format!("RTT scan {} must be scan:START:LEN", s))?;
+            return Ok(RttLocation::Scan {
+                start: parse_u32(start)?,
+                len: parse_u32(len)?,
+            });
+        }
+        Ok(RttLocation::Address(parse_u32(s)?))
+    }
+}
+
+/// Find the control block, returning its address
+pub fn locate(dcc: &mut DccStream, location: &RttLocation) -> Result<u32, DccError> {
+    let addr = match location {
+        RttLocation::Address(addr) => *addr,
+        RttLocation::Scan { start, len } => scan(dcc, *start, *len)?,
+        RttLocation::Elf(path) => {
+            let data = fs::read(path).map_err(|e| DccError::Io(format!("read {}", path.display()), e))?;
+            elf_symbol(&data, SYMBOL)
+                .ok_or_else(|| DccError::InvalidConfig(format!("no _SEGGER_RTT symbol in {}", path.display())))?
+                .0
+        }
+    };
+    let id = read_bytes(dcc, addr, ID.len() as u32)?;
+    if id != ID {
+        return Err(DccError::Other(format!(
+            "no RTT control block at 0x{:x}, the firmware may not have initialised it yet",
+            addr
+        )));
+    }
+    Ok(addr)
+}
+
+fn scan(dcc: &mut DccStream, start: u32, len: u32) -> Result<u32, DccError> {
+    let start = start & !3;
+    if start.checked_add(len).is_none() {
+        return Err(DccError::InvalidConfig(format!(
+            "RTT scan of 0x{:x} bytes from 0x{:x} runs past 4GB",
+            len, start
+        )));
+    }
+    let words = len.div_ceil(4) as usize;
+    let mut at = 0;
+    while at < words {
+        // Overlap the chunks so an ID straddling two is still found
+        let n = SCAN_CHUNK.min(words - at);
+        let overlap = (ID.len() / 4).min(words - at - n);
+        let chunk = dcc.read_block(start + at as u32 * 4, n + overlap)?;
+        let bytes: Vec<u8> = chunk.iter().flat_map(|w| w.to_le_bytes()).collect();
+        if let Some(pos) = bytes.windows(ID.len()).position(|w| w == ID) {
+            return Ok(start + at as u32 * 4 + pos as u32);
+        }
+        at += n;
+    }
+    Err(DccError::Other(format!(
+        "no RTT control block in 0x{:x}-0x{:x}",
+        start,
+        start + len
+    )))
+}
+
+/// `len` bytes from `addr`, which need not be aligned
+fn read_bytes(dcc: &mut DccStream, addr: u32, len: u32) -> Result<Vec<u8>, DccError> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    let end = addr
+        .checked_add(len)
+        .ok_or_else(|| DccError::Other(format!("RTT read of {} bytes from 0x{:x} runs past 4GB", len, addr)))?;
+    let first = addr & !3;
+    let words = (end - first).div_ceil(4);
+    let bytes: Vec<u8> = dcc
+        .read_block(first, words as usize)?
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+    let skip = (addr - first) as usize;
+    Ok(bytes[skip..skip + len as usize].to_vec())
+}
+
+/// The address and size of `name` in the symbol table of a little-endian ELF file
+pub(crate) fn elf_symbol(data: &[u8], name: &[u8]) -> Option<(u32, u64)> {
+    let u16_at = |off: usize| Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?));
+    let u32_at = |off: usize| Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?));
+    let u64_at = |off: usize| Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?));
+    if data.get(..4)? != b"\x7fELF" || *data.get(5)? != 1 {
+        return None;
+    }
+    let elf64 = *data.get(4)? == 2;
+    let word = |off: usize| if elf64 { u64_at(off) } else { u32_at(off).map(u64::from) };
+    let (shoff, shentsize, shnum) = if elf64 {
+        (u64_at(0x28)? as usize, u16_at(0x3a)? as usize, u16_at(0x3c)? as usize)
+    } else {
+        (u32_at(0x20)? as usize, u16_at(0x2e)? as usize, u16_at(0x30)? as usize)
+    };
+    // Offset, size and link of section `i`
+    let section = |i: usize| {
+        let sh = shoff.checked_add(i.checked_mul(shentsize)?)?;
+        if elf64 {
+            Some((
+                u32_at(sh + 4)?,
+                u64_at(sh + 0x18)? as usize,
+                u64_at(sh + 0x20)? as usize,
+                u32_at(sh + 0x28)? as usize,
+            ))
+        } else {
+            Some((
+                u32_at(sh + 4)?,
+                u32_at(sh + 0x10)? as usize,
+                u32_at(sh + 0x14)? as usize,
+                u32_at(sh + 0x18)? as usize,
+            ))
+        }
+    };
+    let (symlen, value_at, size_at, name_at) = if elf64 { (24, 8, 16, 0) } else { (16, 4, 8, 0) };
+    for i in 0..shnum {
+        // SHT_SYMTAB
+        let Some((2, offset, size, link)) = section(i) else {
+            continue;
+        };
+        let (_, strtab, strsize, _) = section(link)?;
+        let strings = data.get(strtab..strtab.checked_add(strsize)?)?;
+        for sym in (offset..offset.checked_add(size)?).step_by(symlen) {
+            let start = u32_at(sym + name_at)? as usize;
+            let sym_name = strings.get(start..)?.split(|&b| b == 0).next()?;
+            if sym_name == name {
+                return Some((word(sym + value_at)? as u32, word(sym + size_at)?));
+            }
+        }
+    }
+    None
+}
+
+/// One up-buffer of a control block, read from the host side
+pub struct RttChannel {
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+}
+
+impl RttChannel {
+    /// Up-buffer `channel` of the control block at `control_block`
+    pub fn open(dcc: &mut DccStream, control_block: u32, channel: u32) -> Result<Self, DccError> {
+        if control_block.checked_add(UP_BUFFERS + MAX_UP_BUFFERS * DESCRIPTOR_LEN).is_none() {
+            return Err(DccError::Other(format!("RTT control block at 0x{:x} runs past 4GB", control_block)));
+        }
+        let up_buffers = dcc.read_mem(control_block + ID_LEN)?;
+        if up_buffers > MAX_UP_BUFFERS {
+            return Err(DccError::Other(format!("RTT control block claims {} up-buffers", up_buffers)));
+        }
+        if channel >= up_buffers {
+            return Err(DccError::InvalidConfig(format!(
+                "RTT channel {} requested, the target has {} up-buffers",
+                channel, up_buffers
+            )));
+        }
+        let descriptor = control_block + UP_BUFFERS + channel * DESCRIPTOR_LEN;
+        let buffer = dcc.read_mem(descriptor + BUFFER_PTR)?;
+        let size = dcc.read_mem(descriptor + BUFFER_SIZE)?;
+        if size == 0 {
+            return Err(DccError::Other(format!("RTT channel {} has no buffer", channel)));
+        }
+        if size > MAX_BUFFER_SIZE || buffer.checked_add(size).is_none() {
+            return Err(DccError::Other(format!(
+                "RTT channel {} claims a buffer of {} bytes at 0x{:x}",
+                channel, size, buffer
+            )));
+        }
+        Ok(Self { descriptor, buffer, size })
+    }
+
+    /// Take up to `max` of the bytes the target has written since the last read, marking them
+    /// read
+    pub fn read(&mut self, dcc: &mut DccStream, max: u32) -> Result<Vec<u8>, DccError> {
+        let wr = dcc.read_mem(self.descriptor + WR_OFF)?;
+        let rd = dcc.read_mem(self.descriptor + RD_OFF)?;
+        if wr >= self.size || rd >= self.size {
+            return Err(DccError::Other(format!(
+                "RTT offsets out of range: write {} read {} size {}",
+                wr, rd, self.size
+            )));
+        }
+        let count = ((wr + self.size - rd) % self.size).min(max);
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        // Up to the end of the buffer, then on from its start if the bytes wrap
+        let first = count.min(self.size - rd);
+        let mut bytes = read_bytes(dcc, self.buffer + rd, first)?;
+        bytes.extend(read_bytes(dcc, self.buffer, count - first)?);
+        dcc.write_mem(self.descriptor + RD_OFF, (rd + count) % self.size)?;
+        bytes.shrink_to_fit();
+        Ok(bytes)
+    }
+}
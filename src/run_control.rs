@@ -0,0 +1,527 @@
+//! Halting and restarting the core through its external debug registers, for catching it as
+//! it comes out of reset, on exceptions or at hardware breakpoints and watchpoints, and
+//! reading its registers once halted.  ARMv7 cores restart through DBGDRCR; ARMv8 cores can only be
+//! restarted through their cross-trigger interface (CTI), which is usually 64KB above the
+//! debug registers.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+use crate::builder::Arch;
+use crate::cancel::CancelToken;
+use crate::error::DccError;
+use crate::stream::DccStream;
+
+// Debug register offsets, from the debug base
+const DBGDIDR: u32 = 0x000;
+const DBGVCR: u32 = 0x01c;
+const EDECR: u32 = 0x024;
+const DTRRX: u32 = 0x080;
+const ITR: u32 = 0x084;
+const DSCR: u32 = 0x088;
+const DTRTX: u32 = 0x08c;
+const DRCR: u32 = 0x090;
+const EDECCR: u32 = 0x098;
+const PRCR: u32 = 0x310;
+const PRSR: u32 = 0x314;
+const EDDFR: u32 = 0xd28;
+// Breakpoint value and control registers: 4 bytes apart on ARMv7 and 16 on ARMv8, where the
+// value is 64 bits
+const V7_BVR: u32 = 0x100;
+const V7_BCR: u32 = 0x140;
+const V8_BVR: u32 = 0x400;
+const V8_BCR: u32 = 0x408;
+// BCR enabled, matching in every mode, on all four bytes of the word.  A Thumb instruction
+// in the top half of a word matches on the top two.
+const BCR_ENABLE: u32 = 1;
+const BCR_ANY_MODE: u32 = 0b11 << 1;
+const BCR_BAS_WORD: u32 = 0b1111 << 5;
+const BCR_BAS_HIGH: u32 = 0b1100 << 5;
+// Watchpoint value and control registers, laid out as the breakpoints
+const V7_WVR: u32 = 0x180;
+const V7_WCR: u32 = 0x1c0;
+const V8_WVR: u32 = 0x800;
+const V8_WCR: u32 = 0x808;
+// WCR enabled, matching in every mode.  The byte address select covers a word, which on
+// ARMv8 is either half of the doubleword the value register holds.
+const WCR_ENABLE: u32 = 1;
+const WCR_ANY_MODE: u32 = 0b11 << 1;
+const WCR_BAS_LOW: u32 = 0x0f << 5;
+const WCR_BAS_HIGH: u32 = 0xf0 << 5;
+// DBGVCR reset vector catch, and EDECR.RCE
+const VCR_RESET: u32 = 1 << 0;
+const EDECR_RCE: u32 = 1 << 1;
+// EDECCR exception catch on entry to EL1, EL2 and EL3 in secure state, and to EL1 and EL2
+// in non-secure state
+const EDECCR_ALL: u32 = 0b1110 | 0b0110 << 4;
+// DSCR.HALTED and RESTARTED on ARMv7, and halting debug enable on both
+const DSCR_HALTED: u32 = 1 << 0;
+const DSCR_RESTARTED: u32 = 1 << 1;
+const DSCR_HDE: u32 = 1 << 14;
+// ARMv7 DSCR.ITRen, and instruction complete and DTRTX full on both
+const DSCR_ITREN: u32 = 1 << 13;
+const DSCR_ITE: u32 = 1 << 24;
+const DSCR_TXFULL: u32 = 1 << 29;
+// ARMv8 EDSCR.RW, which ELs are AArch64, and EDSCR.EL
+const EDSCR_RW_SHIFT: u32 = 10;
+const EDSCR_EL_SHIFT: u32 = 8;
+// DBGDRCR halt request
+const DRCR_HRQ: u32 = 1 << 0; // DBGDRCR restart request and clear sticky exceptions
+const DRCR_RRQ: u32 = 1 << 1;
+const DRCR_CSE: u32 = 1 << 2;
+// Warm reset request
+const PRCR_CWRR: u32 = 1 << 1;
+// Sticky reset and halted, the latter ARMv8 only
+const PRSR_SR: u32 = 1 << 3;
+const PRSR_HALTED: u32 = 1 << 4;
+
+// CTI register offsets, from the CTI base
+const CTICONTROL: u32 = 0x000;
+const CTIINTACK: u32 = 0x010;
+const CTIAPPPULSE: u32 = 0x01c;
+const CTIOUTEN: u32 = 0x0a0;
+const CTILAR: u32 = 0xfb0;
+const LAR_KEY: u32 = 0xc5acce55;
+// Trigger outputs to the core: debug request, and restart
+const TRIGGER_HALT: u32 = 0;
+const TRIGGER_RESTART: u32 = 1;
+// The channels the halt and restart are pulsed on
+const HALT_CHANNEL: u32 = 0;
+const RESTART_CHANNEL: u32 = 1;
+
+// MCR p14, 0, Rt, c0, c5, 0: Rt to DTRTX, the same in the A32 and T32 instruction sets
+const MCR_DTRTX: u32 = 0xee00_0e15;
+// A64: MSR DBGDTR_EL0, Xt; MRS Xt, DBGDTR_EL0; MRS Xt, DLR_EL0; MRS Xt, DSPSR_EL0
+const MSR_DBGDTR: u32 = 0xd513_0400;
+const MRS_DBGDTR: u32 = 0xd533_0400;
+const MRS_DLR: u32 = 0xd53b_4520;
+const MRS_DSPSR: u32 = 0xd53b_4500;
+
+/// Where the CTI usually is, relative to the debug base
+pub const CTI_OFFSET: u32 = 0x10000;
+
+/// How long the core gets to halt or restart when asked
+const TIMEOUT: Duration = Duration::from_secs(1);
+const POLL: Duration = Duration::from_millis(1);
+
+/// Exceptions the core can be made to halt on with `set_vector_catch`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Vector {
+    Reset,
+    /// Undefined instruction
+    Undef,
+    /// Supervisor call
+    Svc,
+    /// Prefetch abort
+    Pabort,
+    /// Data abort
+    Dabort,
+    Irq,
+    Fiq,
+}
+
+impl Vector {
+    /// The DBGVCR bits for the vector in secure and non-secure state
+    fn vcr_bits(self) -> u32 {
+        let bit = match self {
+            Vector::Reset => return VCR_RESET,
+            Vector::Undef => 1,
+            Vector::Svc => 2,
+            Vector::Pabort => 3,
+            Vector::Dabort => 4,
+            Vector::Irq => 6,
+            Vector::Fiq => 7,
+        };
+        1 << bit | 1 << (bit + 24)
+    }
+}
+
+/// Halt the core when it takes any of the exceptions in `vectors`.  ARMv8 has no catch for
+/// particular vectors, so anything but a reset catches every exception taken to EL1 and
+/// above.  Returns DSCR from before halting debug was enabled, for `clear_vector_catch`.
+pub fn set_vector_catch(dcc: &mut DccStream, vectors: &[Vector]) -> Result<u32, DccError> {
+    let base = dcc.debug_base();
+    let dscr = dcc.read_dscr()?;
+    dcc.write_mem(base + DSCR, dscr | DSCR_HDE)?;
+    write_vector_catch(dcc, vectors)?;
+    Ok(dscr)
+}
+
+/// Stop halting on exceptions, putting halting debug back as it was in `dscr`.  A core that
+/// has already halted stays halted.
+pub fn clear_vector_catch(dcc: &mut DccStream, dscr: u32) -> Result<(), DccError> {
+    write_vector_catch(dcc, &[])?;
+    restore_hde(dcc, dscr)
+}
+
+fn restore_hde(dcc: &mut DccStream, dscr: u32) -> Result<(), DccError> {
+    let now = dcc.read_dscr()?;
+    dcc.write_mem(dcc.debug_base() + DSCR, (now & !DSCR_HDE) | (dscr & DSCR_HDE))
+}
+
+fn write_vector_catch(dcc: &mut DccStream, vectors: &[Vector]) -> Result<(), DccError> {
+    let base = dcc.debug_base();
+    match dcc.arch() {
+        Arch::Armv7 => {
+            let vcr = vectors.iter().fold(0, |vcr, v| vcr | v.vcr_bits());
+            dcc.write_mem(base + DBGVCR, vcr)
+        }
+        Arch::Armv8 => {
+            set_catch(dcc, vectors.contains(&Vector::Reset))?;
+            let eccr = if vectors.iter().any(|&v| v != Vector::Reset) {
+                EDECCR_ALL
+            } else {
+                0
+            };
+            dcc.write_mem(base + EDECCR, eccr)
+        }
+    }
+}
+
+/// Why a core halted, going by DSCR: the method of entry on ARMv7 and the status on ARMv8
+pub fn halt_reason(arch: Arch, dscr: u32) -> &'static str {
+    match arch {
+        Arch::Armv7 => match dscr >> 2 & 0xf {
+            0b0000 => "halt request",
+            0b0001 => "breakpoint",
+            0b0010 => "asynchronous watchpoint",
+            0b0011 => "BKPT instruction",
+            0b0100 => "external debug request",
+            0b0101 => "vector catch",
+            0b1010 => "synchronous watchpoint",
+            _ => "unknown",
+        },
+        Arch::Armv8 => match dscr & 0x3f {
+            0b000111 => "breakpoint",
+            0b010011 => "external debug request",
+            0b011011 | 0b011111 | 0b111011 => "halting step",
+            0b100011 => "OS unlock catch",
+            0b100111 => "reset catch",
+            0b101011 => "watchpoint",
+            0b101111 => "HLT instruction",
+            0b110011 => "software access to a debug register",
+            0b110111 => "exception catch",
+            _ => "unknown",
+        },
+    }
+}
+
+/// Why the core is halted, or None if it is running
+pub fn halted_by(dcc: &mut DccStream) -> Result<Option<&'static str>, DccError> {
+    if !halted(dcc)? {
+        return Ok(None);
+    }
+    let dscr = dcc.read_dscr()?;
+    Ok(Some(halt_reason(dcc.arch(), dscr)))
+}
+
+/// Number of hardware breakpoints the core has
+pub fn breakpoint_count(dcc: &mut DccStream) -> Result<usize, DccError> {
+    let base = dcc.debug_base();
+    let brps = match dcc.arch() {
+        Arch::Armv7 => dcc.read_mem(base + DBGDIDR)? >> 24,
+        Arch::Armv8 => dcc.read_mem(base + EDDFR)? >> 12,
+    };
+    Ok((brps & 0xf) as usize + 1)
+}
+
+fn breakpoint_regs(dcc: &DccStream, index: usize) -> (u32, u32) {
+    let base = dcc.debug_base();
+    let index = index as u32;
+    match dcc.arch() {
+        Arch::Armv7 => (base + V7_BVR + 4 * index, base + V7_BCR + 4 * index),
+        Arch::Armv8 => (base + V8_BVR + 16 * index, base + V8_BCR + 16 * index),
+    }
+}
+
+/// Make hardware breakpoint `index` halt the core at `addr`, enabling halting debug.  On
+/// ARMv8 `addr` must be word aligned.
+pub fn set_breakpoint(dcc: &mut DccStream, index: usize, addr: u32) -> Result<(), DccError> {
+    let count = breakpoint_count(dcc)?;
+    if index >= count {
+        return Err(DccError::InvalidConfig(format!(
+            "breakpoint {} doesn't exist, the core has {}",
+            index, count
+        )));
+    }
+    let bas = match dcc.arch() {
+        Arch::Armv7 if addr & 2 != 0 => BCR_BAS_HIGH,
+        Arch::Armv8 if addr & 3 != 0 => {
+            return Err(DccError::InvalidConfig(format!(
+                "breakpoint address 0x{:x} isn't word aligned",
+                addr
+            )));
+        }
+        _ => BCR_BAS_WORD,
+    };
+    let dscr = dcc.read_dscr()?;
+    dcc.write_mem(dcc.debug_base() + DSCR, dscr | DSCR_HDE)?;
+    let (bvr, bcr) = breakpoint_regs(dcc, index);
+    // Disable it while the address changes
+    dcc.write_mem(bcr, 0)?;
+    dcc.write_mem(bvr, addr & !3)?;
+    if dcc.arch() == Arch::Armv8 {
+        dcc.write_mem(bvr + 4, 0)?;
+    }
+    dcc.write_mem(bcr, bas | BCR_ANY_MODE | BCR_ENABLE)
+}
+
+pub fn clear_breakpoint(dcc: &mut DccStream, index: usize) -> Result<(), DccError> {
+    let (_, bcr) = breakpoint_regs(dcc, index);
+    dcc.write_mem(bcr, 0)
+}
+
+/// The address of each hardware breakpoint that is enabled, by index
+pub fn breakpoints(dcc: &mut DccStream) -> Result<Vec<Option<u32>>, DccError> {
+    (0..breakpoint_count(dcc)?)
+        .map(|index| {
+            let (bvr, bcr) = breakpoint_regs(dcc, index);
+            let bcr = dcc.read_mem(bcr)?;
+            if bcr & BCR_ENABLE == 0 {
+                return Ok(None);
+            }
+            let addr = dcc.read_mem(bvr)?;
+            Ok(Some(if bcr & BCR_BAS_WORD == BCR_BAS_HIGH { addr | 2 } else { addr }))
+        })
+        .collect()
+}
+
+/// Which accesses a watchpoint halts on, the WCR load/store control
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Access {
+    Load = 0b01,
+    Store = 0b10,
+    Any = 0b11,
+}
+
+/// Number of hardware watchpoints the core has
+pub fn watchpoint_count(dcc: &mut DccStream) -> Result<usize, DccError> {
+    let base = dcc.debug_base();
+    let wrps = match dcc.arch() {
+        Arch::Armv7 => dcc.read_mem(base + DBGDIDR)? >> 28,
+        Arch::Armv8 => dcc.read_mem(base + EDDFR)? >> 20,
+    };
+    Ok((wrps & 0xf) as usize + 1)
+}
+
+fn watchpoint_regs(dcc: &DccStream, index: usize) -> (u32, u32) {
+    let base = dcc.debug_base();
+    let index = index as u32;
+    match dcc.arch() {
+        Arch::Armv7 => (base + V7_WVR + 4 * index, base + V7_WCR + 4 * index),
+        Arch::Armv8 => (base + V8_WVR + 16 * index, base + V8_WCR + 16 * index),
+    }
+}
+
+/// Make hardware watchpoint `index` halt the core on `access` to the word at `addr`, enabling
+/// halting debug
+pub fn set_watchpoint(dcc: &mut DccStream, index: usize, addr: u32, access: Access) -> Result<(), DccError> {
+    let count = watchpoint_count(dcc)?;
+    if index >= count {
+        return Err(DccError::InvalidConfig(format!(
+            "watchpoint {} doesn't exist, the core has {}",
+            index, count
+        )));
+    }
+    let (value, bas) = match dcc.arch() {
+        Arch::Armv7 => (addr & !3, WCR_BAS_LOW),
+        Arch::Armv8 if addr & 4 != 0 => (addr & !7, WCR_BAS_HIGH),
+        Arch::Armv8 => (addr & !7, WCR_BAS_LOW),
+    };
+    let dscr = dcc.read_dscr()?;
+    dcc.write_mem(dcc.debug_base() + DSCR, dscr | DSCR_HDE)?;
+    let (wvr, wcr) = watchpoint_regs(dcc, index);
+    dcc.write_mem(wcr, 0)?;
+    dcc.write_mem(wvr, value)?;
+    if dcc.arch() == Arch::Armv8 {
+        dcc.write_mem(wvr + 4, 0)?;
+    }
+    dcc.write_mem(wcr, bas | (access as u32) << 3 | WCR_ANY_MODE | WCR_ENABLE)
+}
+
+pub fn clear_watchpoint(dcc: &mut DccStream, index: usize) -> Result<(), DccError> {
+    let (_, wcr) = watchpoint_regs(dcc, index);
+    dcc.write_mem(wcr, 0)
+}
+
+/// Whether the core is halted on a watchpoint
+pub fn watchpoint_hit(dcc: &mut DccStream) -> Result<bool, DccError> {
+    Ok(halted_by(dcc)?.is_some_and(|reason| reason.ends_with("watchpoint")))
+}
+
+/// Whether the core is halted in debug state
+pub fn halted(dcc: &mut DccStream) -> Result<bool, DccError> {
+    let base = dcc.debug_base();
+    Ok(match dcc.arch() {
+        Arch::Armv7 => dcc.read_mem(base + DSCR)? & DSCR_HALTED != 0,
+        Arch::Armv8 => dcc.read_mem(base + PRSR)? & PRSR_HALTED != 0,
+    })
+}
+
+fn wait(
+    dcc: &mut DccStream,
+    stop: &CancelToken,
+    what: &str,
+    mut done: impl FnMut(&mut DccStream) -> Result<bool, DccError>,
+) -> Result<(), DccError> {
+    let start = Instant::now();
+    while !done(dcc)? {
+        if stop.is_cancelled() {
+            return Err(DccError::Interrupted);
+        }
+        if start.elapsed() >= TIMEOUT {
+            return Err(DccError::Other(format!(
+                "core at 0x{:x} didn't {} within {:?}",
+                dcc.debug_base(),
+                what,
+                TIMEOUT
+            )));
+        }
+        thread::sleep(POLL);
+    }
+    Ok(())
+}
+
+/// Set or clear the reset catch
+fn set_catch(dcc: &mut DccStream, on: bool) -> Result<(), DccError> {
+    let (reg, bit) = match dcc.arch() {
+        Arch::Armv7 => (DBGVCR, VCR_RESET),
+        Arch::Armv8 => (EDECR, EDECR_RCE),
+    };
+    let addr = dcc.debug_base() + reg;
+    let value = dcc.read_mem(addr)?;
+    dcc.write_mem(addr, if on { value | bit } else { value & !bit })
+}
+
+/// Warm reset the core with reset catch on, and wait for it to halt at the reset vector.
+/// Returns DSCR from before halting debug was enabled, for `release`.  Needs the core powered
+/// and external debug allowed by the secure state.
+pub fn catch_reset(dcc: &mut DccStream, stop: &CancelToken) -> Result<u32, DccError> {
+    let base = dcc.debug_base();
+    dcc.check_powered()?;
+    dcc.clear_os_lock()?;
+    let dscr = dcc.read_dscr()?;
+    dcc.write_mem(base + DSCR, dscr | DSCR_HDE)?;
+    set_catch(dcc, true)?;
+    // Reading PRSR clears the sticky reset flag left by any earlier reset
+    dcc.read_mem(base + PRSR)?;
+    let prcr = dcc.read_mem(base + PRCR)?;
+    dcc.write_mem(base + PRCR, prcr | PRCR_CWRR)?;
+    let mut reset = false;
+    wait(dcc, stop, "reset", |dcc| {
+        reset |= dcc.read_mem(base + PRSR)? & PRSR_SR != 0;
+        Ok(reset)
+    })?;
+    // A reset may set the OS lock again
+    dcc.clear_os_lock()?;
+    wait(dcc, stop, "halt on reset", halted)?;
+    Ok(dscr)
+}
+
+/// Clear the reset catch and let the core run from where `catch_reset` halted it, putting
+/// halting debug back as it was in `dscr`.  `cti` is the base of the core's CTI, needed on
+/// ARMv8.
+pub fn release(dcc: &mut DccStream, dscr: u32, cti: u32, stop: &CancelToken) -> Result<(), DccError> {
+    set_catch(dcc, false)?;
+    restart(dcc, cti, stop)?;
+    restore_hde(dcc, dscr)
+}
+
+/// Ask the core to halt and wait for it to.  Returns the word the target had left in DTRTX,
+/// which `read_registers` would otherwise overwrite.
+pub fn halt(dcc: &mut DccStream, cti: u32, stop: &CancelToken) -> Result<Option<u32>, DccError> {
+    let base = dcc.debug_base();
+    match dcc.arch() {
+        Arch::Armv7 => dcc.write_mem(base + DRCR, DRCR_HRQ)?,
+        Arch::Armv8 => {
+            dcc.write_mem(cti + CTILAR, LAR_KEY)?;
+            dcc.write_mem(cti + CTICONTROL, 1)?;
+            dcc.write_mem(cti + CTIOUTEN + 4 * TRIGGER_HALT, 1 << HALT_CHANNEL)?;
+            dcc.write_mem(cti + CTIAPPPULSE, 1 << HALT_CHANNEL)?;
+        }
+    }
+    wait(dcc, stop, "halt", halted)?;
+    if dcc.read_dscr()? & DSCR_TXFULL != 0 {
+        return Ok(Some(dcc.read_mem(base + DTRTX)?));
+    }
+    Ok(None)
+}
+
+/// Run `instr` on the halted core and wait for it to complete
+fn execute(dcc: &mut DccStream, instr: u32, stop: &CancelToken) -> Result<(), DccError> {
+    let base = dcc.debug_base();
+    dcc.write_mem(base + ITR, instr)?;
+    wait(dcc, stop, "complete an instruction", |dcc| Ok(dcc.read_dscr()? & DSCR_ITE != 0))
+}
+
+/// Run `instr`, which writes DTRTX, and read what it wrote
+fn execute_read(dcc: &mut DccStream, instr: u32, stop: &CancelToken) -> Result<u32, DccError> {
+    execute(dcc, instr, stop)?;
+    dcc.read_mem(dcc.debug_base() + DTRTX)
+}
+
+/// The halted core's general purpose registers, by name: r0-r14 for AArch32, for which there
+/// is no way to read the PC without knowing the instruction set, and x0-x30 with the PC and
+/// PSTATE for AArch64.  The registers are left as they were.
+pub fn read_registers(dcc: &mut DccStream, stop: &CancelToken) -> Result<Vec<(String, u64)>, DccError> {
+    let base = dcc.debug_base();
+    let dscr = dcc.read_dscr()?;
+    let aarch64 = dcc.arch() == Arch::Armv8 && dscr >> EDSCR_RW_SHIFT >> (dscr >> EDSCR_EL_SHIFT & 3) & 1 != 0;
+    if !aarch64 {
+        if dcc.arch() == Arch::Armv7 {
+            dcc.write_mem(base + DSCR, dscr | DSCR_ITREN)?;
+        }
+        // ARMv8 takes the first halfword of a T32 instruction in the low half of EDITR
+        let armv8 = dcc.arch() == Arch::Armv8;
+        let swap = |instr: u32| if armv8 { instr.rotate_left(16) } else { instr };
+        let regs = (0..15)
+            .map(|n| Ok((format!("r{}", n), execute_read(dcc, swap(MCR_DTRTX | n << 12), stop)? as u64)))
+            .collect();
+        if dcc.arch() == Arch::Armv7 {
+            dcc.write_mem(base + DSCR, dscr)?;
+        }
+        return regs;
+    }
+    let read_x = |dcc: &mut DccStream, n: u32| -> Result<u64, DccError> {
+        execute(dcc, MSR_DBGDTR | n, stop)?;
+        let lo = dcc.read_mem(base + DTRTX)?;
+        let hi = dcc.read_mem(base + DTRRX)?;
+        Ok((hi as u64) << 32 | lo as u64)
+    };
+    let mut regs = vec![];
+    for n in 0..31 {
+        regs.push((format!("x{}", n), read_x(dcc, n)?));
+    }
+    // The PC and PSTATE go through x0, which is put back afterwards
+    execute(dcc, MRS_DLR, stop)?;
+    regs.push(("pc".to_string(), read_x(dcc, 0)?));
+    execute(dcc, MRS_DSPSR, stop)?;
+    regs.push(("pstate".to_string(), read_x(dcc, 0)?));
+    let x0 = regs[0].1;
+    dcc.write_mem(base + DTRRX, x0 as u32)?;
+    dcc.write_mem(base + DTRTX, (x0 >> 32) as u32)?;
+    execute(dcc, MRS_DBGDTR, stop)?;
+    Ok(regs)
+}
+
+/// Restart a halted core and wait for it to leave debug state
+pub fn restart(dcc: &mut DccStream, cti: u32, stop: &CancelToken) -> Result<(), DccError> {
+    let base = dcc.debug_base();
+    match dcc.arch() {
+        Arch::Armv7 => {
+            dcc.write_mem(base + DRCR, DRCR_RRQ | DRCR_CSE)?;
+            wait(dcc, stop, "restart", |dcc| Ok(dcc.read_mem(base + DSCR)? & DSCR_RESTARTED != 0))
+        }
+        Arch::Armv8 => {
+            dcc.write_mem(base + DRCR, DRCR_CSE)?;
+            dcc.write_mem(cti + CTILAR, LAR_KEY)?;
+            dcc.write_mem(cti + CTICONTROL, 1)?;
+            // A halt request left asserted would halt the core again straight away
+            dcc.write_mem(cti + CTIINTACK, 1 << TRIGGER_HALT)?;
+            dcc.write_mem(cti + CTIOUTEN + 4 * TRIGGER_RESTART, 1 << RESTART_CHANNEL)?;
+            dcc.write_mem(cti + CTIAPPPULSE, 1 << RESTART_CHANNEL)?;
+            wait(dcc, stop, "restart", |dcc| Ok(!halted(dcc)?))
+        }
+    }
+}
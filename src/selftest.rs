@@ -0,0 +1,245 @@
+//! `dcc-stream selftest`: check each layer from the cable up to the DCC in turn, to tell a
+//! cable or wiring problem from a target or configuration one
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use dcc_stream::{parse_duration, parse_u32, Arch, CancelToken, DccError, DccStream, DccStreamBuilder, ARM_DAP_IDCODES};
+
+#[derive(Parser, Debug)]
+#[command(
+    bin_name = "dcc-stream selftest",
+    about = "Check the debug path stage by stage and report which stages pass"
+)]
+pub struct SelftestArgs {
+    #[arg(short, long, env = "DCC_CABLE")]
+    cable: String,
+    #[arg(short, long, env = "DCC_BAUD")]
+    baud: u32,
+    #[arg(long, default_value_t = false, env = "DCC_RTCK")]
+    /// Clock TCK from the target's RTCK, falling back to --baud
+    rtck: bool,
+    #[arg(short, long, default_value_t = 0, env = "DCC_TAP_INDEX")]
+    /// Which JTAG TAP to use
+    tap_index: usize,
+    #[arg(short, long, default_value_t = 1, env = "DCC_AP_NUM")]
+    /// Which access port to use
+    ap_num: u32,
+    #[arg(long, value_enum, default_value_t = Arch::Armv7, env = "DCC_ARCH")]
+    /// Debug architecture of the core
+    arch: Arch,
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    /// How long to read the DCC for in the last stage
+    time: Duration,
+    #[arg(value_parser = parse_u32, env = "DCC_DEBUG_BASE")]
+    /// CPU debug base address, prefix with 0x for hexadecimal
+    debug_base: u32,
+}
+
+const STAGES: &[&str] = &[
+    "open cable",
+    "IDCODE",
+    "AP enumeration",
+    "debug component",
+    "DSCR round trip",
+    "DCC burst",
+];
+
+// CoreSight component and peripheral ID registers, as offsets from the debug base
+const DEVTYPE: u32 = 0xfcc;
+const CIDR0: u32 = 0xff0;
+// The fixed bits of CIDR0-3, with the class field masked out
+const CIDR_PREAMBLE: u32 = 0xb105000d;
+const CIDR_CLASS_DEBUG: u32 = 0x9;
+// DSCR bit 20, ExtDCCmode stall on ARMv7 and MA on ARMv8, writable on both
+const DSCR_TEST_BIT: u32 = 1 << 20;
+// IDR.CLASS of a MEM-AP
+const AP_CLASS_MEM: u32 = 0x8;
+
+/// The stages run so far and how many failed
+struct Report {
+    next: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn stage(&mut self, result: Result<String, String>) {
+        let name = STAGES[self.next];
+        self.next += 1;
+        match result {
+            Ok(detail) => println!("PASS  {:<16} {}", name, detail),
+            Err(detail) => {
+                self.failed += 1;
+                println!("FAIL  {:<16} {}", name, detail);
+            }
+        }
+    }
+
+    /// Report the stages that weren't run because an earlier one failed
+    fn skip_rest(&mut self, why: &str) {
+        for name in &STAGES[self.next..] {
+            println!("SKIP  {:<16} {}", name, why);
+        }
+        self.next = STAGES.len();
+    }
+
+    fn finish(&self) -> Result<(), DccError> {
+        if self.failed == 0 {
+            println!("All stages passed");
+            return Ok(());
+        }
+        Err(DccError::Other(format!(
+            "{} of {} self-test stages failed",
+            self.failed,
+            STAGES.len()
+        )))
+    }
+}
+
+pub fn run(args: &SelftestArgs, stop: &CancelToken) -> Result<(), DccError> {
+    let mut report = Report { next: 0, failed: 0 };
+    let built = DccStreamBuilder::new(args.cable.clone(), args.debug_base)
+        .baud(args.baud)
+        .rtck(args.rtck)
+        .tap_index(args.tap_index)
+        .ap_num(args.ap_num)
+        .arch(args.arch)
+        .build();
+    let mut dcc = match built {
+        Ok(dcc) => {
+            report.stage(match (args.rtck, dcc.adaptive_clocking()) {
+                (_, true) => Ok(format!("{} with adaptive clocking", args.cable)),
+                (true, false) => Ok(format!("{} at {} baud, adaptive clocking unavailable", args.cable, args.baud)),
+                (false, false) => Ok(format!("{} at {} baud", args.cable, args.baud)),
+            });
+            dcc
+        }
+        Err(e) => {
+            report.stage(Err(e.to_string()));
+            report.skip_rest("the cable didn't open");
+            return report.finish();
+        }
+    };
+
+    let idcode = dcc.idcode();
+    report.stage(if ARM_DAP_IDCODES.contains(&idcode) {
+        Ok(format!("0x{:08x}, an ARM JTAG-DP", idcode))
+    } else {
+        Err(format!("0x{:08x} is not an ARM JTAG-DP, check --tap-index and the wiring", idcode))
+    });
+
+    report.stage(enumerate_aps(&mut dcc, args.ap_num, stop));
+    let component = check_component(&mut dcc, args.debug_base);
+    let found = component.is_ok();
+    report.stage(component);
+    if !found {
+        report.skip_rest("no debug component at the debug base");
+        return report.finish();
+    }
+    report.stage(dscr_round_trip(&mut dcc, args.debug_base));
+    report.stage(burst(&mut dcc, args.time, stop));
+    report.finish()
+}
+
+/// Find the access ports by their IDRs and check the selected one is a MEM-AP
+fn enumerate_aps(dcc: &mut DccStream, ap_num: u32, stop: &CancelToken) -> Result<String, String> {
+    let mut found = vec![];
+    for ap in 0..=255 {
+        if stop.is_cancelled() {
+            return Err("interrupted".to_string());
+        }
+        match dcc.read_ap_idr(ap) {
+            Ok(None) => return Ok("not supported by this cable, skipped".to_string()),
+            Ok(Some(0)) => {}
+            Ok(Some(idr)) => found.push((ap, idr)),
+            Err(e) => return Err(format!("AP{}: {}", ap, e)),
+        }
+    }
+    let list: Vec<String> = found.iter().map(|(ap, idr)| format!("AP{} 0x{:08x}", ap, idr)).collect();
+    let list = if list.is_empty() { "none".to_string() } else { list.join(", ") };
+    match found.iter().find(|(ap, _)| *ap == ap_num) {
+        Some((_, idr)) if (idr >> 13) & 0xf == AP_CLASS_MEM => Ok(list),
+        Some(_) => Err(format!("{}; AP{} is not a MEM-AP", list, ap_num)),
+        None => Err(format!("{}; AP{} not found, check --ap-num", list, ap_num)),
+    }
+}
+
+/// Check the component ID registers show a CoreSight debug component at the debug base
+fn check_component(dcc: &mut DccStream, base: u32) -> Result<String, String> {
+    let mut cidr = 0;
+    for i in 0..4 {
+        let byte = dcc.read_mem(base + CIDR0 + i * 4).map_err(|e| e.to_string())?;
+        cidr |= (byte & 0xff) << (i * 8);
+    }
+    if cidr & !0xf000 != CIDR_PREAMBLE {
+        return Err(format!("CIDR 0x{:08x} is not a CoreSight component, check the debug base", cidr));
+    }
+    let class = (cidr >> 12) & 0xf;
+    if class != CIDR_CLASS_DEBUG {
+        return Err(format!(
+            "CIDR 0x{:08x} is component class 0x{:x}, not a debug component",
+            cidr, class
+        ));
+    }
+    let devtype = dcc.read_mem(base + DEVTYPE).map_err(|e| e.to_string())?;
+    Ok(format!("CIDR 0x{:08x}, DEVTYPE 0x{:02x}", cidr, devtype & 0xff))
+}
+
+/// Flip a writable DSCR bit and back, then put DSCR as it was
+fn dscr_round_trip(dcc: &mut DccStream, base: u32) -> Result<String, String> {
+    let edprsr = match dcc.check_powered() {
+        Ok(edprsr) => edprsr,
+        Err(e) => return Err(e.to_string()),
+    };
+    dcc.clear_os_lock().map_err(|e| format!("clear OS lock: {}", e))?;
+    let dscr = dcc.read_dscr().map_err(|e| e.to_string())?;
+    let flipped = dscr ^ DSCR_TEST_BIT;
+    let result = dcc
+        .write_mem(base + 0x88, flipped)
+        .and_then(|()| dcc.read_dscr())
+        .map_err(|e| e.to_string());
+    let restored = dcc.write_mem(base + 0x88, dscr).map_err(|e| format!("restore DSCR: {}", e));
+    let readback = result?;
+    restored?;
+    if readback & DSCR_TEST_BIT != flipped & DSCR_TEST_BIT {
+        return Err(format!("wrote 0x{:08x}, read back 0x{:08x}", flipped, readback));
+    }
+    Ok(format!("DSCR 0x{:08x}, EDPRSR 0x{:08x}", dscr, edprsr))
+}
+
+/// Attach and read the DCC for `time`
+fn burst(dcc: &mut DccStream, time: Duration, stop: &CancelToken) -> Result<String, String> {
+    dcc.attach(stop).map_err(|e| format!("attach: {}", e))?;
+    let mut errors = 0;
+    let mut reads = 0;
+    let mut fresh = 0;
+    let mut last = None;
+    let start = Instant::now();
+    while start.elapsed() < time && !stop.is_cancelled() {
+        match dcc.read(dcc.queue_size()) {
+            Ok(words) => {
+                reads += words.len();
+                for word in words {
+                    if last != Some(word) {
+                        fresh += 1;
+                    }
+                    last = Some(word);
+                }
+            }
+            Err(_) => errors += 1,
+        }
+    }
+    let secs = start.elapsed().as_secs_f64();
+    let restored = dcc.restore(false);
+    let summary = format!("{:.0} reads/s, {} new words, {} failed batches", reads as f64 / secs, fresh, errors);
+    if let Err(e) = restored {
+        return Err(format!("{}; restore: {}", summary, e));
+    }
+    if errors > 0 {
+        return Err(summary);
+    }
+    if fresh == 0 {
+        return Ok(format!("{} (the target sent nothing)", summary));
+    }
+    Ok(summary)
+}
@@ -0,0 +1,88 @@
+use clap::ValueEnum;
+
+/// Where the target puts the rolling counter that lets lost words be detected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SequenceMode {
+    /// The top byte of every word counts up by one, wrapping at 256
+    TopByte,
+    /// Every data word follows a word holding a 32 bit counter, which isn't output
+    Word,
+}
+
+/// Checks the counter embedded in the stream for continuity
+pub struct Sequence {
+    mode: SequenceMode,
+    /// The counter the next word should carry
+    expected: Option<u32>,
+    /// In `Word` mode, the next word is a counter
+    counter_next: bool,
+}
+
+impl Sequence {
+    pub fn new(mode: SequenceMode) -> Self {
+        Self {
+            mode,
+            expected: None,
+            counter_next: true,
+        }
+    }
+
+    /// Check a new word.  Returns whether it is data to output, and how many words the
+    /// counter shows went missing before it.
+    pub fn check(&mut self, word: u32) -> (bool, u64) {
+        let (counter, mask, data) = match self.mode {
+            SequenceMode::TopByte => (word >> 24, 0xff, true),
+            SequenceMode::Word if self.counter_next => {
+                self.counter_next = false;
+                (word, u32::MAX, false)
+            }
+            SequenceMode::Word => {
+                self.counter_next = true;
+                return (true, 0);
+            }
+        };
+        let lost = match self.expected {
+            Some(expected) => counter.wrapping_sub(expected) & mask,
+            None => 0,
+        };
+        self.expected = Some(counter.wrapping_add(1) & mask);
+        (data, lost as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_byte_gaps_and_wraparound() {
+        let mut sequence = Sequence::new(SequenceMode::TopByte);
+        // The first word sets the count, whatever it is
+        assert_eq!(sequence.check(0xfd00_0001), (true, 0));
+        assert_eq!(sequence.check(0xfe00_0002), (true, 0));
+        assert_eq!(sequence.check(0xff00_0003), (true, 0));
+        assert_eq!(sequence.check(0x0000_0004), (true, 0));
+        // 1 and 2 never arrived
+        assert_eq!(sequence.check(0x0300_0005), (true, 2));
+        // Two missing across the wrap
+        let mut sequence = Sequence::new(SequenceMode::TopByte);
+        sequence.check(0xfe00_0000);
+        assert_eq!(sequence.check(0x0100_0000), (true, 2));
+        // A repeat looks like 255 lost, the counter can't tell
+        assert_eq!(sequence.check(0x0100_0000), (true, 255));
+    }
+
+    #[test]
+    fn counter_words() {
+        let mut sequence = Sequence::new(SequenceMode::Word);
+        assert_eq!(sequence.check(u32::MAX - 1), (false, 0));
+        assert_eq!(sequence.check(0xaaaa), (true, 0));
+        assert_eq!(sequence.check(u32::MAX), (false, 0));
+        // Data words are never taken for counters, whatever they hold
+        assert_eq!(sequence.check(u32::MAX), (true, 0));
+        assert_eq!(sequence.check(0), (false, 0));
+        assert_eq!(sequence.check(0xbbbb), (true, 0));
+        assert_eq!(sequence.check(4), (false, 3));
+        assert_eq!(sequence.check(0xcccc), (true, 0));
+    }
+}
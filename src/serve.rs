@@ -0,0 +1,80 @@
+//! `dcc-stream serve`: own the cable and let other dcc-stream processes, each started with
+//! `--cable share:SOCKET`, stream from its cores at the same time
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+
+use dcc_stream::jtag::JtagPort;
+use dcc_stream::share;
+use dcc_stream::sim::{self, SimPort};
+use dcc_stream::transport::{DebugPort, RetryPolicy};
+use dcc_stream::{parse_duration, CancelToken, DccError, Target};
+
+use crate::lock;
+
+#[derive(Parser, Debug)]
+#[command(
+    bin_name = "dcc-stream serve",
+    about = "Own the cable and share it through a socket with other dcc-stream processes"
+)]
+pub struct ServeArgs {
+    #[arg(short, long, env = "DCC_CABLE")]
+    /// The JTAG cable, or sim:SCENARIO.toml for a simulated target
+    cable: String,
+    #[arg(short, long, env = "DCC_BAUD")]
+    baud: u32,
+    #[arg(long, default_value_t = false, env = "DCC_RTCK")]
+    /// Clock TCK from the target's RTCK, falling back to --baud
+    rtck: bool,
+    #[arg(short, long, default_value_t = 0, env = "DCC_TAP_INDEX")]
+    /// Which JTAG TAP to use
+    tap_index: usize,
+    #[arg(long, default_value_t = 4)]
+    /// Times a debug access answered with WAIT is retried
+    wait_retries: u32,
+    #[arg(long, default_value = "0.1ms", value_parser = parse_duration)]
+    /// Sleep before the first WAIT retry, doubled for each retry after it
+    wait_backoff: Duration,
+    #[arg(long, default_value_t = false)]
+    /// Don't take the cable lock
+    no_lock: bool,
+    /// Socket to listen on, for clients to name with --cable share:SOCKET
+    socket: PathBuf,
+}
+
+pub fn run(args: &ServeArgs, stop: &CancelToken) -> Result<(), DccError> {
+    let _lock = if args.no_lock { None } else { Some(lock::lock(&args.cable)?) };
+    let target = Target {
+        cable: args.cable.clone(),
+        baud: args.baud,
+        tap_index: args.tap_index,
+        ap_num: 1,
+        debug_base: 0,
+        retry: RetryPolicy {
+            wait_retries: args.wait_retries,
+            wait_backoff: args.wait_backoff,
+        },
+        rtck: args.rtck,
+        clock: None,
+    };
+    eprintln!("Serving {} on {}", args.cable, args.socket.display());
+    if sim::is_sim(&args.cable) {
+        // Each client gets a simulated core of its own
+        let open = |ap_num, debug_base| {
+            let target = Target {
+                ap_num,
+                debug_base,
+                ..target.clone()
+            };
+            Ok(Box::new(SimPort::open(&target)?) as Box<dyn DebugPort>)
+        };
+        return share::serve(&args.socket, open, stop);
+    }
+    let port = JtagPort::open(&target)?;
+    if args.rtck && !port.adaptive_clocking() {
+        eprintln!("Warning: adaptive clocking unavailable, using the fixed --baud");
+    }
+    let open = |ap_num, _| Ok(Box::new(port.with_ap(ap_num)?) as Box<dyn DebugPort>);
+    share::serve(&args.socket, open, stop)
+}
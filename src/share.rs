@@ -0,0 +1,351 @@
+//! Sharing one cable between processes.  `serve` owns the cable and listens on a Unix socket;
+//! each client connects with a cable of `share:SOCKET`, names the AP it wants and then sends
+//! its debug port accesses, which the server carries out one at a time in the order they
+//! arrive.  Clients on different APs or cores stream concurrently through the one probe, and as
+//! each waits for its reply before sending more, a busy one can't shut the others out.
+//!
+//! Each request and reply is a line of text.  The client opens with `hello AP DEBUG_BASE`,
+//! answered with `ok IDCODE ADAPTIVE`, then sends `read ADDR`, `write ADDR VALUE`, `repeated
+//! ADDR COUNT`, `block ADDR COUNT`, `pairs FIRST SECOND COUNT`, `clear`, `reinit`, `apidr AP`
+//! or `acks`.  Numbers are hexadecimal without a prefix.  A reply is `ok` and any values, or `error KIND
+//! MESSAGE` with a KIND of `fault`, `wait` or `other`.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancel::CancelToken;
+use crate::error::DccError;
+use crate::stream::Target;
+use crate::transport::{AckCounts, DebugPort};
+
+/// How often `serve` checks whether it has been asked to stop
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Whether `cable` names a `serve` socket rather than a real cable
+pub fn is_shared(cable: &str) -> bool {
+    cable.starts_with("share:")
+}
+
+enum Op {
+    Hello(u32, u32),
+    Read(u32),
+    Write(u32, u32),
+    Repeated(u32, usize),
+    Block(u32, usize),
+    Pairs(u32, u32, usize),
+    Clear,
+    Reinit,
+    ApIdr(u32),
+    Acks,
+    /// The client went away
+    Close,
+}
+
+impl Op {
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let mut num = || {
+            let word = words.next().ok_or_else(|| format!("{} needs more arguments", cmd))?;
+            u32::from_str_radix(word, 16).map_err(|_| format!("bad number {}", word))
+        };
+        Ok(match cmd {
+            "hello" => Op::Hello(num()?, num()?),
+            "read" => Op::Read(num()?),
+            "write" => Op::Write(num()?, num()?),
+            "repeated" => Op::Repeated(num()?, num()? as usize),
+            "block" => Op::Block(num()?, num()? as usize),
+            "pairs" => Op::Pairs(num()?, num()?, num()? as usize),
+            "clear" => Op::Clear,
+            "reinit" => Op::Reinit,
+            "apidr" => Op::ApIdr(num()?),
+            "acks" => Op::Acks,
+            _ => return Err(format!("unknown request: {}", cmd)),
+        })
+    }
+}
+
+/// An access from a client, waiting for the thread that owns the cable
+struct Request {
+    client: u64,
+    op: Op,
+    reply: Sender<String>,
+}
+
+fn hex(values: impl IntoIterator<Item = u32>) -> String {
+    let values: Vec<String> = values.into_iter().map(|v| format!("{:x}", v)).collect();
+    values.join(" ")
+}
+
+fn error_reply(e: DccError) -> String {
+    match e {
+        DccError::AccessFault(msg) => format!("error fault {}", msg),
+        DccError::Wait(msg) => format!("error wait {}", msg),
+        e => format!("error other {}", e),
+    }
+}
+
+fn serve_client(client: u64, stream: UnixStream, requests: Sender<Request>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let (reply, rx) = mpsc::channel();
+    let result = (|| {
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match Op::parse(&line) {
+                Ok(op) => {
+                    let reply = reply.clone();
+                    if requests.send(Request { client, op, reply }).is_err() {
+                        return Ok(());
+                    }
+                    match rx.recv() {
+                        Ok(response) => response,
+                        Err(_) => return Ok(()),
+                    }
+                }
+                Err(e) => format!("error other {}", e),
+            };
+            writeln!(writer, "{}", response)?;
+        }
+        Ok(())
+    })();
+    let _ = requests.send(Request {
+        client,
+        op: Op::Close,
+        reply,
+    });
+    result
+}
+
+/// Carry out `op` for a client, on the port it opened with `hello`
+fn handle(
+    ports: &mut HashMap<u64, Box<dyn DebugPort>>,
+    open: &mut impl FnMut(u32, u32) -> Result<Box<dyn DebugPort>, DccError>,
+    client: u64,
+    op: Op,
+) -> String {
+    if let Op::Hello(ap_num, debug_base) = op {
+        return match open(ap_num, debug_base) {
+            Ok(port) => {
+                let reply = format!("ok {:x} {}", port.idcode(), port.adaptive_clocking() as u8);
+                ports.insert(client, port);
+                reply
+            }
+            Err(e) => error_reply(e),
+        };
+    }
+    let Some(port) = ports.get_mut(&client) else {
+        return "error other send hello first".to_string();
+    };
+    let result = match op {
+        Op::Hello(..) | Op::Close => unreachable!(),
+        Op::Read(addr) => port.read(addr).map(|v| hex([v])),
+        Op::Write(addr, value) => port.write(addr, value).map(|_| String::new()),
+        Op::Repeated(addr, count) => port.read_repeated(addr, count).map(hex),
+        Op::Block(addr, count) => port.read_block(addr, count).map(hex),
+        Op::Pairs(first, second, count) => port
+            .read_pairs(first, second, count)
+            .map(|pairs| hex(pairs.into_iter().flat_map(|(a, b)| [a, b]))),
+        Op::Clear => port.clear_errors().map(|_| String::new()),
+        Op::Reinit => port.reinit().map(|_| String::new()),
+        Op::ApIdr(ap_num) => port.read_ap_idr(ap_num).map(|idr| match idr {
+            Some(idr) => hex([idr]),
+            None => "none".to_string(),
+        }),
+        Op::Acks => {
+            let acks = port.ack_counts();
+            Ok(hex([acks.waits as u32, acks.faults as u32]))
+        }
+    };
+    match result {
+        Ok(values) if values.is_empty() => "ok".to_string(),
+        Ok(values) => format!("ok {}", values),
+        Err(e) => error_reply(e),
+    }
+}
+
+/// Listen on `path`, replacing any stale socket, and serve clients until `stop` is cancelled.
+/// `open` gives the port for each client's AP and debug base.  It runs on this thread along
+/// with every access, so the ports need not be `Send`.
+pub fn serve(
+    path: &Path,
+    mut open: impl FnMut(u32, u32) -> Result<Box<dyn DebugPort>, DccError>,
+    stop: &CancelToken,
+) -> Result<(), DccError> {
+    if path.exists() {
+        fs::remove_file(path).map_err(|e| DccError::Io(format!("remove {}", path.display()), e))?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| DccError::Io(format!("bind {}", path.display()), e))?;
+    let (tx, requests) = mpsc::channel();
+    thread::spawn(move || {
+        for (client, stream) in listener.incoming().flatten().enumerate() {
+            let tx = tx.clone();
+            thread::spawn(move || serve_client(client as u64, stream, tx));
+        }
+    });
+
+    let mut ports = HashMap::new();
+    while !stop.is_cancelled() {
+        let req = match requests.recv_timeout(STOP_POLL) {
+            Ok(req) => req,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Op::Close = req.op {
+            ports.remove(&req.client);
+            continue;
+        }
+        let _ = req.reply.send(handle(&mut ports, &mut open, req.client, req.op));
+    }
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Connection {
+    /// Send one request and return what follows the `ok` of its reply
+    fn call(&mut self, request: &str) -> Result<String, DccError> {
+        let io_err = |e| DccError::Io("shared cable".to_string(), e);
+        writeln!(self.writer, "{}", request).map_err(io_err)?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).map_err(io_err)? == 0 {
+            return Err(io_err(io::Error::new(io::ErrorKind::UnexpectedEof, "server went away")));
+        }
+        let line = line.trim_end();
+        if let Some(rest) = line.strip_prefix("ok") {
+            return Ok(rest.trim().to_string());
+        }
+        let rest = line.strip_prefix("error ").unwrap_or(line);
+        let (kind, msg) = rest.split_once(' ').unwrap_or((rest, ""));
+        Err(match kind {
+            "fault" => DccError::AccessFault(msg.to_string()),
+            "wait" => DccError::Wait(msg.to_string()),
+            _ => DccError::Other(format!("shared cable: {}", msg)),
+        })
+    }
+
+    fn values(&mut self, request: &str) -> Result<Vec<u32>, DccError> {
+        self.call(request)?
+            .split_whitespace()
+            .map(|v| u32::from_str_radix(v, 16).map_err(|_| DccError::Other(format!("shared cable sent {}", v))))
+            .collect()
+    }
+}
+
+/// A debug port reached through another process's `serve`
+pub struct SharedPort {
+    // `ack_counts` only borrows the port, but still has to ask the server
+    conn: RefCell<Connection>,
+    idcode: u32,
+    adaptive_clocking: bool,
+}
+
+impl SharedPort {
+    /// Connect to the server named by a `share:SOCKET` cable and open `target`'s AP.  The
+    /// server's cable settings apply, so the baud, TAP, RTCK and WAIT retries of `target` are
+    /// ignored.
+    pub fn open(target: &Target) -> Result<Self, DccError> {
+        let path = target
+            .cable
+            .strip_prefix("share:")
+            .ok_or_else(|| DccError::CableNotFound(target.cable.clone()))?;
+        let stream = UnixStream::connect(path).map_err(|_| DccError::CableNotFound(target.cable.clone()))?;
+        let reader = BufReader::new(stream.try_clone().map_err(|e| DccError::Io("shared cable".to_string(), e))?);
+        let mut conn = Connection { reader, writer: stream };
+        let hello = conn.values(&format!("hello {:x} {:x}", target.ap_num, target.debug_base))?;
+        let &[idcode, adaptive] = hello.as_slice() else {
+            return Err(DccError::Other("shared cable: bad hello reply".to_string()));
+        };
+        Ok(Self {
+            conn: RefCell::new(conn),
+            idcode,
+            adaptive_clocking: adaptive != 0,
+        })
+    }
+
+    fn call(&mut self, request: &str) -> Result<(), DccError> {
+        self.conn.get_mut().call(request).map(|_| ())
+    }
+
+    fn values(&mut self, request: &str) -> Result<Vec<u32>, DccError> {
+        self.conn.get_mut().values(request)
+    }
+}
+
+impl DebugPort for SharedPort {
+    fn read(&mut self, addr: u32) -> Result<u32, DccError> {
+        let values = self.values(&format!("read {:x}", addr))?;
+        values
+            .first()
+            .copied()
+            .ok_or_else(|| DccError::Other("shared cable: empty read".to_string()))
+    }
+
+    fn write(&mut self, addr: u32, value: u32) -> Result<(), DccError> {
+        self.call(&format!("write {:x} {:x}", addr, value))
+    }
+
+    fn read_repeated(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        self.values(&format!("repeated {:x} {:x}", addr, count))
+    }
+
+    fn read_block(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        self.values(&format!("block {:x} {:x}", addr, count))
+    }
+
+    fn read_pairs(&mut self, first: u32, second: u32, count: usize) -> Result<Vec<(u32, u32)>, DccError> {
+        let values = self.values(&format!("pairs {:x} {:x} {:x}", first, second, count))?;
+        Ok(values.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect())
+    }
+
+    fn clear_errors(&mut self) -> Result<(), DccError> {
+        self.call("clear")
+    }
+
+    fn reinit(&mut self) -> Result<(), DccError> {
+        self.call("reinit")
+    }
+
+    fn set_baud(&mut self, _baud: u32) -> Result<(), DccError> {
+        Err(DccError::Other("the serve process owns the shared cable's clock".to_string()))
+    }
+
+    fn read_ap_idr(&mut self, ap_num: u32) -> Result<Option<u32>, DccError> {
+        match self.conn.get_mut().call(&format!("apidr {:x}", ap_num))?.as_str() {
+            "none" => Ok(None),
+            idr => u32::from_str_radix(idr, 16)
+                .map(Some)
+                .map_err(|_| DccError::Other(format!("shared cable sent {}", idr))),
+        }
+    }
+
+    fn ack_counts(&self) -> AckCounts {
+        match self.conn.borrow_mut().values("acks").as_deref() {
+            Ok(&[waits, faults]) => AckCounts {
+                waits: waits as u64,
+                faults: faults as u64,
+            },
+            _ => AckCounts::default(),
+        }
+    }
+
+    fn adaptive_clocking(&self) -> bool {
+        self.adaptive_clocking
+    }
+
+    fn idcode(&self) -> u32 {
+        self.idcode
+    }
+}
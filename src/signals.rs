@@ -0,0 +1,110 @@
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+#[cfg(unix)]
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+
+use dcc_stream::CancelToken;
+
+/// Exit status when a second SIGINT/SIGTERM forces us out, matching the shell's for SIGINT
+const FORCED_EXIT: i32 = 130;
+
+/// Requests delivered asynchronously by signal handlers, the TUI or the control socket.  These
+/// are installed once per process and outlive individual capture sessions.  Windows has no
+/// SIGHUP or SIGUSR1/2, so there only the TUI and the control socket set those flags.
+pub struct Signals {
+    /// Cancelled by SIGINT or SIGTERM, or on Windows a console Ctrl+C, Ctrl+Break or close, to
+    /// end the capture after the current batch
+    pub stop: CancelToken,
+    /// Set by SIGHUP to reload the config file
+    pub reload: Arc<AtomicBool>,
+    /// Set by SIGUSR1 to pause output
+    pub pause: Arc<AtomicBool>,
+    /// Set by SIGUSR2 to resume output
+    pub resume: Arc<AtomicBool>,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    pub fn install() -> io::Result<Self> {
+        let stop = CancelToken::new();
+        for sig in [SIGINT, SIGTERM] {
+            // The first signal asks for a clean shutdown.  If that hasn't happened by the time a
+            // second one arrives, e.g. because the cable is wedged in a blocking transfer, exit
+            // immediately.
+            signal_hook::flag::register_conditional_shutdown(sig, FORCED_EXIT, stop.flag())?;
+            signal_hook::flag::register(sig, stop.flag())?;
+        }
+
+        let reload = Arc::new(AtomicBool::new(false));
+        let pause = Arc::new(AtomicBool::new(false));
+        let resume = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGHUP, reload.clone())?;
+        signal_hook::flag::register(SIGUSR1, pause.clone())?;
+        signal_hook::flag::register(SIGUSR2, resume.clone())?;
+
+        Ok(Self {
+            stop,
+            reload,
+            pause,
+            resume,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn install() -> io::Result<Self> {
+        let stop = CancelToken::new();
+        console::install(stop.flag())?;
+        Ok(Self {
+            stop,
+            reload: Arc::new(AtomicBool::new(false)),
+            pause: Arc::new(AtomicBool::new(false)),
+            resume: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+}
+
+/// The console control handler standing in for SIGINT and SIGTERM on Windows
+#[cfg(windows)]
+mod console {
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, OnceLock};
+
+    use windows_sys::core::BOOL;
+    use windows_sys::Win32::System::Console::{SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT};
+
+    use super::FORCED_EXIT;
+
+    static STOP: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+    // Runs on a thread of its own that Windows creates for the event
+    unsafe extern "system" fn handler(event: u32) -> BOOL {
+        let Some(stop) = STOP.get() else {
+            return 0;
+        };
+        match event {
+            CTRL_C_EVENT | CTRL_BREAK_EVENT | CTRL_CLOSE_EVENT => {
+                // As with a second signal on Unix, a second event exits at once
+                if stop.swap(true, Ordering::SeqCst) {
+                    std::process::exit(FORCED_EXIT);
+                }
+                1
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn install(stop: Arc<AtomicBool>) -> io::Result<()> {
+        let _ = STOP.set(stop);
+        if unsafe { SetConsoleCtrlHandler(Some(handler), 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
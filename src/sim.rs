@@ -0,0 +1,885 @@
+//! A simulated DAP and core, selected with a cable of `sim:scenario.toml`, for developing and
+//! testing decoders, sinks and recovery without hardware.  The scenario describes what the
+//! core sends and how the debug path misbehaves:
+//!
+//! ```toml
+//! # Words per second the core writes to DTRTX, waiting while it is full
+//! rate = 10000
+//! # counter (from start, by step), constant (value), random (seed) or values (cycled)
+//! pattern = "counter"
+//! start = 0
+//! step = 1
+//! # Stop sending after this many words
+//! count = 100000
+//! # Chance that a word repeats the one before it
+//! duplicates = 0.01
+//! # Chance that any access faults, or is answered with WAIT
+//! fault_rate = 0.0001
+//! wait_rate = 0.001
+//! # How long each access takes on the simulated cable
+//! access_time = "20us"
+//! # Power the core down once, this long after opening, for this long
+//! power_down_after = "5s"
+//! power_down_for = "1s"
+//! # Take a data abort once, this long after opening, halting if it is caught
+//! abort_after = "3s"
+//! # Store the number of words sent so far to this address at these times after opening,
+//! # halting if a hardware watchpoint catches it
+//! store_addr = 0x20001000
+//! store_after = ["1s", "2s"]
+//! idcode = 0x4ba00477
+//! # Send the words to an RTT control block at this address instead of DTRTX
+//! rtt = 0x20000000
+//! # Or to a word mailbox with its producer index at this address, the consumer index after
+//! # it and 256 words of data 0x100 on, as --mailbox ADDR:ADDR+4:ADDR+0x100:256 reads it
+//! mailbox = 0x20000000
+//! # Whether the halted core is in AArch64 state rather than AArch32, for reading registers
+//! aarch64 = false
+//! # Start with the OS lock clear and stall mode on, as another debugger would leave the core
+//! preattached = false
+//! # A memory-mapped system counter (CNTCV, low word then high) at this address, counting
+//! # from when the scenario was opened at this rate, running fast by this many parts per
+//! # million
+//! counter = 0x2a430000
+//! counter_hz = 24000000
+//! counter_ppm = 0
+//! ```
+//!
+//! Every key is optional.  Addresses outside the debug registers, and debug registers the
+//! simulator doesn't model such as the breakpoints, read back what was written.
+//! A warm reset through EDPRCR starts the pattern again, and with reset catch on (DBGVCR or
+//! EDECR, and DSCR's halting debug enable) the core halts until restarted through EDRCR or
+//! the CTI 64KB above the debug registers.  The abort is caught the same way, by DBGVCR or
+//! EDECCR, and the stores by the first four watchpoints.  DBGDRCR and the CTI also halt the
+//! core on request, and while it is halted ITR runs the instructions that read its registers,
+//! which hold made up values.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use toml::{Table, Value};
+
+use crate::clock::{SharedClock, SystemClock};
+use crate::error::DccError;
+use crate::jtag::ARM_DAP_IDCODE;
+use crate::stream::Target;
+use crate::transport::{AckCounts, DebugPort, RetryPolicy};
+
+// Debug register offsets and bits the simulated core implements
+const DBGDIDR: u32 = 0x0;
+const DBGVCR: u32 = 0x1c;
+const EDECR: u32 = 0x24;
+const DSCR: u32 = 0x88;
+const DTRTX: u32 = 0x8c;
+const DTRRX: u32 = 0x80;
+const ITR: u32 = 0x84;
+const EDRCR: u32 = 0x90;
+const EDECCR: u32 = 0x98;
+const OSLAR: u32 = 0x300;
+const EDPRCR: u32 = 0x310;
+const EDPRSR: u32 = 0x314;
+const DSCR_HALTED: u32 = 1 << 0;
+const DSCR_RESTARTED: u32 = 1 << 1;
+const DSCR_HDE: u32 = 1 << 14;
+const DSCR_ITE: u32 = 1 << 24;
+// EDSCR at EL1, with every EL in AArch64
+const EDSCR_AARCH64_EL1: u32 = 1 << 8 | 0b1111 << 10;
+const MOE_HALT_REQUEST: u32 = 0;
+// The instructions `run_control::read_registers` runs, see there, and the PSTATE the halted
+// core reports
+const MCR_DTRTX: u32 = 0xee00_0e15;
+const MSR_DBGDTR: u32 = 0xd513_0400;
+const MRS_DBGDTR: u32 = 0xd533_0400;
+const MRS_DLR: u32 = 0xd53b_4520;
+const MRS_DSPSR: u32 = 0xd53b_4500;
+const PSTATE_EL1H: u64 = 0x3c5;
+const STATUS_EXTERNAL_DEBUG_REQUEST: u32 = 0b010011;
+// DSCR.MOE for a vector catch on ARMv7, and DSCR.STATUS for ARMv8's reset and exception catch
+const MOE_VECTOR_CATCH: u32 = 0b0101 << 2;
+const STATUS_RESET_CATCH: u32 = 0b100111;
+const STATUS_EXCEPTION_CATCH: u32 = 0b110111;
+const MOE_WATCHPOINT: u32 = 0b1010 << 2;
+const STATUS_WATCHPOINT: u32 = 0b101011;
+// Watchpoint value and control registers on ARMv7 and ARMv8, see `run_control`
+const V7_WVR: u32 = 0x180;
+const V7_WCR: u32 = 0x1c0;
+const V8_WVR: u32 = 0x800;
+const V8_WCR: u32 = 0x808;
+// DBGVCR's data abort catch, in secure and non-secure state
+const VCR_DABORT: u32 = 1 << 4 | 1 << 28;
+const DSCR_STALL: u32 = 1 << 20;
+const DSCR_TXU: u32 = 1 << 26;
+const DSCR_TXFULL: u32 = 1 << 29;
+const OSLAR_KEY: u32 = 0xc5acce55;
+const EDDFR: u32 = 0xd28;
+// Six breakpoints, in the ARMv7 and ARMv8 ID registers
+const DBGDIDR_BRPS: u32 = 5 << 24;
+const EDDFR_BRPS: u32 = 5 << 12;
+const DEVTYPE: u32 = 0xfcc;
+const DEVTYPE_CORE_DEBUG: u32 = 0x15;
+const CIDR0: u32 = 0xff0;
+const CIDR3: u32 = 0xffc;
+// The CTI, its restart output enable and application pulse registers
+const CTI: u32 = 0x10000;
+const CTIOUTEN0: u32 = 0xa0;
+const CTIOUTEN1: u32 = 0xa4;
+const CTIAPPPULSE: u32 = 0x1c;
+// A CoreSight component, class 9
+const CIDR: [u32; 4] = [0x0d, 0x90, 0x05, 0xb1];
+// An APB-AP, which is a MEM-AP
+const AP_IDR: u32 = 0x44770002;
+// The simulated RTT control block has one up-buffer of RTT_SIZE bytes, after the block
+const RTT_ID: &[u8; 16] = b"SEGGER RTT\0\0\0\0\0\0";
+const RTT_BUFFER: u32 = 0x100;
+const RTT_SIZE: u32 = 1024;
+const RTT_WR_OFF: u32 = 24 + 12;
+const RTT_RD_OFF: u32 = 24 + 16;
+// The simulated mailbox's consumer index and data area, from its producer index
+const MAILBOX_CONSUMER: u32 = 4;
+const MAILBOX_DATA: u32 = 0x100;
+const MAILBOX_WORDS: u32 = 256;
+
+/// Whether `cable` names a simulator scenario rather than a real cable
+pub fn is_sim(cable: &str) -> bool {
+    cable.starts_with("sim:")
+}
+
+/// The words the simulated core sends
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Counter { start: u32, step: u32 },
+    Constant(u32),
+    Random(u64),
+    Values(Vec<u32>),
+}
+
+/// What a `SimPort` simulates, see the module docs for the file format
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scenario {
+    pub idcode: u32,
+    pub rate: f64,
+    pub pattern: Pattern,
+    pub count: Option<u64>,
+    pub duplicates: f64,
+    pub fault_rate: f64,
+    pub wait_rate: f64,
+    pub access_time: Duration,
+    pub power_down_after: Option<Duration>,
+    pub power_down_for: Duration,
+    pub abort_after: Option<Duration>,
+    pub store_addr: Option<u32>,
+    pub store_after: Vec<Duration>,
+    pub aarch64: bool,
+    pub preattached: bool,
+    pub counter: Option<u32>,
+    pub counter_hz: f64,
+    pub counter_ppm: f64,
+    /// Address of an RTT control block the words are sent to in place of DTRTX
+    pub rtt: Option<u32>,
+    /// Address of a mailbox the words are sent to in place of DTRTX
+    pub mailbox: Option<u32>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            idcode: ARM_DAP_IDCODE,
+            rate: 1000.0,
+            pattern: Pattern::Counter { start: 0, step: 1 },
+            count: None,
+            duplicates: 0.0,
+            fault_rate: 0.0,
+            wait_rate: 0.0,
+            access_time: Duration::from_micros(20),
+            power_down_after: None,
+            power_down_for: Duration::from_secs(1),
+            abort_after: None,
+            store_addr: None,
+            store_after: vec![],
+            aarch64: false,
+            preattached: false,
+            counter: None,
+            counter_hz: 24_000_000.0,
+            counter_ppm: 0.0,
+            rtt: None,
+            mailbox: None,
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, DccError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| DccError::Io(format!("read scenario {}", path.display()), e))?;
+        let table: Table = text
+            .parse()
+            .map_err(|e| DccError::InvalidConfig(format!("parse scenario {}: {}", path.display(), e)))?;
+        Self::from_table(&table).map_err(|e| DccError::InvalidConfig(format!("scenario {}: {}", path.display(), e)))
+    }
+
+    pub fn from_table(table: &Table) -> Result<Self, String> {
+        let mut scenario = Scenario::default();
+        let int = |key: &str| -> Result<Option<i64>, String> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(Value::Integer(i)) => Ok(Some(*i)),
+                Some(_) => Err(format!("{} must be an integer", key)),
+            }
+        };
+        let float = |key: &str| -> Result<Option<f64>, String> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(Value::Integer(i)) => Ok(Some(*i as f64)),
+                Some(Value::Float(f)) => Ok(Some(*f)),
+                Some(_) => Err(format!("{} must be a number", key)),
+            }
+        };
+        let duration = |key: &str| -> Result<Option<Duration>, String> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(Value::String(s)) => crate::parse_duration(s).map(Some),
+                Some(_) => Err(format!("{} must be a duration string such as \"10ms\"", key)),
+            }
+        };
+
+        if let Some(idcode) = int("idcode")? {
+            scenario.idcode = idcode as u32;
+        }
+        if let Some(rate) = float("rate")? {
+            scenario.rate = rate;
+        }
+        if let Some(count) = int("count")? {
+            scenario.count = Some(count as u64);
+        }
+        scenario.duplicates = float("duplicates")?.unwrap_or(0.0);
+        scenario.fault_rate = float("fault_rate")?.unwrap_or(0.0);
+        scenario.wait_rate = float("wait_rate")?.unwrap_or(0.0);
+        if let Some(time) = duration("access_time")? {
+            scenario.access_time = time;
+        }
+        scenario.power_down_after = duration("power_down_after")?;
+        if let Some(time) = duration("power_down_for")? {
+            scenario.power_down_for = time;
+        }
+        scenario.abort_after = duration("abort_after")?;
+        scenario.aarch64 = match table.get("aarch64") {
+            None => false,
+            Some(Value::Boolean(b)) => *b,
+            Some(_) => return Err("aarch64 must be true or false".to_string()),
+        };
+        scenario.preattached = match table.get("preattached") {
+            None => false,
+            Some(Value::Boolean(b)) => *b,
+            Some(_) => return Err("preattached must be true or false".to_string()),
+        };
+        scenario.store_addr = int("store_addr")?.map(|addr| addr as u32);
+        if let Some(times) = table.get("store_after") {
+            let times = times.as_array().ok_or("store_after must be an array of durations")?;
+            for time in times {
+                let time = time.as_str().ok_or("store_after must be an array of durations")?;
+                scenario.store_after.push(crate::parse_duration(time)?);
+            }
+            scenario.store_after.sort();
+        }
+        if let Some(addr) = int("counter")? {
+            if addr & 3 != 0 {
+                return Err("counter must be word aligned".to_string());
+            }
+            scenario.counter = Some(addr as u32);
+        }
+        if let Some(hz) = float("counter_hz")? {
+            scenario.counter_hz = hz;
+        }
+        scenario.counter_ppm = float("counter_ppm")?.unwrap_or(0.0);
+        if let Some(addr) = int("rtt")? {
+            if addr & 3 != 0 {
+                return Err("rtt must be word aligned".to_string());
+            }
+            scenario.rtt = Some(addr as u32);
+        }
+        if let Some(addr) = int("mailbox")? {
+            if addr & 3 != 0 {
+                return Err("mailbox must be word aligned".to_string());
+            }
+            if scenario.rtt.is_some() {
+                return Err("rtt and mailbox can't both be given".to_string());
+            }
+            scenario.mailbox = Some(addr as u32);
+        }
+
+        let pattern = match table.get("pattern") {
+            None => "counter",
+            Some(Value::String(s)) => s.as_str(),
+            Some(_) => return Err("pattern must be a string".to_string()),
+        };
+        scenario.pattern = match pattern {
+            "counter" => Pattern::Counter {
+                start: int("start")?.unwrap_or(0) as u32,
+                step: int("step")?.unwrap_or(1) as u32,
+            },
+            "constant" => Pattern::Constant(int("value")?.unwrap_or(0) as u32),
+            "random" => Pattern::Random(int("seed")?.unwrap_or(1).max(1) as u64),
+            "values" => {
+                let values = table
+                    .get("values")
+                    .and_then(Value::as_array)
+                    .ok_or("pattern \"values\" needs a values array")?;
+                let values: Option<Vec<u32>> = values.iter().map(|v| v.as_integer().map(|i| i as u32)).collect();
+                match values {
+                    Some(values) if !values.is_empty() => Pattern::Values(values),
+                    _ => return Err("values must be a non-empty array of integers".to_string()),
+                }
+            }
+            other => return Err(format!("unknown pattern {}", other)),
+        };
+        if scenario.rate <= 0.0 {
+            return Err("rate must be above 0".to_string());
+        }
+        Ok(scenario)
+    }
+}
+
+/// xorshift64, for the random pattern and the fault injection
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// True with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// A `DebugPort` onto a simulated core
+pub struct SimPort {
+    scenario: Scenario,
+    base: u32,
+    ap_num: u32,
+    retry: RetryPolicy,
+    clock: SharedClock,
+    /// When the scenario was opened, on `clock`
+    opened: Duration,
+    rng: Rng,
+    /// The pattern's state: the next counter value, random state or list position
+    pattern_state: u64,
+    /// Words the core has written so far
+    sent: u64,
+    /// When the core writes its next word
+    next_due: Duration,
+    /// The word waiting in DTRTX
+    tx: Option<u32>,
+    /// The last word written, what a read of an empty DTRTX returns
+    last: u32,
+    dscr: u32,
+    os_locked: bool,
+    /// EDPRSR.SPD, set by the power-down and cleared by reading EDPRSR
+    sticky_power_down: bool,
+    powered_down_once: bool,
+    vcr: u32,
+    edecr: u32,
+    eccr: u32,
+    /// Halted by reset or vector catch, with the DSCR method of entry or status bits
+    halted: Option<u32>,
+    aborted: bool,
+    /// Stores made so far, from `store_after`
+    stores: usize,
+    /// Registers the debugger changed while the core was halted
+    registers: HashMap<u32, u64>,
+    /// EDPRSR.SR, set by a reset and cleared by reading EDPRSR
+    sticky_reset: bool,
+    memory: HashMap<u32, u32>,
+    acks: AckCounts,
+}
+
+impl SimPort {
+    /// Open the scenario named by a `sim:PATH` cable
+    pub fn open(target: &Target) -> Result<Self, DccError> {
+        let path = target
+            .cable
+            .strip_prefix("sim:")
+            .ok_or_else(|| DccError::CableNotFound(target.cable.clone()))?;
+        let clock = target.clock.clone().unwrap_or_else(SystemClock::shared);
+        Ok(Self::new(
+            Scenario::load(path)?,
+            target.ap_num,
+            target.debug_base,
+            target.retry,
+            clock,
+        ))
+    }
+
+    /// Simulate `scenario`, with its times and the access times on `clock`
+    pub fn new(scenario: Scenario, ap_num: u32, debug_base: u32, retry: RetryPolicy, clock: SharedClock) -> Self {
+        let now = clock.now();
+        let pattern_state = match &scenario.pattern {
+            Pattern::Counter { start, .. } => *start as u64,
+            Pattern::Random(seed) => *seed,
+            _ => 0,
+        };
+        let mut memory = HashMap::new();
+        if let Some(addr) = scenario.rtt {
+            for (i, word) in RTT_ID.chunks_exact(4).enumerate() {
+                memory.insert(addr + i as u32 * 4, u32::from_le_bytes(word.try_into().unwrap()));
+            }
+            // One up-buffer, no down-buffers, then the up-buffer's pBuffer and SizeOfBuffer
+            memory.insert(addr + 16, 1);
+            memory.insert(addr + 24 + 4, addr + RTT_BUFFER);
+            memory.insert(addr + 24 + 8, RTT_SIZE);
+        }
+        let preattached = scenario.preattached;
+        Self {
+            rng: Rng(0x9e3779b97f4a7c15),
+            scenario,
+            base: debug_base,
+            ap_num,
+            retry,
+            clock,
+            opened: now,
+            pattern_state,
+            sent: 0,
+            next_due: now,
+            tx: None,
+            last: 0,
+            dscr: if preattached { DSCR_STALL } else { 0 },
+            // Cores come out of reset with the OS lock set
+            os_locked: !preattached,
+            sticky_power_down: false,
+            powered_down_once: false,
+            vcr: 0,
+            edecr: 0,
+            eccr: 0,
+            halted: None,
+            aborted: false,
+            stores: 0,
+            registers: HashMap::new(),
+            sticky_reset: false,
+            memory,
+            acks: AckCounts::default(),
+        }
+    }
+
+    fn powered(&mut self) -> bool {
+        let Some(after) = self.scenario.power_down_after else {
+            return true;
+        };
+        let elapsed = self.clock.since(self.opened);
+        let down = elapsed >= after && elapsed < after + self.scenario.power_down_for;
+        if down && !self.powered_down_once {
+            // Powering down loses the debug state, as on real cores
+            self.powered_down_once = true;
+            self.sticky_power_down = true;
+            self.os_locked = true;
+            self.dscr = 0;
+            self.tx = None;
+        }
+        !down
+    }
+
+    /// A warm reset: the core starts again, halting if reset catch is on
+    fn reset(&mut self) {
+        self.pattern_state = match &self.scenario.pattern {
+            Pattern::Counter { start, .. } => *start as u64,
+            Pattern::Random(seed) => *seed,
+            _ => 0,
+        };
+        self.sent = 0;
+        self.tx = None;
+        self.last = 0;
+        self.next_due = self.clock.now();
+        self.sticky_reset = true;
+        if self.dscr & DSCR_HDE != 0 {
+            if self.vcr & 1 != 0 {
+                self.halted = Some(MOE_VECTOR_CATCH);
+            } else if self.edecr & (1 << 1) != 0 {
+                self.halted = Some(STATUS_RESET_CATCH);
+            }
+        }
+    }
+
+    /// The halted core's registers: r0-r14 or x0-x30 as changed by the debugger, or else made
+    /// up
+    fn register(&self, n: u32) -> u64 {
+        self.registers.get(&n).copied().unwrap_or(n as u64 * 0x0101_0101)
+    }
+
+    /// Run an instruction written to ITR, one of those `run_control::read_registers` uses
+    fn execute(&mut self, instr: u32) {
+        let rt = instr & 0x1f;
+        let value = match instr & !0x1f {
+            MSR_DBGDTR => self.register(rt),
+            MRS_DLR => {
+                self.registers.insert(rt, 0x8000 + (self.sent % 0x100) * 4);
+                return;
+            }
+            MRS_DSPSR => {
+                self.registers.insert(rt, PSTATE_EL1H);
+                return;
+            }
+            MRS_DBGDTR => {
+                let word = |reg: u32| self.memory.get(&(self.base + reg)).copied().unwrap_or(0) as u64;
+                self.registers.insert(rt, word(DTRTX) << 32 | word(DTRRX));
+                return;
+            }
+            // The MCR as A32 or, with its halfwords swapped, T32
+            _ => match [instr, instr.rotate_left(16)].into_iter().find(|i| i & !(0xf << 12) == MCR_DTRTX) {
+                Some(mcr) => self.register(mcr >> 12 & 0xf),
+                None => return,
+            },
+        };
+        self.tx = Some(value as u32);
+        self.memory.insert(self.base + DTRRX, (value >> 32) as u32);
+    }
+
+    /// Take the scenario's abort or make its stores if they are due
+    fn events(&mut self) {
+        let elapsed = self.clock.since(self.opened);
+        while self.halted.is_none() && self.scenario.store_after.get(self.stores).is_some_and(|&at| elapsed >= at) {
+            self.stores += 1;
+            if let Some(addr) = self.scenario.store_addr {
+                self.memory.insert(addr & !3, self.sent as u32);
+                self.halted = self.watchpoint(addr);
+            }
+        }
+        if self.aborted || self.scenario.abort_after.is_none_or(|after| self.clock.since(self.opened) < after) {
+            return;
+        }
+        self.aborted = true;
+        if self.halted.is_some() || self.dscr & DSCR_HDE == 0 {
+            return;
+        }
+        if self.vcr & VCR_DABORT != 0 {
+            self.halted = Some(MOE_VECTOR_CATCH);
+        } else if self.eccr != 0 {
+            self.halted = Some(STATUS_EXCEPTION_CATCH);
+        }
+    }
+
+    /// The halt status if a watchpoint, in either architecture's registers, catches a store
+    /// to `addr`
+    fn watchpoint(&self, addr: u32) -> Option<u32> {
+        if self.dscr & DSCR_HDE == 0 {
+            return None;
+        }
+        let reg = |offset: u32| self.memory.get(&(self.base + offset)).copied().unwrap_or(0);
+        let catches = |wcr: u32, wvr: u32, bytes: u32| {
+            let bas = (wcr >> 5 & 0xff) >> (addr & (bytes - 1) & !3);
+            wcr & 1 != 0 && wcr & (0b10 << 3) != 0 && bas & 0xf != 0 && wvr == addr & !(bytes - 1)
+        };
+        (0..4).find_map(|n| {
+            if catches(reg(V7_WCR + 4 * n), reg(V7_WVR + 4 * n), 4) {
+                Some(MOE_WATCHPOINT)
+            } else if catches(reg(V8_WCR + 16 * n), reg(V8_WVR + 16 * n), 8) {
+                Some(STATUS_WATCHPOINT)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn next_word(&mut self) -> u32 {
+        if self.sent > 0 && self.rng.chance(self.scenario.duplicates) {
+            return self.last;
+        }
+        match &self.scenario.pattern {
+            Pattern::Counter { step, .. } => {
+                let word = self.pattern_state as u32;
+                self.pattern_state = word.wrapping_add(*step) as u64;
+                word
+            }
+            Pattern::Constant(value) => *value,
+            Pattern::Random(_) => {
+                let mut rng = Rng(self.pattern_state);
+                let word = rng.next() as u32;
+                self.pattern_state = rng.0;
+                word
+            }
+            Pattern::Values(values) => {
+                let word = values[self.pattern_state as usize % values.len()];
+                self.pattern_state += 1;
+                word
+            }
+        }
+    }
+
+    /// Let the core write DTRTX if it is empty and the next word is due
+    fn produce(&mut self) {
+        if self.halted.is_some()
+            || self.tx.is_some()
+            || self.scenario.rtt.is_some()
+            || self.scenario.mailbox.is_some()
+            || self.scenario.count.is_some_and(|c| self.sent >= c)
+        {
+            return;
+        }
+        let now = self.clock.now();
+        if now < self.next_due {
+            return;
+        }
+        let word = self.next_word();
+        self.tx = Some(word);
+        self.last = word;
+        self.sent += 1;
+        // A core that was held up by a full DTRTX doesn't catch up in a burst
+        self.next_due = self.next_due.max(now.saturating_sub(Duration::from_millis(1))) + Duration::from_secs_f64(1.0 / self.scenario.rate);
+    }
+
+    /// Let the core write the words that are due to its RTT up-buffer, while there is room
+    fn produce_rtt(&mut self, control_block: u32) {
+        let wr = self.memory.get(&(control_block + RTT_WR_OFF)).copied().unwrap_or(0);
+        let rd = self.memory.get(&(control_block + RTT_RD_OFF)).copied().unwrap_or(0);
+        let mut wr = (wr % RTT_SIZE) & !3;
+        let now = self.clock.now();
+        while self.halted.is_none()
+            && now >= self.next_due
+            && (wr + 4) % RTT_SIZE != rd
+            && self.scenario.count.is_none_or(|c| self.sent < c)
+        {
+            let word = self.next_word();
+            self.memory.insert(control_block + RTT_BUFFER + wr, word);
+            self.last = word;
+            self.sent += 1;
+            wr = (wr + 4) % RTT_SIZE;
+            self.next_due =
+                self.next_due.max(now.saturating_sub(Duration::from_millis(1))) + Duration::from_secs_f64(1.0 / self.scenario.rate);
+        }
+        self.memory.insert(control_block + RTT_WR_OFF, wr);
+    }
+
+    /// Let the core write the words that are due to its mailbox, while there is room
+    fn produce_mailbox(&mut self, mailbox: u32) {
+        let consumer = self.memory.get(&(mailbox + MAILBOX_CONSUMER)).copied().unwrap_or(0);
+        let mut producer = self.memory.get(&mailbox).copied().unwrap_or(0) % MAILBOX_WORDS;
+        let now = self.clock.now();
+        while self.halted.is_none()
+            && now >= self.next_due
+            && (producer + 1) % MAILBOX_WORDS != consumer
+            && self.scenario.count.is_none_or(|c| self.sent < c)
+        {
+            let word = self.next_word();
+            self.memory.insert(mailbox + MAILBOX_DATA + producer * 4, word);
+            self.last = word;
+            self.sent += 1;
+            producer = (producer + 1) % MAILBOX_WORDS;
+            self.next_due =
+                self.next_due.max(now.saturating_sub(Duration::from_millis(1))) + Duration::from_secs_f64(1.0 / self.scenario.rate);
+        }
+        self.memory.insert(mailbox, producer);
+    }
+
+    /// Injected WAITs and faults, for every access.  WAITs are retried as `JtagPort` does.
+    fn misbehave(&mut self, what: &str) -> Result<(), DccError> {
+        let mut backoff = self.retry.wait_backoff;
+        let mut retries = 0;
+        while self.rng.chance(self.scenario.wait_rate) {
+            self.acks.waits += 1;
+            if retries == self.retry.wait_retries {
+                return Err(DccError::Wait(what.to_string()));
+            }
+            self.clock.sleep(backoff);
+            backoff *= 2;
+            retries += 1;
+        }
+        if self.rng.chance(self.scenario.fault_rate) {
+            self.acks.faults += 1;
+            return Err(DccError::AccessFault(format!("{}: simulated fault", what)));
+        }
+        Ok(())
+    }
+
+    /// One DTRTX read, or `None` when stall mode held it off because DTRTX was empty
+    fn read_dtrtx(&mut self) -> Option<u32> {
+        self.produce();
+        match self.tx.take() {
+            Some(word) => Some(word),
+            None if self.dscr & DSCR_STALL != 0 => None,
+            None => {
+                self.dscr |= DSCR_TXU;
+                Some(self.last)
+            }
+        }
+    }
+
+    /// Take the time of `count` accesses to `addr` and fail them as the scenario and the core's
+    /// state dictate.  Returns whether the core is powered.
+    fn admit(&mut self, addr: u32, write: bool, count: usize) -> Result<bool, DccError> {
+        self.clock.sleep(self.scenario.access_time * count as u32);
+        let what = format!("{} 0x{:x}", if write { "write" } else { "read" }, addr);
+        self.misbehave(&what)?;
+        let powered = self.powered();
+        let reg = addr.wrapping_sub(self.base);
+        // Memory, and the registers in the debug power domain, are always accessible
+        if reg >= 0xf00 || (reg == EDPRSR && !write) {
+            return Ok(powered);
+        }
+        if !powered || (self.os_locked && reg != OSLAR) {
+            self.acks.faults += 1;
+            let state = if powered { "OS locked" } else { "powered down" };
+            return Err(DccError::AccessFault(format!("{}: core {}", what, state)));
+        }
+        Ok(true)
+    }
+
+    fn access(&mut self, addr: u32, write: Option<u32>) -> Result<u32, DccError> {
+        let powered = self.admit(addr, write.is_some(), 1)?;
+        self.events();
+        let reg = addr.wrapping_sub(self.base);
+        if reg >= 0x1000 {
+            let pulse = write.filter(|_| reg == CTI + CTIAPPPULSE).unwrap_or(0);
+            let outen = |trigger: u32| self.memory.get(&(self.base + CTI + trigger)).copied().unwrap_or(0);
+            if pulse & outen(CTIOUTEN1) != 0 {
+                self.halted = None;
+            } else if pulse & outen(CTIOUTEN0) != 0 && self.halted.is_none() {
+                self.halted = Some(STATUS_EXTERNAL_DEBUG_REQUEST);
+            }
+            if let Some(control_block) = self.scenario.rtt.filter(|&cb| write.is_none() && addr == cb + RTT_WR_OFF) {
+                self.produce_rtt(control_block);
+            }
+            if let Some(mailbox) = self.scenario.mailbox.filter(|&mailbox| write.is_none() && addr == mailbox) {
+                self.produce_mailbox(mailbox);
+            }
+            if let Some(counter) = self.scenario.counter.filter(|&counter| write.is_none() && addr & !4 == counter) {
+                let rate = self.scenario.counter_hz * (1.0 + self.scenario.counter_ppm / 1e6);
+                let ticks = (self.clock.since(self.opened).as_secs_f64() * rate) as u64;
+                return Ok(if addr == counter { ticks as u32 } else { (ticks >> 32) as u32 });
+            }
+            return Ok(match write {
+                Some(value) => {
+                    self.memory.insert(addr, value);
+                    0
+                }
+                None => self.memory.get(&addr).copied().unwrap_or(0),
+            });
+        }
+        if reg == EDPRSR && write.is_none() {
+            let mut edprsr = if powered { 1 } else { 0 };
+            if self.sticky_power_down {
+                edprsr |= 1 << 1;
+                self.sticky_power_down = !powered;
+            }
+            if self.sticky_reset {
+                edprsr |= 1 << 3;
+                self.sticky_reset = false;
+            }
+            if self.halted.is_some() {
+                edprsr |= 1 << 4;
+            }
+            if self.os_locked {
+                edprsr |= 1 << 5;
+            }
+            return Ok(edprsr);
+        }
+        match (reg, write) {
+            (DSCR, None) => {
+                self.produce();
+                let run = self.halted.map_or(DSCR_RESTARTED, |status| status | DSCR_HALTED);
+                let state = if self.scenario.aarch64 { EDSCR_AARCH64_EL1 } else { 0 };
+                Ok(self.dscr | run | state | DSCR_ITE | if self.tx.is_some() { DSCR_TXFULL } else { 0 })
+            }
+            (DSCR, Some(value)) => {
+                let writable = DSCR_STALL | DSCR_HDE;
+                self.dscr = (self.dscr & !writable) | (value & writable);
+                Ok(0)
+            }
+            (DBGVCR, None) => Ok(self.vcr),
+            (DBGVCR, Some(value)) => {
+                self.vcr = value;
+                Ok(0)
+            }
+            (EDECR, None) => Ok(self.edecr),
+            (EDECR, Some(value)) => {
+                self.edecr = value;
+                Ok(0)
+            }
+            (EDECCR, None) => Ok(self.eccr),
+            (EDECCR, Some(value)) => {
+                self.eccr = value;
+                Ok(0)
+            }
+            (EDPRCR, Some(value)) => {
+                if value & (1 << 1) != 0 {
+                    self.reset();
+                }
+                Ok(0)
+            }
+            (DTRTX, None) => Ok(self.read_dtrtx().unwrap_or(self.last)),
+            (EDRCR, Some(value)) => {
+                if value & (1 << 2) != 0 {
+                    self.dscr &= !DSCR_TXU;
+                }
+                if value & (1 << 1) != 0 {
+                    self.halted = None;
+                } else if value & 1 != 0 && self.halted.is_none() {
+                    self.halted = Some(MOE_HALT_REQUEST);
+                }
+                Ok(0)
+            }
+            (ITR, Some(instr)) if self.halted.is_some() => {
+                self.execute(instr);
+                Ok(0)
+            }
+            (OSLAR, Some(value)) => {
+                self.os_locked = value == OSLAR_KEY;
+                Ok(0)
+            }
+            (DBGDIDR, None) => Ok(DBGDIDR_BRPS),
+            (EDDFR, None) => Ok(EDDFR_BRPS),
+            (DEVTYPE, None) => Ok(DEVTYPE_CORE_DEBUG),
+            (CIDR0..=CIDR3, None) => Ok(CIDR[(reg - CIDR0) as usize / 4]),
+            (_, Some(value)) => {
+                self.memory.insert(addr, value);
+                Ok(0)
+            }
+            (_, None) => Ok(self.memory.get(&addr).copied().unwrap_or(0)),
+        }
+    }
+}
+
+impl DebugPort for SimPort {
+    fn read(&mut self, addr: u32) -> Result<u32, DccError> {
+        self.access(addr, None)
+    }
+
+    fn write(&mut self, addr: u32, value: u32) -> Result<(), DccError> {
+        self.access(addr, Some(value)).map(|_| ())
+    }
+
+    /// DTRTX reads held off by stall mode are dropped, as jtag_adi drops WAITed reads
+    fn read_repeated(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        if addr != self.base + DTRTX {
+            return (0..count).map(|_| self.read(addr)).collect();
+        }
+        // The batch is queued as one transaction, so it succeeds or fails as a whole
+        self.admit(addr, false, count)?;
+        Ok((0..count).filter_map(|_| self.read_dtrtx()).collect())
+    }
+
+    fn clear_errors(&mut self) -> Result<(), DccError> {
+        Ok(())
+    }
+
+    /// The simulated cable's speed is the scenario's `access_time`, whatever the clock
+    fn set_baud(&mut self, _baud: u32) -> Result<(), DccError> {
+        Ok(())
+    }
+
+    fn ack_counts(&self) -> AckCounts {
+        self.acks
+    }
+
+    /// The simulated DAP has the one AP, the one the target was opened on
+    fn read_ap_idr(&mut self, ap_num: u32) -> Result<Option<u32>, DccError> {
+        self.clock.sleep(self.scenario.access_time);
+        Ok(Some(if ap_num == self.ap_num { AP_IDR } else { 0 }))
+    }
+
+    fn idcode(&self) -> u32 {
+        self.scenario.idcode
+    }
+}
@@ -0,0 +1,496 @@
+//! Destinations for captured records.  Any number of sinks can be fed from one stream.
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+#[cfg(feature = "net")]
+use std::net::TcpStream;
+#[cfg(all(feature = "net", unix))]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::decode::{Frame, FrameData};
+use crate::display::ValueFormat;
+use crate::framing::Framer;
+#[cfg(unix)]
+use crate::mmap::MmapWriter;
+use crate::pingpong::{Accounting, PingPongWriter};
+use crate::stream::Record;
+
+/// How a sink writes the stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// `timestamp: value` lines
+    #[default]
+    Text,
+    /// The words alone as little-endian 32-bit binary, without timestamps or markers
+    Raw,
+    /// Comma separated trace listing in the columns of a Lauterbach TRACE32 trace, for
+    /// importing into TRACE32
+    Trace32,
+    /// The raw words as base64 text in lines of 76 characters, for text-only transports
+    Base64,
+}
+
+pub trait Sink {
+    fn write_record(&mut self, record: &Record) -> io::Result<()>;
+
+    /// Decoder output.  Frames of plain words go to `write_record`, any other kind is ignored
+    /// unless the sink knows what to do with it.
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match frame.data {
+            FrameData::Word(value) => self.write_record(&Record {
+                timestamp: frame.timestamp,
+                value,
+                interpolated: frame.interpolated,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// A batch of words read together around `timestamp`, undecoded.  Sinks that don't need
+    /// a record per word can write the batch out in one go.
+    fn write_words(&mut self, timestamp: u128, words: &[u32]) -> io::Result<()> {
+        for (i, &value) in words.iter().enumerate() {
+            self.write_record(&Record {
+                timestamp,
+                value,
+                interpolated: i != 0,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// An out-of-band event such as a trigger or pause.  Ignored unless the sink has somewhere
+    /// to put it.
+    fn write_marker(&mut self, _timestamp: u128, _msg: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The text formatting options changed, for sinks that render values as text
+    fn set_format(&mut self, _format: &ValueFormat) {}
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Flush and release the destination.  Nothing more is written afterwards.
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Open a destination by name: `-` is stdout, `tcp:host:port` and `unix:path` connect to a
+/// socket and anything else is a file to create.  Sockets need the `net` feature, and Unix
+/// sockets a Unix system; on Windows a `\\.\pipe\NAME` destination connects to a named pipe
+/// instead.  Writes are buffered, so callers should flush when they want the output seen.
+pub fn open_writer(dest: &str) -> io::Result<Box<dyn Write + Send>> {
+    if dest == "-" {
+        Ok(Box::new(BufWriter::new(io::stdout())))
+    } else if dest.starts_with("tcp:") || dest.starts_with("unix:") {
+        Ok(Box::new(BufWriter::new(connect(dest)?)))
+    } else if cfg!(windows) && dest.starts_with(r"\\.\pipe\") {
+        // The pipe's server made it, so it is opened rather than created
+        Ok(Box::new(BufWriter::new(File::options().write(true).open(dest)?)))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(dest)?)))
+    }
+}
+
+#[cfg(feature = "net")]
+fn connect(dest: &str) -> io::Result<Box<dyn Write + Send>> {
+    match dest.strip_prefix("tcp:") {
+        Some(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+        #[cfg(unix)]
+        None => Ok(Box::new(UnixStream::connect(&dest["unix:".len()..])?)),
+        #[cfg(not(unix))]
+        None => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}: Unix sockets need a Unix system, use a \\\\.\\pipe\\ name", dest),
+        )),
+    }
+}
+
+#[cfg(not(feature = "net"))]
+fn connect(dest: &str) -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{}: built without socket destinations (the net feature)", dest),
+    ))
+}
+
+/// How file destinations are written.  Stdout and sockets are always buffered.
+#[derive(Clone, Debug, Default)]
+pub enum FileWriter {
+    #[default]
+    Buffered,
+    /// Through an `MmapWriter`, which needs a Unix system
+    Mmap,
+    /// Through a `PingPongWriter` counting its stalls in the `Accounting`
+    PingPong(Accounting),
+}
+
+/// Whether text lines start with the timestamp
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Timestamps {
+    Off,
+    #[default]
+    On,
+    /// With a `~` after the timestamps that were interpolated rather than measured
+    Marked,
+}
+
+/// Open a sink on `dest` writing `kind`, with file destinations written by `writer`.
+/// `timestamps` applies to text lines.  `sync` puts sync frames in raw and base64 streams, see
+/// `framing`.
+pub fn open(
+    dest: &str,
+    kind: OutputFormat,
+    format: ValueFormat,
+    writer: FileWriter,
+    timestamps: Timestamps,
+    sync: Option<Duration>,
+) -> io::Result<Box<dyn Sink>> {
+    let is_file = dest != "-" && !dest.starts_with("tcp:") && !dest.starts_with("unix:");
+    let out: Box<dyn Write + Send> = match writer {
+        FileWriter::Mmap if is_file => mmap_writer(dest)?,
+        FileWriter::PingPong(accounting) if is_file => Box::new(PingPongWriter::create(dest)?.accounting(accounting)),
+        _ => open_writer(dest)?,
+    };
+    Ok(match kind {
+        OutputFormat::Text => Box::new(
+            TextSink::new(out, format)
+                .timestamps(timestamps != Timestamps::Off)
+                .mark_interpolated(timestamps == Timestamps::Marked),
+        ),
+        OutputFormat::Raw => Box::new(RawSink::new(out).sync(sync)),
+        OutputFormat::Trace32 => Box::new(Trace32Sink::new(out)),
+        OutputFormat::Base64 => Box::new(Base64Sink::new(out).sync(sync)),
+    })
+}
+
+#[cfg(unix)]
+fn mmap_writer(dest: &str) -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(MmapWriter::create(dest)?))
+}
+
+#[cfg(not(unix))]
+fn mmap_writer(dest: &str) -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{}: --mmap needs a Unix system", dest),
+    ))
+}
+
+/// One `timestamp: value` line per record and `timestamp: # message` per marker
+pub struct TextSink {
+    out: Box<dyn Write + Send>,
+    format: ValueFormat,
+    timestamps: bool,
+    mark_interpolated: bool,
+}
+
+impl TextSink {
+    pub fn new(out: Box<dyn Write + Send>, format: ValueFormat) -> Self {
+        Self {
+            out,
+            format,
+            timestamps: true,
+            mark_interpolated: false,
+        }
+    }
+
+    /// Whether lines start with the timestamp, on by default
+    pub fn timestamps(mut self, on: bool) -> Self {
+        self.timestamps = on;
+        self
+    }
+
+    /// Write the timestamps that were interpolated rather than measured as `TIMESTAMP~:`, off
+    /// by default
+    pub fn mark_interpolated(mut self, on: bool) -> Self {
+        self.mark_interpolated = on;
+        self
+    }
+
+    fn mark(&self, interpolated: bool) -> &'static str {
+        if self.mark_interpolated && interpolated {
+            "~"
+        } else {
+            ""
+        }
+    }
+
+    /// Text sink on the destination named by `dest`, see `open_writer`
+    pub fn open(dest: &str, format: ValueFormat) -> io::Result<Self> {
+        Ok(Self::new(open_writer(dest)?, format))
+    }
+
+    /// Buffered text sink on stdout, written out when flushed
+    pub fn stdout(format: ValueFormat) -> Self {
+        Self::new(Box::new(BufWriter::new(io::stdout())), format)
+    }
+}
+
+impl Sink for TextSink {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        if !self.timestamps {
+            return writeln!(self.out, "{}", self.format.format(record.value));
+        }
+        writeln!(
+            self.out,
+            "{}{}: {}",
+            record.timestamp,
+            self.mark(record.interpolated),
+            self.format.format(record.value)
+        )
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.timestamps {
+            return writeln!(self.out, "{}", frame.to_text(&self.format));
+        }
+        writeln!(
+            self.out,
+            "{}{}: {}",
+            frame.timestamp,
+            self.mark(frame.interpolated),
+            frame.to_text(&self.format)
+        )
+    }
+
+    fn write_marker(&mut self, timestamp: u128, msg: &str) -> io::Result<()> {
+        if !self.timestamps {
+            return writeln!(self.out, "# {}", msg);
+        }
+        writeln!(self.out, "{}: # {}", timestamp, msg)
+    }
+
+    fn set_format(&mut self, format: &ValueFormat) {
+        self.format = format.clone();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The words as little-endian binary, e.g. for a capture file to decode later
+pub struct RawSink {
+    out: Box<dyn Write + Send>,
+    framer: Option<Framer>,
+}
+
+impl RawSink {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out, framer: None }
+    }
+
+    /// Put a sync frame in the stream at least every `interval`, see `framing`
+    pub fn sync(mut self, interval: Option<Duration>) -> Self {
+        self.framer = interval.map(Framer::new);
+        self
+    }
+}
+
+impl Sink for RawSink {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.out.write_all(&framed(&mut self.framer, record.timestamp, &[record.value]))
+    }
+
+    fn write_words(&mut self, timestamp: u128, words: &[u32]) -> io::Result<()> {
+        self.out.write_all(&framed(&mut self.framer, timestamp, words))
+    }
+
+    /// Words as they are and packets as their bytes, lines and errors are dropped.  Packets
+    /// aren't framed, so they don't mix with sync frames.
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match &frame.data {
+            FrameData::Word(value) => self.out.write_all(&framed(&mut self.framer, frame.timestamp, &[*value])),
+            FrameData::Bytes(bytes) => self.out.write_all(bytes),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// A TRACE32 style trace listing: a header row, then one numbered row per record with its time
+/// in seconds, the access and the data.  Markers become `;` comment lines and decoded frames
+/// other than words are written quoted in the data column.
+pub struct Trace32Sink {
+    out: Box<dyn Write + Send>,
+    record: u64,
+    header: bool,
+}
+
+impl Trace32Sink {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out,
+            record: 0,
+            header: false,
+        }
+    }
+
+    fn row(&mut self, timestamp: u128, cycle: &str, data: &str) -> io::Result<()> {
+        if !self.header {
+            self.header = true;
+            writeln!(self.out, "record,ti.zero,cycle,data")?;
+        }
+        let record = self.record;
+        self.record += 1;
+        writeln!(
+            self.out,
+            "{},{}.{:06},{},{}",
+            record,
+            timestamp / 1_000_000,
+            timestamp % 1_000_000,
+            cycle,
+            data
+        )
+    }
+}
+
+impl Sink for Trace32Sink {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.row(record.timestamp, "rd-long", &format!("0x{:08x}", record.value))
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match frame.data {
+            FrameData::Word(value) => self.row(frame.timestamp, "rd-long", &format!("0x{:08x}", value)),
+            _ => {
+                let text = frame.to_text(&ValueFormat::default()).replace('"', "\"\"");
+                self.row(frame.timestamp, "message", &format!("\"{}\"", text))
+            }
+        }
+    }
+
+    fn write_marker(&mut self, timestamp: u128, msg: &str) -> io::Result<()> {
+        writeln!(self.out, "; {}.{:06} {}", timestamp / 1_000_000, timestamp % 1_000_000, msg)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+// Bytes per full line of 76 base64 characters
+const BASE64_LINE: usize = 57;
+
+/// What `RawSink` writes, base64 encoded.  A flush writes out the whole 3 byte groups so far
+/// as a line, even a short one, and only the close pads, so the output stays one valid stream
+/// that `base64 -d` decodes.
+pub struct Base64Sink {
+    out: Box<dyn Write + Send>,
+    // Bytes not yet encoded
+    pending: Vec<u8>,
+    framer: Option<Framer>,
+}
+
+impl Base64Sink {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out,
+            pending: vec![],
+            framer: None,
+        }
+    }
+
+    /// Put a sync frame in the stream at least every `interval`, see `framing`
+    pub fn sync(mut self, interval: Option<Duration>) -> Self {
+        self.framer = interval.map(Framer::new);
+        self
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(bytes);
+        let full = self.pending.len() - self.pending.len() % BASE64_LINE;
+        self.encode(full)
+    }
+
+    /// Write the first `len` pending bytes, `len` a multiple of 3 unless closing, in lines
+    fn encode(&mut self, len: usize) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut text = Vec::with_capacity(len.div_ceil(3) * 4 + len / BASE64_LINE + 1);
+        for line in self.pending[..len].chunks(BASE64_LINE) {
+            for group in line.chunks(3) {
+                let n = (group[0] as u32) << 16 | (*group.get(1).unwrap_or(&0) as u32) << 8 | *group.get(2).unwrap_or(&0) as u32;
+                for i in 0..4 {
+                    text.push(if i <= group.len() {
+                        BASE64[(n >> (18 - 6 * i)) as usize & 0x3f]
+                    } else {
+                        b'='
+                    });
+                }
+            }
+            text.push(b'\n');
+        }
+        self.pending.drain(..len);
+        self.out.write_all(&text)
+    }
+}
+
+impl Sink for Base64Sink {
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let bytes = framed(&mut self.framer, record.timestamp, &[record.value]).into_owned();
+        self.push(&bytes)
+    }
+
+    fn write_words(&mut self, timestamp: u128, words: &[u32]) -> io::Result<()> {
+        let bytes = framed(&mut self.framer, timestamp, words);
+        self.push(&bytes)
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match &frame.data {
+            FrameData::Word(value) => {
+                let bytes = framed(&mut self.framer, frame.timestamp, &[*value]).into_owned();
+                self.push(&bytes)
+            }
+            FrameData::Bytes(bytes) => self.push(bytes),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let whole = self.pending.len() - self.pending.len() % 3;
+        self.encode(whole)?;
+        self.out.flush()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.encode(self.pending.len())?;
+        self.out.flush()
+    }
+}
+
+/// `words` as bytes, with sync frames if `framer` is set
+fn framed<'a>(framer: &mut Option<Framer>, timestamp: u128, words: &'a [u32]) -> Cow<'a, [u8]> {
+    match framer {
+        Some(framer) => {
+            let mut bytes = Vec::with_capacity(words.len() * 4);
+            framer.encode(timestamp, words, &mut bytes);
+            Cow::Owned(bytes)
+        }
+        None => word_bytes(words),
+    }
+}
+
+/// `words` as little-endian bytes, borrowed where that's how they are already stored
+#[cfg(target_endian = "little")]
+fn word_bytes(words: &[u32]) -> Cow<'_, [u8]> {
+    // A u32 slice is always valid as bytes
+    let bytes = unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, std::mem::size_of_val(words)) };
+    Cow::Borrowed(bytes)
+}
+
+#[cfg(target_endian = "big")]
+fn word_bytes(words: &[u32]) -> Cow<'_, [u8]> {
+    Cow::Owned(words.iter().flat_map(|w| w.to_le_bytes()).collect())
+}
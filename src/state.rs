@@ -0,0 +1,178 @@
+//! The debug configuration a core was in before dcc-stream first changed it, kept in a small
+//! file per board profile so that it can be put back after any number of captures rather than
+//! each one restoring whatever the one before left.  Each core is a table named as the stats
+//! name it:
+//!
+//! ```toml
+//! ["ap1@0x80010000"]
+//! dscr = 50331650
+//! os_locked = true
+//! csw = 587202642
+//! saved = 1718000000
+//! ```
+//!
+//! `dscr` is left out if the OS lock kept it from being read, `csw` is only there for
+//! transports that can read it, and `saved` is when, in seconds since 1970.  A core's entry is kept until it is restored.
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use toml::{Table, Value};
+
+use crate::error::DccError;
+use crate::stream::DccStream;
+
+/// What `DccStream::save_state` reads and `DccStream::restore_state` puts back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebugState {
+    /// None if the OS lock kept DSCR from being read
+    pub dscr: Option<u32>,
+    pub os_locked: bool,
+    /// The debug AP's CSW, if the transport exposes it
+    pub csw: Option<u32>,
+}
+
+impl DebugState {
+    fn to_table(self) -> Table {
+        let mut table = Table::new();
+        if let Some(dscr) = self.dscr {
+            table.insert("dscr".to_string(), Value::Integer(dscr as i64));
+        }
+        table.insert("os_locked".to_string(), Value::Boolean(self.os_locked));
+        if let Some(csw) = self.csw {
+            table.insert("csw".to_string(), Value::Integer(csw as i64));
+        }
+        let saved = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        table.insert("saved".to_string(), Value::Integer(saved as i64));
+        table
+    }
+
+    fn from_table(table: &Table) -> Result<Self, String> {
+        let word = |key: &str| match table.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) if (0..=u32::MAX as i64).contains(i) => Ok(Some(*i as u32)),
+            Some(_) => Err(format!("{} must be a 32-bit number", key)),
+        };
+        Ok(Self {
+            dscr: word("dscr")?,
+            os_locked: match table.get("os_locked") {
+                Some(Value::Boolean(b)) => *b,
+                _ => return Err("os_locked must be true or false".to_string()),
+            },
+            csw: word("csw")?,
+        })
+    }
+}
+
+/// The state file of one board profile
+#[derive(Clone, Debug)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// The state file of `profile` in `dir`
+    pub fn new(dir: &Path, profile: &str) -> Self {
+        Self {
+            path: dir.join(format!("{}.toml", profile)),
+        }
+    }
+
+    /// Where state files go by default: `$XDG_STATE_HOME/dcc-stream`, falling back to
+    /// `~/.local/state/dcc-stream`, or `%LOCALAPPDATA%\dcc-stream` on Windows
+    pub fn default_dir() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            env::var_os("LOCALAPPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_STATE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        };
+        dir.map(|dir| dir.join("dcc-stream"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved state of every core, none if there is no file
+    pub fn load(&self) -> Result<BTreeMap<String, DebugState>, DccError> {
+        let fail = |e: String| DccError::InvalidConfig(format!("state file {}: {}", self.path.display(), e));
+        self.read_table()?
+            .iter()
+            .map(|(core, state)| {
+                let state = state.as_table().ok_or_else(|| fail(format!("{} must be a table", core)))?;
+                let state = DebugState::from_table(state).map_err(|e| fail(format!("{}: {}", core, e)))?;
+                Ok((core.clone(), state))
+            })
+            .collect()
+    }
+
+    /// Save `state` for `core` unless it already has an entry, which is the older and so the
+    /// one to go back to.  Returns whether it was saved.
+    pub fn save_if_new(&self, core: &str, state: DebugState) -> Result<bool, DccError> {
+        let mut table = self.read_table()?;
+        if table.contains_key(core) {
+            return Ok(false);
+        }
+        table.insert(core.to_string(), Value::Table(state.to_table()));
+        self.write_table(&table)?;
+        Ok(true)
+    }
+
+    /// Save the state `dcc` is in as `core`'s, before attaching to it, unless an earlier
+    /// capture's is still waiting to be restored.  Returns whether it was saved.
+    pub fn save(&self, core: &str, dcc: &mut DccStream) -> Result<bool, DccError> {
+        let state = dcc.save_state()?;
+        self.save_if_new(core, state)
+    }
+
+    /// Put `core`'s saved state back on `dcc` and drop it, returning it, or None if nothing
+    /// was saved for it
+    pub fn restore(&self, core: &str, dcc: &mut DccStream) -> Result<Option<DebugState>, DccError> {
+        let Some(state) = self.load()?.remove(core) else {
+            return Ok(None);
+        };
+        dcc.restore_state(&state)?;
+        self.remove(core)?;
+        Ok(Some(state))
+    }
+
+    /// Drop `core`'s entry once it is restored, and the file with the last one
+    pub fn remove(&self, core: &str) -> Result<(), DccError> {
+        let mut table = self.read_table()?;
+        if table.remove(core).is_none() {
+            return Ok(());
+        }
+        if table.is_empty() {
+            return fs::remove_file(&self.path).map_err(|e| DccError::Io(format!("remove state file {}", self.path.display()), e));
+        }
+        self.write_table(&table)
+    }
+
+    fn read_table(&self) -> Result<Table, DccError> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => text
+                .parse()
+                .map_err(|e: toml::de::Error| DccError::InvalidConfig(format!("state file {}: {}", self.path.display(), e))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Table::new()),
+            Err(e) => Err(DccError::Io(format!("read state file {}", self.path.display()), e)),
+        }
+    }
+
+    /// Replace the file in one rename, so a capture killed part way can't leave half of it
+    fn write_table(&self, table: &Table) -> Result<(), DccError> {
+        let write = || -> io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = self.path.with_extension("toml.tmp");
+            fs::write(&tmp, table.to_string())?;
+            fs::rename(&tmp, &self.path)
+        };
+        write().map_err(|e| DccError::Io(format!("write state file {}", self.path.display()), e))
+    }
+}
@@ -0,0 +1,655 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use dcc_stream::sink;
+use dcc_stream::SharedClock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// Human readable STATS: lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Open the destination for stats records.  `None` means stderr, `tcp:host:port` and
+/// `unix:path` connect to a socket, anything else is treated as a file to create.
+pub fn open_output(dest: Option<&str>) -> io::Result<Box<dyn Write>> {
+    match dest {
+        None | Some("-") => Ok(Box::new(io::stderr())),
+        Some(dest) => Ok(sink::open_writer(dest)?),
+    }
+}
+
+// Sub-buckets per power of two in a `Histogram`, so values are reported to within 12.5%
+const SUB_BUCKETS: u32 = 8;
+const LINEAR_BUCKETS: u64 = 2 * SUB_BUCKETS as u64;
+
+/// Log-scale histogram of microsecond values for percentiles without keeping every sample
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; Self::bucket(u64::MAX) + 1],
+            total: 0,
+        }
+    }
+
+    fn bucket(v: u64) -> usize {
+        if v < LINEAR_BUCKETS {
+            return v as usize;
+        }
+        let msb = 63 - v.leading_zeros();
+        let shift = msb - SUB_BUCKETS.trailing_zeros();
+        let sub = (v >> shift) as u32 & (SUB_BUCKETS - 1);
+        (LINEAR_BUCKETS as u32 + (shift - 1) * SUB_BUCKETS + sub) as usize
+    }
+
+    /// The smallest value that falls in bucket `i`
+    fn bucket_low(i: usize) -> u64 {
+        let i = i as u64;
+        if i < LINEAR_BUCKETS {
+            return i;
+        }
+        let shift = (i - LINEAR_BUCKETS) / SUB_BUCKETS as u64 + 1;
+        let sub = (i - LINEAR_BUCKETS) % SUB_BUCKETS as u64;
+        (SUB_BUCKETS as u64 + sub) << shift
+    }
+
+    pub fn record(&mut self, v: u64) {
+        self.counts[Self::bucket(v)] += 1;
+        self.total += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|c| *c = 0);
+        self.total = 0;
+    }
+
+    /// The value `p` (0 to 1) of the way through the samples, 0 if there are none
+    pub fn percentile(&self, p: f64) -> u64 {
+        let target = ((p * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Self::bucket_low(i);
+            }
+        }
+        0
+    }
+
+    fn text(&self) -> String {
+        format!("{}/{}/{}us", self.percentile(0.5), self.percentile(0.95), self.percentile(0.99))
+    }
+
+    fn json(&self) -> String {
+        format!(
+            "{{\"p50\":{},\"p95\":{},\"p99\":{}}}",
+            self.percentile(0.5),
+            self.percentile(0.95),
+            self.percentile(0.99)
+        )
+    }
+}
+
+/// A histogram for the current reporting interval and one for the whole session
+pub struct Distribution {
+    interval: Histogram,
+    session: Histogram,
+}
+
+impl Distribution {
+    fn new() -> Self {
+        Self {
+            interval: Histogram::new(),
+            session: Histogram::new(),
+        }
+    }
+
+    pub fn record(&mut self, v: u64) {
+        self.interval.record(v);
+        self.session.record(v);
+    }
+}
+
+// Shortest throughput bucket, however often stats are reported
+const MIN_BUCKET: Duration = Duration::from_millis(100);
+
+/// Counters for one core, reported separately when streaming from more than one
+pub struct CoreCounts {
+    pub name: String,
+    pub total: u64,
+    pub dup: u64,
+    pub errors: u64,
+    pub overflow: u64,
+    pub lost: u64,
+    pub overruns: u64,
+    pub rate_estimate: f64,
+}
+
+impl CoreCounts {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            total: 0,
+            dup: 0,
+            errors: 0,
+            overflow: 0,
+            lost: 0,
+            overruns: 0,
+            rate_estimate: 0.0,
+        }
+    }
+}
+
+/// Running counters for the DCC stream, reported periodically
+pub struct Stats {
+    pub total: u64,
+    pub dup: u64,
+    pub errors: u64,
+    pub reattaches: u64,
+    /// Words dropped by the output rate limit
+    pub dropped: u64,
+    /// Words the reader thread discarded because the output fell behind
+    pub overflow: u64,
+    /// Words the --sequence counter shows never arrived
+    pub lost: u64,
+    /// Health checks where DSCR showed DCC data was lost
+    pub overruns: u64,
+    /// --crc-marker checkpoints checked, and how many of those failed
+    pub checkpoints: u64,
+    pub crc_failures: u64,
+    /// --heartbeat words seen, and seconds since the last one
+    pub heartbeats: u64,
+    pub heartbeat_age: Option<f64>,
+    /// With --ping-pong, times the output files' writers waited for the disk and for how long
+    pub disk_stalls: Option<(u64, Duration)>,
+    /// WAIT acknowledgements retried by the transport
+    pub waits: u64,
+    /// FAULT acknowledgements recovered from by the transport
+    pub faults: u64,
+    /// Words per second the reader estimates the target is sending
+    pub rate_estimate: f64,
+    /// Microseconds each batch took to read from the cable
+    pub latency: Distribution,
+    /// Microseconds between one new word and the next
+    pub gap: Distribution,
+    /// Each core's share of the counters, in the order they were given
+    pub cores: Vec<CoreCounts>,
+    interval: Duration,
+    format: StatsFormat,
+    out: Box<dyn Write>,
+    /// `total` at the end of each `bucket` since the start, for the throughput over time
+    buckets: Vec<u64>,
+    bucket: Duration,
+    clock: SharedClock,
+    start: Duration,
+    last_report: Duration,
+    last_total: u64,
+}
+
+impl Stats {
+    pub fn new(interval: Duration, format: StatsFormat, out: Box<dyn Write>, clock: SharedClock) -> Self {
+        let now = clock.now();
+        Self {
+            total: 0,
+            dup: 0,
+            errors: 0,
+            reattaches: 0,
+            dropped: 0,
+            overflow: 0,
+            lost: 0,
+            overruns: 0,
+            checkpoints: 0,
+            crc_failures: 0,
+            heartbeats: 0,
+            heartbeat_age: None,
+            disk_stalls: None,
+            waits: 0,
+            faults: 0,
+            rate_estimate: 0.0,
+            latency: Distribution::new(),
+            gap: Distribution::new(),
+            cores: vec![],
+            interval,
+            format,
+            out,
+            buckets: vec![],
+            bucket: interval.max(MIN_BUCKET),
+            clock,
+            start: now,
+            last_report: now,
+            last_total: 0,
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn set_format(&mut self, format: StatsFormat) {
+        self.format = format;
+    }
+
+    /// Close off any throughput buckets that have ended, for calling regularly
+    pub fn sample(&mut self) {
+        let elapsed = self.clock.since(self.start);
+        while self.bucket * (self.buckets.len() as u32 + 1) <= elapsed {
+            self.buckets.push(self.total);
+        }
+    }
+
+    /// Words per second in each bucket so far, the last one partial
+    fn throughput(&self) -> Vec<f64> {
+        let width = self.bucket.as_secs_f64();
+        let partial = self.clock.since(self.start).as_secs_f64() - width * self.buckets.len() as f64;
+        let mut prev = 0;
+        let mut rates: Vec<f64> = self
+            .buckets
+            .iter()
+            .map(|&total| {
+                let rate = (total - prev) as f64 / width;
+                prev = total;
+                rate
+            })
+            .collect();
+        if partial > 0.0 {
+            rates.push((self.total - prev) as f64 / partial);
+        }
+        rates
+    }
+
+    /// Returns true once the reporting interval has passed since the last report
+    pub fn due(&self) -> bool {
+        self.clock.since(self.last_report) >= self.interval
+    }
+
+    fn dup_ratio(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.dup as f64 / self.total as f64
+        }
+    }
+
+    fn avg_rate(&self) -> f64 {
+        let secs = self.clock.since(self.start).as_secs_f64();
+        if secs > 0.0 {
+            self.total as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Set a core's rate estimate, keeping the overall estimate their sum
+    pub fn set_rate_estimate(&mut self, core: usize, rate: f64) {
+        if let Some(counts) = self.cores.get_mut(core) {
+            counts.rate_estimate = rate;
+            self.rate_estimate = self.cores.iter().map(|c| c.rate_estimate).sum();
+        } else {
+            self.rate_estimate = rate;
+        }
+    }
+
+    fn cores_text(&self) -> String {
+        if self.cores.len() < 2 {
+            return String::new();
+        }
+        self.cores
+            .iter()
+            .map(|c| {
+                format!(
+                    " {}: {} ({} bytes) duplicate: {} errors: {} overflow: {} lost: {} overruns: {} est: {:.0} words/s",
+                    c.name,
+                    c.total,
+                    c.total * 4,
+                    c.dup,
+                    c.errors,
+                    c.overflow,
+                    c.lost,
+                    c.overruns,
+                    c.rate_estimate
+                )
+            })
+            .collect()
+    }
+
+    fn cores_json(&self) -> String {
+        if self.cores.len() < 2 {
+            return String::new();
+        }
+        let cores: Vec<String> = self
+            .cores
+            .iter()
+            .map(|c| {
+                format!(
+                    "{{\"name\":\"{}\",\"total\":{},\"bytes\":{},\"duplicate\":{},\"errors\":{},\"overflow\":{},\"lost\":{},\"overruns\":{},\"rate_estimate\":{:.1}}}",
+                    c.name, c.total, c.total * 4, c.dup, c.errors, c.overflow, c.lost, c.overruns, c.rate_estimate
+                )
+            })
+            .collect();
+        format!(",\"cores\":[{}]", cores.join(","))
+    }
+
+    fn heartbeat_text(&self) -> String {
+        match self.heartbeat_age {
+            Some(age) => format!(" heartbeats: {} heartbeat age: {:.1}s", self.heartbeats, age),
+            None => String::new(),
+        }
+    }
+
+    fn heartbeat_json(&self) -> String {
+        match self.heartbeat_age {
+            Some(age) => format!(",\"heartbeats\":{},\"heartbeat_age\":{:.3}", self.heartbeats, age),
+            None => String::new(),
+        }
+    }
+
+    fn disk_text(&self) -> String {
+        match self.disk_stalls {
+            Some((count, time)) => format!(" disk stalls: {} ({:.1}s)", count, time.as_secs_f64()),
+            None => String::new(),
+        }
+    }
+
+    fn disk_json(&self) -> String {
+        match self.disk_stalls {
+            Some((count, time)) => format!(",\"disk_stalls\":{},\"disk_stalled\":{:.3}", count, time.as_secs_f64()),
+            None => String::new(),
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        // Losing a stats record is not worth aborting the capture over
+        let _ = writeln!(self.out, "{}", line);
+        let _ = self.out.flush();
+    }
+
+    /// Emit a stats record covering the interval since the last report
+    pub fn report(&mut self) {
+        let secs = self.clock.since(self.last_report).as_secs_f64();
+        let rate = if secs > 0.0 {
+            (self.total - self.last_total) as f64 / secs
+        } else {
+            0.0
+        };
+        let avg = self.avg_rate();
+        let line = match self.format {
+            StatsFormat::Text => format!(
+                "STATS: total: {} duplicate: {} ({:.1}%) errors: {} reattaches: {} dropped: {} overflow: {} lost: {} overruns: {} checkpoints: {} crc failures: {} waits: {} faults: {} rate: {:.0} words/s avg: {:.0} words/s est: {:.0} words/s kbps: {:.1} latency p50/p95/p99: {} gap p50/p95/p99: {}{}{}{}",
+                self.total, self.dup, self.dup_ratio() * 100.0, self.errors, self.reattaches, self.dropped, self.overflow, self.lost, self.overruns, self.checkpoints, self.crc_failures, self.waits, self.faults, rate, avg, self.rate_estimate, avg * 32.0 / 1000.0,
+                self.latency.interval.text(), self.gap.interval.text(), self.heartbeat_text(), self.disk_text(), self.cores_text()
+            ),
+            StatsFormat::Json => format!(
+                "{{\"type\":\"interval\",\"elapsed\":{:.3},\"total\":{},\"duplicate\":{},\"duplicate_ratio\":{:.4},\"errors\":{},\"reattaches\":{},\"dropped\":{},\"overflow\":{},\"lost\":{},\"overruns\":{},\"checkpoints\":{},\"crc_failures\":{},\"waits\":{},\"faults\":{},\"rate\":{:.1},\"avg_rate\":{:.1},\"rate_estimate\":{:.1},\"latency_us\":{},\"gap_us\":{}{}{}{}}}",
+                self.clock.since(self.start).as_secs_f64(), self.total, self.dup, self.dup_ratio(), self.errors, self.reattaches, self.dropped, self.overflow, self.lost, self.overruns, self.checkpoints, self.crc_failures, self.waits, self.faults, rate, avg, self.rate_estimate,
+                self.latency.interval.json(), self.gap.interval.json(), self.heartbeat_json(), self.disk_json(), self.cores_json()
+            ),
+        };
+        self.emit(line);
+        self.last_report = self.clock.now();
+        self.last_total = self.total;
+        self.latency.interval.clear();
+        self.gap.interval.clear();
+    }
+
+    /// The current counters as a JSON object, for the control API
+    pub fn snapshot(&self) -> String {
+        format!(
+            "{{\"type\":\"snapshot\",\"elapsed\":{:.3},\"total\":{},\"duplicate\":{},\"duplicate_ratio\":{:.4},\"errors\":{},\"reattaches\":{},\"dropped\":{},\"overflow\":{},\"lost\":{},\"overruns\":{},\"checkpoints\":{},\"crc_failures\":{},\"waits\":{},\"faults\":{},\"avg_rate\":{:.1},\"rate_estimate\":{:.1},\"latency_us\":{},\"gap_us\":{}{}{}{}}}",
+            self.clock.since(self.start).as_secs_f64(), self.total, self.dup, self.dup_ratio(), self.errors, self.reattaches, self.dropped, self.overflow, self.lost, self.overruns, self.checkpoints, self.crc_failures, self.waits, self.faults, self.avg_rate(), self.rate_estimate,
+            self.latency.session.json(), self.gap.session.json(), self.heartbeat_json(), self.disk_json(), self.cores_json()
+        )
+    }
+
+    /// Write the totals for the whole session and the throughput over time to `path`, as CSV
+    /// `name,value` rows if it ends in .csv and a JSON object otherwise
+    pub fn export(&self, path: &Path) -> io::Result<()> {
+        let counts = [
+            ("total", self.total),
+            ("unique", self.total - self.dup),
+            ("duplicate", self.dup),
+            ("errors", self.errors),
+            ("reattaches", self.reattaches),
+            ("dropped", self.dropped),
+            ("overflow", self.overflow),
+            ("lost", self.lost),
+            ("overruns", self.overruns),
+            ("checkpoints", self.checkpoints),
+            ("crc_failures", self.crc_failures),
+            ("waits", self.waits),
+            ("faults", self.faults),
+        ];
+        let heartbeats = self.heartbeat_age.map(|_| self.heartbeats);
+        let disk_stalls = self.disk_stalls.map(|(count, _)| count);
+        let elapsed = self.clock.since(self.start).as_secs_f64();
+        let width = self.bucket.as_secs_f64();
+        let throughput = self.throughput();
+        let text = if path.extension().is_some_and(|e| e == "csv") {
+            let mut csv = format!("name,value\nelapsed,{:.3}\n", elapsed);
+            for (name, value) in counts {
+                csv += &format!("{},{}\n", name, value);
+            }
+            if let Some(count) = heartbeats {
+                csv += &format!("heartbeats,{}\n", count);
+            }
+            if let Some(count) = disk_stalls {
+                csv += &format!("disk_stalls,{}\n", count);
+            }
+            csv += &format!("duplicate_ratio,{:.4}\navg_rate,{:.1}\n", self.dup_ratio(), self.avg_rate());
+            for (i, rate) in throughput.iter().enumerate() {
+                csv += &format!("rate_at_{}s,{:.1}\n", i as f64 * width, rate);
+            }
+            csv
+        } else {
+            let counts: String = counts
+                .iter()
+                .copied()
+                .chain(heartbeats.map(|count| ("heartbeats", count)))
+                .chain(disk_stalls.map(|count| ("disk_stalls", count)))
+                .map(|(name, value)| format!(",\"{}\":{}", name, value))
+                .collect();
+            let rates: Vec<String> = throughput.iter().map(|r| format!("{:.1}", r)).collect();
+            format!(
+                "{{\"elapsed\":{:.3}{},\"duplicate_ratio\":{:.4},\"avg_rate\":{:.1},\"bucket_secs\":{:.3},\"throughput\":[{}]{}}}\n",
+                elapsed,
+                counts,
+                self.dup_ratio(),
+                self.avg_rate(),
+                width,
+                rates.join(","),
+                self.cores_json()
+            )
+        };
+        fs::write(path, text)
+    }
+
+    /// Emit the totals for the whole session
+    pub fn summary(&mut self) {
+        let avg = self.avg_rate();
+        let elapsed = self.clock.since(self.start).as_secs_f64();
+        let line = match self.format {
+            StatsFormat::Text => format!(
+                "STATS: total: {} duplicate: {} ({:.1}%) errors: {} reattaches: {} dropped: {} overflow: {} lost: {} overruns: {} checkpoints: {} crc failures: {} waits: {} faults: {} elapsed: {:.1}s avg: {:.0} words/s kbps: {:.1} latency p50/p95/p99: {} gap p50/p95/p99: {}{}{}{}",
+                self.total, self.dup, self.dup_ratio() * 100.0, self.errors, self.reattaches, self.dropped, self.overflow, self.lost, self.overruns, self.checkpoints, self.crc_failures, self.waits, self.faults, elapsed, avg, avg * 32.0 / 1000.0,
+                self.latency.session.text(), self.gap.session.text(), self.heartbeat_text(), self.disk_text(), self.cores_text()
+            ),
+            StatsFormat::Json => self.summary_json(),
+        };
+        self.emit(line);
+    }
+
+    /// The totals for the whole session as a JSON object
+    pub fn summary_json(&self) -> String {
+        format!(
+            "{{\"type\":\"summary\",\"elapsed\":{:.3},\"total\":{},\"duplicate\":{},\"duplicate_ratio\":{:.4},\"errors\":{},\"reattaches\":{},\"dropped\":{},\"overflow\":{},\"lost\":{},\"overruns\":{},\"checkpoints\":{},\"crc_failures\":{},\"waits\":{},\"faults\":{},\"avg_rate\":{:.1},\"latency_us\":{},\"gap_us\":{}{}{}{}}}",
+            self.clock.since(self.start).as_secs_f64(), self.total, self.dup, self.dup_ratio(), self.errors, self.reattaches, self.dropped, self.overflow, self.lost, self.overruns, self.checkpoints, self.crc_failures, self.waits, self.faults, self.avg_rate(),
+            self.latency.session.json(), self.gap.session.json(), self.heartbeat_json(), self.disk_json(), self.cores_json()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use dcc_stream::VirtualClock;
+
+    /// Stats output kept for the test to read
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bucket_boundaries() {
+        // Exact below LINEAR_BUCKETS, then SUB_BUCKETS to each power of two
+        for v in 0..LINEAR_BUCKETS {
+            assert_eq!(Histogram::bucket(v), v as usize);
+            assert_eq!(Histogram::bucket_low(v as usize), v);
+        }
+        assert_eq!(Histogram::bucket(16), 16);
+        assert_eq!(Histogram::bucket(17), 16);
+        assert_eq!(Histogram::bucket(18), 17);
+        assert_eq!(Histogram::bucket(32), 24);
+        assert_eq!(Histogram::bucket_low(24), 32);
+        let last = Histogram::bucket(u64::MAX);
+        assert_eq!(Histogram::new().counts.len(), last + 1);
+        for i in LINEAR_BUCKETS as usize..last {
+            let low = Histogram::bucket_low(i);
+            let next = Histogram::bucket_low(i + 1);
+            assert_eq!(Histogram::bucket(low), i);
+            assert_eq!(Histogram::bucket(next - 1), i);
+            // Each bucket is within 12.5% of its lowest value
+            assert!(next - low <= low / SUB_BUCKETS as u64, "bucket {} is {}..{}", i, low, next);
+        }
+        assert_eq!(Histogram::bucket(Histogram::bucket_low(last)), last);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.0), 0);
+        assert_eq!(histogram.percentile(0.5), 0);
+        assert_eq!(histogram.percentile(1.0), 0);
+        assert_eq!(histogram.text(), "0/0/0us");
+
+        histogram.record(7);
+        assert_eq!(histogram.percentile(0.0), 7);
+        assert_eq!(histogram.percentile(1.0), 7);
+
+        histogram.clear();
+        for v in 1..=100 {
+            histogram.record(v);
+        }
+        assert_eq!(histogram.percentile(0.01), 1);
+        assert_eq!(histogram.percentile(0.1), 10);
+        // 50 and 95 share buckets with the values below them
+        assert_eq!(histogram.percentile(0.5), 48);
+        assert_eq!(histogram.percentile(0.95), 88);
+        assert_eq!(histogram.percentile(1.0), 96);
+        assert_eq!(histogram.json(), "{\"p50\":48,\"p95\":88,\"p99\":96}");
+    }
+
+    #[test]
+    fn stat_lines() {
+        let clock = VirtualClock::shared();
+        let out = Captured::default();
+        let mut stats = Stats::new(Duration::from_secs(1), StatsFormat::Text, Box::new(out.clone()), clock.clone());
+        assert!(!stats.due());
+        stats.total = 100;
+        stats.dup = 10;
+        stats.latency.record(5);
+        clock.sleep(Duration::from_secs(2));
+        assert!(stats.due());
+        stats.report();
+        assert!(!stats.due());
+
+        stats.set_format(StatsFormat::Json);
+        stats.heartbeats = 3;
+        stats.heartbeat_age = Some(0.5);
+        stats.report();
+
+        let lines = out.lines();
+        assert_eq!(
+            lines[0],
+            "STATS: total: 100 duplicate: 10 (10.0%) errors: 0 reattaches: 0 dropped: 0 overflow: 0 lost: 0 overruns: 0 \
+             checkpoints: 0 crc failures: 0 waits: 0 faults: 0 rate: 50 words/s avg: 50 words/s est: 0 words/s kbps: 1.6 \
+             latency p50/p95/p99: 5/5/5us gap p50/p95/p99: 0/0/0us"
+        );
+        // Nothing new since the last report, whose latencies have been cleared
+        assert_eq!(
+            lines[1],
+            "{\"type\":\"interval\",\"elapsed\":2.000,\"total\":100,\"duplicate\":10,\"duplicate_ratio\":0.1000,\"errors\":0,\
+             \"reattaches\":0,\"dropped\":0,\"overflow\":0,\"lost\":0,\"overruns\":0,\"checkpoints\":0,\"crc_failures\":0,\
+             \"waits\":0,\"faults\":0,\"rate\":0.0,\"avg_rate\":50.0,\"rate_estimate\":0.0,\"latency_us\":{\"p50\":0,\"p95\":0,\
+             \"p99\":0},\"gap_us\":{\"p50\":0,\"p95\":0,\"p99\":0},\"heartbeats\":3,\"heartbeat_age\":0.500}"
+        );
+        // The session keeps the latencies
+        assert!(stats.summary_json().contains("\"latency_us\":{\"p50\":5,\"p95\":5,\"p99\":5}"));
+    }
+
+    #[test]
+    fn export_formats() {
+        let clock = VirtualClock::shared();
+        let mut stats = Stats::new(Duration::from_secs(1), StatsFormat::Text, Box::new(io::sink()), clock.clone());
+        clock.sleep(Duration::from_secs(1));
+        stats.total = 100;
+        stats.sample();
+        clock.sleep(Duration::from_secs(1));
+        stats.total = 300;
+        stats.sample();
+
+        let dir = std::env::temp_dir();
+        let csv = dir.join(format!("dcc-stream-{}-stats.csv", std::process::id()));
+        stats.export(&csv).unwrap();
+        assert_eq!(
+            fs::read_to_string(&csv).unwrap(),
+            "name,value\nelapsed,2.000\ntotal,300\nunique,300\nduplicate,0\nerrors,0\nreattaches,0\ndropped,0\noverflow,0\n\
+             lost,0\noverruns,0\ncheckpoints,0\ncrc_failures,0\nwaits,0\nfaults,0\nduplicate_ratio,0.0000\navg_rate,150.0\n\
+             rate_at_0s,100.0\nrate_at_1s,200.0\n"
+        );
+        fs::remove_file(&csv).unwrap();
+
+        let json = dir.join(format!("dcc-stream-{}-stats.json", std::process::id()));
+        stats.export(&json).unwrap();
+        assert_eq!(
+            fs::read_to_string(&json).unwrap(),
+            "{\"elapsed\":2.000,\"total\":300,\"unique\":300,\"duplicate\":0,\"errors\":0,\"reattaches\":0,\"dropped\":0,\
+             \"overflow\":0,\"lost\":0,\"overruns\":0,\"checkpoints\":0,\"crc_failures\":0,\"waits\":0,\"faults\":0,\
+             \"duplicate_ratio\":0.0000,\"avg_rate\":150.0,\"bucket_secs\":1.000,\"throughput\":[100.0,200.0]}\n"
+        );
+        fs::remove_file(&json).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::stats::Stats;
+
+// Rates shown in the sparkline, one per redraw
+const HISTORY: usize = 24;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// One line on stderr, redrawn in place, with a sparkline of the recent throughput and the
+/// duplicate and error counts
+pub struct StatusLine {
+    rates: VecDeque<f64>,
+    last_draw: Instant,
+    last_total: u64,
+}
+
+impl StatusLine {
+    /// Returns `None` if stderr isn't a terminal
+    pub fn new() -> Option<Self> {
+        if !io::stderr().is_terminal() {
+            return None;
+        }
+        Some(Self {
+            rates: VecDeque::with_capacity(HISTORY),
+            last_draw: Instant::now(),
+            last_total: 0,
+        })
+    }
+
+    pub fn update(&mut self, stats: &Stats) {
+        let secs = self.last_draw.elapsed().as_secs_f64();
+        if secs < REDRAW_INTERVAL.as_secs_f64() {
+            return;
+        }
+        let kbps = (stats.total - self.last_total) as f64 * 32.0 / 1000.0 / secs;
+        self.last_draw = Instant::now();
+        self.last_total = stats.total;
+        if self.rates.len() == HISTORY {
+            self.rates.pop_front();
+        }
+        self.rates.push_back(kbps);
+        self.draw(stats);
+    }
+
+    fn draw(&self, stats: &Stats) {
+        // Scaled to the busiest point shown, so a steady stream is a flat line at the top
+        let max = self.rates.iter().cloned().fold(0.0, f64::max);
+        let spark: String = self
+            .rates
+            .iter()
+            .map(|&r| {
+                if max > 0.0 {
+                    BARS[((r / max) * (BARS.len() - 1) as f64).round() as usize]
+                } else {
+                    BARS[0]
+                }
+            })
+            .collect();
+        let dup = if stats.total > 0 {
+            stats.dup as f64 * 100.0 / stats.total as f64
+        } else {
+            0.0
+        };
+        let kbps = self.rates.back().copied().unwrap_or(0.0);
+        let mut stderr = io::stderr();
+        // Clear to the end of the line in case the last draw was longer
+        let _ = write!(
+            stderr,
+            "\r{:<width$} {:8.1} kbps  dup {:4.1}%  errors {}  faults {}\x1b[K",
+            spark,
+            kbps,
+            dup,
+            stats.errors,
+            stats.faults,
+            width = HISTORY
+        );
+        let _ = stderr.flush();
+    }
+
+    /// Leave the last status on its own line
+    pub fn finish(&self) {
+        eprintln!();
+    }
+}
@@ -0,0 +1,566 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::builder::Arch;
+use crate::cancel::CancelToken;
+use crate::clock::{SharedClock, SystemClock};
+use crate::error::DccError;
+use crate::event::Event;
+use crate::init::{self, Step};
+use crate::state::DebugState;
+use crate::transport::{AckCounts, DebugPort, RetryPolicy};
+
+/// Where to find the core
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// Cable name as understood by `jtag_taps::cable::new_from_string`
+    pub cable: String,
+    pub baud: u32,
+    /// Which JTAG TAP to use
+    pub tap_index: usize,
+    /// Which access port to use
+    pub ap_num: u32,
+    /// Base address of the core's external debug registers
+    pub debug_base: u32,
+    /// What to do with WAIT acknowledgements
+    pub retry: RetryPolicy,
+    /// Clock TCK from the target's RTCK where the cable can, falling back to `baud`
+    pub rtck: bool,
+    /// Time for the simulator, the wall clock if `None`
+    pub clock: Option<SharedClock>,
+}
+
+/// One word read from the DCC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Microseconds since the stream was opened.  Words from the same batch are spread evenly
+    /// over the time the batch took to read, each at the start of the read that returned it.
+    pub timestamp: u128,
+    pub value: u32,
+    /// The timestamp was spread over the batch rather than measured.  Only the word from the
+    /// first read of a batch has a measured time, that of the batch starting.
+    pub interpolated: bool,
+}
+
+type RecordCallback = Box<dyn FnMut(&Record) + Send>;
+type EventCallback = Box<dyn FnMut(&Event) + Send>;
+
+// DSCR flags for DTRTX holding a word, and the sticky DTRRX overrun and DTRTX underrun
+const DSCR_TXFULL: u32 = 1 << 29;
+// DSCR.ExtDCCmode value for stall mode
+const DSCR_STALL: u32 = 1 << 20;
+const DSCR_RXO: u32 = 1 << 27;
+const DSCR_TXU: u32 = 1 << 26;
+// The DSCR bits that streaming and run control change: ExtDCCmode and halting debug enable
+const DSCR_CONFIG: u32 = 0b11 << 20 | 1 << 14;
+
+// EDPRSR flag for the OS lock being set
+const EDPRSR_OSLK: u32 = 1 << 5;
+
+// How often EDPRSR is read while waiting for the core to power up
+const POWER_POLL: Duration = Duration::from_millis(10);
+
+/// A connection to one core's DCC
+pub struct DccStream {
+    port: Box<dyn DebugPort>,
+    base: u32,
+    arch: Arch,
+    init: Vec<Step>,
+    queue_size: usize,
+    nodups: bool,
+    txfull: bool,
+    attach_only: bool,
+    /// How long `attach` keeps trying to read DSCR, forever if `None`
+    dscr_timeout: Option<Duration>,
+    /// Records read but not yet returned by `next_record`
+    pending: VecDeque<Record>,
+    last: Option<u32>,
+    /// DSCR before the first attach, for `restore`
+    orig_dscr: Option<u32>,
+    clock: SharedClock,
+    /// When the stream was opened, on `clock`
+    opened: Duration,
+    record_callbacks: Vec<RecordCallback>,
+    event_callbacks: Vec<EventCallback>,
+}
+
+impl DccStream {
+    /// Stream from the core at `debug_base` through `port`.  The core isn't touched until
+    /// `attach`.  Use `DccStreamBuilder` to get here with a validated configuration.
+    pub(crate) fn new(port: Box<dyn DebugPort>, debug_base: u32, arch: Arch) -> Self {
+        Self {
+            port,
+            base: debug_base,
+            arch,
+            init: vec![],
+            queue_size: 16,
+            nodups: false,
+            txfull: false,
+            attach_only: false,
+            dscr_timeout: None,
+            pending: VecDeque::new(),
+            last: None,
+            orig_dscr: None,
+            clock: SystemClock::shared(),
+            opened: Duration::ZERO,
+            record_callbacks: vec![],
+            event_callbacks: vec![],
+        }
+    }
+
+    /// Call `f` with every record returned by `next_record`, `iter` and `drain`, before it is
+    /// returned.  Callbacks are called in the order they were added.
+    pub fn on_record(&mut self, f: impl FnMut(&Record) + Send + 'static) {
+        self.record_callbacks.push(Box::new(f));
+    }
+
+    /// Call `f` when the core is attached, the session is lost or DCC data is lost
+    pub fn on_event(&mut self, f: impl FnMut(&Event) + Send + 'static) {
+        self.event_callbacks.push(Box::new(f));
+    }
+
+    fn notify(&mut self, event: Event) {
+        for f in &mut self.event_callbacks {
+            f(&event);
+        }
+    }
+
+    /// The IDCODE of the debug port, normally `ARM_DAP_IDCODE`
+    pub fn idcode(&self) -> u32 {
+        self.port.idcode()
+    }
+
+    /// Where the core's debug registers are in the debug AP's address space
+    pub fn debug_base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn arch(&self) -> Arch {
+        self.arch
+    }
+
+    /// The IDR of another access port in the same DAP, see `DebugPort::read_ap_idr`
+    pub fn read_ap_idr(&mut self, ap_num: u32) -> Result<Option<u32>, DccError> {
+        self.port.read_ap_idr(ap_num)
+    }
+
+    /// Whether the cable is clocked from RTCK, which `DccStreamBuilder::rtck` asks for
+    pub fn adaptive_clocking(&self) -> bool {
+        self.port.adaptive_clocking()
+    }
+
+    /// WAIT and FAULT acknowledgements the transport has seen, see `DebugPort::ack_counts`
+    pub fn ack_counts(&self) -> AckCounts {
+        self.port.ack_counts()
+    }
+
+    /// Timestamp records by `clock`, counting from now
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.opened = clock.now();
+        self.clock = clock;
+    }
+
+    /// Steps to run before every attach, see the `init` module
+    pub fn set_init(&mut self, steps: Vec<Step>) {
+        self.init = steps;
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    /// Number of words read per batch by `iter`
+    pub fn set_queue_size(&mut self, queue_size: usize) {
+        self.queue_size = queue_size.max(1);
+    }
+
+    /// Whether `iter` skips words equal to the one before them.  Ignored with `set_txfull`,
+    /// where every word is new.
+    pub fn set_nodups(&mut self, nodups: bool) {
+        self.nodups = nodups;
+    }
+
+    /// Read DSCR with every DTRTX read and only keep the words it shows were there, instead of
+    /// relying on stall mode or repeats.  Takes effect at the next attach, since stall mode
+    /// then has to be left off.
+    pub fn set_txfull(&mut self, txfull: bool) {
+        self.txfull = txfull;
+    }
+
+    /// Give up on `attach` with a diagnostic if DSCR can't be read for `timeout`, as when the
+    /// debug base is wrong, instead of trying until cancelled
+    pub fn set_dscr_timeout(&mut self, timeout: Option<Duration>) {
+        self.dscr_timeout = timeout;
+    }
+
+    /// Leave the core as it is found: `attach` doesn't clear the OS lock or set stall mode,
+    /// overruns aren't checked since that clears their sticky bits, and `restore` only clears
+    /// the debug port's sticky errors.  For a core that another debugger or the firmware has
+    /// set up and that must not be disturbed.  Streaming then depends on how DSCR was left, so
+    /// use `set_txfull` or `set_nodups` unless it is in stall mode.
+    pub fn set_attach_only(&mut self, attach_only: bool) {
+        self.attach_only = attach_only;
+    }
+
+    /// Whether reads of DTRTX wait for the target, as we set them up
+    fn stalls(&self) -> bool {
+        self.arch.has_stall_mode() && !self.txfull && !self.attach_only
+    }
+
+    /// Run the init steps
+    pub fn run_init(&mut self) -> Result<(), DccError> {
+        let steps = std::mem::take(&mut self.init);
+        let result = init::run(self, &steps);
+        self.init = steps;
+        result
+    }
+
+    /// Read `count` consecutive words from the debug AP's address space, starting at `addr`
+    pub fn read_block(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        self.port.read_block(addr, count)
+    }
+
+    /// Read a word from the debug AP's address space
+    pub fn read_mem(&mut self, addr: u32) -> Result<u32, DccError> {
+        self.port.read(addr)
+    }
+
+    /// Write a word to the debug AP's address space
+    pub fn write_mem(&mut self, addr: u32, value: u32) -> Result<(), DccError> {
+        self.port.write(addr, value)
+    }
+
+    /// Make sure the CPU is powered up, returning EDPRSR
+    pub fn check_powered(&mut self) -> Result<u32, DccError> {
+        let edprsr = self.read_mem(self.base + 0x314)?;
+        if edprsr & 1 != 1 {
+            return Err(DccError::PoweredDown(edprsr));
+        }
+        Ok(edprsr)
+    }
+
+    pub fn clear_os_lock(&mut self) -> Result<(), DccError> {
+        self.write_mem(self.base + 0x300, 0)
+    }
+
+    pub fn read_dscr(&mut self) -> Result<u32, DccError> {
+        self.read_mem(self.base + 0x88)
+    }
+
+    /// Prepare the core for streaming: check power, clear the OS lock and enable stall mode
+    /// where the architecture has it.  Attach-only, a set OS lock is an error instead.
+    fn bring_up(&mut self, stop: &CancelToken) -> Result<(), DccError> {
+        let edprsr = self.check_powered()?;
+        if !self.attach_only {
+            self.clear_os_lock()?;
+        } else if edprsr & EDPRSR_OSLK != 0 {
+            return Err(DccError::AccessFault(
+                "the OS lock is set, and attaching only may not clear it".to_string(),
+            ));
+        }
+
+        let start = Instant::now();
+        while !stop.is_cancelled() {
+            match self.read_dscr() {
+                Ok(dscr) => {
+                    if self.stalls() {
+                        // Enable "stall" mode
+                        self.write_mem(self.base + 0x88, dscr | DSCR_STALL)?;
+                    }
+                    self.orig_dscr.get_or_insert(dscr);
+                    return Ok(());
+                }
+                Err(e) if self.dscr_timeout.is_some_and(|t| start.elapsed() >= t) => {
+                    return Err(DccError::AccessFault(format!(
+                        "DSCR at 0x{:x} still unreadable after {:?} ({}).  Check that the debug base \
+                         is right, that the core isn't held in reset and that external debug isn't \
+                         disabled by the secure state (DBGEN/SPIDEN)",
+                        self.base + 0x88,
+                        start.elapsed(),
+                        e
+                    )));
+                }
+                Err(_) => {}
+            }
+        }
+        Err(DccError::Interrupted)
+    }
+
+    /// Run the init steps and prepare the core for streaming.  Gives up with `Interrupted` if
+    /// `stop` is cancelled while waiting for DSCR to become readable, or after the
+    /// `set_dscr_timeout` timeout.
+    pub fn attach(&mut self, stop: &CancelToken) -> Result<(), DccError> {
+        self.run_init()?;
+        self.bring_up(stop)?;
+        self.notify(Event::Attached);
+        Ok(())
+    }
+
+    /// `attach`, but if the core is powered down, e.g. in an idle state, wait for it to power up
+    /// instead of failing.  `Event::PoweredDown` is sent once when the wait starts.
+    pub fn attach_powered(&mut self, stop: &CancelToken) -> Result<(), DccError> {
+        let mut waiting = false;
+        loop {
+            match self.attach(stop) {
+                Err(DccError::PoweredDown(edprsr)) if !stop.is_cancelled() => {
+                    if !waiting {
+                        self.notify(Event::PoweredDown(edprsr));
+                        waiting = true;
+                    }
+                    thread::sleep(POWER_POLL);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether EDPRSR shows the core powered down right now.  A failed read counts as powered
+    /// up, since the fault is then elsewhere.
+    pub fn powered_down(&mut self) -> bool {
+        matches!(self.check_powered(), Err(DccError::PoweredDown(_)))
+    }
+
+    /// Whether EDPRSR shows the OS lock set, e.g. by the OS after a warm reset.  A failed read
+    /// counts as unlocked.
+    pub fn os_locked(&mut self) -> bool {
+        matches!(self.read_mem(self.base + 0x314), Ok(edprsr) if edprsr & EDPRSR_OSLK != 0)
+    }
+
+    /// Read up to `count` words from DTRTX.  With `set_txfull` only the words the target had
+    /// written are returned, so there may be fewer.
+    pub fn read(&mut self, count: usize) -> Result<Vec<u32>, DccError> {
+        if !self.txfull {
+            return self.port.read_repeated(self.base + 0x8c, count);
+        }
+        Ok(self.read_slots(count)?.into_iter().map(|(_, value)| value).collect())
+    }
+
+    /// As `read`, with which of the `count` reads returned each word.  They only have gaps
+    /// with `set_txfull`, where the reads that found DTRTX empty return nothing.
+    pub fn read_slots(&mut self, count: usize) -> Result<Vec<(usize, u32)>, DccError> {
+        if !self.txfull {
+            return Ok(self.port.read_repeated(self.base + 0x8c, count)?.into_iter().enumerate().collect());
+        }
+        // A word written between the two reads of a pair is dropped with DTRTX; the window is a
+        // single scan so it is tolerated
+        let pairs = self.port.read_pairs(self.base + 0x88, self.base + 0x8c, count)?;
+        Ok(pairs
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (dscr, _))| dscr & DSCR_TXFULL != 0)
+            .map(|(slot, (_, value))| (slot, value))
+            .collect())
+    }
+
+    /// Read up to `count` words from DTRTX along with when they arrived
+    pub fn read_records(&mut self, count: usize) -> Result<Vec<Record>, DccError> {
+        let start = self.clock.since(self.opened).as_micros();
+        let words = self.read_slots(count)?;
+        let delta = self.clock.since(self.opened).as_micros() - start;
+        // Without --txfull a read returns a word however few the target sent
+        let n = if self.txfull { count } else { words.len() } as u128;
+        Ok(words
+            .into_iter()
+            .map(|(slot, value)| Record {
+                timestamp: start + delta * slot as u128 / n,
+                value,
+                interpolated: slot != 0,
+            })
+            .collect())
+    }
+
+    /// Return the next record, reading the configured queue size at a time and skipping
+    /// duplicates if asked to.  Blocks until there is one or a read fails.
+    pub fn next_record(&mut self) -> Result<Record, DccError> {
+        loop {
+            if let Some(record) = self.take_pending() {
+                return Ok(record);
+            }
+            let records = self.read_records(self.queue_size)?;
+            self.pending.extend(records);
+        }
+    }
+
+    /// The next pending record that isn't a duplicate we've been asked to skip
+    fn take_pending(&mut self) -> Option<Record> {
+        while let Some(record) = self.pending.pop_front() {
+            let dup = !self.txfull && self.last == Some(record.value);
+            self.last = Some(record.value);
+            if !(dup && self.nodups) {
+                for f in &mut self.record_callbacks {
+                    f(&record);
+                }
+                return Some(record);
+            }
+        }
+        None
+    }
+
+    /// Take the records already read but not yet returned by `next_record`, for shutting down
+    /// without losing the rest of the last batch
+    pub fn drain(&mut self) -> Vec<Record> {
+        std::iter::from_fn(|| self.take_pending()).collect()
+    }
+
+    /// Blocking iterator over `next_record`.  It never ends by itself; failed reads are
+    /// yielded as errors and reading carries on with the next batch.  Records already read
+    /// carry over from one iterator to the next.
+    ///
+    /// ```no_run
+    /// # fn example(dcc: &mut dcc_stream::DccStream) {
+    /// for record in dcc.iter().take(1000) {
+    ///     match record {
+    ///         Ok(record) => println!("{}: {:x}", record.timestamp, record.value),
+    ///         Err(e) => eprintln!("{}", e),
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn iter(&mut self) -> Records<'_> {
+        Records { dcc: self }
+    }
+
+    /// Clear any sticky error flags in the debug port left behind by a faulted transaction
+    pub fn clear_sticky(&mut self) -> Result<(), DccError> {
+        self.port.clear_errors()
+    }
+
+    /// Reset the debug transport after repeated failures, see `DebugPort::reinit`.  The core
+    /// is left as it is.
+    pub fn reinit(&mut self) -> Result<(), DccError> {
+        self.port.reinit()
+    }
+
+    /// Change TCK while attached, see `DebugPort::set_baud`.  Other cores on the cable follow.
+    pub fn set_baud(&mut self, baud: u32) -> Result<(), DccError> {
+        self.port.set_baud(baud)
+    }
+
+    /// Check EDPRSR for signs that the core was powered down or reset since the last check.
+    /// The sticky bits are cleared by the check.  See `check_overrun` for lost DCC data.
+    pub fn session_lost(&mut self) -> Option<String> {
+        let reason = match self.read_mem(self.base + 0x314) {
+            Err(e) => Some(format!("EDPRSR read failed: {}", e)),
+            Ok(edprsr) if edprsr & 1 == 0 => Some("core powered down".to_string()),
+            Ok(edprsr) if edprsr & (1 << 1) != 0 => Some("core was powered down".to_string()),
+            Ok(edprsr) if edprsr & (1 << 3) != 0 => Some("core was reset".to_string()),
+            Ok(_) => None,
+        };
+        if let Some(reason) = &reason {
+            self.notify(Event::SessionLost(reason.clone()));
+        }
+        reason
+    }
+
+    /// Check DSCR for DCC data lost since the last check and clear the sticky bits, returning
+    /// the DSCR that showed it if any was.  Without stall mode every read of an empty DTRTX
+    /// counts as an underrun, so only overruns are reported there.
+    pub fn check_overrun(&mut self) -> Result<Option<u32>, DccError> {
+        if self.attach_only {
+            return Ok(None);
+        }
+        let dscr = self.read_dscr()?;
+        let mask = if self.stalls() { DSCR_RXO | DSCR_TXU } else { DSCR_RXO };
+        if dscr & (DSCR_RXO | DSCR_TXU) != 0 {
+            // EDRCR.CSE clears the TXU, RXO and ERR sticky bits
+            self.write_mem(self.base + 0x90, 1 << 2)?;
+        }
+        if dscr & mask == 0 {
+            return Ok(None);
+        }
+        self.notify(Event::Overrun(dscr));
+        Ok(Some(dscr))
+    }
+
+    /// Check that DSCR still has stall mode set where it is used, and set it again if firmware
+    /// or a reset cleared it.  Returns the DSCR found if it had to be.
+    pub fn check_config(&mut self) -> Result<Option<u32>, DccError> {
+        if !self.stalls() {
+            return Ok(None);
+        }
+        let dscr = self.read_dscr()?;
+        if dscr & DSCR_STALL != 0 {
+            return Ok(None);
+        }
+        self.write_mem(self.base + 0x88, dscr | DSCR_STALL)?;
+        self.notify(Event::Reconfigured(dscr));
+        Ok(Some(dscr))
+    }
+
+    /// Repeat the attach until it succeeds or `stop` is cancelled
+    pub fn reattach(&mut self, stop: &CancelToken) {
+        while !stop.is_cancelled() {
+            let _ = self.clear_sticky();
+            // A reset may have undone the init script
+            if self.attach(stop).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Undo the attach: put the stall bit back how we found it, clear the DCC sticky errors and
+    /// optionally set the OS lock again
+    pub fn restore(&mut self, relock: bool) -> Result<(), DccError> {
+        if self.attach_only {
+            return self.clear_sticky();
+        }
+        let base = self.base;
+        if let Some(orig) = self.orig_dscr.filter(|_| self.stalls()) {
+            let dscr = self.read_dscr()?;
+            let dscr = (dscr & !DSCR_STALL) | (orig & DSCR_STALL);
+            self.write_mem(base + 0x88, dscr)?;
+        }
+
+        // EDRCR.CSE clears the TXU, RXO and ERR sticky bits
+        self.write_mem(base + 0x90, 1 << 2)?;
+
+        if relock {
+            self.write_mem(base + 0x300, 0xc5acce55)?;
+        }
+        self.clear_sticky()
+    }
+
+    /// Read the configuration that attaching changes, for `restore_state` to put back later.
+    /// Needs the core powered.
+    pub fn save_state(&mut self) -> Result<DebugState, DccError> {
+        let edprsr = self.check_powered()?;
+        let os_locked = edprsr & EDPRSR_OSLK != 0;
+        Ok(DebugState {
+            dscr: if os_locked { None } else { Some(self.read_dscr()?) },
+            os_locked,
+            csw: self.port.read_csw()?,
+        })
+    }
+
+    /// Put back a state from `save_state`: DSCR's stall mode and halting debug enable bits,
+    /// then the OS lock and last the debug AP's CSW, which the accesses before it rely on
+    pub fn restore_state(&mut self, state: &DebugState) -> Result<(), DccError> {
+        if let Some(saved) = state.dscr {
+            if self.os_locked() {
+                self.clear_os_lock()?;
+            }
+            let dscr = self.read_dscr()?;
+            self.write_mem(self.base + 0x88, (dscr & !DSCR_CONFIG) | (saved & DSCR_CONFIG))?;
+        }
+        self.write_mem(self.base + 0x300, if state.os_locked { 0xc5acce55 } else { 0 })?;
+        if let Some(csw) = state.csw {
+            self.port.write_csw(csw)?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator returned by `DccStream::iter`
+pub struct Records<'a> {
+    dcc: &'a mut DccStream,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<Record, DccError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.dcc.next_record())
+    }
+}
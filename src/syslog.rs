@@ -0,0 +1,52 @@
+//! Minimal syslog client for daemon mode.  Messages go to /dev/log, which is also where the
+//! systemd journal listens for syslog traffic.  There is no daemon mode on Windows, so it is
+//! never enabled there.
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+#[cfg(unix)]
+static SOCKET: OnceLock<UnixDatagram> = OnceLock::new();
+
+// LOG_DAEMON
+const FACILITY: u8 = 3;
+
+#[derive(Clone, Copy)]
+pub enum Severity {
+    Err = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+/// Start sending log messages to syslog instead of stderr
+#[cfg(unix)]
+pub fn open() -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect("/dev/log")?;
+    let _ = SOCKET.set(socket);
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn enabled() -> bool {
+    SOCKET.get().is_some()
+}
+
+#[cfg(not(unix))]
+pub fn enabled() -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub fn log(severity: Severity, msg: &str) {
+    if let Some(socket) = SOCKET.get() {
+        let line = format!("<{}>dcc-stream[{}]: {}", FACILITY * 8 + severity as u8, std::process::id(), msg);
+        let _ = socket.send(line.as_bytes());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn log(_severity: Severity, _msg: &str) {}
@@ -0,0 +1,78 @@
+//! --target-clock: read the target's memory-mapped generic timer counter now and then and keep
+//! a mapping from host time to target time, so the capture can be correlated with trace the
+//! target timestamps itself.  The counter is sampled while the core runs, unlike CNTVCT
+//! through instruction injection, which would need it halted.
+use dcc_stream::{DccError, DccStream};
+
+/// Read the 64-bit counter CNTCV at `addr`, low word first.  The high word is read either side
+/// of the low one, and again if it moved.
+pub fn read_counter(dcc: &mut DccStream, addr: u32) -> Result<u64, DccError> {
+    loop {
+        let hi = dcc.read_mem(addr + 4)?;
+        let lo = dcc.read_mem(addr)?;
+        if dcc.read_mem(addr + 4)? == hi {
+            return Ok((hi as u64) << 32 | lo as u64);
+        }
+    }
+}
+
+/// Maps microseconds since the capture started to microseconds of target counter time, going
+/// by the samples so far: the latest one for the offset, and the rate between it and the
+/// first once they are far enough apart to measure drift.
+pub struct TargetClock {
+    hz: f64,
+    first: Option<(u128, u64)>,
+    last: Option<(u128, u64)>,
+    /// The last time mapped, so a new sample never takes the output back in time
+    mapped: u128,
+}
+
+/// How far apart the samples must be before their rate is used in place of --target-clock-hz
+const MIN_SPAN_US: u128 = 1_000_000;
+
+impl TargetClock {
+    pub fn new(hz: u64) -> Self {
+        Self {
+            hz: hz as f64,
+            first: None,
+            last: None,
+            mapped: 0,
+        }
+    }
+
+    /// The counter read `ticks` at `host`
+    pub fn sample(&mut self, host: u128, ticks: u64) {
+        self.first.get_or_insert((host, ticks));
+        self.last = Some((host, ticks));
+    }
+
+    /// Whether there has been a sample yet
+    pub fn sampled(&self) -> bool {
+        self.last.is_some()
+    }
+
+    /// Counter ticks per host microsecond
+    fn rate(&self) -> f64 {
+        match (self.first, self.last) {
+            (Some((h0, t0)), Some((h1, t1))) if h1 - h0 >= MIN_SPAN_US => t1.wrapping_sub(t0) as f64 / (h1 - h0) as f64,
+            _ => self.hz / 1e6,
+        }
+    }
+
+    /// How far the target clock runs from the host's, in parts per million, once measured
+    pub fn drift_ppm(&self) -> Option<f64> {
+        match (self.first, self.last) {
+            (Some((h0, _)), Some((h1, _))) if h1 - h0 >= MIN_SPAN_US => Some((self.rate() * 1e6 / self.hz - 1.0) * 1e6),
+            _ => None,
+        }
+    }
+
+    /// `host` in target time, or None before the first sample
+    pub fn map(&mut self, host: u128) -> Option<u128> {
+        let (h1, t1) = self.last?;
+        let ticks = t1 as f64 + (host as f64 - h1 as f64) * self.rate();
+        let us = (ticks.max(0.0) * 1e6 / self.hz) as u128;
+        self.mapped = self.mapped.max(us);
+        Some(self.mapped)
+    }
+}
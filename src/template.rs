@@ -0,0 +1,170 @@
+//! Output path templates, so an unattended rig names each capture file by itself, e.g.
+//! `cap-{profile}-{date}-{seq}.bin`:
+//!
+//! - `{profile}` is the name of the --config file without its extension, or "default"
+//! - `{date}` and `{time}` are when the file is opened, as YYYYMMDD and HHMMSS in UTC
+//! - `{seq}` is the lowest number from 001 that makes a path that doesn't exist yet
+//!
+//! `{{` and `}}` stand for a literal brace.
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether `dest` has anything to expand
+pub fn is_template(dest: &str) -> bool {
+    dest.contains('{') || dest.contains('}')
+}
+
+/// The board profile a capture run with `config` is for: the file's name without its
+/// extension, or "default"
+pub fn profile(config: Option<&Path>) -> String {
+    config
+        .and_then(Path::file_stem)
+        .map_or("default".to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+/// Expand `template` for a capture run with `config`
+pub fn expand(template: &str, config: Option<&Path>) -> Result<String, String> {
+    let profile = profile(config);
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_date(secs / 86400);
+    let time = secs % 86400;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!("{:02}{:02}{:02}", time / 3600, time / 60 % 60, time % 60);
+    let fill = |seq: Option<u32>| substitute(template, &profile, &date, &time, seq);
+    let (path, has_seq) = fill(None)?;
+    if !has_seq {
+        return Ok(path);
+    }
+    for seq in 1..=999_999 {
+        let (path, _) = fill(Some(seq))?;
+        if !Path::new(&path).exists() {
+            return Ok(path);
+        }
+    }
+    Err(format!("{}: every {{seq}} is taken", template))
+}
+
+/// The template filled in, and whether it has a `{seq}`.  With `seq` None, `{seq}` is left
+/// empty.
+fn substitute(template: &str, profile: &str, date: &str, time: &str, seq: Option<u32>) -> Result<(String, bool), String> {
+    let mut out = String::new();
+    let mut has_seq = false;
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            out.push_str(&rest[..1]);
+            rest = after;
+            continue;
+        }
+        let end = match rest.find('}') {
+            Some(end) if rest.starts_with('{') => end,
+            _ => return Err(format!("{}: unmatched brace, use {{{{ or }}}} for a literal one", template)),
+        };
+        match &rest[1..end] {
+            "profile" => out.push_str(profile),
+            "date" => out.push_str(date),
+            "time" => out.push_str(time),
+            "seq" => {
+                has_seq = true;
+                if let Some(seq) = seq {
+                    out.push_str(&format!("{:03}", seq));
+                }
+            }
+            name => {
+                return Err(format!(
+                    "{}: unknown field {{{}}}, expected {{profile}}, {{date}}, {{time}} or {{seq}}",
+                    template, name
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok((out, has_seq))
+}
+
+/// Year, month and day of `days` since 1970-01-01, in the proleptic Gregorian calendar
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Counted from 0000-03-01, so the leap day ends each 400-year era's years
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn fill(template: &str, seq: Option<u32>) -> Result<(String, bool), String> {
+        substitute(template, "board", "20260314", "015926", seq)
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            fill("cap-{profile}-{date}-{time}.bin", None),
+            Ok(("cap-board-20260314-015926.bin".to_string(), false))
+        );
+        assert_eq!(fill("{seq}-{seq}", Some(7)), Ok(("007-007".to_string(), true)));
+        assert_eq!(fill("cap{seq}", None), Ok(("cap".to_string(), true)));
+        assert_eq!(fill("cap{seq}", Some(1234)), Ok(("cap1234".to_string(), true)));
+        assert_eq!(profile(Some(Path::new("/etc/dcc/rig-a.toml"))), "rig-a");
+        assert_eq!(profile(None), "default");
+    }
+
+    #[test]
+    fn unknown_placeholders() {
+        assert_eq!(
+            fill("cap-{board}.bin", None),
+            Err("cap-{board}.bin: unknown field {board}, expected {profile}, {date}, {time} or {seq}".to_string())
+        );
+        assert_eq!(
+            fill("{}", None),
+            Err("{}: unknown field {}, expected {profile}, {date}, {time} or {seq}".to_string())
+        );
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(fill("{{seq}}-{{{date}}}", None), Ok(("{seq}-{20260314}".to_string(), false)));
+        assert!(is_template("a}}b"));
+        assert!(!is_template("cap.bin"));
+        for template in ["cap-{date", "cap-}", "cap-{date}x{"] {
+            let unmatched = format!("{}: unmatched brace, use {{{{ or }}}} for a literal one", template);
+            assert_eq!(fill(template, None), Err(unmatched), "{}", template);
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(59), (1970, 3, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(11_017), (2000, 3, 1));
+        assert_eq!(civil_date(20_526), (2026, 3, 14));
+        assert_eq!(civil_date(47_540), (2100, 2, 28));
+        assert_eq!(civil_date(47_541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn first_free_seq() {
+        let dir = std::env::temp_dir().join(format!("dcc-stream-{}-template", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let template = format!("{}/cap-{{seq}}.bin", dir.display());
+        fs::write(dir.join("cap-001.bin"), b"").unwrap();
+        fs::write(dir.join("cap-002.bin"), b"").unwrap();
+        assert_eq!(expand(&template, None), Ok(format!("{}/cap-003.bin", dir.display())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
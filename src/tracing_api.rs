@@ -0,0 +1,94 @@
+//! `tracing` events for host applications that already route their logs through tracing
+//! subscribers, behind the `tracing` feature.
+//!
+//! Every record becomes an event with target `dcc_stream` and fields `core`, `channel`,
+//! `timestamp`, `interpolated` and `value`, so the usual filters, e.g. `dcc_stream=info`, and exporters
+//! apply.  The DCC is channel 0; RTT captures use the up-buffer number.
+//!
+//! ```no_run
+//! use dcc_stream::{tracing_api, CancelToken, DccStreamBuilder};
+//!
+//! let stop = CancelToken::new();
+//! let mut dcc = DccStreamBuilder::new("jlink", 0x80010000).build()?;
+//! tracing_api::emit(&mut dcc, 0);
+//! dcc.attach(&stop)?;
+//! for record in dcc.iter().take(100) {
+//!     record?;
+//! }
+//! dcc.restore(false)?;
+//! # Ok::<(), dcc_stream::DccError>(())
+//! ```
+use tracing::Level;
+
+use crate::decode::{Frame, FrameData};
+use crate::event::Event;
+use crate::stream::{DccStream, Record};
+
+/// The target of every event emitted here
+pub const TARGET: &str = "dcc_stream";
+
+/// Emit every record `dcc` returns, as being from `core`, and its session events: attaching at
+/// info level, lost sessions, overruns and the like at warn
+pub fn emit(dcc: &mut DccStream, core: u32) {
+    dcc.on_record(move |r| record(core, 0, r));
+    dcc.on_event(move |e| event(core, e));
+}
+
+/// Emit one record read from `channel` of `core`
+pub fn record(core: u32, channel: u32, record: &Record) {
+    tracing::event!(
+        target: TARGET,
+        Level::INFO,
+        core,
+        channel,
+        timestamp = record.timestamp as u64,
+        interpolated = record.interpolated,
+        value = record.value,
+        "dcc record"
+    );
+}
+
+/// Emit a decoded frame read from `channel` of `core`.  Words are the `value` field as with
+/// `record`, text and packets the `text` and `bytes` fields, and frames from one thread of the
+/// target add `thread` and `contextidr`.  Frames the decoder couldn't make sense of are warnings.
+pub fn frame(core: u32, channel: u32, frame: &Frame) {
+    let timestamp = frame.timestamp as u64;
+    let (data, thread) = match &frame.data {
+        FrameData::Context { thread, contextidr, data } => (&**data, Some((*thread, *contextidr))),
+        data => (data, None),
+    };
+    let (thread, contextidr) = (thread.map(|t| t.0), thread.map(|t| t.1));
+    match data {
+        FrameData::Word(value) => {
+            tracing::event!(target: TARGET, Level::INFO, core, channel, timestamp, thread, contextidr, value, "dcc record")
+        }
+        FrameData::Text(text) => {
+            tracing::event!(target: TARGET, Level::INFO, core, channel, timestamp, thread, contextidr, text = %text, "dcc text")
+        }
+        FrameData::Bytes(bytes) => {
+            tracing::event!(target: TARGET, Level::INFO, core, channel, timestamp, thread, contextidr, bytes = ?bytes, "dcc packet")
+        }
+        FrameData::Invalid(msg) => {
+            tracing::event!(target: TARGET, Level::WARN, core, channel, timestamp, thread, contextidr, error = %msg, "dcc decode error")
+        }
+        // Contexts don't nest
+        FrameData::Context { .. } => {}
+    }
+}
+
+/// Emit a session event of `core`
+pub fn event(core: u32, event: &Event) {
+    match event {
+        Event::Attached => tracing::event!(target: TARGET, Level::INFO, core, "attached"),
+        Event::SessionLost(reason) => tracing::event!(target: TARGET, Level::WARN, core, reason = %reason, "session lost"),
+        Event::PoweredDown(edprsr) => {
+            tracing::event!(target: TARGET, Level::WARN, core, edprsr = format_args!("{:#x}", edprsr), "core powered down")
+        }
+        Event::Reconfigured(dscr) => {
+            tracing::event!(target: TARGET, Level::WARN, core, dscr = format_args!("{:#x}", dscr), "stall mode cleared, set again")
+        }
+        Event::Overrun(dscr) => {
+            tracing::event!(target: TARGET, Level::WARN, core, dscr = format_args!("{:#x}", dscr), "dcc data lost")
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! The debug access a `DccStream` needs, so that it can run over something other than
+//! jtag_adi, e.g. another probe library or a simulator.  `jtag::JtagPort` is the built-in
+//! implementation.
+use std::time::Duration;
+
+use crate::error::DccError;
+
+/// How WAIT acknowledgements from the DAP are retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Times a transaction answered with WAIT is retried before giving up with `DccError::Wait`
+    pub wait_retries: u32,
+    /// Sleep before the first retry, doubled for each one after it
+    pub wait_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            wait_retries: 4,
+            wait_backoff: Duration::from_micros(100),
+        }
+    }
+}
+
+/// Acknowledgements other than OK seen by a `DebugPort`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AckCounts {
+    pub waits: u64,
+    pub faults: u64,
+}
+
+/// Word accesses to the address space of the core's debug access port
+pub trait DebugPort {
+    fn read(&mut self, addr: u32) -> Result<u32, DccError>;
+
+    fn write(&mut self, addr: u32, value: u32) -> Result<(), DccError>;
+
+    /// Read `addr` up to `count` times in a row, as a FIFO.  Backends that can queue the reads
+    /// should, since this is how the DCC is drained.
+    fn read_repeated(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        (0..count).map(|_| self.read(addr)).collect()
+    }
+
+    /// Read `count` consecutive words starting at `addr`, as from RAM
+    fn read_block(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DccError> {
+        (0..count as u32).map(|i| self.read(addr + i * 4)).collect()
+    }
+
+    /// Read `first` then `second`, `count` times over, returning the pairs of values.  Used to
+    /// read DSCR alongside each DTRTX read.
+    fn read_pairs(&mut self, first: u32, second: u32, count: usize) -> Result<Vec<(u32, u32)>, DccError> {
+        (0..count).map(|_| Ok((self.read(first)?, self.read(second)?))).collect()
+    }
+
+    /// Clear sticky error flags left behind by a faulted access
+    fn clear_errors(&mut self) -> Result<(), DccError> {
+        Ok(())
+    }
+
+    /// Get the transport back into a known state after repeated failures, without touching
+    /// the core.  By default only the sticky errors are cleared.
+    fn reinit(&mut self) -> Result<(), DccError> {
+        self.clear_errors()
+    }
+
+    /// The identification register of access port `ap_num`, 0 where there is no AP, or `None`
+    /// from backends that can only reach their own AP
+    fn read_ap_idr(&mut self, _ap_num: u32) -> Result<Option<u32>, DccError> {
+        Ok(None)
+    }
+
+    /// Change TCK to `baud` without touching the core, for every port on the cable.  Backends
+    /// whose clock is fixed refuse.
+    fn set_baud(&mut self, _baud: u32) -> Result<(), DccError> {
+        Err(DccError::Other("this transport's clock can't be changed".to_string()))
+    }
+
+    /// The control and status word of the port's own access port, or `None` from backends
+    /// that don't expose it
+    fn read_csw(&mut self) -> Result<Option<u32>, DccError> {
+        Ok(None)
+    }
+
+    /// Set the port's access port CSW, as read by `read_csw`.  Ignored by backends that don't
+    /// expose it.
+    fn write_csw(&mut self, _csw: u32) -> Result<(), DccError> {
+        Ok(())
+    }
+
+    /// WAIT and FAULT acknowledgements seen so far, for backends that see them
+    fn ack_counts(&self) -> AckCounts {
+        AckCounts::default()
+    }
+
+    /// Whether TCK follows the target's RTCK rather than a fixed rate
+    fn adaptive_clocking(&self) -> bool {
+        false
+    }
+
+    /// Identification of the debug port, `ARM_DAP_IDCODE` for an ARM JTAG-DP
+    fn idcode(&self) -> u32;
+}
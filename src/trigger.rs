@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+
+use dcc_stream::Record;
+
+/// Holds back output until a sentinel word is seen in the stream, remembering the most recent
+/// words so the lead-up to the trigger can still be output
+pub struct StartTrigger {
+    word: Option<u32>,
+    include: bool,
+    fired: bool,
+    pre: usize,
+    history: VecDeque<Record>,
+    /// Waiting for `fire` rather than a word
+    external: bool,
+}
+
+/// What to do with a word while a start trigger is configured
+#[derive(Debug, PartialEq, Eq)]
+pub enum Gate {
+    /// Still waiting for the trigger, drop the word
+    Waiting,
+    /// This word is the trigger
+    Fired,
+    /// Capture is running
+    Open,
+}
+
+impl StartTrigger {
+    /// `pre` is how many words from before the trigger to keep
+    pub fn new(word: Option<u32>, include: bool, pre: usize) -> Self {
+        Self {
+            word,
+            include,
+            fired: word.is_none(),
+            pre,
+            history: VecDeque::with_capacity(pre),
+            external: false,
+        }
+    }
+
+    /// Hold output back until `fire`, e.g. for a watchpoint, even without a trigger word
+    pub fn arm(&mut self) {
+        self.fired = false;
+        self.external = true;
+    }
+
+    /// Start the capture from outside the stream.  Returns false if it had already started.
+    pub fn fire(&mut self) -> bool {
+        !std::mem::replace(&mut self.fired, true)
+    }
+
+    /// Change the trigger word, e.g. after a config reload.  A capture that already started
+    /// keeps running.
+    pub fn update(&mut self, word: Option<u32>, include: bool, pre: usize) {
+        self.word = word;
+        self.include = include;
+        self.fired |= word.is_none() && !self.external;
+        self.pre = pre;
+        while self.history.len() > pre {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn check(&mut self, record: Record) -> Gate {
+        if self.fired {
+            Gate::Open
+        } else if Some(record.value) == self.word {
+            self.fired = true;
+            Gate::Fired
+        } else {
+            if self.pre > 0 {
+                if self.history.len() == self.pre {
+                    self.history.pop_front();
+                }
+                self.history.push_back(record);
+            }
+            Gate::Waiting
+        }
+    }
+
+    /// The words seen just before the trigger fired, oldest first
+    pub fn take_history(&mut self) -> Vec<Record> {
+        self.history.drain(..).collect()
+    }
+
+    /// Whether the trigger word itself is part of the capture
+    pub fn include(&self) -> bool {
+        self.include
+    }
+}
+
+/// Ends the capture when a sentinel word is seen, optionally after some more words
+pub struct StopTrigger {
+    word: Option<u32>,
+    post: u64,
+    remaining: Option<u64>,
+}
+
+impl StopTrigger {
+    pub fn new(word: Option<u32>, post: u64) -> Self {
+        Self {
+            word,
+            post,
+            remaining: None,
+        }
+    }
+
+    /// Change the trigger, e.g. after a config reload.  Has no effect once the trigger fired.
+    pub fn update(&mut self, word: Option<u32>, post: u64) {
+        self.word = word;
+        self.post = post;
+    }
+
+    /// Account for a word that is about to be output.  Returns true if it is the stop trigger.
+    pub fn check(&mut self, word: u32) -> bool {
+        match &mut self.remaining {
+            Some(remaining) => {
+                *remaining = remaining.saturating_sub(1);
+                false
+            }
+            None if Some(word) == self.word => {
+                self.remaining = Some(self.post);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop the capture from outside the stream, after the words --post-trigger asks for.
+    /// Returns false if it was already stopping.
+    pub fn fire(&mut self) -> bool {
+        if self.remaining.is_some() {
+            return false;
+        }
+        self.remaining = Some(self.post);
+        true
+    }
+
+    /// True once the trigger and all the words after it have been seen
+    pub fn done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dcc_stream::{Clock, VirtualClock};
+    use std::time::Duration;
+
+    /// `value` read now, a millisecond after the word before
+    fn read(clock: &VirtualClock, value: u32) -> Record {
+        clock.advance(Duration::from_millis(1));
+        Record {
+            timestamp: clock.now().as_micros(),
+            value,
+            interpolated: false,
+        }
+    }
+
+    #[test]
+    fn start_without_a_word_is_open() {
+        let clock = VirtualClock::new();
+        let mut start = StartTrigger::new(None, false, 4);
+        assert_eq!(start.check(read(&clock, 1)), Gate::Open);
+        assert!(start.take_history().is_empty());
+    }
+
+    #[test]
+    fn start_keeps_the_words_before_it() {
+        let clock = VirtualClock::new();
+        let mut start = StartTrigger::new(Some(0xaa), true, 2);
+        for value in 1..=3 {
+            assert_eq!(start.check(read(&clock, value)), Gate::Waiting);
+        }
+        assert_eq!(start.check(read(&clock, 0xaa)), Gate::Fired);
+        let history = start.take_history();
+        assert_eq!(history.iter().map(|r| r.value).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(history.iter().map(|r| r.timestamp).collect::<Vec<_>>(), [2000, 3000]);
+        assert!(start.include());
+        // Later trigger words are just data
+        assert_eq!(start.check(read(&clock, 0xaa)), Gate::Open);
+    }
+
+    #[test]
+    fn start_fired_from_outside() {
+        let clock = VirtualClock::new();
+        let mut start = StartTrigger::new(None, false, 0);
+        start.arm();
+        assert_eq!(start.check(read(&clock, 1)), Gate::Waiting);
+        // A reload without a trigger word doesn't open a watchpoint's gate
+        start.update(None, false, 0);
+        assert_eq!(start.check(read(&clock, 2)), Gate::Waiting);
+        assert!(start.fire());
+        assert!(!start.fire());
+        assert_eq!(start.check(read(&clock, 3)), Gate::Open);
+    }
+
+    #[test]
+    fn start_update_trims_the_history() {
+        let clock = VirtualClock::new();
+        let mut start = StartTrigger::new(Some(0xaa), false, 4);
+        for value in 1..=4 {
+            start.check(read(&clock, value));
+        }
+        start.update(Some(0xbb), false, 1);
+        assert_eq!(start.check(read(&clock, 0xaa)), Gate::Waiting);
+        assert_eq!(start.check(read(&clock, 0xbb)), Gate::Fired);
+        assert_eq!(start.take_history().iter().map(|r| r.value).collect::<Vec<_>>(), [0xaa]);
+    }
+
+    #[test]
+    fn stop_after_post_trigger_words() {
+        let mut stop = StopTrigger::new(Some(0xee), 2);
+        assert!(!stop.check(1));
+        assert!(stop.check(0xee));
+        assert!(!stop.done());
+        assert!(!stop.check(0xee));
+        assert!(!stop.done());
+        stop.check(3);
+        assert!(stop.done());
+    }
+
+    #[test]
+    fn stop_on_the_word_itself() {
+        let mut stop = StopTrigger::new(Some(0xee), 0);
+        assert!(stop.check(0xee));
+        assert!(stop.done());
+    }
+
+    #[test]
+    fn stop_fired_from_outside() {
+        let mut stop = StopTrigger::new(None, 1);
+        assert!(!stop.check(0xee));
+        assert!(stop.fire());
+        assert!(!stop.fire());
+        assert!(!stop.done());
+        stop.check(1);
+        assert!(stop.done());
+    }
+}
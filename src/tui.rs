@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Sparkline};
+use ratatui::DefaultTerminal;
+
+use crate::stats::Stats;
+
+// Lines of stream output kept for the scrolling pane
+const MAX_LINES: usize = 1000;
+// Seconds of history shown in the graphs
+const MAX_HISTORY: usize = 300;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something the user asked for with a key press
+pub enum Action {
+    Quit,
+    Marker,
+    TogglePause,
+    /// Double the batch size, or halve it
+    Queue(bool),
+    /// Double TCK, or halve it
+    Clock(bool),
+}
+
+/// Full screen view of the stream with live throughput and duplicate graphs
+pub struct Tui {
+    terminal: DefaultTerminal,
+    lines: VecDeque<String>,
+    paused: bool,
+    /// The batch size and TCK, for the status line
+    tuning: (usize, u32),
+    throughput: VecDeque<u64>,
+    duplicates: VecDeque<u64>,
+    last_draw: Instant,
+    last_sample: Instant,
+    sample_total: u64,
+    sample_dup: u64,
+}
+
+impl Tui {
+    pub fn new() -> io::Result<Self> {
+        let terminal = ratatui::try_init()?;
+        let now = Instant::now();
+        Ok(Self {
+            terminal,
+            lines: VecDeque::new(),
+            paused: false,
+            tuning: (0, 0),
+            throughput: VecDeque::new(),
+            duplicates: VecDeque::new(),
+            last_draw: now,
+            last_sample: now,
+            sample_total: 0,
+            sample_dup: 0,
+        })
+    }
+
+    /// Show whether output is paused in the status line
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Show the batch size and TCK in the status line
+    pub fn set_tuning(&mut self, queue_size: usize, baud: u32) {
+        self.tuning = (queue_size, baud);
+    }
+
+    /// Add a line to the stream pane
+    pub fn push(&mut self, line: String) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn sample(&mut self, stats: &Stats) {
+        let words = stats.total - self.sample_total;
+        let dups = stats.dup - self.sample_dup;
+        let secs = self.last_sample.elapsed().as_secs_f64();
+        let kbps = (words as f64 * 32.0 / 1000.0 / secs) as u64;
+        let dup_pct = (dups * 100).checked_div(words).unwrap_or(0);
+
+        for (history, value) in [(&mut self.throughput, kbps), (&mut self.duplicates, dup_pct)] {
+            if history.len() == MAX_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(value);
+        }
+
+        self.sample_total = stats.total;
+        self.sample_dup = stats.dup;
+        self.last_sample = Instant::now();
+    }
+
+    /// Handle pending key presses and redraw if it is time.  This never blocks.
+    pub fn update(&mut self, stats: &Stats) -> io::Result<Option<Action>> {
+        let mut action = None;
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') => action = Some(Action::Quit),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => action = Some(Action::Quit),
+                    KeyCode::Char('p') | KeyCode::Char(' ') => action = Some(Action::TogglePause),
+                    KeyCode::Char('m') => action = Some(Action::Marker),
+                    KeyCode::Char('+') | KeyCode::Char('=') => action = Some(Action::Queue(true)),
+                    KeyCode::Char('-') => action = Some(Action::Queue(false)),
+                    KeyCode::Char('>') | KeyCode::Char('.') => action = Some(Action::Clock(true)),
+                    KeyCode::Char('<') | KeyCode::Char(',') => action = Some(Action::Clock(false)),
+                    _ => {}
+                }
+            }
+        }
+
+        if self.last_sample.elapsed() >= SAMPLE_INTERVAL {
+            self.sample(stats);
+        }
+        if self.last_draw.elapsed() >= REDRAW_INTERVAL {
+            self.draw(stats)?;
+            self.last_draw = Instant::now();
+        }
+        Ok(action)
+    }
+
+    fn draw(&mut self, stats: &Stats) -> io::Result<()> {
+        let throughput: Vec<u64> = self.throughput.iter().copied().collect();
+        let duplicates: Vec<u64> = self.duplicates.iter().copied().collect();
+        let counters = format!(
+            "total: {}  duplicate: {}  errors: {}  reattaches: {}  waits: {}  faults: {}  queue: {}  TCK: {} Hz{}",
+            stats.total,
+            stats.dup,
+            stats.errors,
+            stats.reattaches,
+            stats.waits,
+            stats.faults,
+            self.tuning.0,
+            self.tuning.1,
+            if self.paused { "  [PAUSED]" } else { "" }
+        );
+        let lines = &self.lines;
+
+        self.terminal.draw(|frame| {
+            let [stream, graphs, status] =
+                Layout::vertical([Constraint::Min(3), Constraint::Length(6), Constraint::Length(3)]).areas(frame.area());
+            let [rate, dups] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(graphs);
+
+            let height = stream.height.saturating_sub(2) as usize;
+            let visible: Vec<Line> = lines
+                .iter()
+                .skip(lines.len().saturating_sub(height))
+                .map(|l| Line::raw(l.as_str()))
+                .collect();
+            frame.render_widget(Paragraph::new(visible).block(Block::bordered().title("Stream")), stream);
+
+            let last_rate = throughput.last().copied().unwrap_or(0);
+            frame.render_widget(
+                Sparkline::default()
+                    .block(Block::bordered().title(format!("Throughput {} kbps", last_rate)))
+                    .data(&throughput[throughput.len().saturating_sub(rate.width as usize)..]),
+                rate,
+            );
+            let last_dups = duplicates.last().copied().unwrap_or(0);
+            frame.render_widget(
+                Sparkline::default()
+                    .block(Block::bordered().title(format!("Duplicates {}%", last_dups)))
+                    .max(100)
+                    .data(&duplicates[duplicates.len().saturating_sub(dups.width as usize)..]),
+                dups,
+            );
+
+            frame.render_widget(
+                Paragraph::new(counters).block(Block::bordered().title("q: quit  p: pause/resume  m: marker  +/-: queue  </>: TCK")),
+                status,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
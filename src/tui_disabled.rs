@@ -0,0 +1,38 @@
+//! Stand-in for the full screen view in builds without the `tui` feature.  `--tui` is refused
+//! while parsing the arguments, so a `Tui` is never created.
+use std::io;
+
+use crate::stats::Stats;
+
+#[allow(dead_code)]
+pub enum Action {
+    Quit,
+    Marker,
+    TogglePause,
+    Queue(bool),
+    Clock(bool),
+}
+
+pub enum Tui {}
+
+impl Tui {
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "built without the tui feature"))
+    }
+
+    pub fn set_paused(&mut self, _paused: bool) {
+        match *self {}
+    }
+
+    pub fn set_tuning(&mut self, _queue_size: usize, _baud: u32) {
+        match *self {}
+    }
+
+    pub fn push(&mut self, _line: String) {
+        match *self {}
+    }
+
+    pub fn update(&mut self, _stats: &Stats) -> io::Result<Option<Action>> {
+        match *self {}
+    }
+}